@@ -0,0 +1,1399 @@
+//! Attachment lookups, files and media inspection
+
+use super::*;
+
+/// The halves of a Live Photo
+pub(crate) enum LivePhotoPart {
+    Image,
+    Video,
+}
+
+/// Classify a file as a possible Live Photo still or motion part by extension
+pub(crate) fn live_photo_part(path: &Path) -> Option<LivePhotoPart> {
+    match path.extension()?.to_string_lossy().to_lowercase().as_str() {
+        "heic" | "jpg" | "jpeg" => Some(LivePhotoPart::Image),
+        "mov" => Some(LivePhotoPart::Video),
+        _ => None,
+    }
+}
+
+/// Find the file next to `path` with the same name and one of `extensions`
+pub(crate) fn live_photo_partner(path: &Path, extensions: &[&str]) -> Option<PathBuf> {
+    extensions
+        .iter()
+        .map(|extension| path.with_extension(extension))
+        .find(|partner| partner.is_file())
+}
+
+#[pymethods]
+impl IMessageDB {
+    /// Summarize attachment storage by MIME type, chat, and year
+    ///
+    /// Sizes come from `attachment.total_bytes`. An attachment counts once,
+    /// toward the chat and year of the earliest message it was sent in.
+    pub(crate) fn attachment_stats(&self, py: Python) -> PyResult<PyAttachmentStats> {
+        self.without_gil(py, |db| {
+            let mut stmt = db.conn.prepare_cached(
+                "SELECT bytes, mime_type, chat_id, date
+                FROM (
+                    SELECT
+                        COALESCE(a.total_bytes, 0) as bytes,
+                        COALESCE(NULLIF(a.mime_type, ''), 'unknown') as mime_type,
+                        c.chat_id,
+                        m.date,
+                        ROW_NUMBER() OVER (
+                            PARTITION BY a.ROWID
+                            ORDER BY m.date IS NULL, m.date, m.ROWID, c.chat_id
+                        ) as n
+                    FROM attachment a
+                    LEFT JOIN message_attachment_join maj ON maj.attachment_id = a.ROWID
+                    LEFT JOIN message m ON m.ROWID = maj.message_id
+                    LEFT JOIN chat_message_join c ON c.message_id = m.ROWID
+                )
+                WHERE n = 1"
+            ).map_err(|e| runtime_err("Failed to prepare attachment stats query", e))?;
+
+            let rows = stmt.query_map([], |row| {
+                Ok((
+                    row.get::<_, i64>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, Option<i32>>(2)?,
+                    row.get::<_, Option<i64>>(3)?,
+                ))
+            }).map_err(|e| runtime_err("Failed to execute attachment stats query", e))?;
+
+            let mut stats = PyAttachmentStats {
+                attachment_count: 0,
+                total_bytes: 0,
+                bytes_by_mime_type: HashMap::new(),
+                bytes_by_chat: HashMap::new(),
+                bytes_by_year: HashMap::new(),
+            };
+            for row in rows {
+                let (bytes, mime_type, chat_id, date) = row.map_err(|e| runtime_err("Failed to read attachment stats", e))?;
+
+                stats.attachment_count += 1;
+                stats.total_bytes += bytes;
+                *stats.bytes_by_mime_type.entry(mime_type).or_default() += bytes;
+                if let Some(chat_id) = chat_id {
+                    *stats.bytes_by_chat.entry(chat_id).or_default() += bytes;
+                }
+                let year = date
+                    .and_then(|date| chrono::DateTime::from_timestamp(apple_to_unix(date) as i64, 0))
+                    .map(|date| date.year());
+                if let Some(year) = year {
+                    *stats.bytes_by_year.entry(year).or_default() += bytes;
+                }
+            }
+
+            Ok(stats)
+        })
+    }
+
+    /// Get message attachments
+    pub(crate) fn get_message_attachments(&self, message_rowid: i32) -> PyResult<Vec<PyAttachment>> {
+        let mut stmt = self.conn.prepare_cached(
+            "SELECT a.*
+             FROM attachment a
+             INNER JOIN message_attachment_join maj ON a.rowid = maj.attachment_id
+             WHERE maj.message_id = ?"
+        ).map_err(|e| runtime_err("Failed to prepare attachments query", e))?;
+
+        let attachments = stmt.query_map([message_rowid], |row| {
+            attachment_from_row(row, &self.db_path)
+        }).map_err(|e| runtime_err("Failed to execute attachments query", e))?;
+
+        let mut result = Vec::new();
+        for attachment in attachments {
+            result.push(attachment.map_err(|e| runtime_err("Failed to read attachment", e))?);
+        }
+
+        Ok(result)
+    }
+
+    /// Get an attachment by ROWID
+    pub(crate) fn get_attachment(&self, attachment_rowid: i32) -> PyResult<Option<PyAttachment>> {
+        self.conn.query_row(
+            "SELECT * FROM attachment WHERE ROWID = ?",
+            [attachment_rowid],
+            |row| attachment_from_row(row, &self.db_path),
+        ).optional().map_err(|e| runtime_err("Failed to fetch attachment", e))
+    }
+
+    /// Find attachments across the database, with the message and chat each was sent in
+    ///
+    /// All filters are optional: `mime_prefix` matches the start of the MIME type
+    /// (e.g. `"image/"` or `"application/pdf"`), `min_bytes` the recorded size,
+    /// `after`/`before` the message's Unix timestamp, and `chat_id` the chat.
+    /// Results are in chronological order.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn query_attachments(
+        &self,
+        py: Python,
+        mime_prefix: Option<&str>,
+        min_bytes: Option<i64>,
+        after: Option<f64>,
+        chat_id: Option<i32>,
+        before: Option<f64>,
+        limit: Option<usize>,
+    ) -> PyResult<Vec<PyAttachmentContext>> {
+        self.without_gil(py, |db| {
+            let mut stmt = db.conn.prepare_cached(
+                "SELECT
+                    a.*,
+                    m.ROWID as message_rowid,
+                    m.guid as message_guid,
+                    c.chat_id as message_chat_id,
+                    m.handle_id as message_handle_id,
+                    m.is_from_me as message_is_from_me,
+                    m.date as message_date
+                FROM attachment a
+                INNER JOIN message_attachment_join maj ON maj.attachment_id = a.ROWID
+                INNER JOIN message m ON m.ROWID = maj.message_id
+                LEFT JOIN chat_message_join c ON c.message_id = m.ROWID
+                WHERE (?1 IS NULL OR substr(a.mime_type, 1, length(?1)) = ?1)
+                    AND (?2 IS NULL OR a.total_bytes >= ?2)
+                    AND (?3 IS NULL OR m.date > ?3)
+                    AND (?4 IS NULL OR c.chat_id = ?4)
+                    AND (?5 IS NULL OR m.date < ?5)
+                GROUP BY a.ROWID, m.ROWID
+                ORDER BY m.date ASC, a.ROWID ASC
+                LIMIT ?6"
+            ).map_err(|e| runtime_err("Failed to prepare attachments query", e))?;
+
+            let params = rusqlite::params![
+                mime_prefix,
+                min_bytes,
+                after.map(unix_to_apple),
+                chat_id,
+                before.map(unix_to_apple),
+                limit.map(|l| l as i64).unwrap_or(-1),
+            ];
+            let rows = stmt.query_map(params, |row| {
+                Ok(PyAttachmentContext {
+                    attachment: attachment_from_row(row, &db.db_path)?,
+                    message_rowid: row.get("message_rowid")?,
+                    message_guid: row.get("message_guid")?,
+                    chat_id: row.get("message_chat_id")?,
+                    handle_id: row.get("message_handle_id")?,
+                    is_from_me: row.get("message_is_from_me")?,
+                    date: apple_to_unix(row.get("message_date")?),
+                })
+            }).map_err(|e| runtime_err("Failed to execute attachments query", e))?;
+
+            let mut result = Vec::new();
+            for row in rows {
+                result.push(row.map_err(|e| runtime_err("Failed to read attachment", e))?);
+            }
+
+            Ok(result)
+        })
+    }
+
+    /// Copy an attachment's file into `dest_dir`, returning the new path
+    ///
+    /// The copy is named `<guid>_<original name>` so attachments with the same
+    /// name never overwrite each other. `dest_dir` is created if needed. Raises
+    /// `IOError` when the file is not on disk, e.g. because it was offloaded.
+    ///
+    /// Like imessage-exporter, `convert_images` transcodes HEIC/HEIF images to
+    /// JPEG (with `sips` or ImageMagick) and `convert_videos` transcodes QuickTime
+    /// videos, usually HEVC, to H.264 MP4 (with `ffmpeg`). When no converter is
+    /// installed or it fails, the original file is copied instead.
+    pub(crate) fn copy_attachment(&self, py: Python, attachment_rowid: i32, dest_dir: PathBuf, convert_images: Option<bool>, convert_videos: Option<bool>) -> PyResult<String> {
+        self.without_gil(py, |db| {
+            let attachment = db.get_attachment(attachment_rowid)?.ok_or_else(|| {
+                PyErr::new::<pyo3::exceptions::PyValueError, _>(
+                    format!("No attachment with ROWID {}", attachment_rowid)
+                )
+            })?;
+            let source = resolve_attachment_path(attachment.filename.as_deref(), &db.db_path).map_err(|reason| io_err(format!("Attachment {} is unavailable", attachment_rowid), reason))?;
+
+            let dest = export_attachment_file(
+                &attachment,
+                &source,
+                &dest_dir,
+                convert_images.unwrap_or(false),
+                convert_videos.unwrap_or(false),
+            ).map_err(PyErr::new::<pyo3::exceptions::PyIOError, _>)?;
+
+            Ok(dest.to_string_lossy().to_string())
+        })
+    }
+
+    /// Export attachments into `dest` in one pass, with a `manifest.json`
+    ///
+    /// `organize_by` picks the folder layout: `"chat"` (default, `chat-<id>/`),
+    /// `"date"` (`<year>/<month>/` of the message), or `"type"` (`image/`,
+    /// `video/`, ...). Files are named like `copy_attachment` names them, and
+    /// `convert_images` transcodes HEIC to JPEG. With `deduplicate` (default),
+    /// a file whose contents were already exported is not written again; its
+    /// manifest entry points at the first copy. `mime_prefix`, `chat_id`,
+    /// `after`, and `before` filter like `query_attachments`. `progress` is
+    /// called with `(done, total)` after each attachment.
+    ///
+    /// The manifest lists every attachment with its message, chat, source path,
+    /// exported path, SHA-256, and the reason it was skipped, if any.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn export_attachments(
+        &self,
+        py: Python,
+        dest: PathBuf,
+        organize_by: Option<&str>,
+        convert_images: Option<bool>,
+        deduplicate: Option<bool>,
+        mime_prefix: Option<&str>,
+        chat_id: Option<i32>,
+        after: Option<f64>,
+        before: Option<f64>,
+        progress: Option<PyObject>,
+    ) -> PyResult<PyExportSummary> {
+        let organize_by = organize_by.unwrap_or("chat");
+        if !matches!(organize_by, "chat" | "date" | "type") {
+            return Err(PyErr::new::<pyo3::exceptions::PyValueError, _>(
+                format!("Unknown organize_by: {} (expected \"chat\", \"date\", or \"type\")", organize_by)
+            ));
+        }
+        let convert_images = convert_images.unwrap_or(false);
+        let deduplicate = deduplicate.unwrap_or(true);
+
+        let mut seen = std::collections::HashSet::new();
+        let contexts: Vec<PyAttachmentContext> = self
+            .query_attachments(py, mime_prefix, None, after, chat_id, before, None)?
+            .into_iter()
+            .filter(|context| seen.insert(context.attachment.rowid))
+            .collect();
+
+        // Hashes only need to live for this export
+        let mut hashes = open_hash_cache(None).map_err(|e| runtime_err("Failed to open hash cache", e))?;
+        let mut exported_by_hash: HashMap<String, String> = HashMap::new();
+
+        let mut summary = PyExportSummary {
+            exported: 0,
+            duplicates: 0,
+            unavailable: 0,
+            manifest_path: dest.join("manifest.json").to_string_lossy().to_string(),
+        };
+        let mut manifest = Vec::with_capacity(contexts.len());
+        let total = contexts.len();
+
+        for (done, context) in contexts.into_iter().enumerate() {
+            // Hashing and copying run with the GIL released; only `progress` needs it
+            let (summary, hashes, exported_by_hash) = (&mut summary, &mut hashes, &mut exported_by_hash);
+            let (db_path, dest) = (&self.db_path, &dest);
+            let entry = py.allow_threads(move || {
+                export_listed_attachment(&context, db_path, dest, organize_by, convert_images, deduplicate, hashes, exported_by_hash, summary)
+            });
+            manifest.push(entry);
+
+            if let Some(callback) = &progress {
+                callback.call1(py, (done + 1, total))?;
+            }
+        }
+
+        let json = serde_json::to_string_pretty(&manifest).map_err(|e| runtime_err("Failed to encode manifest", e))?;
+        std::fs::create_dir_all(&dest)
+            .and_then(|_| std::fs::write(&summary.manifest_path, json))
+            .map_err(|e| io_err(format!("Failed to write {}", summary.manifest_path), e))?;
+
+        Ok(summary)
+    }
+
+    /// Read an attachment's file into memory
+    ///
+    /// Raises `ValueError` instead of reading when the file is larger than
+    /// `max_bytes`, and `IOError` when it is not on disk.
+    pub(crate) fn read_attachment(&self, py: Python, attachment_rowid: i32, max_bytes: Option<u64>) -> PyResult<PyObject> {
+        let bytes = self.without_gil(py, |db| {
+            let attachment = db.get_attachment(attachment_rowid)?.ok_or_else(|| {
+                PyErr::new::<pyo3::exceptions::PyValueError, _>(
+                    format!("No attachment with ROWID {}", attachment_rowid)
+                )
+            })?;
+            let source = resolve_attachment_path(attachment.filename.as_deref(), &db.db_path).map_err(|reason| io_err(format!("Attachment {} is unavailable", attachment_rowid), reason))?;
+
+            if let Some(max_bytes) = max_bytes {
+                let size = std::fs::metadata(&source).map(|meta| meta.len()).map_err(|e| io_err(format!("Failed to read {}", source.display()), e))?;
+                if size > max_bytes {
+                    return Err(PyErr::new::<pyo3::exceptions::PyValueError, _>(
+                        format!("Attachment {} is {} bytes, over the {} byte limit", attachment_rowid, size, max_bytes)
+                    ));
+                }
+            }
+
+            std::fs::read(&source).map_err(|e| io_err(format!("Failed to read {}", source.display()), e))
+        })?;
+
+        Ok(PyBytes::new_bound(py, &bytes).into())
+    }
+
+    /// Group attachments whose files have the same SHA-256 hash
+    ///
+    /// Only files that share their size with another file are hashed. Hashes
+    /// are cached in an `attachment_hash` table in the SQLite file at
+    /// `cache_path` and reused while a file's size and modification time are
+    /// unchanged; without `cache_path` nothing is persisted. Attachments whose
+    /// files are not on disk are skipped.
+    pub(crate) fn find_duplicate_attachments(&self, py: Python, cache_path: Option<PathBuf>) -> PyResult<Vec<PyDuplicateGroup>> {
+        self.without_gil(py, |db| {
+            let cache = open_hash_cache(cache_path.as_deref()).map_err(|e| io_err("Failed to open hash cache", e))?;
+
+            let mut stmt = db.conn.prepare_cached(
+                "SELECT * FROM attachment ORDER BY ROWID"
+            ).map_err(|e| runtime_err("Failed to prepare attachments query", e))?;
+            let attachments = stmt.query_map([], |row| {
+                attachment_from_row(row, &db.db_path)
+            }).map_err(|e| runtime_err("Failed to execute attachments query", e))?;
+
+            // Bucket by size first so unique sizes never need hashing
+            let mut by_size: HashMap<u64, Vec<(PyAttachment, PathBuf, i64)>> = HashMap::new();
+            for attachment in attachments {
+                let attachment = attachment.map_err(|e| runtime_err("Failed to read attachment", e))?;
+                let Ok(path) = resolve_attachment_path(attachment.filename.as_deref(), &db.db_path) else {
+                    continue;
+                };
+                let Ok(meta) = std::fs::metadata(&path) else {
+                    continue;
+                };
+                let mtime = meta.modified().ok()
+                    .and_then(|time| time.duration_since(std::time::UNIX_EPOCH).ok())
+                    .map(|since| since.as_secs() as i64)
+                    .unwrap_or(0);
+                by_size.entry(meta.len()).or_default().push((attachment, path, mtime));
+            }
+
+            let mut groups: HashMap<(u64, String), Vec<PyAttachment>> = HashMap::new();
+            for (size, candidates) in by_size.into_iter().filter(|(_, c)| c.len() > 1) {
+                for (attachment, path, mtime) in candidates {
+                    let Some(sha256) = cached_sha256(&cache, &path, size, mtime) else {
+                        continue;
+                    };
+                    groups.entry((size, sha256)).or_default().push(attachment);
+                }
+            }
+
+            let mut result: Vec<PyDuplicateGroup> = groups
+                .into_iter()
+                .filter(|(_, attachments)| attachments.len() > 1)
+                .map(|((size, sha256), mut attachments)| {
+                    attachments.sort_by_key(|attachment| attachment.rowid);
+                    PyDuplicateGroup { sha256, size, attachments }
+                })
+                .collect();
+            result.sort_by_key(|group| group.attachments[0].rowid);
+
+            Ok(result)
+        })
+    }
+
+    /// Get the duration and, optionally, a waveform of a .caf or .m4a audio attachment
+    ///
+    /// With `waveform_points`, the audio is downsampled to that many peak
+    /// amplitudes for drawing a voice message bubble; this needs `ffmpeg`.
+    /// Returns `None` when the file is not on disk.
+    pub(crate) fn get_audio_info(&self, py: Python, attachment_rowid: i32, waveform_points: Option<usize>) -> PyResult<Option<PyAudioInfo>> {
+        self.without_gil(py, |db| {
+            let attachment = db.get_attachment(attachment_rowid)?.ok_or_else(|| {
+                PyErr::new::<pyo3::exceptions::PyValueError, _>(
+                    format!("No attachment with ROWID {}", attachment_rowid)
+                )
+            })?;
+            let Ok(source) = resolve_attachment_path(attachment.filename.as_deref(), &db.db_path) else {
+                return Ok(None);
+            };
+
+            let duration = std::fs::read(&source).ok().and_then(|data| audio_file_duration(&data));
+            let waveform = waveform_points.and_then(|points| audio_waveform(&source, points));
+
+            Ok(Some(PyAudioInfo { attachment_rowid, duration, waveform }))
+        })
+    }
+
+    /// Read the duration, resolution, and codec of an MP4/QuickTime video attachment
+    ///
+    /// Returns `None` when the file is not on disk or is not an MP4/QuickTime
+    /// container.
+    pub(crate) fn get_video_info(&self, py: Python, attachment_rowid: i32) -> PyResult<Option<PyVideoInfo>> {
+        self.without_gil(py, |db| {
+            let attachment = db.get_attachment(attachment_rowid)?.ok_or_else(|| {
+                PyErr::new::<pyo3::exceptions::PyValueError, _>(
+                    format!("No attachment with ROWID {}", attachment_rowid)
+                )
+            })?;
+            let Ok(source) = resolve_attachment_path(attachment.filename.as_deref(), &db.db_path) else {
+                return Ok(None);
+            };
+            // Only the `moov` box is read, so large recordings stay on disk
+            let Some(data) = read_mp4_moov(&source) else {
+                return Ok(None);
+            };
+
+            let track = mp4_video_track(&data);
+            Ok(Some(PyVideoInfo {
+                attachment_rowid,
+                duration: mp4_duration(&data),
+                width: track.as_ref().map(|(width, _, _)| *width),
+                height: track.as_ref().map(|(_, height, _)| *height),
+                codec: track.map(|(_, _, codec)| codec),
+            }))
+        })
+    }
+
+    /// Render a small JPEG preview of an image or video attachment
+    ///
+    /// The preview fits within `max_px` (default 256) on its longest side.
+    /// HEIC images and video frames go through the same converters as
+    /// `copy_attachment`. Returns `None` when the file is not on disk or cannot
+    /// be decoded.
+    pub(crate) fn get_attachment_thumbnail(&self, py: Python, attachment_rowid: i32, max_px: Option<u32>) -> PyResult<Option<PyObject>> {
+        let jpeg = self.without_gil(py, |db| -> PyResult<Option<Vec<u8>>> {
+            let attachment = db.get_attachment(attachment_rowid)?.ok_or_else(|| {
+                PyErr::new::<pyo3::exceptions::PyValueError, _>(
+                    format!("No attachment with ROWID {}", attachment_rowid)
+                )
+            })?;
+            let Ok(source) = resolve_attachment_path(attachment.filename.as_deref(), &db.db_path) else {
+                return Ok(None);
+            };
+
+            let Some(image) = decode_preview_image(&source, attachment.mime_type.as_deref()) else {
+                return Ok(None);
+            };
+            let max_px = max_px.unwrap_or(256).max(1);
+            let thumbnail = image.thumbnail(max_px, max_px).to_rgb8();
+
+            let mut jpeg = Vec::new();
+            image::codecs::jpeg::JpegEncoder::new_with_quality(&mut jpeg, 80)
+                .encode_image(&thumbnail)
+                .map_err(|e| runtime_err("Failed to encode thumbnail", e))?;
+            Ok(Some(jpeg))
+        })?;
+
+        Ok(jpeg.map(|jpeg| PyBytes::new_bound(py, &jpeg).into()))
+    }
+
+    /// Read the capture time, GPS position, and camera of an image attachment
+    ///
+    /// Works for JPEG, HEIC, PNG, TIFF, and WebP files. Returns `None` when the
+    /// file is not on disk or has no EXIF data.
+    pub(crate) fn get_attachment_metadata(&self, py: Python, attachment_rowid: i32) -> PyResult<Option<PyImageMetadata>> {
+        self.without_gil(py, |db| {
+            let attachment = db.get_attachment(attachment_rowid)?.ok_or_else(|| {
+                PyErr::new::<pyo3::exceptions::PyValueError, _>(
+                    format!("No attachment with ROWID {}", attachment_rowid)
+                )
+            })?;
+            let Ok(source) = resolve_attachment_path(attachment.filename.as_deref(), &db.db_path) else {
+                return Ok(None);
+            };
+
+            Ok(image_metadata(attachment_rowid, &source))
+        })
+    }
+
+    /// Decode the iMessage app payload of a message
+    ///
+    /// Returns `None` for messages without a `balloon_bundle_id`. Otherwise the
+    /// dictionary holds `bundle_id`, `app_type`, the decoded `payload_data` plist
+    /// under `payload`, and for known Apple bundles the fields shown in the bubble
+    /// (`title`, `subtitle`, `caption`, `app_name`, ...).
+    pub(crate) fn get_app_payload(&self, py: Python, message_rowid: i32) -> PyResult<Option<PyObject>> {
+        let msg = self.conn.query_row(
+            "SELECT m.*, NULL as chat_id, 0 as num_attachments, NULL as deleted_from, 0 as num_replies
+             FROM message as m WHERE m.ROWID = ?",
+            [message_rowid],
+            Message::from_row,
+        ).optional().map_err(|e| runtime_err("Failed to fetch message", e))?;
+
+        let Some(msg) = msg else {
+            return Ok(None);
+        };
+        let (Some(bundle_id), Some(app_type)) = app_info(&msg) else {
+            return Ok(None);
+        };
+
+        let payload = msg.payload_data(&self.conn).map(|raw| {
+            parse_ns_keyed_archiver(&raw).unwrap_or(raw)
+        });
+
+        let dict = PyDict::new_bound(py);
+        dict.set_item("bundle_id", bundle_id)?;
+        dict.set_item("app_type", &app_type)?;
+
+        if let Some(payload) = &payload {
+            if app_type == "url" {
+                if let Ok(preview) = URLMessage::from_map(payload) {
+                    dict.set_item("url", preview.url.or(preview.original_url))?;
+                    dict.set_item("title", preview.title)?;
+                    dict.set_item("summary", preview.summary)?;
+                    dict.set_item("site_name", preview.site_name)?;
+                }
+            } else if let Ok(app) = AppMessage::from_map(payload) {
+                dict.set_item("url", app.url)?;
+                dict.set_item("image", app.image)?;
+                dict.set_item("title", app.title)?;
+                dict.set_item("subtitle", app.subtitle)?;
+                dict.set_item("caption", app.caption)?;
+                dict.set_item("subcaption", app.subcaption)?;
+                dict.set_item("trailing_caption", app.trailing_caption)?;
+                dict.set_item("trailing_subcaption", app.trailing_subcaption)?;
+                dict.set_item("app_name", app.app_name)?;
+                dict.set_item("ldtext", app.ldtext)?;
+            }
+        }
+
+        dict.set_item("payload", payload.map(|value| plist_to_py(py, &value)))?;
+
+        Ok(Some(dict.into()))
+    }
+
+    /// Render a handwritten message as SVG
+    ///
+    /// Returns `None` when the message is not a handwritten message. When
+    /// `output_path` is given the SVG is also written to that file.
+    pub(crate) fn render_handwriting(&self, message_rowid: i32, output_path: Option<PathBuf>) -> PyResult<Option<String>> {
+        let msg = self.conn.query_row(
+            "SELECT m.*, NULL as chat_id, 0 as num_attachments, NULL as deleted_from, 0 as num_replies
+             FROM message as m WHERE m.ROWID = ?",
+            [message_rowid],
+            Message::from_row,
+        ).optional().map_err(|e| runtime_err("Failed to fetch message", e))?;
+
+        let Some(msg) = msg else {
+            return Ok(None);
+        };
+        if app_info(&msg).1.as_deref() != Some("handwriting") {
+            return Ok(None);
+        }
+        let Some(payload) = msg.raw_payload_data(&self.conn) else {
+            return Ok(None);
+        };
+
+        let handwriting = HandwrittenMessage::from_payload(&payload).map_err(|e| runtime_err("Failed to parse handwriting", e))?;
+        let svg = handwriting.render_svg();
+
+        if let Some(path) = output_path {
+            std::fs::write(&path, &svg).map_err(|e| io_err(format!("Failed to write {}", path.display()), e))?;
+        }
+
+        Ok(Some(svg))
+    }
+}
+
+/// Build a `PyAttachment` from a row selecting the `attachment` table's columns
+///
+/// `uti` and `hide_attachment` are missing on older schemas and default to unset.
+pub(crate) fn attachment_from_row(row: &rusqlite::Row, db_path: &Path) -> rusqlite::Result<PyAttachment> {
+    Ok(PyAttachment {
+        rowid: row.get("ROWID")?,
+        guid: row.get("guid")?,
+        filename: row.get("filename")?,
+        mime_type: row.get("mime_type")?,
+        transfer_name: row.get("transfer_name")?,
+        total_bytes: row.get("total_bytes")?,
+        is_sticker: row.get::<_, Option<bool>>("is_sticker")?.unwrap_or(false),
+        hide_attachment: row.get::<_, Option<bool>>("hide_attachment").ok().flatten().unwrap_or(false),
+        uti: row.get("uti").ok().flatten(),
+        db_path: db_path.to_path_buf(),
+    })
+}
+
+/// Hash and copy one attachment for `export_attachments`, recording the outcome in `summary`
+///
+/// `hashes` is the digest cache that `cached_sha256` reads and adds to. It is
+/// borrowed mutably because a `&mut` to a connection can be moved into
+/// `allow_threads` and a shared one cannot.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn export_listed_attachment(
+    context: &PyAttachmentContext,
+    db_path: &Path,
+    dest: &Path,
+    organize_by: &str,
+    convert_images: bool,
+    deduplicate: bool,
+    hashes: &mut Connection,
+    exported_by_hash: &mut HashMap<String, String>,
+    summary: &mut PyExportSummary,
+) -> ManifestEntry {
+    let attachment = &context.attachment;
+    let mut entry = ManifestEntry {
+        attachment_rowid: attachment.rowid,
+        attachment_guid: attachment.guid.clone(),
+        message_rowid: context.message_rowid,
+        message_guid: context.message_guid.clone(),
+        chat_id: context.chat_id,
+        date: context.date,
+        mime_type: attachment.mime_type.clone(),
+        source: None,
+        path: None,
+        sha256: None,
+        duplicate_of: None,
+        error: None,
+    };
+
+    match resolve_attachment_path(attachment.filename.as_deref(), db_path) {
+        Err(reason) => {
+            summary.unavailable += 1;
+            entry.error = Some(reason);
+        }
+        Ok(source) => {
+            entry.source = Some(source.to_string_lossy().to_string());
+            let sha256 = if deduplicate {
+                std::fs::metadata(&source).ok().and_then(|meta| {
+                    let mtime = meta.modified().ok()
+                        .and_then(|time| time.duration_since(std::time::UNIX_EPOCH).ok())
+                        .map(|since| since.as_secs() as i64)
+                        .unwrap_or(0);
+                    cached_sha256(hashes, &source, meta.len(), mtime)
+                })
+            } else {
+                None
+            };
+            entry.sha256 = sha256.clone();
+
+            match sha256.as_ref().and_then(|hash| exported_by_hash.get(hash)) {
+                Some(first) => {
+                    summary.duplicates += 1;
+                    entry.path = Some(first.clone());
+                    entry.duplicate_of = Some(first.clone());
+                }
+                None => {
+                    let folder = dest.join(export_folder(organize_by, context));
+                    match export_attachment_file(attachment, &source, &folder, convert_images, false) {
+                        Ok(path) => {
+                            summary.exported += 1;
+                            let path = path.to_string_lossy().to_string();
+                            if let Some(hash) = sha256 {
+                                exported_by_hash.insert(hash, path.clone());
+                            }
+                            entry.path = Some(path);
+                        }
+                        Err(error) => {
+                            summary.unavailable += 1;
+                            entry.error = Some(error);
+                        }
+                    }
+                }
+            }
+        }
+    }
+    entry
+}
+
+/// Find an attachment file on disk, or explain why it is unavailable
+///
+/// Tries the recorded path with `~` expanded, then the same `Attachments/...`
+/// subpath inside an `Attachments` folder next to the database. Found paths
+/// are made absolute.
+pub(crate) fn resolve_attachment_path(filename: Option<&str>, db_path: &Path) -> Result<PathBuf, String> {
+    let Some(filename) = filename.filter(|f| !f.is_empty()) else {
+        return Err("no file path is recorded for the attachment".to_string());
+    };
+
+    let recorded = match filename.strip_prefix("~/") {
+        Some(rest) => PathBuf::from(home()).join(rest),
+        None => PathBuf::from(filename),
+    };
+    if recorded.is_file() {
+        return Ok(recorded.canonicalize().unwrap_or(recorded));
+    }
+
+    let copied = filename
+        .split_once("Attachments/")
+        .zip(db_path.parent())
+        .map(|((_, subpath), db_dir)| db_dir.join("Attachments").join(subpath));
+    if let Some(copied) = copied.filter(|path| path.is_file()) {
+        return Ok(copied.canonicalize().unwrap_or(copied));
+    }
+
+    Err(format!(
+        "file not found at {}; it may have been offloaded to iCloud or deleted",
+        recorded.display()
+    ))
+}
+
+/// Copy one attachment into `dest_dir` as `<guid>_<original name>`, converting it if asked
+///
+/// Falls back to copying the original when conversion is unavailable or fails.
+pub(crate) fn export_attachment_file(
+    attachment: &PyAttachment,
+    source: &Path,
+    dest_dir: &Path,
+    convert_images: bool,
+    convert_videos: bool,
+) -> Result<PathBuf, String> {
+    let name = attachment.transfer_name
+        .clone()
+        .filter(|name| !name.is_empty())
+        .or_else(|| source.file_name().map(|name| name.to_string_lossy().to_string()))
+        .unwrap_or_default()
+        .replace(['/', ':'], "_");
+    let dest = dest_dir.join(format!("{}_{}", attachment.guid, name));
+
+    std::fs::create_dir_all(dest_dir)
+        .map_err(|e| format!("Failed to create {}: {}", dest_dir.display(), e))?;
+
+    let converted = match media_conversion(attachment.mime_type.as_deref(), source) {
+        Some(MediaConversion::Image) if convert_images => transcode_image(source, &dest.with_extension("jpeg")),
+        Some(MediaConversion::Video) if convert_videos => transcode_video(source, &dest.with_extension("mp4")),
+        _ => None,
+    };
+    if let Some(converted) = converted {
+        return Ok(converted);
+    }
+
+    std::fs::copy(source, &dest)
+        .map_err(|e| format!("Failed to copy {} to {}: {}", source.display(), dest.display(), e))?;
+    Ok(dest)
+}
+
+/// Folder, relative to the export root, that an attachment is exported into
+pub(crate) fn export_folder(organize_by: &str, context: &PyAttachmentContext) -> PathBuf {
+    match organize_by {
+        "date" => chrono::DateTime::from_timestamp(context.date as i64, 0)
+            .map(|date| PathBuf::from(date.format("%Y").to_string()).join(date.format("%m").to_string()))
+            .unwrap_or_else(|| PathBuf::from("undated")),
+        "type" => {
+            let kind = context.attachment.mime_type
+                .as_deref()
+                .and_then(|mime| mime.split('/').next())
+                .filter(|kind| !kind.is_empty())
+                .unwrap_or("other");
+            PathBuf::from(kind)
+        }
+        _ => match context.chat_id {
+            Some(chat_id) => PathBuf::from(format!("chat-{}", chat_id)),
+            None => PathBuf::from("no-chat"),
+        },
+    }
+}
+
+/// One attachment's line in an export's `manifest.json`
+#[derive(Serialize)]
+pub(crate) struct ManifestEntry {
+    pub(crate) attachment_rowid: i32,
+    pub(crate) attachment_guid: String,
+    pub(crate) message_rowid: i32,
+    pub(crate) message_guid: String,
+    pub(crate) chat_id: Option<i32>,
+    pub(crate) date: f64,  // Unix timestamp of the message
+    pub(crate) mime_type: Option<String>,
+    pub(crate) source: Option<String>,  // Resolved path the file was read from
+    pub(crate) path: Option<String>,  // Exported file, or the first copy for duplicates
+    pub(crate) sha256: Option<String>,
+    pub(crate) duplicate_of: Option<String>,
+    pub(crate) error: Option<String>,  // Why the attachment was not exported
+}
+
+/// Attachment formats that many viewers cannot open
+pub(crate) enum MediaConversion {
+    Image,  // HEIC/HEIF
+    Video,  // QuickTime, usually HEVC
+}
+
+/// Decide whether an attachment needs converting for export, by MIME type or extension
+pub(crate) fn media_conversion(mime_type: Option<&str>, path: &Path) -> Option<MediaConversion> {
+    let extension = path.extension()?.to_string_lossy().to_lowercase();
+    match (mime_type, extension.as_str()) {
+        (Some("image/heic" | "image/heif"), _) | (_, "heic" | "heif") => Some(MediaConversion::Image),
+        (Some("video/quicktime"), _) | (_, "mov") => Some(MediaConversion::Video),
+        _ => None,
+    }
+}
+
+/// Run an external converter, returning `dest` if it produced the file
+pub(crate) fn run_converter(program: &str, args: &[&std::ffi::OsStr], dest: &Path) -> Option<PathBuf> {
+    let status = std::process::Command::new(program)
+        .args(args)
+        .stdin(std::process::Stdio::null())
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .status()
+        .ok()?;
+    (status.success() && dest.is_file()).then(|| dest.to_path_buf())
+}
+
+/// Convert a HEIC image to JPEG with `sips` (macOS) or ImageMagick
+pub(crate) fn transcode_image(source: &Path, dest: &Path) -> Option<PathBuf> {
+    let (src, out) = (source.as_os_str(), dest.as_os_str());
+    run_converter("sips", &["-s".as_ref(), "format".as_ref(), "jpeg".as_ref(), src, "--out".as_ref(), out], dest)
+        .or_else(|| run_converter("magick", &[src, out], dest))
+        .or_else(|| run_converter("convert", &[src, out], dest))
+}
+
+/// Convert a QuickTime video to H.264/AAC MP4 with `ffmpeg`
+pub(crate) fn transcode_video(source: &Path, dest: &Path) -> Option<PathBuf> {
+    let args: [&std::ffi::OsStr; 14] = [
+        "-y".as_ref(), "-loglevel".as_ref(), "error".as_ref(),
+        "-i".as_ref(), source.as_os_str(),
+        "-c:v".as_ref(), "libx264".as_ref(), "-pix_fmt".as_ref(), "yuv420p".as_ref(),
+        "-c:a".as_ref(), "aac".as_ref(),
+        "-movflags".as_ref(), "+faststart".as_ref(),
+        dest.as_os_str(),
+    ];
+    run_converter("ffmpeg", &args, dest)
+}
+
+/// Open the attachment hash cache at `path`, or an in-memory one, creating its table
+pub(crate) fn open_hash_cache(path: Option<&Path>) -> rusqlite::Result<Connection> {
+    let cache = match path {
+        Some(path) => Connection::open(path)?,
+        None => Connection::open_in_memory()?,
+    };
+    cache.execute_batch(
+        "CREATE TABLE IF NOT EXISTS attachment_hash (
+            path TEXT PRIMARY KEY,
+            size INTEGER NOT NULL,
+            mtime INTEGER NOT NULL,
+            sha256 TEXT NOT NULL
+        )"
+    )?;
+    Ok(cache)
+}
+
+/// Hash a file with SHA-256, reusing the cached digest while its size and mtime match
+pub(crate) fn cached_sha256(cache: &Connection, path: &Path, size: u64, mtime: i64) -> Option<String> {
+    let key = path.to_string_lossy();
+    let cached: Option<String> = cache.query_row(
+        "SELECT sha256 FROM attachment_hash WHERE path = ?1 AND size = ?2 AND mtime = ?3",
+        rusqlite::params![key, size as i64, mtime],
+        |row| row.get(0),
+    ).optional().ok().flatten();
+    if cached.is_some() {
+        return cached;
+    }
+
+    let mut file = std::fs::File::open(path).ok()?;
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut file, &mut hasher).ok()?;
+    let sha256: String = hasher.finalize().iter().map(|byte| format!("{:02x}", byte)).collect();
+
+    // A failed cache write only costs rehashing next time
+    let _ = cache.execute(
+        "INSERT OR REPLACE INTO attachment_hash (path, size, mtime, sha256) VALUES (?1, ?2, ?3, ?4)",
+        rusqlite::params![key, size as i64, mtime, sha256],
+    );
+    Some(sha256)
+}
+
+/// Grab the first frame of a video as PNG with `ffmpeg`
+pub(crate) fn video_frame(source: &Path, dest: &Path) -> Option<PathBuf> {
+    let args: [&std::ffi::OsStr; 8] = [
+        "-y".as_ref(), "-loglevel".as_ref(), "error".as_ref(),
+        "-i".as_ref(), source.as_os_str(),
+        "-frames:v".as_ref(), "1".as_ref(),
+        dest.as_os_str(),
+    ];
+    run_converter("ffmpeg", &args, dest)
+}
+
+/// Decode an attachment into an image for previews
+///
+/// Formats the `image` crate cannot read are first converted to a temporary
+/// file from `claim_temp_file`: HEIC images to JPEG, and videos to their first frame.
+pub(crate) fn decode_preview_image(source: &Path, mime_type: Option<&str>) -> Option<image::DynamicImage> {
+    let is_video = mime_type.is_some_and(|mime| mime.starts_with("video/"));
+    if !is_video {
+        let decoded = image::ImageReader::open(source)
+            .and_then(|reader| reader.with_guessed_format())
+            .ok()
+            .and_then(|reader| reader.decode().ok());
+        if decoded.is_some() {
+            return decoded;
+        }
+    }
+
+    let conversion = match media_conversion(mime_type, source) {
+        None if is_video => MediaConversion::Video,
+        conversion => conversion?,
+    };
+    let extension = match conversion {
+        MediaConversion::Image => "jpeg",
+        MediaConversion::Video => "png",
+    };
+    // The converters overwrite the empty file claimed here
+    let (scratch, _) = claim_temp_file("imessage-bridge-preview", extension).ok()?;
+    let converted = match conversion {
+        MediaConversion::Image => transcode_image(source, &scratch),
+        MediaConversion::Video => video_frame(source, &scratch),
+    };
+
+    let decoded = converted.and_then(|converted| image::open(converted).ok());
+    let _ = std::fs::remove_file(&scratch);
+    decoded
+}
+
+/// Read EXIF capture metadata from an image attachment's file
+pub(crate) fn image_metadata(attachment_rowid: i32, path: &Path) -> Option<PyImageMetadata> {
+    let file = std::fs::File::open(path).ok()?;
+    let exif = exif::Reader::new()
+        .read_from_container(&mut std::io::BufReader::new(file))
+        .ok()?;
+
+    let ascii = |tag: exif::Tag| match exif.get_field(tag, exif::In::PRIMARY).map(|field| &field.value) {
+        Some(exif::Value::Ascii(values)) => values
+            .first()
+            .map(|value| String::from_utf8_lossy(value).trim_matches(|c: char| c == '\0' || c.is_whitespace()).to_string())
+            .filter(|value| !value.is_empty()),
+        _ => None,
+    };
+    let coordinate = |tag: exif::Tag, negative_ref: &str, ref_tag: exif::Tag| {
+        let exif::Value::Rational(parts) = &exif.get_field(tag, exif::In::PRIMARY)?.value else {
+            return None;
+        };
+        let degrees = parts.iter().zip([1.0, 60.0, 3600.0]).map(|(part, scale)| part.to_f64() / scale).sum::<f64>();
+        match ascii(ref_tag) {
+            Some(r) if r.eq_ignore_ascii_case(negative_ref) => Some(-degrees),
+            _ => Some(degrees),
+        }
+    };
+
+    // EXIF dates are "YYYY:MM:DD HH:MM:SS" in the camera's local time
+    let captured_at = ascii(exif::Tag::DateTimeOriginal)
+        .or_else(|| ascii(exif::Tag::DateTime))
+        .and_then(|value| chrono::NaiveDateTime::parse_from_str(&value, "%Y:%m:%d %H:%M:%S").ok())
+        .map(|local| {
+            let offset = ascii(exif::Tag::OffsetTimeOriginal).or_else(|| ascii(exif::Tag::OffsetTime));
+            match offset {
+                Some(offset) => format!("{}{}", local.format("%Y-%m-%dT%H:%M:%S"), offset),
+                None => local.format("%Y-%m-%dT%H:%M:%S").to_string(),
+            }
+        });
+
+    Some(PyImageMetadata {
+        attachment_rowid,
+        captured_at,
+        latitude: coordinate(exif::Tag::GPSLatitude, "S", exif::Tag::GPSLatitudeRef),
+        longitude: coordinate(exif::Tag::GPSLongitude, "W", exif::Tag::GPSLongitudeRef),
+        camera_make: ascii(exif::Tag::Make),
+        camera_model: ascii(exif::Tag::Model),
+    })
+}
+
+/// Duration of a voice message, read from its attachment on disk
+pub(crate) fn audio_duration(message_rowid: i32, conn: &Connection, db_path: &Path) -> Option<f64> {
+    let filename: String = conn.query_row(
+        "SELECT a.filename FROM attachment a
+         INNER JOIN message_attachment_join maj ON a.rowid = maj.attachment_id
+         WHERE maj.message_id = ? AND a.filename IS NOT NULL
+         LIMIT 1",
+        [message_rowid],
+        |row| row.get(0),
+    ).ok()?;
+
+    let path = resolve_attachment_path(Some(&filename), db_path).ok()?;
+    audio_file_duration(&std::fs::read(path).ok()?)
+}
+
+/// Compute the duration of a CAF or MP4/M4A audio file
+pub(crate) fn audio_file_duration(data: &[u8]) -> Option<f64> {
+    caf_duration(data).or_else(|| mp4_duration(data))
+}
+
+/// Read a big-endian `u32` at a byte offset
+pub(crate) fn read_be_u32(data: &[u8], at: usize) -> Option<u32> {
+    data.get(at..at + 4).map(|b| u32::from_be_bytes(b.try_into().unwrap()))
+}
+
+/// Read a big-endian `u64` at a byte offset
+pub(crate) fn read_be_u64(data: &[u8], at: usize) -> Option<u64> {
+    data.get(at..at + 8).map(|b| u64::from_be_bytes(b.try_into().unwrap()))
+}
+
+/// List the boxes of an MP4/QuickTime file between `start` and `end`
+///
+/// Each box is a 4 byte size (covering the header) then a 4 byte type; the
+/// returned ranges are the box bodies.
+pub(crate) fn mp4_boxes(data: &[u8], start: usize, end: usize) -> Vec<([u8; 4], usize, usize)> {
+    let end = end.min(data.len());
+    let mut boxes = Vec::new();
+    let mut pos = start;
+    while pos + 8 <= end {
+        let Some(size) = read_be_u32(data, pos).map(|size| size as usize) else {
+            break;
+        };
+        let kind = data[pos + 4..pos + 8].try_into().unwrap();
+        // A size of 0 means the box runs to the end of the file
+        let box_end = if size == 0 { end } else { (pos + size).min(end) };
+        boxes.push((kind, pos + 8, box_end));
+        if size < 8 {
+            break;
+        }
+        pos += size;
+    }
+    boxes
+}
+
+/// Find the first box of a type between `start` and `end`, returning its body range
+pub(crate) fn mp4_box(data: &[u8], start: usize, end: usize, kind: &[u8; 4]) -> Option<(usize, usize)> {
+    mp4_boxes(data, start, end)
+        .into_iter()
+        .find(|(found, _, _)| found == kind)
+        .map(|(_, body, end)| (body, end))
+}
+
+/// Read the top-level `moov` box of an MP4/QuickTime file, header included
+///
+/// The other top-level boxes, such as the media data, are skipped with seeks
+/// instead of being read. Returns `None` when the file has no `moov` box.
+pub(crate) fn read_mp4_moov(path: &Path) -> Option<Vec<u8>> {
+    let mut file = std::fs::File::open(path).ok()?;
+    let len = file.metadata().ok()?.len();
+    let mut pos = 0;
+    while pos + 8 <= len {
+        let mut header = [0u8; 8];
+        file.seek(SeekFrom::Start(pos)).ok()?;
+        file.read_exact(&mut header).ok()?;
+        let size = match u32::from_be_bytes(header[..4].try_into().unwrap()) {
+            // A size of 0 means the box runs to the end of the file
+            0 => len - pos,
+            // A size of 1 means a 64-bit size follows the type
+            1 => {
+                let mut large = [0u8; 8];
+                file.read_exact(&mut large).ok()?;
+                u64::from_be_bytes(large)
+            }
+            size => size as u64,
+        };
+        if size < 8 {
+            return None;
+        }
+        if &header[4..] == b"moov" {
+            // `mp4_boxes` reads 32-bit sizes only; a truncated box is cut at the end of the file
+            let size = usize::try_from(size.min(len - pos)).ok().filter(|&size| size <= u32::MAX as usize)?;
+            let mut moov = vec![0; size];
+            file.seek(SeekFrom::Start(pos)).ok()?;
+            file.read_exact(&mut moov).ok()?;
+            return Some(moov);
+        }
+        pos += size;
+    }
+    None
+}
+
+/// Read the duration of an MP4/M4A file from its `moov/mvhd` box
+pub(crate) fn mp4_duration(data: &[u8]) -> Option<f64> {
+    let (moov, moov_end) = mp4_box(data, 0, data.len(), b"moov")?;
+    let (mvhd, _) = mp4_box(data, moov, moov_end, b"mvhd")?;
+    let (timescale, duration) = match data.get(mvhd)? {
+        1 => (read_be_u32(data, mvhd + 20)?, read_be_u64(data, mvhd + 24)?),
+        _ => (read_be_u32(data, mvhd + 12)?, read_be_u32(data, mvhd + 16)? as u64),
+    };
+
+    (timescale > 0).then(|| duration as f64 / timescale as f64)
+}
+
+/// Read the resolution and codec of the first video track of an MP4/QuickTime file
+pub(crate) fn mp4_video_track(data: &[u8]) -> Option<(u32, u32, String)> {
+    let (moov, moov_end) = mp4_box(data, 0, data.len(), b"moov")?;
+    mp4_boxes(data, moov, moov_end)
+        .into_iter()
+        .filter(|(kind, _, _)| kind == b"trak")
+        .find_map(|(_, trak, trak_end)| {
+            let (mdia, mdia_end) = mp4_box(data, trak, trak_end, b"mdia")?;
+            let (hdlr, _) = mp4_box(data, mdia, mdia_end, b"hdlr")?;
+            if data.get(hdlr + 8..hdlr + 12)? != b"vide" {
+                return None;
+            }
+
+            // Width and height are 16.16 fixed point at the end of `tkhd`
+            let (tkhd, _) = mp4_box(data, trak, trak_end, b"tkhd")?;
+            let size_at = if *data.get(tkhd)? == 1 { tkhd + 88 } else { tkhd + 76 };
+            let width = read_be_u32(data, size_at)? >> 16;
+            let height = read_be_u32(data, size_at + 4)? >> 16;
+
+            let (minf, minf_end) = mp4_box(data, mdia, mdia_end, b"minf")?;
+            let (stbl, stbl_end) = mp4_box(data, minf, minf_end, b"stbl")?;
+            let (stsd, _) = mp4_box(data, stbl, stbl_end, b"stsd")?;
+            let codec = match data.get(stsd + 12..stsd + 16)? {
+                b"avc1" | b"avc3" => "h264".to_string(),
+                b"hvc1" | b"hev1" => "hevc".to_string(),
+                other => String::from_utf8_lossy(other).trim().to_string(),
+            };
+
+            Some((width, height, codec))
+        })
+}
+
+/// Downsample an audio file to `points` peak amplitudes between 0 and 1
+///
+/// Decoding Opus/AAC voice messages is left to `ffmpeg`; returns `None` when
+/// it is not installed or cannot read the file.
+pub(crate) fn audio_waveform(path: &Path, points: usize) -> Option<Vec<f32>> {
+    if points == 0 {
+        return None;
+    }
+    let output = std::process::Command::new("ffmpeg")
+        .args(["-loglevel", "error", "-i"])
+        .arg(path)
+        .args(["-f", "s16le", "-ac", "1", "-ar", "8000", "-"])
+        .stdin(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+
+    let samples: Vec<i16> = output.stdout
+        .chunks_exact(2)
+        .map(|pair| i16::from_le_bytes([pair[0], pair[1]]))
+        .collect();
+    peak_waveform(&samples, points)
+}
+
+/// Peak amplitude of each of `points` equal spans of `samples`, between 0 and 1
+///
+/// Spans are cut at `i * len / points`, so there are always exactly `points`
+/// values; with fewer samples than points, neighbouring spans share a sample.
+pub(crate) fn peak_waveform(samples: &[i16], points: usize) -> Option<Vec<f32>> {
+    if samples.is_empty() || points == 0 {
+        return None;
+    }
+    let waveform = (0..points)
+        .map(|i| {
+            let start = i * samples.len() / points;
+            let end = ((i + 1) * samples.len() / points).max(start + 1);
+            samples[start..end].iter().map(|s| s.unsigned_abs()).max().unwrap_or(0) as f32 / 32768.0
+        })
+        .collect();
+    Some(waveform)
+}
+
+/// Compute the duration of a Core Audio Format file from its `desc` and `pakt` chunks
+///
+/// Voice messages are Opus in CAF, where the packet table records the number of
+/// frames; uncompressed audio falls back to the size of the `data` chunk.
+pub(crate) fn caf_duration(data: &[u8]) -> Option<f64> {
+    if data.get(0..4)? != b"caff" {
+        return None;
+    }
+
+    let read_u32 = |at: usize| data.get(at..at + 4).map(|b| u32::from_be_bytes(b.try_into().unwrap()));
+    let read_i64 = |at: usize| data.get(at..at + 8).map(|b| i64::from_be_bytes(b.try_into().unwrap()));
+
+    let mut sample_rate = None;
+    let mut bytes_per_packet = 0;
+    let mut frames_per_packet = 0;
+    let mut valid_frames = None;
+    let mut data_size = None;
+
+    // Chunks follow the 8 byte file header: 4 byte type, 8 byte size, then the body
+    let mut pos = 8;
+    while pos + 12 <= data.len() {
+        let kind = &data[pos..pos + 4];
+        let size = read_i64(pos + 4)?;
+        let body = pos + 12;
+
+        match kind {
+            b"desc" => {
+                sample_rate = read_i64(body).map(|bits| f64::from_bits(bits as u64));
+                bytes_per_packet = read_u32(body + 16)?;
+                frames_per_packet = read_u32(body + 20)?;
+            }
+            b"pakt" => valid_frames = read_i64(body + 8),
+            b"data" => {
+                // A size of -1 means the data runs to the end of the file
+                data_size = Some(if size < 0 { (data.len() - body) as i64 } else { size });
+            }
+            _ => {}
+        }
+
+        if size < 0 {
+            break;
+        }
+        pos = body.checked_add(size as usize)?;
+    }
+
+    let sample_rate = sample_rate.filter(|rate| *rate > 0.0)?;
+    let frames = match valid_frames {
+        Some(frames) => frames as f64,
+        None if bytes_per_packet > 0 => {
+            // The data chunk starts with a 4 byte edit count
+            let packets = (data_size? - 4).max(0) as f64 / bytes_per_packet as f64;
+            packets * frames_per_packet as f64
+        }
+        None => return None,
+    };
+
+    Some(frames / sample_rate)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Bytes from a fixed linear congruential sequence, so failures reproduce
+    fn noise(seed: u64, len: usize) -> Vec<u8> {
+        let mut state = seed;
+        (0..len).map(|_| {
+            state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            (state >> 56) as u8
+        }).collect()
+    }
+
+    /// Run `parse` on every prefix of `data`, on `data` with each byte set to 0xff, and on noise
+    fn assert_survives<T>(data: &[u8], parse: impl Fn(&[u8]) -> T) {
+        for end in 0..data.len() {
+            parse(&data[..end]);
+        }
+        for at in 0..data.len() {
+            let mut corrupt = data.to_vec();
+            corrupt[at] = 0xff;
+            parse(&corrupt);
+        }
+        for seed in 0..64 {
+            let mut random = noise(seed, 256);
+            random[..data.len().min(8)].copy_from_slice(&data[..data.len().min(8)]);
+            parse(&random);
+        }
+    }
+
+    #[test]
+    fn peak_waveform_has_exactly_the_requested_points() {
+        let samples = [0, 16384, -32768, 8192, 0, -4096, 0, 0, 32767];
+        let waveform = peak_waveform(&samples, 4).unwrap();
+        assert_eq!(waveform, vec![0.5, 1.0, 0.125, 32767.0 / 32768.0]);
+
+        assert_eq!(peak_waveform(&samples, 20).unwrap().len(), 20);
+        assert_eq!(peak_waveform(&samples, 9).unwrap().len(), 9);
+        assert_eq!(peak_waveform(&samples, 0), None);
+        assert_eq!(peak_waveform(&[], 4), None);
+    }
+
+    #[test]
+    fn failed_preview_conversion_removes_its_scratch_file() {
+        let previews = || std::fs::read_dir(std::env::temp_dir()).unwrap()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_name().to_string_lossy().starts_with("imessage-bridge-preview-"))
+            .count();
+        let (source, _) = claim_temp_file("imessage-bridge-test-video", "mov").unwrap();
+        std::fs::write(&source, noise(1, 512)).unwrap();
+
+        let before = previews();
+        assert!(decode_preview_image(&source, Some("video/quicktime")).is_none());
+        assert_eq!(previews(), before);
+        std::fs::remove_file(&source).unwrap();
+    }
+
+    fn caf_chunk(kind: &[u8; 4], size: i64, body: &[u8]) -> Vec<u8> {
+        [&kind[..], &size.to_be_bytes(), body].concat()
+    }
+
+    /// A CAF header and `desc` chunk for 48 kHz audio
+    fn caf_header(bytes_per_packet: u32, frames_per_packet: u32) -> Vec<u8> {
+        let mut desc = 48000f64.to_bits().to_be_bytes().to_vec();
+        desc.extend_from_slice(b"opus");
+        desc.extend_from_slice(&0u32.to_be_bytes());
+        desc.extend_from_slice(&bytes_per_packet.to_be_bytes());
+        desc.extend_from_slice(&frames_per_packet.to_be_bytes());
+        desc.extend_from_slice(&[0; 8]);
+        [&b"caff\x00\x01\x00\x00"[..], &caf_chunk(b"desc", 32, &desc)].concat()
+    }
+
+    #[test]
+    fn caf_duration_from_packet_table() {
+        let mut pakt = 50i64.to_be_bytes().to_vec();
+        pakt.extend_from_slice(&96000i64.to_be_bytes());
+        pakt.extend_from_slice(&[0; 8]);
+        let caf = [caf_header(0, 960), caf_chunk(b"pakt", 24, &pakt)].concat();
+        assert_eq!(caf_duration(&caf), Some(2.0));
+        assert_survives(&caf, caf_duration);
+    }
+
+    #[test]
+    fn caf_duration_from_data_size() {
+        // Runs to the end of the file: a 4 byte edit count, then one second of 16 bit mono
+        let data = [&[0u8; 4][..], &[0; 96000]].concat();
+        let caf = [caf_header(2, 1), caf_chunk(b"data", -1, &data)].concat();
+        assert_eq!(caf_duration(&caf), Some(1.0));
+        assert_survives(&caf[..200], caf_duration);
+    }
+
+    #[test]
+    fn caf_duration_rejects_other_files() {
+        assert_eq!(caf_duration(b""), None);
+        assert_eq!(caf_duration(b"RIFF\x00\x00\x00\x00WAVE"), None);
+        // No `desc` chunk to give a sample rate
+        assert_eq!(caf_duration(&[&b"caff\x00\x01\x00\x00"[..], &caf_chunk(b"free", 0, &[])].concat()), None);
+        assert_eq!(caf_duration(b"caff\x00\x01\x00\x00"), None);
+    }
+
+    fn atom(kind: &[u8; 4], body: &[u8]) -> Vec<u8> {
+        [&(body.len() as u32 + 8).to_be_bytes()[..], kind, body].concat()
+    }
+
+    /// A version 0 `mvhd` body: version and flags, creation and modification times, timescale, duration
+    fn mvhd(timescale: u32, duration: u32) -> Vec<u8> {
+        [&[0u8; 12][..], &timescale.to_be_bytes(), &duration.to_be_bytes(), &[0; 80]].concat()
+    }
+
+    #[test]
+    fn mp4_duration_from_movie_header() {
+        let m4a = [atom(b"ftyp", b"M4A \x00\x00\x00\x00"), atom(b"moov", &atom(b"mvhd", &mvhd(600, 1500)))].concat();
+        assert_eq!(mp4_duration(&m4a), Some(2.5));
+        assert_survives(&m4a, mp4_duration);
+
+        // Version 1 has 64 bit times and duration
+        let v1 = [&[1u8, 0, 0, 0][..], &[0; 16], &1000u32.to_be_bytes(), &4500u64.to_be_bytes(), &[0; 80]].concat();
+        let m4a = atom(b"moov", &atom(b"mvhd", &v1));
+        assert_eq!(mp4_duration(&m4a), Some(4.5));
+        assert_survives(&m4a, mp4_duration);
+    }
+
+    #[test]
+    fn mp4_duration_rejects_unusable_headers() {
+        assert_eq!(mp4_duration(&atom(b"moov", &atom(b"mvhd", &mvhd(0, 1500)))), None);
+        assert_eq!(mp4_duration(&atom(b"moov", &atom(b"free", &mvhd(600, 1500)))), None);
+        assert_eq!(mp4_duration(&atom(b"mdat", &atom(b"mvhd", &mvhd(600, 1500)))), None);
+        // Sizes that stop short of their own header, or claim to run to the end of the file
+        assert_eq!(mp4_duration(&[0, 0, 0, 4, b'm', b'o', b'o', b'v']), None);
+        assert_eq!(mp4_duration(&[&[0u8, 0, 0, 0][..], b"moov", &[0, 0, 0, 0], b"mvhd"].concat()), None);
+    }
+
+    /// A `trak` box whose handler is `handler`, sized `width`x`height` and coded as `format`
+    fn trak(handler: &[u8; 4], width: u32, height: u32, format: &[u8; 4]) -> Vec<u8> {
+        let tkhd = [&[0u8; 76][..], &(width << 16).to_be_bytes(), &(height << 16).to_be_bytes()].concat();
+        let hdlr = [&[0u8; 8][..], handler, &[0; 12]].concat();
+        let stsd = [&[0u8, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 86][..], format, &[0; 78]].concat();
+        let minf = atom(b"minf", &atom(b"stbl", &atom(b"stsd", &stsd)));
+        let mdia = atom(b"mdia", &[atom(b"mdhd", &[0; 24]), atom(b"hdlr", &hdlr), minf].concat());
+        atom(b"trak", &[atom(b"tkhd", &tkhd), mdia].concat())
+    }
+
+    #[test]
+    fn mp4_video_track_skips_other_tracks() {
+        let moov = [atom(b"mvhd", &mvhd(600, 600)), trak(b"soun", 0, 0, b"mp4a"), trak(b"vide", 1920, 1080, b"hvc1")].concat();
+        let mov = [atom(b"ftyp", b"qt  \x00\x00\x00\x00"), atom(b"moov", &moov)].concat();
+        assert_eq!(mp4_video_track(&mov), Some((1920, 1080, "hevc".to_string())));
+        assert_survives(&mov, mp4_video_track);
+
+        assert_eq!(mp4_video_track(&atom(b"moov", &trak(b"vide", 640, 480, b"avc1"))), Some((640, 480, "h264".to_string())));
+        assert_eq!(mp4_video_track(&atom(b"moov", &trak(b"vide", 640, 480, b"mp4v"))), Some((640, 480, "mp4v".to_string())));
+    }
+
+    #[test]
+    fn mp4_video_track_without_video() {
+        assert_eq!(mp4_video_track(&atom(b"moov", &trak(b"soun", 0, 0, b"mp4a"))), None);
+        assert_eq!(mp4_video_track(&atom(b"moov", &atom(b"mvhd", &mvhd(600, 600)))), None);
+        assert_eq!(mp4_video_track(&noise(7, 512)), None);
+    }
+
+    #[test]
+    fn read_mp4_moov_skips_media_data() {
+        let moov = atom(b"moov", &[atom(b"mvhd", &mvhd(600, 1200)), trak(b"vide", 1280, 720, b"avc1")].concat());
+        // A 64-bit sized `mdat` ahead of the `moov` box, as written by long recordings
+        let mdat = [&1u32.to_be_bytes()[..], b"mdat", &4112u64.to_be_bytes(), &[0; 4096]].concat();
+        let (path, _) = claim_temp_file("imessage-bridge-test-video", "mov").unwrap();
+        std::fs::write(&path, [atom(b"ftyp", b"qt  \x00\x00\x00\x00"), mdat, moov.clone()].concat()).unwrap();
+        let read = read_mp4_moov(&path);
+        std::fs::write(&path, [atom(b"ftyp", b"qt  \x00\x00\x00\x00"), atom(b"mdat", &[0; 64])].concat()).unwrap();
+        let missing = read_mp4_moov(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(read.as_deref(), Some(&moov[..]));
+        assert_eq!(mp4_duration(&moov), Some(2.0));
+        assert_eq!(mp4_video_track(&moov), Some((1280, 720, "h264".to_string())));
+        assert_eq!(missing, None);
+    }
+}
@@ -0,0 +1,910 @@
+//! Chats, handles and participants
+
+use super::*;
+
+#[pymethods]
+impl IMessageDB {
+    /// Get a chat's group photo changes and removals, oldest first
+    ///
+    /// Each event's `group_photo_attachment` points at the new image, which can
+    /// be looked up with `get_message_attachments`; removals have none.
+    pub(crate) fn get_group_photo_history(&self, py: Python, chat_id: i32) -> PyResult<Vec<PyMessage>> {
+        self.without_gil(py, |db| {
+            let query = "SELECT 
+                    m.*,
+                    c.chat_id,
+                    (SELECT COUNT(*) FROM message_attachment_join a WHERE m.ROWID = a.message_id) as num_attachments,
+                    NULL as deleted_from,
+                    0 as num_replies
+                FROM message as m
+                INNER JOIN chat_message_join as c ON m.ROWID = c.message_id
+                WHERE c.chat_id = ? AND m.item_type = 3 AND m.group_action_type IN (1, 2)
+                ORDER BY m.date ASC, m.ROWID ASC";
+
+            db.collect_messages(query, [chat_id])
+        })
+    }
+
+    /// Group chats into logical conversations, one per contact
+    ///
+    /// The same person often has separate SMS and iMessage chats, or chats under
+    /// different formats of their number. `strategy` controls how 1:1 chats merge:
+    ///
+    /// - `"identifier"` (default): chats whose participant phone/email match, as in `find_chats_for_handle`
+    /// - `"person"`: chats whose handles share Apple's `person_centric_id`, falling
+    ///   back to `"identifier"` for handles without one
+    /// - `"none"`: every chat is its own conversation
+    ///
+    /// Group chats are only merged with chats sharing the same `chat_identifier`.
+    pub(crate) fn get_conversations(&self, py: Python, strategy: Option<&str>) -> PyResult<Vec<PyConversation>> {
+        self.without_gil(py, |db| {
+            let strategy = strategy.unwrap_or("identifier");
+            if !matches!(strategy, "identifier" | "person" | "none") {
+                return Err(PyErr::new::<pyo3::exceptions::PyValueError, _>(
+                    format!("Unknown merge strategy: {}", strategy)
+                ));
+            }
+
+            let query = |person_column: &str| format!(
+                "SELECT c.rowid, c.chat_identifier, c.style, COUNT(chj.handle_id), MIN(h.id), MIN({})
+                 FROM chat c
+                 LEFT JOIN chat_handle_join chj ON c.rowid = chj.chat_id
+                 LEFT JOIN handle h ON h.rowid = chj.handle_id
+                 GROUP BY c.rowid
+                 ORDER BY c.rowid",
+                person_column
+            );
+
+            // `person_centric_id` is missing from older schemas
+            let mut stmt = db.conn.prepare_cached(&query("h.person_centric_id"))
+                .or_else(|_| db.conn.prepare_cached(&query("NULL")))
+                .map_err(|e| runtime_err("Failed to prepare conversations query", e))?;
+
+            let rows = stmt.query_map([], |row| {
+                Ok((
+                    row.get::<_, i32>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, Option<i32>>(2)?,
+                    row.get::<_, i64>(3)?,
+                    row.get::<_, Option<String>>(4)?,
+                    row.get::<_, Option<String>>(5)?,
+                ))
+            }).map_err(|e| runtime_err("Failed to execute conversations query", e))?;
+
+            // Every chat is read first, so which numbers merge does not depend on row order
+            let rows = rows
+                .map(|row| row.map(|(chat_id, chat_identifier, style, num_handles, handle_id, person_id)| {
+                    let identifier = handle_id.unwrap_or_else(|| chat_identifier.clone());
+                    (chat_id, chat_identifier, is_group_chat(style, num_handles as usize), identifier, person_id)
+                }))
+                .collect::<rusqlite::Result<Vec<_>>>()
+                .map_err(|e| runtime_err("Failed to read conversation", e))?;
+
+            // 1:1 numbers written with their country code, by their last digits,
+            // which national spellings of the same number merge into
+            let mut international: HashMap<String, Vec<String>> = HashMap::new();
+            for (_, _, is_group, identifier, _) in &rows {
+                let number = normalize_identifier(identifier);
+                if !is_group && is_phone_number(identifier) && number.starts_with('+') {
+                    let numbers = international.entry(phone_tail(&number).to_string()).or_default();
+                    if !numbers.contains(&number) {
+                        numbers.push(number);
+                    }
+                }
+            }
+
+            let mut conversations: Vec<PyConversation> = Vec::new();
+            let mut index: HashMap<String, usize> = HashMap::new();
+            for (chat_id, chat_identifier, is_group, identifier, person_id) in rows {
+                let key = if strategy == "none" {
+                    format!("chat:{}", chat_id)
+                } else if is_group {
+                    format!("group:{}", chat_identifier)
+                } else if let Some(person_id) = person_id.filter(|_| strategy == "person") {
+                    format!("person:{}", person_id)
+                } else if identifier.contains('@') {
+                    format!("handle:{}", normalize_identifier(&identifier))
+                } else if is_phone_number(&identifier) {
+                    // A national number joins the one international number it matches;
+                    // when it matches numbers from several countries it stays apart
+                    let number = normalize_identifier(&identifier);
+                    let mut matches = international.get(phone_tail(&number)).into_iter().flatten()
+                        .filter(|other| identifiers_match(other, &number));
+                    let number = match (matches.next(), matches.next()) {
+                        (Some(only), None) => only.clone(),
+                        _ => number,
+                    };
+                    format!("handle:{}", number.trim_start_matches('+'))
+                } else {
+                    // Sender names such as `AMAZON` and `urn:biz:` business chats
+                    format!("handle:{}", identifier.trim())
+                };
+
+                match index.get(&key) {
+                    Some(&i) => conversations[i].chat_ids.push(chat_id),
+                    None => {
+                        index.insert(key.clone(), conversations.len());
+                        conversations.push(PyConversation {
+                            key,
+                            chat_ids: vec![chat_id],
+                            is_group,
+                            display_name: None,
+                        });
+                    }
+                }
+            }
+
+            for conversation in conversations.iter_mut() {
+                conversation.display_name = db.resolve_chat_name(conversation.chat_ids[0])?;
+            }
+
+            Ok(conversations)
+        })
+    }
+
+    /// Get handle (contact) information by ID
+    pub(crate) fn get_handle(&self, py: Python, handle_id: i32) -> PyResult<Option<PyHandle>> {
+        self.without_gil(py, |db| db.handle(handle_id))
+    }
+
+    /// Forget the handles and chats cached on this connection
+    ///
+    /// Lookups treat them as fixed once read. Call this in a long-running
+    /// session after a chat is renamed or its participants change.
+    pub(crate) fn clear_lookup_cache(&self) {
+        *self.lookups.borrow_mut() = LookupCache::default();
+    }
+
+    /// Get all handles (contacts)
+    pub(crate) fn get_all_handles(&self) -> PyResult<Vec<PyHandle>> {
+        let mut stmt = self.conn.prepare_cached(
+            "SELECT rowid, id, service, uncanonicalized_id FROM handle ORDER BY rowid"
+        ).map_err(|e| runtime_err("Failed to prepare handles query", e))?;
+
+        let handles = stmt.query_map([], |row| {
+            Ok(PyHandle {
+                rowid: row.get(0)?,
+                id: row.get(1)?,
+                service: row.get(2)?,
+                uncanonicalized_id: row.get(3)?,
+            })
+        }).map_err(|e| runtime_err("Failed to execute handles query", e))?;
+
+        let mut result = Vec::new();
+        for handle in handles {
+            result.push(handle.map_err(|e| runtime_err("Failed to read handle", e))?);
+        }
+
+        Ok(result)
+    }
+
+    /// Resolve a human-readable name for a chat
+    ///
+    /// Returns the chat's display name when one is set. Otherwise falls back to the
+    /// participant handles joined in sorted order (matching imessage-exporter), and
+    /// finally to the raw `chat_identifier`. Returns `None` if the chat does not exist.
+    pub(crate) fn resolve_chat_name(&self, chat_id: i32) -> PyResult<Option<String>> {
+        if let Some(name) = self.lookups.borrow_mut().chat_names.get(&chat_id) {
+            return Ok(Some(name.clone()));
+        }
+
+        let mut stmt = self.conn.prepare_cached(
+            "SELECT * FROM chat WHERE rowid = ?"
+        ).map_err(|e| runtime_err("Failed to prepare chat query", e))?;
+
+        let chat = stmt.query_row([chat_id], Chat::from_row).optional().map_err(|e| runtime_err("Failed to fetch chat", e))?;
+
+        let chat = match chat {
+            Some(chat) => chat,
+            None => return Ok(None),
+        };
+
+        let name = match chat.display_name() {
+            Some(name) => name.to_string(),
+            None => {
+                let mut participants: Vec<String> = self.chat_participants(chat_id)?
+                    .0
+                    .into_iter()
+                    .map(|h| h.id)
+                    .collect();
+                participants.sort();
+
+                if participants.is_empty() {
+                    chat.chat_identifier
+                } else {
+                    participants.join(", ")
+                }
+            }
+        };
+        self.lookups.borrow_mut().chat_names.put(chat_id, name.clone());
+        Ok(Some(name))
+    }
+
+    /// Get the participants of a chat and whether it is a group chat
+    ///
+    /// Returns `(participants, is_group)`, with `is_group` decided by `is_group_chat`.
+    pub(crate) fn get_chat_participants(&self, py: Python, chat_id: i32) -> PyResult<(Vec<PyHandle>, bool)> {
+        self.without_gil(py, |db| db.chat_participants(chat_id))
+    }
+
+    /// Get a summary of every chat, most recently active first
+    ///
+    /// Counts and the latest message are computed in a single grouped query; only
+    /// the latest message of each chat is decoded from attributedBody when needed.
+    /// Unread counts include received messages that have not been marked read.
+    pub(crate) fn get_chat_summaries(&self, py: Python) -> PyResult<Vec<PyChatSummary>> {
+        self.without_gil(py, |db| {
+            // SQLite returns bare columns (`m.*`) from the row that produced MAX(m.date)
+            let mut stmt = db.conn.prepare_cached(&format!(
+                "SELECT
+                    m.*,
+                    c.chat_id,
+                    (SELECT COUNT(*) FROM message_attachment_join a WHERE m.ROWID = a.message_id) as num_attachments,
+                    NULL as deleted_from,
+                    0 as num_replies,
+                    ch.ROWID as summary_chat_id,
+                    ch.chat_identifier,
+                    ch.display_name,
+                    ch.service_name,
+                    {} as chat_is_group,
+                    COUNT(m.ROWID) as message_count,
+                    COALESCE(SUM(CASE WHEN m.is_from_me = 0 AND m.is_read = 0 THEN 1 ELSE 0 END), 0) as unread_count,
+                    MAX(m.date) as last_date
+                FROM chat as ch
+                LEFT JOIN chat_message_join as c ON ch.ROWID = c.chat_id
+                LEFT JOIN message as m ON m.ROWID = c.message_id
+                GROUP BY ch.ROWID
+                ORDER BY last_date DESC",
+                group_chat_sql("ch")
+            )).map_err(|e| runtime_err("Failed to prepare chat summaries query", e))?;
+
+            let mut rows = stmt.query([]).map_err(|e| runtime_err("Failed to execute chat summaries query", e))?;
+
+            let read_summary = |row: &rusqlite::Row| -> rusqlite::Result<PyChatSummary> {
+                Ok(PyChatSummary {
+                    chat_id: row.get("summary_chat_id")?,
+                    chat_identifier: row.get("chat_identifier")?,
+                    display_name: row.get::<_, Option<String>>("display_name")?.filter(|n| !n.is_empty()),
+                    service_name: row.get("service_name")?,
+                    is_group: row.get("chat_is_group")?,
+                    message_count: row.get("message_count")?,
+                    unread_count: row.get("unread_count")?,
+                    last_message_rowid: None,
+                    last_message_date: None,
+                    last_message_text: None,
+                    last_message_is_from_me: None,
+                })
+            };
+
+            let mut summaries = Vec::new();
+            while let Some(row) = rows.next().map_err(|e| runtime_err("Failed to fetch chat summary", e))? {
+                let mut summary = read_summary(row).map_err(|e| runtime_err("Failed to read chat summary", e))?;
+
+                if summary.message_count > 0 {
+                    let mut msg = Message::from_row(row).map_err(|e| runtime_err("Failed to parse message", e))?;
+
+                    summary.last_message_text = decode_text(&mut msg, &db.conn);
+                    summary.last_message_rowid = Some(msg.rowid);
+                    summary.last_message_date = Some(apple_to_unix(msg.date));
+                    summary.last_message_is_from_me = Some(msg.is_from_me);
+                }
+
+                summaries.push(summary);
+            }
+
+            Ok(summaries)
+        })
+    }
+
+    /// Find every chat (SMS and iMessage) involving a phone number or email
+    ///
+    /// Emails are compared case-insensitively; phone numbers are compared on their
+    /// digits, so `(555) 000-0001` matches `+15550000001`. Pins are read as in
+    /// `get_pinned_chats`, from `pinning_plist` when given.
+    pub(crate) fn find_chats_for_handle(&self, py: Python, identifier: &str, pinning_plist: Option<String>) -> PyResult<Vec<PyChat>> {
+        self.without_gil(py, |db| {
+            let target = normalize_identifier(identifier);
+            if target.is_empty() {
+                return Ok(Vec::new());
+            }
+
+            // Emails match exactly in SQL. Phone numbers are written with any
+            // punctuation, so SQL only keeps identifiers ending in the target's
+            // last digits, in order, and `identifiers_match` decides the rest
+            let (condition, param) = if target.contains('@') {
+                ("lower(trim(c.chat_identifier)) = ?1 OR lower(trim(h.id)) = ?1", target.clone())
+            } else {
+                ("c.chat_identifier LIKE ?1 OR h.id LIKE ?1", phone_tail(&target).chars().flat_map(|d| ['%', d]).collect())
+            };
+
+            let mut stmt = db.conn.prepare_cached(&format!(
+                "SELECT c.rowid, c.guid, c.chat_identifier, c.service_name, c.display_name, c.group_id, c.style, h.id, {}
+                 FROM chat c
+                 LEFT JOIN chat_handle_join chj ON c.rowid = chj.chat_id
+                 LEFT JOIN handle h ON h.rowid = chj.handle_id
+                 WHERE {}
+                 ORDER BY c.rowid",
+                group_chat_sql("c"), condition
+            )).map_err(|e| runtime_err("Failed to prepare chats query", e))?;
+
+            let rows = stmt.query_map([&param], |row| {
+                Ok((
+                    PyChat {
+                        rowid: row.get(0)?,
+                        guid: row.get(1)?,
+                        chat_identifier: row.get(2)?,
+                        service_name: row.get(3)?,
+                        display_name: row.get::<_, Option<String>>(4)?.filter(|n| !n.is_empty()),
+                        group_id: row.get(5)?,
+                        style: row.get(6)?,
+                        is_group: row.get(8)?,
+                        is_pinned: false,
+                        pin_order: None,
+                    },
+                    row.get::<_, Option<String>>(7)?,
+                ))
+            }).map_err(|e| runtime_err("Failed to execute chats query", e))?;
+
+            let mut result: Vec<PyChat> = Vec::new();
+            for row in rows {
+                let (chat, handle_id) = row.map_err(|e| runtime_err("Failed to read chat", e))?;
+
+                // Group chats use opaque identifiers (`chat123...`), so only 1:1 identifiers are compared
+                let is_handle_identifier = is_phone_number(&chat.chat_identifier) || chat.chat_identifier.contains('@');
+
+                let matches = (is_handle_identifier
+                    && identifiers_match(&target, &normalize_identifier(&chat.chat_identifier)))
+                    || handle_id.is_some_and(|id| identifiers_match(&target, &normalize_identifier(&id)));
+
+                if matches && result.last().map(|c| c.rowid) != Some(chat.rowid) {
+                    result.push(chat);
+                }
+            }
+
+            if !result.is_empty() {
+                let path = pinning_plist.map(PathBuf::from).unwrap_or_else(default_pinning_path);
+                let pinned = db.pinned_identifiers(&path)?;
+                for chat in result.iter_mut() {
+                    apply_pin_order(chat, &pinned);
+                }
+            }
+
+            Ok(result)
+        })
+    }
+
+    /// Get the chats pinned in Messages.app, in pinned order
+    ///
+    /// Pins live outside chat.db, in `~/Library/Preferences/com.apple.messages.pinning.plist`;
+    /// pass `pinning_plist` to read a copied preferences file instead.
+    pub(crate) fn get_pinned_chats(&self, py: Python, pinning_plist: Option<String>) -> PyResult<Vec<PyChat>> {
+        self.without_gil(py, |db| {
+            let path = pinning_plist.map(PathBuf::from).unwrap_or_else(default_pinning_path);
+            let pinned = db.pinned_identifiers(&path)?;
+            if pinned.is_empty() {
+                return Ok(Vec::new());
+            }
+
+            let mut stmt = db.conn.prepare_cached(&format!(
+                "SELECT c.rowid, c.guid, c.chat_identifier, c.service_name, c.display_name, c.group_id, c.style, {} FROM chat c ORDER BY c.rowid",
+                group_chat_sql("c")
+            )).map_err(|e| runtime_err("Failed to prepare chats query", e))?;
+
+            let chats = stmt.query_map([], |row| {
+                Ok(PyChat {
+                    rowid: row.get(0)?,
+                    guid: row.get(1)?,
+                    chat_identifier: row.get(2)?,
+                    service_name: row.get(3)?,
+                    display_name: row.get::<_, Option<String>>(4)?.filter(|n| !n.is_empty()),
+                    group_id: row.get(5)?,
+                    style: row.get(6)?,
+                    is_group: row.get(7)?,
+                    is_pinned: false,
+                    pin_order: None,
+                })
+            }).map_err(|e| runtime_err("Failed to execute chats query", e))?;
+
+            let mut result = Vec::new();
+            for chat in chats {
+                let mut chat = chat.map_err(|e| runtime_err("Failed to read chat", e))?;
+                apply_pin_order(&mut chat, &pinned);
+                if chat.is_pinned {
+                    result.push(chat);
+                }
+            }
+
+            result.sort_by_key(|chat| chat.pin_order);
+            Ok(result)
+        })
+    }
+
+    /// Split a chat's history into sessions separated by quiet periods
+    ///
+    /// A gap of more than `gap_seconds` (default two hours) between consecutive
+    /// messages starts a new session. Sessions are returned in chronological order.
+    pub(crate) fn get_sessions(&self, py: Python, chat_id: i32, gap_seconds: Option<f64>) -> PyResult<Vec<PySession>> {
+        let gap_seconds = gap_seconds.unwrap_or(7200.0);
+        let (messages, _) = self.get_chat_messages(py, chat_id, None, None, None)?;
+
+        let mut sessions: Vec<PySession> = Vec::new();
+        for msg in messages {
+            match sessions.last_mut() {
+                Some(session) if msg.date - session.end_date <= gap_seconds => {
+                    session.end_date = msg.date;
+                    session.messages.push(msg);
+                }
+                _ => sessions.push(PySession {
+                    chat_id,
+                    start_date: msg.date,
+                    end_date: msg.date,
+                    messages: vec![msg],
+                }),
+            }
+        }
+
+        Ok(sessions)
+    }
+
+    /// Get aggregate statistics for a chat, computed in SQL
+    ///
+    /// Reactions (tapbacks) count towards `message_count`. `reaction_count` is
+    /// the tapbacks still standing: as with `attach_reactions`, each removal
+    /// cancels a tapback of the same kind from the same sender on the same
+    /// message part, and is not counted itself.
+    pub(crate) fn get_chat_stats(&self, py: Python, chat_id: i32) -> PyResult<PyChatStats> {
+        self.without_gil(py, |db| {
+            let mut stats = db.conn.query_row(
+                "SELECT
+                    COUNT(*),
+                    COALESCE(SUM(m.is_from_me), 0),
+                    MIN(m.date),
+                    MAX(m.date),
+                    (SELECT COALESCE(SUM(MAX(added - removed, 0)), 0) FROM (
+                        SELECT
+                            SUM(r.associated_message_type BETWEEN 2000 AND 2999) as added,
+                            SUM(r.associated_message_type BETWEEN 3000 AND 3999) as removed
+                        FROM message r
+                        INNER JOIN chat_message_join rc ON r.ROWID = rc.message_id
+                        WHERE rc.chat_id = ?1 AND r.associated_message_type BETWEEN 2000 AND 3999
+                        GROUP BY r.associated_message_guid, r.associated_message_type % 1000, r.is_from_me, r.handle_id
+                    )),
+                    (SELECT COUNT(*)
+                     FROM message_attachment_join maj
+                     INNER JOIN chat_message_join cmj ON maj.message_id = cmj.message_id
+                     WHERE cmj.chat_id = ?1)
+                FROM message m
+                INNER JOIN chat_message_join c ON m.ROWID = c.message_id
+                WHERE c.chat_id = ?1",
+                [chat_id],
+                |row| {
+                    let message_count: i64 = row.get(0)?;
+                    let sent_count: i64 = row.get(1)?;
+                    Ok(PyChatStats {
+                        chat_id,
+                        message_count,
+                        sent_count,
+                        received_count: message_count - sent_count,
+                        messages_by_sender: HashMap::new(),
+                        first_message_date: row.get::<_, Option<i64>>(2)?.map(apple_to_unix),
+                        last_message_date: row.get::<_, Option<i64>>(3)?.map(apple_to_unix),
+                        attachment_count: row.get(5)?,
+                        reaction_count: row.get(4)?,
+                    })
+                },
+            ).map_err(|e| runtime_err("Failed to compute chat stats", e))?;
+
+            let mut stmt = db.conn.prepare_cached(
+                "SELECT
+                    CASE WHEN m.is_from_me = 1 THEN ?2 ELSE COALESCE(h.id, ?3) END as sender,
+                    COUNT(*)
+                FROM message m
+                INNER JOIN chat_message_join c ON m.ROWID = c.message_id
+                LEFT JOIN handle h ON h.ROWID = m.handle_id
+                WHERE c.chat_id = ?1
+                GROUP BY sender"
+            ).map_err(|e| runtime_err("Failed to prepare sender counts query", e))?;
+
+            let counts = stmt.query_map(rusqlite::params![chat_id, ME, UNKNOWN], |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)?))
+            }).map_err(|e| runtime_err("Failed to execute sender counts query", e))?;
+
+            for count in counts {
+                let (sender, count) = count.map_err(|e| runtime_err("Failed to read sender count", e))?;
+                stats.messages_by_sender.insert(sender, count);
+            }
+
+            Ok(stats)
+        })
+    }
+
+    /// Get chats that have messages in "Recently Deleted"
+    ///
+    /// Newer macOS versions move deleted messages into `chat_recoverable_message_join`
+    /// for up to 30 days. A chat whose remaining count is zero was deleted entirely.
+    /// Returns an empty list on databases that predate the recoverable tables.
+    pub(crate) fn get_recoverable_chats(&self, py: Python) -> PyResult<Vec<PyRecoverableChat>> {
+        self.without_gil(py, |db| {
+            if !table_exists(&db.conn, "chat_recoverable_message_join")? {
+                return Ok(Vec::new());
+            }
+
+            let mut stmt = db.conn.prepare_cached(
+                "SELECT
+                    ch.ROWID,
+                    ch.chat_identifier,
+                    ch.display_name,
+                    COUNT(*) as recoverable_count,
+                    (SELECT COUNT(*) FROM chat_message_join cmj WHERE cmj.chat_id = ch.ROWID) as remaining_count,
+                    MIN(d.delete_date),
+                    MAX(d.delete_date)
+                FROM chat_recoverable_message_join d
+                INNER JOIN chat ch ON ch.ROWID = d.chat_id
+                GROUP BY ch.ROWID
+                ORDER BY MAX(d.delete_date) DESC"
+            ).map_err(|e| runtime_err("Failed to prepare recoverable chats query", e))?;
+
+            let chats = stmt.query_map([], |row| {
+                let remaining_count: i64 = row.get(4)?;
+                Ok(PyRecoverableChat {
+                    chat_id: row.get(0)?,
+                    chat_identifier: row.get(1)?,
+                    display_name: row.get::<_, Option<String>>(2)?.filter(|n| !n.is_empty()),
+                    recoverable_count: row.get(3)?,
+                    remaining_count,
+                    is_fully_deleted: remaining_count == 0,
+                    first_deleted: row.get::<_, Option<i64>>(5)?.and_then(apple_to_unix_opt),
+                    last_deleted: row.get::<_, Option<i64>>(6)?.and_then(apple_to_unix_opt),
+                })
+            }).map_err(|e| runtime_err("Failed to execute recoverable chats query", e))?;
+
+            let mut result = Vec::new();
+            for chat in chats {
+                result.push(chat.map_err(|e| runtime_err("Failed to read recoverable chat", e))?);
+            }
+
+            Ok(result)
+        })
+    }
+
+    /// Resolve a chat reference to chat rowids
+    ///
+    /// Accepts a `chat_identifier` (`chat123...`, phone number, email), a chat GUID
+    /// such as `iMessage;+;chat123...` or `SMS;-;+15550000001`, or a group `group_id`.
+    /// Several rowids come back when the same identifier exists on multiple services.
+    pub(crate) fn get_chat_ids_for_identifier(&self, identifier: &str) -> PyResult<Vec<i32>> {
+        // Chat GUIDs are `service;style;chat_identifier`
+        let bare = identifier.rsplit(';').next().unwrap_or(identifier);
+
+        let mut stmt = self.conn.prepare_cached(
+            "SELECT rowid FROM chat
+             WHERE chat_identifier = ?1 OR chat_identifier = ?2 OR guid = ?1 OR group_id = ?1
+             ORDER BY rowid"
+        ).map_err(|e| runtime_err("Failed to prepare chat lookup query", e))?;
+
+        let ids = stmt.query_map([identifier, bare], |row| row.get::<_, i32>(0)).map_err(|e| runtime_err("Failed to execute chat lookup query", e))?;
+
+        let mut result = Vec::new();
+        for id in ids {
+            result.push(id.map_err(|e| runtime_err("Failed to read chat id", e))?);
+        }
+
+        Ok(result)
+    }
+
+    /// Get the handles behind a chat reference (see `get_chat_ids_for_identifier`)
+    ///
+    /// 1:1 chats missing from `chat_handle_join` fall back to the handle whose id
+    /// equals the chat identifier.
+    pub(crate) fn get_handles_for_chat_identifier(&self, py: Python, identifier: &str) -> PyResult<Vec<PyHandle>> {
+        self.without_gil(py, |db| {
+            let mut result: Vec<PyHandle> = Vec::new();
+            for chat_id in db.get_chat_ids_for_identifier(identifier)? {
+                for handle in db.chat_participants(chat_id)?.0 {
+                    if !result.iter().any(|h| h.rowid == handle.rowid) {
+                        result.push(handle);
+                    }
+                }
+            }
+
+            if result.is_empty() {
+                let bare = identifier.rsplit(';').next().unwrap_or(identifier);
+                for handle in db.get_all_handles()? {
+                    if handle.id == bare {
+                        result.push(handle);
+                    }
+                }
+            }
+
+            Ok(result)
+        })
+    }
+
+    /// Get the identifiers of every chat a handle participates in
+    pub(crate) fn get_chat_identifiers_for_handle(&self, handle_id: i32) -> PyResult<Vec<String>> {
+        let mut stmt = self.conn.prepare_cached(
+            "SELECT c.chat_identifier
+             FROM chat c
+             INNER JOIN chat_handle_join chj ON c.rowid = chj.chat_id
+             WHERE chj.handle_id = ?1
+             UNION
+             SELECT c.chat_identifier
+             FROM chat c
+             INNER JOIN handle h ON h.id = c.chat_identifier
+             WHERE h.rowid = ?1
+             ORDER BY 1"
+        ).map_err(|e| runtime_err("Failed to prepare chat identifiers query", e))?;
+
+        let identifiers = stmt.query_map([handle_id], |row| row.get::<_, String>(0)).map_err(|e| runtime_err("Failed to execute chat identifiers query", e))?;
+
+        let mut result = Vec::new();
+        for identifier in identifiers {
+            result.push(identifier.map_err(|e| runtime_err("Failed to read chat identifier", e))?);
+        }
+
+        Ok(result)
+    }
+
+    /// Get message participants (for group messages)
+    pub(crate) fn get_message_participants(&self, message_rowid: i32) -> PyResult<Vec<PyHandle>> {
+        let mut stmt = self.conn.prepare_cached(
+            "SELECT DISTINCT h.rowid, h.id, h.service, h.uncanonicalized_id
+             FROM handle h
+             INNER JOIN chat_handle_join chj ON h.rowid = chj.handle_id
+             INNER JOIN chat_message_join cmj ON chj.chat_id = cmj.chat_id
+             WHERE cmj.message_id = ?"
+        ).map_err(|e| runtime_err("Failed to prepare participants query", e))?;
+
+        let handles = stmt.query_map([message_rowid], |row| {
+            Ok(PyHandle {
+                rowid: row.get(0)?,
+                id: row.get(1)?,
+                service: row.get(2)?,
+                uncanonicalized_id: row.get(3)?,
+            })
+        }).map_err(|e| runtime_err("Failed to execute participants query", e))?;
+
+        let mut result = Vec::new();
+        for handle in handles {
+            result.push(handle.map_err(|e| runtime_err("Failed to read participant", e))?);
+        }
+
+        Ok(result)
+    }
+}
+
+impl IMessageDB {
+    /// Handle by ROWID for `get_handle`, from the lookup cache once read
+    pub(crate) fn handle(&self, handle_id: i32) -> PyResult<Option<PyHandle>> {
+        if let Some(handle) = self.lookups.borrow_mut().handles.get(&handle_id) {
+            return Ok(Some(handle.clone()));
+        }
+
+        let mut stmt = self.conn.prepare_cached(
+            "SELECT rowid, id, service, uncanonicalized_id FROM handle WHERE rowid = ?"
+        ).map_err(|e| runtime_err("Failed to prepare handle query", e))?;
+
+        let handle = stmt.query_row([handle_id], |row| {
+            Ok(PyHandle {
+                rowid: row.get(0)?,
+                id: row.get(1)?,
+                service: row.get(2)?,
+                uncanonicalized_id: row.get(3)?,
+            })
+        }).optional().map_err(|e| runtime_err("Failed to fetch handle", e))?;
+
+        if let Some(handle) = &handle {
+            self.lookups.borrow_mut().handles.put(handle_id, handle.clone());
+        }
+        Ok(handle)
+    }
+
+    /// Participants of a chat for `get_chat_participants`, from the lookup cache once read
+    pub(crate) fn chat_participants(&self, chat_id: i32) -> PyResult<(Vec<PyHandle>, bool)> {
+        if let Some(participants) = self.lookups.borrow_mut().chat_participants.get(&chat_id) {
+            return Ok(participants.clone());
+        }
+
+        let chat_style: Option<Option<i32>> = self.conn.query_row(
+            "SELECT style FROM chat WHERE rowid = ?",
+            [chat_id],
+            |row| row.get(0),
+        ).optional().map_err(|e| runtime_err("Failed to fetch chat style", e))?;
+        let style = chat_style.flatten();
+
+        let mut stmt = self.conn.prepare_cached(
+            "SELECT h.rowid, h.id, h.service, h.uncanonicalized_id
+             FROM handle h
+             INNER JOIN chat_handle_join chj ON h.rowid = chj.handle_id
+             WHERE chj.chat_id = ?
+             ORDER BY h.rowid"
+        ).map_err(|e| runtime_err("Failed to prepare chat participants query", e))?;
+
+        let handles = stmt.query_map([chat_id], |row| {
+            Ok(PyHandle {
+                rowid: row.get(0)?,
+                id: row.get(1)?,
+                service: row.get(2)?,
+                uncanonicalized_id: row.get(3)?,
+            })
+        }).map_err(|e| runtime_err("Failed to execute chat participants query", e))?;
+
+        let mut result = Vec::new();
+        for handle in handles {
+            result.push(handle.map_err(|e| runtime_err("Failed to read chat participant", e))?);
+        }
+
+        let is_group = is_group_chat(style, result.len());
+        // Leave out chats that do not exist yet, so they are found once created
+        if chat_style.is_some() {
+            self.lookups.borrow_mut().chat_participants.put(chat_id, (result.clone(), is_group));
+        }
+        Ok((result, is_group))
+    }
+
+    /// Pinned conversation identifiers, reread only when the file changes
+    pub(crate) fn pinned_identifiers(&self, path: &Path) -> PyResult<Vec<String>> {
+        let modified = std::fs::metadata(path).and_then(|m| m.modified()).ok();
+        if let Some((cached_path, cached_modified, pinned)) = &self.lookups.borrow().pinned {
+            if cached_path == path && *cached_modified == modified {
+                return Ok(pinned.clone());
+            }
+        }
+
+        let pinned = read_pinned_identifiers(path)?;
+        self.lookups.borrow_mut().pinned = Some((path.to_path_buf(), modified, pinned.clone()));
+        Ok(pinned)
+    }
+}
+
+/// Default location of the Messages.app pinned conversations preferences
+pub(crate) fn default_pinning_path() -> PathBuf {
+    PathBuf::from(format!("{}/Library/Preferences/com.apple.messages.pinning.plist", home()))
+}
+
+/// Read the ordered list of pinned conversation identifiers
+///
+/// The identifiers live in the `pP` array of the `pinnedConversations` dictionary
+/// and may be a chat GUID, `chat_identifier`, or `group_id`. A missing file means
+/// nothing is pinned.
+pub(crate) fn read_pinned_identifiers(path: &Path) -> PyResult<Vec<String>> {
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+
+    let plist = plist::Value::from_file(path).map_err(|e| io_err("Failed to read pinning preferences", e))?;
+
+    let pinned = plist
+        .as_dictionary()
+        .and_then(|d| d.get("pinnedConversations"))
+        .and_then(|p| match p {
+            plist::Value::Dictionary(d) => d.get("pP").and_then(|v| v.as_array()),
+            plist::Value::Array(a) => Some(a),
+            _ => None,
+        });
+
+    Ok(pinned
+        .map(|items| items.iter().filter_map(|v| v.as_string().map(String::from)).collect())
+        .unwrap_or_default())
+}
+
+/// Set a chat's pin fields from the ordered pinned identifiers
+pub(crate) fn apply_pin_order(chat: &mut PyChat, pinned: &[String]) {
+    chat.pin_order = pinned.iter().position(|id| {
+        *id == chat.guid
+            || *id == chat.chat_identifier
+            || chat.group_id.as_deref() == Some(id.as_str())
+    });
+    chat.is_pinned = chat.pin_order.is_some();
+}
+
+/// Shortest national phone number `identifiers_match` pairs with an international one
+pub(crate) const MIN_NATIONAL_NUMBER_DIGITS: usize = 7;
+
+/// Normalize a phone number or email for comparison
+///
+/// Emails are trimmed and lowercased; anything else is reduced to its digits,
+/// keeping a leading `+` that marks a number written with its country code.
+pub(crate) fn normalize_identifier(identifier: &str) -> String {
+    let identifier = identifier.trim();
+    if identifier.contains('@') {
+        identifier.to_lowercase()
+    } else {
+        let plus = identifier.starts_with('+').then_some('+');
+        plus.into_iter().chain(identifier.chars().filter(|c| c.is_ascii_digit())).collect()
+    }
+}
+
+/// Whether a 1:1 identifier is a phone number, rather than an email, a sender
+/// name such as `AMAZON` or a `urn:biz:` business chat
+pub(crate) fn is_phone_number(identifier: &str) -> bool {
+    identifier.chars().any(|c| c.is_ascii_digit())
+        && identifier.trim().chars().all(|c| c.is_ascii_digit() || "+-(). ".contains(c))
+}
+
+/// The last digits of a normalized phone number, shared by every number it matches
+fn phone_tail(number: &str) -> &str {
+    let digits = number.trim_start_matches('+');
+    &digits[digits.len().saturating_sub(MIN_NATIONAL_NUMBER_DIGITS)..]
+}
+
+/// Compare two normalized identifiers
+///
+/// Emails must be equal, and phone numbers must have the same digits unless
+/// only one of them has a country code. The other is then taken to be dialed
+/// nationally, and matches when it equals what follows a 1-3 digit country
+/// code, once a `0` trunk prefix is dropped: `+15550000001` matches
+/// `5550000001` and `+447700900123` matches `07700900123`, but two numbers
+/// with country codes, or two without, never match on their last digits alone.
+pub(crate) fn identifiers_match(a: &str, b: &str) -> bool {
+    if a.contains('@') || b.contains('@') {
+        return a == b;
+    }
+
+    let (a_digits, b_digits) = (a.trim_start_matches('+'), b.trim_start_matches('+'));
+    if a_digits == b_digits {
+        return true;
+    }
+
+    let (international, national) = match (a.starts_with('+'), b.starts_with('+')) {
+        (true, false) => (a_digits, b_digits),
+        (false, true) => (b_digits, a_digits),
+        _ => return false,
+    };
+    let national = national.strip_prefix('0').unwrap_or(national);
+    national.len() >= MIN_NATIONAL_NUMBER_DIGITS
+        && international.ends_with(national)
+        && (1..=3).contains(&(international.len() - national.len()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalize_identifier_keeps_country_code_marker() {
+        assert_eq!(normalize_identifier(" +1 (555) 000-0001 "), "+15550000001");
+        assert_eq!(normalize_identifier("(555) 000-0001"), "5550000001");
+        assert_eq!(normalize_identifier(" User@Example.COM "), "user@example.com");
+        assert_eq!(normalize_identifier("+"), "+");
+        assert_eq!(normalize_identifier(""), "");
+    }
+
+    #[test]
+    fn identifiers_match_national_numbers() {
+        let matches = |a: &str, b: &str| identifiers_match(&normalize_identifier(a), &normalize_identifier(b));
+        assert!(matches("+15550000001", "15550000001"));
+        assert!(matches("+15550000001", "(555) 000-0001"));
+        assert!(matches("5550000001", "+1 555 000 0001"));
+        assert!(matches("+447700900123", "07700900123"));
+        assert!(matches("+353861234567", "0861234567"));
+        assert!(matches("user@example.com", "USER@example.com"));
+    }
+
+    #[test]
+    fn identifiers_match_rejects_other_numbers() {
+        let matches = |a: &str, b: &str| identifiers_match(&normalize_identifier(a), &normalize_identifier(b));
+        // Same last ten digits behind different country codes
+        assert!(!matches("+15550000001", "+445550000001"));
+        // Neither side has a country code to account for the extra digits
+        assert!(!matches("15550000001", "5550000001"));
+        // Too short to be a national number, or a longer prefix than a country code
+        assert!(!matches("+15550001", "550001"));
+        assert!(!matches("+12345550000001", "5550000001"));
+        assert!(!matches("+15550000001", "5550000002"));
+        assert!(!matches("user@example.com", "user@example.org"));
+        assert!(!matches("5550000001@example.com", "5550000001"));
+    }
+
+    #[test]
+    fn is_phone_number_needs_digits_and_punctuation() {
+        assert!(is_phone_number("+1 (555) 000-0001"));
+        assert!(is_phone_number("07700 900.123"));
+        assert!(is_phone_number("262966"));
+        assert!(!is_phone_number("AMAZON"));
+        assert!(!is_phone_number("urn:biz:1f2e3d4c-0000-1111-2222-333344445555"));
+        assert!(!is_phone_number("user@example.com"));
+        assert!(!is_phone_number("+"));
+        assert!(!is_phone_number(""));
+    }
+}
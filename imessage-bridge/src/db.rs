@@ -0,0 +1,403 @@
+//! The `IMessageDB` connection, its options and snapshots
+
+use super::*;
+
+/// `chat.style` value for group chats
+pub(crate) const GROUP_CHAT_STYLE: i32 = 43;
+
+/// Whether a chat with this `style` and number of participants is a group chat
+///
+/// `style` is the usual marker, but some group chats, e.g. ones that started
+/// as SMS, carry the 1:1 style, so more than one participant also makes a
+/// group. `group_chat_sql` is the same rule in SQL.
+pub(crate) fn is_group_chat(style: Option<i32>, participants: usize) -> bool {
+    style == Some(GROUP_CHAT_STYLE) || participants > 1
+}
+
+/// SQL for `is_group_chat` of the `chat` row aliased `chat`, 1 for group chats
+///
+/// A NULL `style` counts as 1:1, as in `is_group_chat`, so the result is never NULL.
+pub(crate) fn group_chat_sql(chat: &str) -> String {
+    format!(
+        "(COALESCE({0}.style, 0) = {1} OR (SELECT COUNT(*) FROM chat_handle_join WHERE chat_id = {0}.ROWID) > 1)",
+        chat, GROUP_CHAT_STYLE
+    )
+}
+
+/// Prepared statements kept per connection, enough for every fixed query in this module
+pub(crate) const PREPARED_STATEMENT_CACHE_CAPACITY: usize = 64;
+
+/// Lazily loaded messages decoded together on first access to any one of them
+///
+/// Large enough that reading a result set in order costs one query per batch,
+/// small enough that touching a single message decodes little else.
+pub(crate) const LAZY_TEXT_BATCH_SIZE: usize = 256;
+
+/// Handles and chats remembered per connection, more than most archives have
+pub(crate) const LOOKUP_CACHE_CAPACITY: NonZeroUsize = NonZeroUsize::new(4096).unwrap();
+
+/// Settings applied to every read connection of an `IMessageDB`
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ConnectionOptions {
+    pub(crate) mmap_size: Option<i64>,
+    pub(crate) cache_size: Option<i64>,
+    pub(crate) temp_store: Option<String>,
+    pub(crate) read_uncommitted: bool,
+    pub(crate) eager_text: bool,  // Decode `attributedBody` while loading rather than on first access
+    pub(crate) snapshot: Option<Arc<Snapshot>>,  // Indexed copy that connections open in place of `db_path`
+}
+
+/// Private copy of the database, freed once the last connection using it is gone
+#[derive(Debug)]
+pub(crate) enum Snapshot {
+    File {
+        path: PathBuf,  // In the temp directory, opened `immutable=1` so no locks are taken
+        _handle: std::fs::File,  // From the `create_new` that claimed `path`, held until the copy is removed
+    },
+    Memory {
+        uri: String,  // Shared-cache in-memory database filled with the backup API
+        _keep_alive: Mutex<Connection>,  // Holds the database open even while no reader has it open
+    },
+}
+
+impl Snapshot {
+    /// URI that read connections open in place of the live database
+    pub(crate) fn uri(&self) -> String {
+        match self {
+            // `?` and `#` end the path of a URI filename and `%` starts an escape
+            Snapshot::File { path, .. } => format!(
+                "file:{}?immutable=1",
+                path.to_string_lossy().replace('%', "%25").replace('?', "%3f").replace('#', "%23")
+            ),
+            Snapshot::Memory { uri, .. } => uri.clone(),
+        }
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        if let Snapshot::File { path, .. } = self {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// Main database interface
+#[pyclass(unsendable)]
+pub(crate) struct IMessageDB {
+    pub(crate) conn: DbConnection,
+    pub(crate) db_path: PathBuf,
+    pub(crate) options: ConnectionOptions,  // Reused for the connections of worker threads
+    pub(crate) decoder: OnceCell<Arc<Mutex<IMessageDB>>>,  // Opened for the first lazily loaded message
+    pub(crate) workers: RefCell<Vec<Mutex<IMessageDB>>>,  // One per `fetch_parallel` worker, opened on first use
+    pub(crate) lookups: RefCell<LookupCache>,
+}
+
+/// The SQLite connection of an `IMessageDB`, gone once lent back to an `IMessageDBPool`
+pub(crate) struct DbConnection(pub(crate) Option<Connection>);
+
+impl std::ops::Deref for DbConnection {
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection {
+        self.0.as_ref().expect("connection was returned to its IMessageDBPool")
+    }
+}
+
+/// Handles and chats already read on a connection
+///
+/// Both are small and treated as fixed for the life of the connection, so the
+/// lookups that hydration repeats for every message hit the database once per
+/// handle or chat. Only rows that were found are kept, so handles created
+/// while the connection is open are still picked up.
+pub(crate) struct LookupCache {
+    pub(crate) handles: LruCache<i32, PyHandle>,
+    pub(crate) chat_participants: LruCache<i32, (Vec<PyHandle>, bool)>,
+    pub(crate) chat_names: LruCache<i32, String>,
+    /// Pinning preferences path, its modification time, and the identifiers read from it
+    pub(crate) pinned: Option<(PathBuf, Option<std::time::SystemTime>, Vec<String>)>,
+}
+
+impl Default for LookupCache {
+    fn default() -> Self {
+        LookupCache {
+            handles: LruCache::new(LOOKUP_CACHE_CAPACITY),
+            chat_participants: LruCache::new(LOOKUP_CACHE_CAPACITY),
+            chat_names: LruCache::new(LOOKUP_CACHE_CAPACITY),
+            pinned: None,
+        }
+    }
+}
+
+#[pymethods]
+impl IMessageDB {
+    /// Create a new connection to the iMessage database
+    ///
+    /// The remaining arguments tune the SQLite connection and default to
+    /// SQLite's own settings. `mmap_size` memory-maps up to that many bytes of
+    /// the file; `cache_size` is the page cache in pages, or in KiB when
+    /// negative; `temp_store` is `"default"`, `"file"` or `"memory"` for sort
+    /// and index temporaries; `read_uncommitted` skips read locks against other
+    /// connections sharing a cache. A large `mmap_size` (e.g. 1 GiB) and
+    /// `cache_size` (e.g. -262144) speed up queries on big archives several times.
+    ///
+    /// Messages decode `attributedBody`, and the `text`, `mentions`, `links`,
+    /// `kind` and other fields derived from it, on first access to one of
+    /// them, so rows that are only filtered or counted never pay for it. The
+    /// rest of the per-message reads wait with it: `apple_pay`,
+    /// `digital_touch`, `sticker` and `location` payloads, `edit_history`,
+    /// `group_photo_attachment`, and `audio_duration`, which reads the audio
+    /// file. Pass `eager_text=True` to do all of this while loading instead,
+    /// e.g. when every message's text will be read anyway.
+    ///
+    /// Pass `snapshot=True` to query a private copy instead of the live
+    /// database. The copy is taken once, with anything still in the WAL folded
+    /// in, and gets indexes on `message(date)` and `chat_message_join(chat_id,
+    /// message_id)`, so repeated analytical queries run faster and never fail
+    /// with SQLITE_BUSY while Messages.app is writing. It does not see later
+    /// messages and is freed when the connection is dropped. Attachment paths
+    /// still resolve against `db_path`. `snapshot_mode` picks where the copy
+    /// lives and implies `snapshot=True`:
+    ///
+    /// - `"file"` (default): a file in the temp directory, opened with
+    ///   `immutable=1` so reads take no locks at all
+    /// - `"memory"`: cloned in-process with the SQLite backup API, for when the
+    ///   temp directory is slow or small enough to matter; uses as much memory
+    ///   as the database is large
+    #[new]
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn new(db_path: Option<String>, mmap_size: Option<i64>, cache_size: Option<i64>, temp_store: Option<String>, read_uncommitted: Option<bool>, eager_text: Option<bool>, snapshot: Option<bool>, snapshot_mode: Option<&str>) -> PyResult<Self> {
+        let db_path = match db_path {
+            Some(path) => PathBuf::from(path),
+            None => {
+                let path = default_db_path();
+                if path.exists() {
+                    path
+                } else {
+                    return Err(PyErr::new::<pyo3::exceptions::PyIOError, _>(
+                        "Could not find default iMessage database path"
+                    ));
+                }
+            }
+        };
+
+        if let Some(temp_store) = temp_store.as_deref() {
+            if !matches!(temp_store, "default" | "file" | "memory") {
+                return Err(PyErr::new::<pyo3::exceptions::PyValueError, _>(
+                    format!("Unknown temp_store: {} (expected \"default\", \"file\" or \"memory\")", temp_store)
+                ));
+            }
+        }
+        let snapshot = match snapshot_mode {
+            Some(mode @ ("file" | "memory")) => Some(mode),
+            Some(mode) => {
+                return Err(PyErr::new::<pyo3::exceptions::PyValueError, _>(
+                    format!("Unknown snapshot_mode: {} (expected \"file\" or \"memory\")", mode)
+                ));
+            }
+            None if snapshot.unwrap_or(false) => Some("file"),
+            None => None,
+        };
+        let options = ConnectionOptions {
+            mmap_size,
+            cache_size,
+            temp_store,
+            read_uncommitted: read_uncommitted.unwrap_or(false),
+            eager_text: eager_text.unwrap_or(false),
+            snapshot: snapshot.map(|mode| create_snapshot(&db_path, mode).map(Arc::new)).transpose()?,
+        };
+
+        IMessageDB::open(db_path, options)
+    }
+
+    /// Get the database path
+    #[getter]
+    pub(crate) fn path(&self) -> String {
+        self.db_path.to_string_lossy().to_string()
+    }
+
+    /// Path of the copy being queried when opened with `snapshot=True`, `None` for in-memory snapshots
+    #[getter]
+    pub(crate) fn snapshot_path(&self) -> Option<String> {
+        match self.options.snapshot.as_deref() {
+            Some(Snapshot::File { path, .. }) => Some(path.to_string_lossy().to_string()),
+            _ => None,
+        }
+    }
+}
+
+impl IMessageDB {
+    /// Open a read-only connection to `db_path` tuned by `options`
+    pub(crate) fn open(db_path: PathBuf, options: ConnectionOptions) -> PyResult<IMessageDB> {
+        let conn = open_read_only(&db_path, &options)?;
+        Ok(IMessageDB { conn: DbConnection(Some(conn)), db_path, options, decoder: OnceCell::new(), workers: RefCell::default(), lookups: RefCell::default() })
+    }
+
+    /// Move the connection and its caches out, leaving an `IMessageDB` that can no longer query
+    pub(crate) fn detach(&mut self) -> IMessageDB {
+        let closed = IMessageDB {
+            conn: DbConnection(None),
+            db_path: self.db_path.clone(),
+            options: self.options.clone(),
+            decoder: OnceCell::new(),
+            workers: RefCell::default(),
+            lookups: RefCell::default(),
+        };
+        std::mem::replace(self, closed)
+    }
+
+    /// Open another connection to the same database with the same tuning
+    pub(crate) fn reopen(&self) -> PyResult<IMessageDB> {
+        IMessageDB::open(self.db_path.clone(), self.options.clone())
+    }
+
+    /// Run `f` against this database with the GIL released
+    ///
+    /// Other Python threads keep running while SQLite executes the query and
+    /// rows are decoded. `f` must not touch Python objects, which `Send` rules
+    /// out for everything but the database itself.
+    pub(crate) fn without_gil<T, F>(&self, py: Python, f: F) -> T
+    where
+        T: Send,
+        F: Send + FnOnce(&IMessageDB) -> T,
+    {
+        /// A reference to an `IMessageDB` that can be passed into `allow_threads`
+        struct UnsyncRef<'a>(&'a IMessageDB);
+
+        // SAFETY: `IMessageDB` is `unsendable`, so pyo3 only hands it out on the thread
+        // that created it, and `IMessageDBPool` only lends a connection to one caller at
+        // a time. `allow_threads` runs its closure on the calling thread, so the
+        // connection never leaves that thread; it just sits idle for Python, and the
+        // caller's borrow keeps it alive, while the GIL is released.
+        unsafe impl Send for UnsyncRef<'_> {}
+
+        impl<'a> UnsyncRef<'a> {
+            fn into_inner(self) -> &'a IMessageDB {
+                self.0
+            }
+        }
+
+        let db = UnsyncRef(self);
+        py.allow_threads(move || f(db.into_inner()))
+    }
+}
+
+/// Open the database read-only, the way every `IMessageDB` connection is opened
+pub(crate) fn open_read_only(db_path: &Path, options: &ConnectionOptions) -> PyResult<Connection> {
+    let conn = match options.snapshot.as_deref() {
+        Some(snapshot) => Connection::open_with_flags(
+            snapshot.uri(),
+            OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_URI
+        ),
+        None => Connection::open_with_flags(
+            db_path,
+            OpenFlags::SQLITE_OPEN_READ_ONLY
+        ),
+    }.map_err(|e| io_err("Failed to open database", e))?;
+    // Statements are cached by their SQL text, so repeated calls skip parsing
+    conn.set_prepared_statement_cache_capacity(PREPARED_STATEMENT_CACHE_CAPACITY);
+
+    let tune = |pragma: &str, value: &dyn rusqlite::ToSql| {
+        conn.pragma_update(None, pragma, value).map_err(|e| runtime_err(format!("Failed to set {}", pragma), e))
+    };
+    if let Some(mmap_size) = options.mmap_size {
+        tune("mmap_size", &mmap_size)?;
+    }
+    if let Some(cache_size) = options.cache_size {
+        tune("cache_size", &cache_size)?;
+    }
+    if let Some(temp_store) = &options.temp_store {
+        tune("temp_store", temp_store)?;
+    }
+    if options.read_uncommitted {
+        tune("read_uncommitted", &true)?;
+    }
+    Ok(conn)
+}
+
+/// Copy the database at `db_path` and index the copy for analytical queries
+///
+/// Both ways of copying read a consistent view through SQLite, so pages still
+/// in the WAL are checkpointed into the copy and a concurrent write by
+/// Messages.app cannot tear it. `"file"` uses `VACUUM INTO` a temp file with a
+/// rollback journal, leaving no `-wal` or `-shm` files behind; `"memory"` runs
+/// the backup API in a single step into a shared-cache in-memory database.
+pub(crate) fn create_snapshot(db_path: &Path, mode: &str) -> PyResult<Snapshot> {
+    static TAKEN: AtomicUsize = AtomicUsize::new(0);
+    let name = format!("imessage-bridge-snapshot-{}-{}", std::process::id(), TAKEN.fetch_add(1, Ordering::Relaxed));
+    let open_error = |e: rusqlite::Error| io_err("Failed to open snapshot", e);
+    let index = |copy: &Connection| {
+        copy.execute_batch(
+            "CREATE INDEX IF NOT EXISTS snapshot_message_date ON message(date);
+            CREATE INDEX IF NOT EXISTS snapshot_chat_message_join_chat ON chat_message_join(chat_id, message_id);"
+        ).map_err(|e| runtime_err("Failed to index snapshot", e))
+    };
+
+    let source = Connection::open_with_flags(db_path, OpenFlags::SQLITE_OPEN_READ_ONLY).map_err(|e| io_err("Failed to open database", e))?;
+
+    if mode == "memory" {
+        let uri = format!("file:{}?mode=memory&cache=shared", name);
+        let mut copy = Connection::open_with_flags(
+            &uri,
+            OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE | OpenFlags::SQLITE_OPEN_URI
+        ).map_err(open_error)?;
+        let copied = rusqlite::backup::Backup::new(&source, &mut copy)
+            .and_then(|backup| backup.step(-1))
+            .map_err(|e| e.to_string());
+        if !matches!(copied, Ok(rusqlite::backup::StepResult::Done)) {
+            return Err(PyErr::new::<pyo3::exceptions::PyIOError, _>(
+                format!("Failed to copy database into memory: {}", copied.map_or_else(|e| e, |step| format!("{:?}", step)))
+            ));
+        }
+        index(&copy)?;
+        return Ok(Snapshot::Memory { uri, _keep_alive: Mutex::new(copy) });
+    }
+
+    // `VACUUM INTO` accepts the empty file it finds
+    let (path, handle) = claim_temp_file(&name, "db").map_err(|e| io_err("Failed to create snapshot file", e))?;
+    // Created first so that a partial copy is removed if anything below fails
+    let snapshot = Snapshot::File { path: path.clone(), _handle: handle };
+    source.execute("VACUUM INTO ?", [path.to_string_lossy()]).map_err(|e| io_err(format!("Failed to copy database to {}", path.display()), e))?;
+    let copy = Connection::open(&path).map_err(open_error)?;
+    copy.execute_batch("PRAGMA journal_mode = DELETE;").map_err(open_error)?;
+    index(&copy)?;
+    Ok(snapshot)
+}
+
+/// Create an empty file in the temp dir, named `{prefix}-{random}.{extension}`
+///
+/// The random name is claimed with `create_new` and owner-only permissions,
+/// so no other user can put a file or symlink there first or read what is
+/// written to it.
+pub(crate) fn claim_temp_file(prefix: &str, extension: &str) -> std::io::Result<(PathBuf, std::fs::File)> {
+    loop {
+        let path = std::env::temp_dir().join(format!("{}-{:016x}.{}", prefix, fastrand::u64(..), extension));
+        let mut file_options = std::fs::OpenOptions::new();
+        file_options.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut file_options, 0o600);
+        match file_options.open(&path) {
+            Ok(handle) => return Ok((path, handle)),
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Check whether a table exists, since the schema varies across macOS versions
+pub(crate) fn table_exists(conn: &Connection, table: &str) -> PyResult<bool> {
+    conn.query_row(
+        "SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name = ?",
+        [table],
+        |row| row.get::<_, i64>(0),
+    ).map(|count| count > 0).map_err(|e| runtime_err("Failed to inspect schema", e))
+}
+
+/// Check whether a table has a column, since the schema varies across macOS versions
+pub(crate) fn column_exists(conn: &Connection, table: &str, column: &str) -> PyResult<bool> {
+    conn.query_row(
+        "SELECT COUNT(*) FROM pragma_table_info(?1) WHERE name = ?2",
+        [table, column],
+        |row| row.get::<_, i64>(0),
+    ).map(|count| count > 0).map_err(|e| runtime_err("Failed to inspect schema", e))
+}
@@ -0,0 +1,854 @@
+//! Decoding of message bodies, payloads and metadata into `PyMessage` fields
+
+use super::*;
+
+/// Read the `delete_date` of a recoverable message, selected only by deleted-message queries
+pub(crate) fn date_deleted(row: &rusqlite::Row) -> Option<f64> {
+    row.get::<_, Option<i64>>("delete_date").ok().flatten().and_then(apple_to_unix_opt)
+}
+
+/// Find the sticker attachment of a message, with its source and the message it was placed on
+pub(crate) fn sticker_info(msg: &Message, conn: &Connection) -> Option<PySticker> {
+    if !msg.has_attachments() {
+        return None;
+    }
+
+    let attachment = Attachment::from_message(conn, msg)
+        .ok()?
+        .into_iter()
+        .find(|attachment| attachment.is_sticker)?;
+
+    let (source, source_app) = match attachment.get_sticker_source(conn) {
+        Some(StickerSource::Genmoji) => (Some("genmoji"), None),
+        Some(StickerSource::Memoji) => (Some("memoji"), None),
+        Some(StickerSource::UserGenerated) => (Some("user_generated"), None),
+        Some(StickerSource::App(bundle_id)) => (
+            Some("app"),
+            attachment.get_sticker_source_application_name(conn).or(Some(bundle_id)),
+        ),
+        None => (None, None),
+    };
+
+    let (target_guid, target_part) = match msg.associated_message_guid {
+        Some(_) => reaction_target(msg),
+        None => (None, None),
+    };
+
+    Some(PySticker {
+        attachment_rowid: attachment.rowid,
+        filename: attachment.filename,
+        source: source.map(str::to_string),
+        source_app,
+        emoji_description: attachment.emoji_description,
+        target_guid,
+        target_part,
+    })
+}
+
+/// Decode location sharing events, Maps placemarks, and Find My messages
+pub(crate) fn location_info(msg: &Message, conn: &Connection) -> Option<PyLocation> {
+    let empty = |kind: &str| PyLocation {
+        kind: kind.to_string(),
+        latitude: None,
+        longitude: None,
+        name: None,
+        address: None,
+        city: None,
+        state: None,
+        postal_code: None,
+        country: None,
+        url: None,
+        description: None,
+    };
+
+    if msg.started_sharing_location() {
+        return Some(empty("started_sharing"));
+    }
+    if msg.stopped_sharing_location() {
+        return Some(empty("stopped_sharing"));
+    }
+
+    let app_type = app_info(msg).1?;
+    if app_type != "url" && app_type != "find_my" {
+        return None;
+    }
+
+    let payload = msg.payload_data(conn)?;
+    let payload = parse_ns_keyed_archiver(&payload).unwrap_or(payload);
+
+    if app_type == "find_my" {
+        let app = AppMessage::from_map(&payload).ok()?;
+        let mut location = empty("find_my");
+        location.description = app.ldtext.map(|text| text.replace('\u{a0}', " "));
+        location.url = app.url.map(str::to_string);
+        return Some(location);
+    }
+
+    let placemark = PlacemarkMessage::from_map(&payload).ok()?;
+    let url = placemark.url.or(placemark.original_url);
+    let (latitude, longitude) = url.and_then(maps_coordinates).unzip();
+    Some(PyLocation {
+        kind: "placemark".to_string(),
+        latitude,
+        longitude,
+        name: placemark.placemark.name.map(str::to_string),
+        address: placemark.place_name.or(placemark.placemark.address).map(str::to_string),
+        city: placemark.placemark.city.map(str::to_string),
+        state: placemark.placemark.state.map(str::to_string),
+        postal_code: placemark.placemark.postal_code.map(str::to_string),
+        country: placemark.placemark.country.map(str::to_string),
+        url: url.map(str::to_string),
+        description: None,
+    })
+}
+
+/// Read the `ll=<lat>,<lon>` parameter of an Apple Maps URL
+pub(crate) fn maps_coordinates(url: &str) -> Option<(f64, f64)> {
+    let query = url.split_once('?')?.1;
+    let ll = query.split('&').find_map(|param| param.strip_prefix("ll="))?;
+    let (lat, lon) = ll.split_once(',')?;
+    Some((lat.parse().ok()?, lon.parse().ok()?))
+}
+
+/// Read `is_audio_message` and `expire_state`, which `Message` does not carry
+pub(crate) fn audio_columns(row: &rusqlite::Row) -> (bool, Option<i32>) {
+    let is_audio = row.get::<_, Option<bool>>("is_audio_message").ok().flatten().unwrap_or(false);
+    let expire_state = row.get::<_, Option<i32>>("expire_state").ok().flatten();
+    (is_audio, expire_state)
+}
+
+/// Get the transcription Apple stores with the audio attachment in attributedBody
+pub(crate) fn audio_transcript(msg: &Message) -> Option<String> {
+    msg.components.iter().find_map(|component| match component {
+        BubbleComponent::Attachment(meta) => meta.transcription.clone(),
+        _ => None,
+    })
+}
+
+/// Classify a tapback row, returning the reaction kind and its custom emoji
+///
+/// Removed tapbacks are reported with a `removed_` prefix, e.g. `removed_loved`.
+pub(crate) fn reaction_kind(msg: &Message) -> (Option<String>, Option<String>) {
+    let (action, tapback) = match msg.variant() {
+        Variant::Tapback(_, action, tapback) => (action, tapback),
+        _ => return (None, None),
+    };
+
+    let (kind, emoji) = match tapback {
+        Tapback::Loved => ("loved", None),
+        Tapback::Liked => ("liked", None),
+        Tapback::Disliked => ("disliked", None),
+        Tapback::Laughed => ("laughed", None),
+        Tapback::Emphasized => ("emphasized", None),
+        Tapback::Questioned => ("questioned", None),
+        Tapback::Emoji(emoji) => ("emoji", emoji.map(str::to_string)),
+        Tapback::Sticker => ("sticker", None),
+    };
+
+    let kind = match action {
+        TapbackAction::Added => kind.to_string(),
+        TapbackAction::Removed => format!("removed_{}", kind),
+    };
+
+    (Some(kind), emoji)
+}
+
+/// Decode `expressive_send_style_id`, returning the effect name and whether it is a bubble or screen effect
+///
+/// Unrecognized effects keep their raw style id with an `unknown` type.
+pub(crate) fn expressive_effect(msg: &Message) -> (Option<String>, Option<String>) {
+    let (effect, effect_type) = match msg.get_expressive() {
+        Expressive::Bubble(effect) => {
+            let name = match effect {
+                BubbleEffect::Slam => "slam",
+                BubbleEffect::Loud => "loud",
+                BubbleEffect::Gentle => "gentle",
+                BubbleEffect::InvisibleInk => "invisible_ink",
+            };
+            (name.to_string(), "bubble")
+        }
+        Expressive::Screen(effect) => {
+            let name = match effect {
+                ScreenEffect::Confetti => "confetti",
+                ScreenEffect::Echo => "echo",
+                ScreenEffect::Fireworks => "fireworks",
+                ScreenEffect::Balloons => "balloons",
+                ScreenEffect::Heart => "heart",
+                ScreenEffect::Lasers => "lasers",
+                ScreenEffect::ShootingStar => "shooting_star",
+                ScreenEffect::Sparkles => "sparkles",
+                ScreenEffect::Spotlight => "spotlight",
+            };
+            (name.to_string(), "screen")
+        }
+        Expressive::Unknown(style_id) => (style_id.to_string(), "unknown"),
+        Expressive::None => return (None, None),
+    };
+
+    (Some(effect), Some(effect_type.to_string()))
+}
+
+/// Collect @-mentions from the parsed body, converting byte ranges to character offsets
+pub(crate) fn extract_mentions(msg: &Message) -> Vec<PyMention> {
+    let Some(text) = msg.text.as_deref() else {
+        return Vec::new();
+    };
+
+    let mut mentions = Vec::new();
+    for component in &msg.components {
+        let BubbleComponent::Text(attrs) = component else {
+            continue;
+        };
+        for attr in attrs {
+            for effect in &attr.effects {
+                let TextEffect::Mention(identifier) = effect else {
+                    continue;
+                };
+                let (Some(before), Some(mentioned)) = (text.get(..attr.start), text.get(attr.start..attr.end)) else {
+                    continue;
+                };
+                let start = before.chars().count();
+                mentions.push(PyMention {
+                    identifier: identifier.clone(),
+                    start,
+                    end: start + mentioned.chars().count(),
+                    text: mentioned.to_string(),
+                });
+            }
+        }
+    }
+
+    mentions
+}
+
+/// Split the parsed body into its parts, e.g. a caption followed by two photos
+pub(crate) fn body_parts(msg: &Message) -> Vec<PyBodyPart> {
+    msg.components
+        .iter()
+        .enumerate()
+        .map(|(index, component)| {
+            let (kind, text, attachment) = match component {
+                BubbleComponent::Text(attrs) => {
+                    let start = attrs.iter().map(|attr| attr.start).min().unwrap_or(0);
+                    let end = attrs.iter().map(|attr| attr.end).max().unwrap_or(0);
+                    let text = msg.text.as_deref().and_then(|text| text.get(start..end));
+                    ("text", text.map(str::to_string), None)
+                }
+                BubbleComponent::Attachment(meta) => ("attachment", None, Some(meta)),
+                BubbleComponent::App => ("app", None, None),
+                BubbleComponent::Retracted => ("retracted", None, None),
+            };
+            PyBodyPart {
+                index,
+                kind: kind.to_string(),
+                text,
+                attachment_guid: attachment.and_then(|meta| meta.guid.clone()),
+                attachment_name: attachment.and_then(|meta| meta.name.clone()),
+            }
+        })
+        .collect()
+}
+
+/// Collect the bold/italic/strikethrough/underline runs of the parsed body
+///
+/// Returns the runs with character offsets and, when there is at least one,
+/// the text rendered as Markdown (underline uses `<u>` tags).
+pub(crate) fn text_styles(msg: &Message) -> (Vec<PyTextAttribute>, Option<String>) {
+    let Some(text) = msg.text.as_deref() else {
+        return (Vec::new(), None);
+    };
+
+    let mut attributes = Vec::new();
+    let mut markdown = String::new();
+    let mut copied = 0;
+
+    for component in &msg.components {
+        let BubbleComponent::Text(attrs) = component else {
+            continue;
+        };
+        for attr in attrs {
+            let (Some(before), Some(segment)) = (text.get(..attr.start), text.get(attr.start..attr.end)) else {
+                continue;
+            };
+            if attr.start < copied {
+                continue;
+            }
+
+            let styles: Vec<&Style> = attr.effects.iter()
+                .filter_map(|effect| match effect {
+                    TextEffect::Styles(styles) => Some(styles),
+                    _ => None,
+                })
+                .flatten()
+                .collect();
+
+            let start = before.chars().count();
+            for style in &styles {
+                attributes.push(PyTextAttribute {
+                    start,
+                    end: start + segment.chars().count(),
+                    style: match style {
+                        Style::Bold => "bold",
+                        Style::Italic => "italic",
+                        Style::Strikethrough => "strikethrough",
+                        Style::Underline => "underline",
+                    }.to_string(),
+                });
+            }
+
+            // Markers must hug the text, so keep surrounding whitespace outside them
+            markdown.push_str(&text[copied..attr.start]);
+            let trimmed = segment.trim();
+            if styles.is_empty() || trimmed.is_empty() {
+                markdown.push_str(segment);
+            } else {
+                let leading = &segment[..segment.len() - segment.trim_start().len()];
+                let trailing = &segment[segment.trim_end().len()..];
+                let (mut open, mut close) = (String::new(), String::new());
+                for style in &styles {
+                    let (o, c) = match style {
+                        Style::Bold => ("**", "**"),
+                        Style::Italic => ("*", "*"),
+                        Style::Strikethrough => ("~~", "~~"),
+                        Style::Underline => ("<u>", "</u>"),
+                    };
+                    open.push_str(o);
+                    close.insert_str(0, c);
+                }
+                markdown.push_str(leading);
+                markdown.push_str(&open);
+                markdown.push_str(trimmed);
+                markdown.push_str(&close);
+                markdown.push_str(trailing);
+            }
+            copied = attr.end;
+        }
+    }
+
+    if attributes.is_empty() {
+        return (attributes, None);
+    }
+    markdown.push_str(&text[copied..]);
+    (attributes, Some(markdown))
+}
+
+/// Collect shared URLs: the link preview from `payload_data`, then any other links in the text
+pub(crate) fn extract_links(msg: &Message, conn: &Connection) -> Vec<PyLink> {
+    let mut links = Vec::new();
+
+    if msg.is_url() {
+        let payload = msg
+            .payload_data(conn)
+            .and_then(|raw| parse_ns_keyed_archiver(&raw).ok());
+        if let Some(payload) = payload {
+            if let Ok(preview) = URLMessage::from_map(&payload) {
+                if let Some(url) = preview.url.or(preview.original_url) {
+                    links.push(PyLink {
+                        url: url.to_string(),
+                        original_url: preview.original_url.map(str::to_string),
+                        title: preview.title.map(str::to_string),
+                        summary: preview.summary.map(str::to_string),
+                        site_name: preview.site_name.map(str::to_string),
+                    });
+                }
+            }
+        }
+    }
+
+    for component in &msg.components {
+        let BubbleComponent::Text(attrs) = component else {
+            continue;
+        };
+        for attr in attrs {
+            for effect in &attr.effects {
+                let TextEffect::Link(url) = effect else {
+                    continue;
+                };
+                let seen = links.iter().any(|link: &PyLink| {
+                    link.url == *url || link.original_url.as_deref() == Some(url.as_str())
+                });
+                if !seen {
+                    links.push(PyLink {
+                        url: url.clone(),
+                        original_url: None,
+                        title: None,
+                        summary: None,
+                        site_name: None,
+                    });
+                }
+            }
+        }
+    }
+
+    links
+}
+
+/// Find a message's FaceTime link and, for SharePlay/FaceTime rows, the call outcome
+///
+/// SharePlay rows record whether the session started or ended in
+/// `group_action_type`; other outcomes are not stored in `chat.db`.
+pub(crate) fn facetime_info(msg: &Message, links: &[PyLink]) -> (Option<String>, Option<String>) {
+    let facetime_url = links
+        .iter()
+        .map(|link| link.url.as_str())
+        .find(|url| {
+            url.starts_with("https://facetime.apple.com/")
+                || url.starts_with("facetime:")
+                || url.starts_with("facetime-audio:")
+        })
+        .map(str::to_string);
+
+    let call_outcome = if msg.is_shareplay() {
+        match msg.group_action_type {
+            0 => Some("started".to_string()),
+            1 => Some("ended".to_string()),
+            _ => None,
+        }
+    } else {
+        None
+    };
+
+    (facetime_url, call_outcome)
+}
+
+/// Get a message's iMessage app bundle id and the kind of app it belongs to
+pub(crate) fn app_info(msg: &Message) -> (Option<String>, Option<String>) {
+    let Some(bundle_id) = parse_balloon_bundle_id(msg.balloon_bundle_id.as_deref()) else {
+        return (None, None);
+    };
+
+    let app_type = match bundle_id {
+        "com.apple.messages.URLBalloonProvider" => "url",
+        "com.apple.Handwriting.HandwritingProvider" => "handwriting",
+        "com.apple.DigitalTouchBalloonProvider" => "digital_touch",
+        "com.apple.PassbookUIService.PeerPaymentMessagesExtension" => "apple_pay",
+        "com.apple.ActivityMessagesApp.MessagesExtension" => "fitness",
+        "com.apple.mobileslideshow.PhotosMessagesApp" => "slideshow",
+        "com.apple.SafetyMonitorApp.SafetyMonitorMessages" => "check_in",
+        "com.apple.findmy.FindMyMessagesApp" => "find_my",
+        _ => "application",
+    };
+
+    (Some(bundle_id.to_string()), Some(app_type.to_string()))
+}
+
+/// Parse the bubble text of an Apple Pay message into amount, currency, and transaction type
+pub(crate) fn apple_pay_info(msg: &Message, conn: &Connection) -> Option<PyApplePay> {
+    if app_info(msg).1.as_deref() != Some("apple_pay") {
+        return None;
+    }
+
+    let payload = msg.payload_data(conn)?;
+    let payload = parse_ns_keyed_archiver(&payload).unwrap_or(payload);
+    let app = AppMessage::from_map(&payload).ok()?;
+
+    // Apple uses non-breaking spaces, e.g. "Apple\u{a0}Pay"
+    let description = app.ldtext.map(|text| text.replace('\u{a0}', " "));
+    let (amount, currency) = description
+        .as_deref()
+        .or(app.subcaption)
+        .map(parse_amount)
+        .unwrap_or((None, None));
+
+    let transaction_type = description.as_deref().and_then(|text| {
+        let lower = text.to_lowercase();
+        if lower.contains("weekly") || lower.contains("monthly") || lower.contains("every") {
+            Some("recurring")
+        } else if lower.starts_with("sent") || lower.starts_with("sending") {
+            Some("sent")
+        } else if lower.starts_with("received") {
+            Some("received")
+        } else if lower.starts_with("request") {
+            Some("requested")
+        } else {
+            None
+        }
+    });
+
+    Some(PyApplePay {
+        amount,
+        currency,
+        transaction_type: transaction_type.map(str::to_string),
+        description,
+    })
+}
+
+/// Decode which Digital Touch effect a message carries
+pub(crate) fn digital_touch_kind(msg: &Message, conn: &Connection) -> Option<String> {
+    if app_info(msg).1.as_deref() != Some("digital_touch") {
+        return None;
+    }
+
+    let kind = match msg.raw_payload_data(conn).and_then(|payload| digital_touch::from_payload(&payload)) {
+        Some(DigitalTouch::Tap) => "tap",
+        Some(DigitalTouch::Heartbeat) => "heartbeat",
+        Some(DigitalTouch::Sketch) => "sketch",
+        Some(DigitalTouch::Kiss) => "kiss",
+        Some(DigitalTouch::Fireball) => "fireball",
+        Some(DigitalTouch::Unknown) | None => "unknown",
+    };
+
+    Some(kind.to_string())
+}
+
+/// Find the first currency amount in a string like "Sent $265 with Apple Pay."
+///
+/// The amount must come with a currency symbol or ISO 4217 code, either
+/// attached as in "$5" or "5€", or as the word before or after it as in
+/// "USD 5" or "12,50 €". Numbers such as "(about 5)" or "x2" are passed over.
+pub(crate) fn parse_amount(text: &str) -> (Option<f64>, Option<String>) {
+    const OPENING: [char; 6] = ['(', '[', '"', '\'', '\u{201c}', '\u{2018}'];
+    const CLOSING: [char; 11] = [')', ']', '"', '\'', '\u{201d}', '\u{2019}', '.', ',', '!', '?', ';'];
+
+    let words: Vec<&str> = text.split_whitespace().collect();
+    for (i, word) in words.iter().enumerate() {
+        let Some(digits_at) = word.find(|c: char| c.is_ascii_digit()) else {
+            continue;
+        };
+        let number: String = word[digits_at..]
+            .chars()
+            .take_while(|c| c.is_ascii_digit() || *c == '.' || *c == ',')
+            .collect();
+        // Opening punctuation, as in "($5)", and closing punctuation, as in "5€.", are not part of the symbol
+        let before = word[..digits_at].trim_start_matches(OPENING);
+        let after = word[digits_at + number.len()..].trim_end_matches(CLOSING);
+
+        let currency = match (before, after) {
+            ("", "") => {
+                let previous = i.checked_sub(1).and_then(|i| currency_code(words[i].trim_start_matches(OPENING)));
+                previous.or_else(|| words.get(i + 1).and_then(|next| currency_code(next.trim_end_matches(CLOSING))))
+            }
+            ("", symbol) | (symbol, "") => currency_code(symbol),
+            _ => None,
+        };
+        let Some(currency) = currency else {
+            continue;
+        };
+        let Some(amount) = parse_grouped_number(number.trim_end_matches(['.', ','])) else {
+            continue;
+        };
+
+        return (Some(amount), Some(currency.to_string()));
+    }
+
+    (None, None)
+}
+
+/// Parse a number written with either `.` or `,` as the decimal separator
+///
+/// The last separator is the decimal one, as in "1,234.50" or "1.234,50",
+/// unless exactly three digits follow it and it is the only kind used, as
+/// in "1,234" or "1.234.567". Digit groups before it must be of three.
+pub(crate) fn parse_grouped_number(number: &str) -> Option<f64> {
+    let (integer, fraction, grouping) = match number.rfind(['.', ',']) {
+        Some(at) => {
+            let (separator, other) = if number[at..].starts_with('.') { ('.', ',') } else { (',', '.') };
+            let (integer, fraction) = (&number[..at], &number[at + 1..]);
+            if fraction.len() != 3 || integer.contains(other) {
+                (integer, Some(fraction), other)
+            } else {
+                (number, None, separator)
+            }
+        }
+        None => (number, None, ','),
+    };
+
+    let mut groups = integer.split(grouping);
+    let first = groups.next()?;
+    if first.is_empty() || !first.chars().all(|c| c.is_ascii_digit()) || (integer.contains(grouping) && first.len() > 3) {
+        return None;
+    }
+    let mut digits = first.to_string();
+    for group in groups {
+        if group.len() != 3 || !group.chars().all(|c| c.is_ascii_digit()) {
+            return None;
+        }
+        digits.push_str(group);
+    }
+    if let Some(fraction) = fraction {
+        digits.push('.');
+        digits.push_str(fraction);
+    }
+    digits.parse().ok()
+}
+
+/// ISO 4217 codes accepted as written before an amount, e.g. "CHF5"
+pub(crate) const CURRENCY_CODES: &[&str] = &[
+    "AED", "AUD", "BRL", "CAD", "CHF", "CNY", "CZK", "DKK", "EUR", "GBP", "HKD", "HUF", "IDR", "ILS",
+    "INR", "JPY", "KRW", "MXN", "MYR", "NOK", "NZD", "PHP", "PLN", "SAR", "SEK", "SGD", "THB", "TRY",
+    "TWD", "USD", "ZAR",
+];
+
+/// ISO 4217 code of a currency symbol or code, `None` if `symbol` is neither
+pub(crate) fn currency_code(symbol: &str) -> Option<&'static str> {
+    let code = match symbol {
+        "$" | "US$" => "USD",
+        "€" => "EUR",
+        "£" => "GBP",
+        "¥" => "JPY",
+        "₹" => "INR",
+        "₩" => "KRW",
+        "₪" => "ILS",
+        "CA$" | "C$" => "CAD",
+        "A$" => "AUD",
+        "NZ$" => "NZD",
+        "HK$" => "HKD",
+        "S$" => "SGD",
+        "R$" => "BRL",
+        code => return CURRENCY_CODES.iter().find(|known| **known == code).copied(),
+    };
+    Some(code)
+}
+
+/// Classify a message into a single kind
+///
+/// One of `text`, `attachment`, `reaction`, `unsent`, `group_event`,
+/// `audio_message`, `sticker`, `facetime`, `app_message`, `shareplay`, or
+/// `announcement`. Edited messages keep the kind of their content; the edit
+/// itself shows in `date_edited` and `edit_history`.
+/// Earlier kinds in the checks below win, e.g. a sticker placed on a message
+/// is a `reaction`.
+pub(crate) fn message_kind(
+    msg: &Message,
+    event_type: Option<&str>,
+    reaction: Option<&str>,
+    was_unsent: bool,
+    is_audio_message: bool,
+    is_sticker: bool,
+    is_facetime: bool,
+) -> String {
+    let kind = if event_type.is_some() {
+        "group_event"
+    } else if msg.is_shareplay() {
+        "shareplay"
+    } else if msg.item_type != 0 {
+        "announcement"
+    } else if reaction.is_some() {
+        "reaction"
+    } else if was_unsent {
+        "unsent"
+    } else if is_audio_message {
+        "audio_message"
+    } else if is_sticker {
+        "sticker"
+    } else if is_facetime {
+        "facetime"
+    } else if msg.balloon_bundle_id.is_some() {
+        "app_message"
+    } else if msg.has_attachments() && is_placeholder_text(msg.text.as_deref()) {
+        "attachment"
+    } else {
+        "text"
+    };
+
+    kind.to_string()
+}
+
+/// Cut `text` to at most `length` characters without splitting a grapheme cluster
+pub(crate) fn truncate_graphemes(text: &str, length: usize) -> (String, bool) {
+    let mut end = 0;
+    for (count, (start, grapheme)) in text.grapheme_indices(true).enumerate() {
+        if count == length {
+            return (text[..start].to_string(), true);
+        }
+        end = start + grapheme.len();
+    }
+    (text[..end].to_string(), false)
+}
+
+/// Find the emoji in a message and whether the text is nothing but emoji
+///
+/// Works on grapheme clusters so that skin tones, ZWJ sequences, flags, and
+/// keycaps each count as a single emoji.
+pub(crate) fn emoji_info(text: Option<&str>, reaction_emoji: Option<&str>) -> (bool, Vec<String>) {
+    let mut emoji = Vec::new();
+    let mut is_emoji_only = false;
+
+    if let Some(text) = text {
+        let mut has_other = false;
+        for grapheme in text.graphemes(true) {
+            if is_emoji_grapheme(grapheme) {
+                emoji.push(grapheme.to_string());
+            } else if !grapheme.trim().is_empty() && grapheme != "\u{fffc}" {
+                has_other = true;
+            }
+        }
+        is_emoji_only = !emoji.is_empty() && !has_other;
+    }
+
+    if let Some(reaction_emoji) = reaction_emoji {
+        emoji.push(reaction_emoji.to_string());
+    }
+
+    (is_emoji_only, emoji)
+}
+
+/// `true` if a grapheme cluster renders as an emoji
+///
+/// Symbols such as ✓, → or ♞ are text unless followed by the emoji
+/// presentation selector U+FE0F; U+FE0E asks for text even on characters
+/// that default to emoji.
+pub(crate) fn is_emoji_grapheme(grapheme: &str) -> bool {
+    if grapheme.contains('\u{fe0e}') {
+        return false;
+    }
+    grapheme.chars().any(|c| matches!(c, '\u{fe0f}' | '\u{20e3}') || has_emoji_presentation(c))
+}
+
+/// `true` for characters shown as emoji by default, the Unicode `Emoji_Presentation` property
+///
+/// Ranges are those of `emoji-data.txt` from Unicode 16.0; pictographs that
+/// default to text, such as 🌡 U+1F321 or 🛠 U+1F6E0, are left out.
+pub(crate) fn has_emoji_presentation(c: char) -> bool {
+    matches!(c as u32,
+        0x231A..=0x231B | 0x23E9..=0x23EC | 0x23F0 | 0x23F3 | 0x25FD..=0x25FE
+        | 0x2614..=0x2615 | 0x2648..=0x2653 | 0x267F | 0x2693 | 0x26A1 | 0x26AA..=0x26AB
+        | 0x26BD..=0x26BE | 0x26C4..=0x26C5 | 0x26CE | 0x26D4 | 0x26EA | 0x26F2..=0x26F3
+        | 0x26F5 | 0x26FA | 0x26FD | 0x2705 | 0x270A..=0x270B | 0x2728 | 0x274C | 0x274E
+        | 0x2753..=0x2755 | 0x2757 | 0x2795..=0x2797 | 0x27B0 | 0x27BF
+        | 0x2B1B..=0x2B1C | 0x2B50 | 0x2B55
+        | 0x1F004 | 0x1F0CF | 0x1F18E | 0x1F191..=0x1F19A
+        | 0x1F1E6..=0x1F1FF  // Regional indicators, paired into flags
+        | 0x1F201 | 0x1F21A | 0x1F22F | 0x1F232..=0x1F236 | 0x1F238..=0x1F23A | 0x1F250..=0x1F251
+        // Pictographs, emoticons, and skin tone modifiers
+        | 0x1F300..=0x1F320 | 0x1F32D..=0x1F335 | 0x1F337..=0x1F37C | 0x1F37E..=0x1F393
+        | 0x1F3A0..=0x1F3CA | 0x1F3CF..=0x1F3D3 | 0x1F3E0..=0x1F3F0 | 0x1F3F4 | 0x1F3F8..=0x1F43E
+        | 0x1F440 | 0x1F442..=0x1F4FC | 0x1F4FF..=0x1F53D | 0x1F54B..=0x1F54E | 0x1F550..=0x1F567
+        | 0x1F57A | 0x1F595..=0x1F596 | 0x1F5A4 | 0x1F5FB..=0x1F64F
+        // Transport and map symbols
+        | 0x1F680..=0x1F6C5 | 0x1F6CC | 0x1F6D0..=0x1F6D2 | 0x1F6D5..=0x1F6D7 | 0x1F6DC..=0x1F6DF
+        | 0x1F6EB..=0x1F6EC | 0x1F6F4..=0x1F6FC
+        | 0x1F7E0..=0x1F7EB | 0x1F7F0
+        | 0x1F90C..=0x1F93A | 0x1F93C..=0x1F945 | 0x1F947..=0x1F9FF
+        // Newer emoji
+        | 0x1FA70..=0x1FA7C | 0x1FA80..=0x1FA89 | 0x1FA8F..=0x1FAC6 | 0x1FACE..=0x1FADC
+        | 0x1FADF..=0x1FAE9 | 0x1FAF0..=0x1FAF8
+    )
+}
+
+/// Merge an SMS/MMS subject with the body, subject first on its own line
+///
+/// A body that is only attachment placeholders is dropped in favor of the subject.
+pub(crate) fn combine_subject(subject: Option<&str>, text: Option<&str>) -> Option<String> {
+    let subject = subject.map(str::trim).filter(|s| !s.is_empty());
+    match (subject, text) {
+        (Some(subject), Some(text)) if !is_placeholder_text(Some(text)) => Some(format!("{}\n{}", subject, text)),
+        (Some(subject), _) => Some(subject.to_string()),
+        (None, text) => text.map(str::to_string),
+    }
+}
+
+/// `true` if a message has no readable text, only whitespace or attachment placeholders
+pub(crate) fn is_placeholder_text(text: Option<&str>) -> bool {
+    text.is_none_or(|text| text.chars().all(|c| c == '\u{fffc}' || c.is_whitespace()))
+}
+
+/// Convert a plist value into the equivalent Python object
+pub(crate) fn plist_to_py(py: Python, value: &plist::Value) -> PyObject {
+    match value {
+        plist::Value::Dictionary(dict) => {
+            let out = PyDict::new_bound(py);
+            for (key, item) in dict {
+                // Setting a string key cannot fail
+                let _ = out.set_item(key, plist_to_py(py, item));
+            }
+            out.into()
+        }
+        plist::Value::Array(items) => items
+            .iter()
+            .map(|item| plist_to_py(py, item))
+            .collect::<Vec<_>>()
+            .into_py(py),
+        plist::Value::Boolean(b) => b.into_py(py),
+        plist::Value::Integer(i) => match i.as_signed() {
+            Some(i) => i.into_py(py),
+            None => i.as_unsigned().into_py(py),
+        },
+        plist::Value::Real(f) => f.into_py(py),
+        plist::Value::String(s) => s.into_py(py),
+        plist::Value::Date(d) => d.to_xml_format().into_py(py),
+        plist::Value::Data(bytes) => PyBytes::new_bound(py, bytes).into(),
+        plist::Value::Uid(uid) => uid.get().into_py(py),
+        _ => py.None(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_amount_reads_symbol_and_number() {
+        assert_eq!(parse_amount("Sent $265 with Apple Pay."), (Some(265.0), Some("USD".to_string())));
+        assert_eq!(parse_amount("Requested €1,234.50"), (Some(1234.5), Some("EUR".to_string())));
+        assert_eq!(parse_amount("You received £12."), (Some(12.0), Some("GBP".to_string())));
+        assert_eq!(parse_amount("CA$40 for dinner"), (Some(40.0), Some("CAD".to_string())));
+        assert_eq!(parse_amount("CHF5"), (Some(5.0), Some("CHF".to_string())));
+        assert_eq!(parse_amount("Paid ($12.50)"), (Some(12.5), Some("USD".to_string())));
+    }
+
+    #[test]
+    fn parse_amount_reads_comma_decimals() {
+        assert_eq!(parse_amount("Sent €1.234,50 with Apple Pay."), (Some(1234.5), Some("EUR".to_string())));
+        assert_eq!(parse_amount("Requested €12,50"), (Some(12.5), Some("EUR".to_string())));
+        assert_eq!(parse_amount("€1.234.567,89"), (Some(1234567.89), Some("EUR".to_string())));
+        assert_eq!(parse_amount("$1,234,567"), (Some(1234567.0), Some("USD".to_string())));
+        assert_eq!(parse_amount("€1.234"), (Some(1234.0), Some("EUR".to_string())));
+        // The symbol or code may follow the amount, or be a word of its own
+        assert_eq!(parse_amount("Sent 12,50 € with Apple Pay"), (Some(12.5), Some("EUR".to_string())));
+        assert_eq!(parse_amount("1.234,50 €"), (Some(1234.5), Some("EUR".to_string())));
+        assert_eq!(parse_amount("Paid 12,50€."), (Some(12.5), Some("EUR".to_string())));
+        assert_eq!(parse_amount("USD 5"), (Some(5.0), Some("USD".to_string())));
+        assert_eq!(parse_amount("Requested 20 CHF."), (Some(20.0), Some("CHF".to_string())));
+        // Groups of other than three digits are not a number
+        assert_eq!(parse_amount("$12,34,5 and $1,23,456"), (None, None));
+    }
+
+    #[test]
+    fn parse_amount_needs_a_known_currency() {
+        assert_eq!(parse_amount("(about 5)"), (None, None));
+        assert_eq!(parse_amount("about 5 dollars"), (None, None));
+        assert_eq!(parse_amount("x2 and RAM16 and #3"), (None, None));
+        assert_eq!(parse_amount("(5) then €3"), (Some(3.0), Some("EUR".to_string())));
+    }
+
+    #[test]
+    fn parse_amount_skips_bare_numbers() {
+        assert_eq!(parse_amount("Sent 3 times, then $7"), (Some(7.0), Some("USD".to_string())));
+        assert_eq!(parse_amount("Apple Pay"), (None, None));
+        assert_eq!(parse_amount(""), (None, None));
+        assert_eq!(parse_amount("$1.2.3 and $4"), (Some(4.0), Some("USD".to_string())));
+    }
+
+    #[test]
+    fn emoji_graphemes_need_emoji_presentation() {
+        for emoji in ["😀", "👍🏽", "👨‍👩‍👧", "🇯🇵", "❤️", "☺️", "#️⃣", "⚽", "✅", "⭐", "♈", "🌡️", "🛠️", "🫩"] {
+            assert!(is_emoji_grapheme(emoji), "{:?}", emoji);
+        }
+        // Pictographs that default to text, like 🌡 U+1F321 and 🛠 U+1F6E0, need U+FE0F
+        for text in ["✓", "→", "➔", "☐", "❘", "♞", "♃", "★", "☺", "❤", "⚽\u{fe0e}", "a", "é", "\u{1f321}", "\u{1f6e0}", "\u{1f6e9}", "\u{1f549}"] {
+            assert!(!is_emoji_grapheme(text), "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn emoji_info_leaves_symbols_as_text() {
+        assert_eq!(emoji_info(Some("✓ done → next"), None), (false, Vec::new()));
+        assert_eq!(emoji_info(Some("☐ ❘ ♞"), None), (false, Vec::new()));
+        assert_eq!(emoji_info(Some("👍 ❤️"), None), (true, vec!["👍".to_string(), "❤️".to_string()]));
+    }
+
+    #[test]
+    fn truncate_graphemes_keeps_clusters_whole() {
+        assert_eq!(truncate_graphemes("hello", 3), ("hel".to_string(), true));
+        assert_eq!(truncate_graphemes("hello", 5), ("hello".to_string(), false));
+        assert_eq!(truncate_graphemes("hello", 0), (String::new(), true));
+        assert_eq!(truncate_graphemes("", 0), (String::new(), false));
+        // A family emoji, a skin tone and a flag are one character each
+        let text = "👨‍👩‍👧👍🏽🇯🇵é";
+        assert_eq!(truncate_graphemes(text, 1), ("👨‍👩‍👧".to_string(), true));
+        assert_eq!(truncate_graphemes(text, 3), ("👨‍👩‍👧👍🏽🇯🇵".to_string(), true));
+        assert_eq!(truncate_graphemes(text, 4), (text.to_string(), false));
+        assert_eq!(truncate_graphemes("e\u{301}x", 1), ("e\u{301}".to_string(), true));
+    }
+}
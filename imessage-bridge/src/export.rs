@@ -0,0 +1,1171 @@
+//! Arrow, pandas, polars, JSONL, CSV, Parquet and SQLite export
+
+use super::*;
+
+/// One line of a JSON Lines export: a message with its sender's handle resolved
+#[derive(Serialize)]
+pub(crate) struct ExportedMessage<'a> {
+    #[serde(flatten)]
+    pub(crate) message: &'a PyMessage,
+    pub(crate) handle: Option<PyHandle>,
+}
+
+#[pymethods]
+impl IMessageDB {
+    /// Write every message to `path` as JSON Lines in chronological order
+    ///
+    /// Memory use stays flat regardless of archive size. Use
+    /// `messages()...export_jsonl()` to export filtered messages.
+    pub(crate) fn export_messages(slf: Py<Self>, py: Python, path: PathBuf, batch_size: Option<usize>) -> PyResult<usize> {
+        Self::messages(slf).export_jsonl(py, path, batch_size, None)
+    }
+
+    /// Return the messages matching `filters` as a `pyarrow.RecordBatch`
+    ///
+    /// `filters` is a `MessageQuery` from `messages()`; without one every
+    /// message is returned. Building the columns in Rust skips creating a
+    /// `PyMessage` per row, which dominates the cost of loading a large archive
+    /// into a DataFrame. `batch_size` is as for `MessageQuery.fetch_arrow`.
+    pub(crate) fn query_messages_arrow(slf: Py<Self>, py: Python, filters: Option<PyRef<MessageQuery>>, batch_size: Option<usize>) -> PyResult<PyObject> {
+        match filters {
+            Some(query) => query.fetch_arrow(py, batch_size),
+            None => Self::messages(slf).fetch_arrow(py, batch_size),
+        }
+    }
+
+    /// Write the messages matching `filters` to `path` as JSON Lines, ready for downstream pipelines
+    ///
+    /// `filters` is a `MessageQuery` from `messages()`; without one every
+    /// message is exported. Tapbacks are folded into the `reactions` of their
+    /// target and each line carries the sender's `handle`, while memory use
+    /// stays flat. Returns the number of lines written. See
+    /// `MessageQuery.export_jsonl`.
+    pub(crate) fn export_jsonl(slf: Py<Self>, py: Python, path: PathBuf, filters: Option<PyRef<MessageQuery>>, batch_size: Option<usize>) -> PyResult<usize> {
+        let (db, filter) = match filters {
+            Some(query) => (query.db.clone_ref(py), query.filter.clone()),
+            None => (slf, MessageFilter::default()),
+        };
+        MessageQuery { db, filter: MessageFilter { attach_reactions: true, ..filter } }.export_jsonl(py, path, batch_size, None)
+    }
+
+    /// Write the messages matching `filters` to `path` as Parquet, for DuckDB, pandas and the like
+    ///
+    /// `filters` is a `MessageQuery` from `messages()`; without one every
+    /// message is exported. Tapbacks are rows of their own, pointing at their
+    /// target through `reaction_target_guid`. See `MessageQuery.export_parquet`.
+    pub(crate) fn export_parquet(slf: Py<Self>, py: Python, path: PathBuf, filters: Option<PyRef<MessageQuery>>, batch_size: Option<usize>) -> PyResult<usize> {
+        match filters {
+            Some(query) => query.export_parquet(py, path, batch_size, None),
+            None => Self::messages(slf).export_parquet(py, path, batch_size, None),
+        }
+    }
+
+    /// Write the messages matching `filters` under the directory `path` as Parquet partitioned by month
+    ///
+    /// `filters` is a `MessageQuery` from `messages()`; without one every
+    /// message is exported. See `MessageQuery.export_parquet_partitioned`.
+    pub(crate) fn export_parquet_partitioned(slf: Py<Self>, py: Python, path: PathBuf, filters: Option<PyRef<MessageQuery>>, batch_size: Option<usize>) -> PyResult<usize> {
+        match filters {
+            Some(query) => query.export_parquet_partitioned(py, path, batch_size, None),
+            None => Self::messages(slf).export_parquet_partitioned(py, path, batch_size, None),
+        }
+    }
+
+    /// Write the messages matching `filters` to `path` as a normalized SQLite database
+    ///
+    /// `filters` is a `MessageQuery` from `messages()`; without one every
+    /// message is exported. See `MessageQuery.export_sqlite` for the schema.
+    pub(crate) fn export_sqlite(slf: Py<Self>, py: Python, path: PathBuf, filters: Option<PyRef<MessageQuery>>, batch_size: Option<usize>) -> PyResult<usize> {
+        match filters {
+            Some(query) => query.export_sqlite(py, path, batch_size, None),
+            None => Self::messages(slf).export_sqlite(py, path, batch_size, None),
+        }
+    }
+
+    /// Write the messages matching `filters` to `path` as CSV for opening in a spreadsheet
+    ///
+    /// `filters` is a `MessageQuery` from `messages()`; without one every
+    /// message is exported. Tapbacks are folded into the `reactions` column of
+    /// their target. See `MessageQuery.export_csv` for `columns`.
+    pub(crate) fn export_csv(slf: Py<Self>, py: Python, path: PathBuf, filters: Option<PyRef<MessageQuery>>, columns: Option<Vec<String>>) -> PyResult<usize> {
+        let (db, filter) = match filters {
+            Some(query) => (query.db.clone_ref(py), query.filter.clone()),
+            None => (slf, MessageFilter::default()),
+        };
+        MessageQuery { db, filter: MessageFilter { attach_reactions: true, ..filter } }.export_csv(py, path, columns, None, None)
+    }
+}
+
+/// Arrow record batches of the messages matching a filter, read a page at a time on a connection of its own
+pub(crate) struct ArrowPages {
+    pub(crate) db: IMessageDB,
+    pub(crate) filter: MessageFilter,
+    pub(crate) position: Option<PagePosition>,  // `None` once exhausted or after an error
+    pub(crate) remaining: usize,
+    pub(crate) batch_size: usize,
+    pub(crate) schema: SchemaRef,
+}
+
+impl Iterator for ArrowPages {
+    type Item = Result<RecordBatch, ArrowError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let position = self.position.take().filter(|_| self.remaining > 0)?;
+        let page = self.filter.page(&self.db, position, self.batch_size.min(self.remaining))
+            .and_then(|(messages, next)| {
+                self.position = next;
+                self.remaining -= messages.len();
+                message_batch(&messages)
+            });
+        match page {
+            Ok(batch) if batch.num_rows() == 0 => None,
+            Ok(batch) => Some(Ok(batch)),
+            Err(e) => Some(Err(ArrowError::ExternalError(Box::new(e)))),
+        }
+    }
+}
+
+impl RecordBatchReader for ArrowPages {
+    fn schema(&self) -> SchemaRef {
+        self.schema.clone()
+    }
+}
+
+impl MessageQuery {
+    /// Database path and options for the connections an export reads from
+    ///
+    /// Exports write out the text of every row, so messages are decoded as
+    /// they are read rather than left to resolve lazily a batch at a time.
+    pub(crate) fn export_source(&self, py: Python) -> (PathBuf, ConnectionOptions) {
+        let db = self.db.borrow(py);
+        (db.db_path.clone(), ConnectionOptions { eager_text: true, ..db.options.clone() })
+    }
+
+    /// Run the query into a single batch of `message_batch` columns; see `fetch_arrow`
+    pub(crate) fn fetch_batch(&self, py: Python, batch_size: Option<usize>) -> PyResult<RecordBatch> {
+        let (db_path, options) = self.export_source(py);
+        let filter = &self.filter;
+        let batch_size = batch_size.unwrap_or(10000).max(1);
+
+        py.allow_threads(|| {
+            let mut batches = vec![message_batch(&[])?];
+            stream_pages(&db_path, &options, filter, batch_size, 1, |messages| {
+                batches.push(message_batch(&messages)?);
+                Ok(())
+            })?;
+            arrow_select::concat::concat_batches(&batches[0].schema(), &batches).map_err(|e| runtime_err("Failed to build record batch", e))
+        })
+    }
+
+    /// Stream the matching messages, decoded and folded for export, to `write` one at a time
+    ///
+    /// `write` runs on the calling thread with the GIL released and gets a
+    /// connection of its own for looking up handles. See `export_jsonl`.
+    pub(crate) fn export_each(
+        &self,
+        py: Python,
+        batch_size: Option<usize>,
+        buffered: Option<usize>,
+        mut write: impl FnMut(&IMessageDB, &PyMessage) -> PyResult<()> + Send,
+    ) -> PyResult<usize> {
+        let (db_path, options) = self.export_source(py);
+        let fold = self.filter.attach_reactions;
+        // Tapbacks arrive with their targets instead
+        let filter = &MessageFilter { exclude_reactions: self.filter.exclude_reactions || fold, ..self.filter.clone() };
+        let batch_size = batch_size.unwrap_or(500).max(1);
+        let buffered = buffered.unwrap_or(4).max(1);
+
+        py.allow_threads(|| {
+            let lookups = IMessageDB::open(db_path.clone(), options.clone())?;
+            if fold {
+                lookups.index_tapbacks()?;
+            }
+            let mut written = 0;
+
+            stream_pages(&db_path, &options, filter, batch_size, buffered, |batch| {
+                let batch = if fold { lookups.fold_tapbacks(batch)? } else { batch };
+                for message in &batch {
+                    write(&lookups, message)?;
+                }
+                written += batch.len();
+                Ok(())
+            })?;
+            Ok(written)
+        })
+    }
+
+    /// Fill a new SQLite mirror at `path` for `export_sqlite`
+    pub(crate) fn write_sqlite_mirror(&self, py: Python, path: &Path, batch_size: Option<usize>, buffered: Option<usize>) -> PyResult<usize> {
+        let write_error = |e: rusqlite::Error| io_err(format!("Failed to write {}", path.display()), e);
+        let mut mirror = Connection::open(path).map_err(write_error)?;
+        // The file is discarded on any error, so it needs no journal
+        mirror.execute_batch("PRAGMA journal_mode = OFF; PRAGMA synchronous = OFF; BEGIN;").map_err(write_error)?;
+        mirror.execute_batch(SQLITE_MIRROR_SCHEMA).map_err(write_error)?;
+        mirror.pragma_update(None, "user_version", SQLITE_MIRROR_VERSION).map_err(write_error)?;
+        let out = &mut mirror;
+        self.db.borrow(py).without_gil(py, move |db| copy_directory(&db.conn, out)).map_err(write_error)?;
+
+        let query = MessageQuery { db: self.db.clone_ref(py), filter: MessageFilter { attach_reactions: true, ..self.filter.clone() } };
+        let out = &mut mirror;
+        let written = query.export_each(py, batch_size, buffered, move |lookups, message| {
+            mirror_message(out, message, lookups)
+        })?;
+
+        mirror.execute_batch("COMMIT").map_err(write_error)?;
+        mirror.close().map_err(|(_, e)| write_error(e))?;
+        Ok(written)
+    }
+}
+
+#[pymethods]
+impl MessageQuery {
+    /// Stream the matching messages as a `pyarrow.RecordBatchReader`
+    ///
+    /// Each batch holds up to `batch_size` messages (default 10000) and is read
+    /// only when pyarrow asks for it, on a connection owned by the reader, so
+    /// the first rows arrive without waiting for the whole query. Columns are
+    /// those of `fetch_arrow`. Honours `limit`; `attach_reactions` is ignored.
+    pub(crate) fn iter_arrow(&self, py: Python, batch_size: Option<usize>) -> PyResult<PyObject> {
+        let (db_path, options) = self.export_source(py);
+        let db = IMessageDB::open(db_path, options)?;
+        let pages = ArrowPages {
+            db,
+            filter: self.filter.clone(),
+            position: Some(self.filter.start()),
+            remaining: self.filter.limit.unwrap_or(usize::MAX),
+            batch_size: batch_size.unwrap_or(10000).max(1),
+            schema: message_batch(&[])?.schema(),
+        };
+        Ok(reader_to_pyarrow(py, Box::new(pages))?.unbind())
+    }
+
+    /// Write the matching messages to `path` as JSON Lines, one message per line
+    ///
+    /// A reader thread with its own connection decodes `batch_size` messages
+    /// (default 500) at a time and hands them to the writer through a channel
+    /// holding at most `buffered` batches (default 4), so memory use stays flat
+    /// however large the archive is. Each line also carries the sender's
+    /// `handle`. With `attach_reactions`, tapbacks are folded into their
+    /// target's `reactions` even when stored pages apart, and tapbacks whose
+    /// target is not exported are left out. Honours `limit`, counting messages
+    /// other than tapbacks when folding. Returns the number of lines written.
+    pub(crate) fn export_jsonl(&self, py: Python, path: PathBuf, batch_size: Option<usize>, buffered: Option<usize>) -> PyResult<usize> {
+        let write_error = |e: std::io::Error| io_err(format!("Failed to write {}", path.display()), e);
+        let mut out = std::io::BufWriter::new(std::fs::File::create(&path).map_err(write_error)?);
+
+        let written = self.export_each(py, batch_size, buffered, |lookups, message| {
+            let handle = match message.handle_id {
+                Some(handle_id) => lookups.handle(handle_id)?,
+                None => None,
+            };
+            serde_json::to_writer(&mut out, &ExportedMessage { message, handle })
+                .map_err(std::io::Error::from)
+                .map_err(write_error)?;
+            out.write_all(b"\n").map_err(write_error)
+        })?;
+
+        out.flush().map_err(write_error)?;
+        Ok(written)
+    }
+
+    /// Write the matching messages to `path` as CSV, one message per row after a header
+    ///
+    /// `columns` picks and orders the fields written, by default `rowid`,
+    /// `date`, `sender`, `is_from_me`, `service` and `text`. Other columns
+    /// are scalar `PyMessage` fields of the same name, such as `guid`,
+    /// `date_read`, `kind` or `thread_originator_guid`; an unknown one raises
+    /// ValueError listing them all. Dates are ISO 8601 in UTC and `sender` is
+    /// the handle of the person who sent a message, or `Me`. Fields are
+    /// quoted as RFC 4180 requires and rows end in CRLF, so spreadsheets open
+    /// the file as is. Reading, `batch_size`, `buffered`, `limit` and
+    /// `attach_reactions` behave as for `export_jsonl`; folded tapbacks fill
+    /// the `reactions` column. Returns the number of rows written.
+    pub(crate) fn export_csv(&self, py: Python, path: PathBuf, columns: Option<Vec<String>>, batch_size: Option<usize>, buffered: Option<usize>) -> PyResult<usize> {
+        let columns = columns.unwrap_or_else(|| CSV_DEFAULT_COLUMNS.iter().map(|c| c.to_string()).collect());
+        if let Some(unknown) = columns.iter().find(|c| !CSV_COLUMNS.contains(&c.as_str())) {
+            return Err(PyErr::new::<pyo3::exceptions::PyValueError, _>(
+                format!("Unknown column: {} (expected one of {})", unknown, CSV_COLUMNS.join(", "))
+            ));
+        }
+
+        let write_error = |e: csv::Error| io_err(format!("Failed to write {}", path.display()), e);
+        let mut out = csv::WriterBuilder::new()
+            .terminator(csv::Terminator::CRLF)
+            .from_path(&path)
+            .map_err(write_error)?;
+        out.write_record(&columns).map_err(write_error)?;
+
+        let written = self.export_each(py, batch_size, buffered, |lookups, message| {
+            for column in &columns {
+                out.write_field(csv_field(column, message, lookups)?).map_err(write_error)?;
+            }
+            out.write_record(None::<&[u8]>).map_err(write_error)
+        })?;
+
+        out.flush().map_err(|e| write_error(e.into()))?;
+        Ok(written)
+    }
+
+    /// Run the query, returning a `pyarrow.RecordBatch` with one row per message
+    ///
+    /// Columns are filled in on the Rust side a page of `batch_size` messages
+    /// (default 10000) at a time and handed to pyarrow without copying, so no
+    /// `PyMessage` objects are created; `pyarrow` must be installed. Only scalar
+    /// fields become columns, so reactions, attachments and text styles are
+    /// left out. Honours `limit`; `attach_reactions` is ignored.
+    pub(crate) fn fetch_arrow(&self, py: Python, batch_size: Option<usize>) -> PyResult<PyObject> {
+        let batch = self.fetch_batch(py, batch_size)?;
+        batch_to_pyarrow(py, batch)
+    }
+
+    /// Run the query, returning a pandas DataFrame with one row per message
+    ///
+    /// Built on `fetch_arrow`, with the same columns, except that the Unix
+    /// timestamps (`date`, `date_read` and so on) become UTC `datetime64`
+    /// columns; `pyarrow` and `pandas` must be installed.
+    pub(crate) fn to_pandas(&self, py: Python, batch_size: Option<usize>) -> PyResult<PyObject> {
+        let batch = with_datetimes(self.fetch_batch(py, batch_size)?)?;
+        batch_to_pyarrow(py, batch)?.call_method0(py, "to_pandas")
+    }
+
+    /// Run the query, returning a polars DataFrame with one row per message
+    ///
+    /// Same columns as `to_pandas`, built from the Arrow batch without going
+    /// through pandas; `pyarrow` and `polars` must be installed.
+    pub(crate) fn to_polars(&self, py: Python, batch_size: Option<usize>) -> PyResult<PyObject> {
+        let batch = with_datetimes(self.fetch_batch(py, batch_size)?)?;
+        batch_to_polars(py, batch)
+    }
+
+    /// Write the matching messages to `path` as a Parquet file, one row per message
+    ///
+    /// Columns, their types and their nullability are those of `fetch_arrow`
+    /// and stay the same whatever the query matches, so files from separate
+    /// runs can be read together. Pages of `batch_size` messages (default
+    /// 10000) are read as for `export_jsonl` with at most `buffered` (default
+    /// 4) waiting, and compressed with Snappy. Honours `limit`;
+    /// `attach_reactions` is ignored. Returns the number of rows written.
+    pub(crate) fn export_parquet(&self, py: Python, path: PathBuf, batch_size: Option<usize>, buffered: Option<usize>) -> PyResult<usize> {
+        let (db_path, options) = self.export_source(py);
+        let filter = &self.filter;
+        let batch_size = batch_size.unwrap_or(10000).max(1);
+        let buffered = buffered.unwrap_or(4).max(1);
+
+        py.allow_threads(|| {
+            let write_error = |e: parquet::errors::ParquetError| io_err(format!("Failed to write {}", path.display()), e);
+            let file = std::fs::File::create(&path).map_err(|e| write_error(e.into()))?;
+            let properties = WriterProperties::builder().set_compression(Compression::SNAPPY).build();
+            let mut out = ArrowWriter::try_new(file, message_batch(&[])?.schema(), Some(properties)).map_err(write_error)?;
+            let mut written = 0;
+
+            stream_pages(&db_path, &options, filter, batch_size, buffered, |messages| {
+                out.write(&message_batch(&messages)?).map_err(write_error)?;
+                written += messages.len();
+                Ok(())
+            })?;
+
+            out.close().map_err(write_error)?;
+            Ok(written)
+        })
+    }
+
+    /// Write the matching messages under the directory `path` as Parquet partitioned by month
+    ///
+    /// Files go to `year=YYYY/month=MM/part-N.parquet`, with the columns of
+    /// `export_parquet` and months taken from `date` in UTC, so DuckDB,
+    /// polars and Spark can read the directory as one table and skip whole
+    /// months, e.g. `read_parquet('path/**/*.parquet', hive_partitioning = true)`
+    /// in DuckDB. `path` is created if missing and must otherwise be empty.
+    /// Reading, `batch_size` and `buffered` behave as for `export_parquet`.
+    /// Returns the number of messages written.
+    pub(crate) fn export_parquet_partitioned(&self, py: Python, path: PathBuf, batch_size: Option<usize>, buffered: Option<usize>) -> PyResult<usize> {
+        let (db_path, options) = self.export_source(py);
+        let filter = &self.filter;
+        let batch_size = batch_size.unwrap_or(10000).max(1);
+        let buffered = buffered.unwrap_or(4).max(1);
+
+        py.allow_threads(|| {
+            let write_error = |e: parquet::errors::ParquetError| io_err(format!("Failed to write {}", path.display()), e);
+            std::fs::create_dir_all(&path).map_err(|e| write_error(e.into()))?;
+            if std::fs::read_dir(&path).map_err(|e| write_error(e.into()))?.next().is_some() {
+                return Err(PyErr::new::<pyo3::exceptions::PyIOError, _>(
+                    format!("Failed to write {}: directory is not empty", path.display())
+                ));
+            }
+            let schema = message_batch(&[])?.schema();
+            let properties = WriterProperties::builder().set_compression(Compression::SNAPPY).build();
+            let month = |msg: &PyMessage| {
+                chrono::DateTime::from_timestamp(msg.date.floor() as i64, 0)
+                    .map(|date| (date.year(), date.month()))
+                    .unwrap_or((1970, 1))
+            };
+            // Pages come in date order, so months arrive one after another; a
+            // month seen again still gets a part file of its own
+            let mut parts: HashMap<(i32, u32), usize> = HashMap::new();
+            let mut current: Option<((i32, u32), ArrowWriter<std::fs::File>)> = None;
+            let mut written = 0;
+
+            stream_pages(&db_path, &options, filter, batch_size, buffered, |messages| {
+                for run in messages.chunk_by(|a, b| month(a) == month(b)) {
+                    let key = month(&run[0]);
+                    if current.as_ref().map(|(open, _)| *open) != Some(key) {
+                        if let Some((_, out)) = current.take() {
+                            out.close().map_err(write_error)?;
+                        }
+                        let part = parts.entry(key).or_insert(0);
+                        let dir = path.join(format!("year={}", key.0)).join(format!("month={:02}", key.1));
+                        std::fs::create_dir_all(&dir).map_err(|e| write_error(e.into()))?;
+                        let file = std::fs::File::create(dir.join(format!("part-{}.parquet", part))).map_err(|e| write_error(e.into()))?;
+                        *part += 1;
+                        current = Some((key, ArrowWriter::try_new(file, schema.clone(), Some(properties.clone())).map_err(write_error)?));
+                    }
+                    if let Some((_, out)) = current.as_mut() {
+                        out.write(&message_batch(run)?).map_err(write_error)?;
+                    }
+                }
+                written += messages.len();
+                Ok(())
+            })?;
+
+            if let Some((_, out)) = current {
+                out.close().map_err(write_error)?;
+            }
+            Ok(written)
+        })
+    }
+
+    /// Write the matching messages to `path` as a normalized SQLite database
+    ///
+    /// Unlike chat.db, whose layout shifts between macOS releases, the mirror
+    /// has a fixed, commented schema (see `sqlite_master`, versioned through
+    /// `PRAGMA user_version`): `messages` with decoded text, `chats`,
+    /// `handles` and `chat_handles`, each message's `attachments`, and
+    /// tapbacks as `reactions` of the message they apply to. Dates are Unix
+    /// timestamps. Every chat and handle is copied, whatever the filters. The
+    /// database is written to `path` + `.partial` and replaces any existing
+    /// file at `path` only once complete. Reading, `batch_size`, `buffered`
+    /// and `limit` behave as for `export_jsonl` with `attach_reactions`.
+    /// Returns the number of messages written.
+    pub(crate) fn export_sqlite(&self, py: Python, path: PathBuf, batch_size: Option<usize>, buffered: Option<usize>) -> PyResult<usize> {
+        // Built beside `path` and moved into place once complete, so a failed export leaves no partial mirror
+        let mut partial = path.clone().into_os_string();
+        partial.push(".partial");
+        let partial = PathBuf::from(partial);
+        if partial.exists() {
+            std::fs::remove_file(&partial).map_err(|e| io_err(format!("Failed to replace {}", partial.display()), e))?;
+        }
+
+        let written = self.write_sqlite_mirror(py, &partial, batch_size, buffered);
+        match written {
+            Ok(_) => std::fs::rename(&partial, &path).map_err(|e| io_err(format!("Failed to write {}", path.display()), e))?,
+            Err(_) => {
+                let _ = std::fs::remove_file(&partial);
+            }
+        }
+        written
+    }
+}
+
+/// Builds Arrow columns from one field of each row
+pub(crate) struct Columns<'a, T>(pub(crate) &'a [T]);
+
+impl<'a, T> Columns<'a, T> {
+    pub(crate) fn strings(&self, field: impl Fn(&'a T) -> Option<&'a str>) -> ArrayRef {
+        Arc::new(self.0.iter().map(field).collect::<StringArray>())
+    }
+
+    pub(crate) fn ints(&self, field: impl Fn(&'a T) -> Option<i32>) -> ArrayRef {
+        Arc::new(self.0.iter().map(field).collect::<Int32Array>())
+    }
+
+    pub(crate) fn longs(&self, field: impl Fn(&'a T) -> Option<i64>) -> ArrayRef {
+        Arc::new(self.0.iter().map(field).collect::<Int64Array>())
+    }
+
+    pub(crate) fn floats(&self, field: impl Fn(&'a T) -> Option<f64>) -> ArrayRef {
+        Arc::new(self.0.iter().map(field).collect::<Float64Array>())
+    }
+
+    pub(crate) fn flags(&self, field: impl Fn(&'a T) -> Option<bool>) -> ArrayRef {
+        Arc::new(self.0.iter().map(field).collect::<BooleanArray>())
+    }
+}
+
+/// Assemble named columns, each with its nullability, into a batch
+pub(crate) fn record_batch(columns: Vec<(&str, ArrayRef, bool)>) -> PyResult<RecordBatch> {
+    RecordBatch::try_from_iter_with_nullable(columns).map_err(|e| runtime_err("Failed to build record batch", e))
+}
+
+/// Lay out the scalar fields of `messages` as Arrow columns, one row per message
+///
+/// Nested fields such as reactions, attachments and text styles are left out;
+/// fetch the few messages that need them by ROWID. Nullability follows the
+/// field types rather than the rows, so batches of different pages concatenate.
+pub(crate) fn message_batch(messages: &[PyMessage]) -> PyResult<RecordBatch> {
+    let rows = Columns(messages);
+    record_batch(vec![
+        ("rowid", rows.ints(|m| Some(m.rowid)), false),
+        ("guid", rows.strings(|m| Some(&m.guid)), false),
+        ("text", rows.strings(|m| m.text.as_deref()), true),
+        ("display_text", rows.strings(|m| m.display_text.as_deref()), true),
+        ("kind", rows.strings(|m| Some(&m.kind)), false),
+        ("service", rows.strings(|m| Some(&m.service)), false),
+        ("handle_id", rows.ints(|m| m.handle_id), true),
+        ("subject", rows.strings(|m| m.subject.as_deref()), true),
+        ("date", rows.floats(|m| Some(m.date)), false),
+        ("date_read", rows.floats(|m| m.date_read), true),
+        ("date_delivered", rows.floats(|m| m.date_delivered), true),
+        ("date_edited", rows.floats(|m| m.date_edited), true),
+        ("date_retracted", rows.floats(|m| m.date_retracted), true),
+        ("is_from_me", rows.flags(|m| Some(m.is_from_me)), false),
+        ("is_read", rows.flags(|m| Some(m.is_read)), false),
+        ("is_sent", rows.flags(|m| Some(m.is_sent)), false),
+        ("is_delivered", rows.flags(|m| Some(m.is_delivered)), false),
+        ("was_unsent", rows.flags(|m| Some(m.was_unsent)), false),
+        ("is_deleted", rows.flags(|m| Some(m.is_deleted)), false),
+        ("cache_roomnames", rows.strings(|m| m.cache_roomnames.as_deref()), true),
+        ("group_title", rows.strings(|m| m.group_title.as_deref()), true),
+        ("thread_originator_guid", rows.strings(|m| m.thread_originator_guid.as_deref()), true),
+        ("associated_message_guid", rows.strings(|m| m.associated_message_guid.as_deref()), true),
+        ("associated_message_type", rows.ints(|m| m.associated_message_type), true),
+        ("item_type", rows.ints(|m| Some(m.item_type)), false),
+        ("event_type", rows.strings(|m| m.event_type.as_deref()), true),
+        ("reaction", rows.strings(|m| m.reaction.as_deref()), true),
+        ("reaction_target_guid", rows.strings(|m| m.reaction_target_guid.as_deref()), true),
+        ("effect", rows.strings(|m| m.effect.as_deref()), true),
+        ("balloon_bundle_id", rows.strings(|m| m.balloon_bundle_id.as_deref()), true),
+        ("app_type", rows.strings(|m| m.app_type.as_deref()), true),
+        ("is_audio_message", rows.flags(|m| Some(m.is_audio_message)), false),
+        ("audio_duration", rows.floats(|m| m.audio_duration), true),
+    ])
+}
+
+pub(crate) fn message_lite_batch(messages: &[PyMessageLite]) -> PyResult<RecordBatch> {
+    let rows = Columns(messages);
+    record_batch(vec![
+        ("rowid", rows.ints(|m| Some(m.rowid)), false),
+        ("guid", rows.strings(|m| Some(&m.guid)), false),
+        ("date", rows.floats(|m| Some(m.date)), false),
+        ("handle_id", rows.ints(|m| m.handle_id), true),
+        ("is_from_me", rows.flags(|m| Some(m.is_from_me)), false),
+        ("chat_id", rows.ints(|m| m.chat_id), true),
+        ("text", rows.strings(|m| m.text.as_deref()), true),
+        ("is_truncated", rows.flags(|m| Some(m.is_truncated)), false),
+    ])
+}
+
+pub(crate) fn handle_batch(handles: &[PyHandle]) -> PyResult<RecordBatch> {
+    let rows = Columns(handles);
+    record_batch(vec![
+        ("rowid", rows.ints(|h| Some(h.rowid)), false),
+        ("id", rows.strings(|h| Some(&h.id)), false),
+        ("service", rows.strings(|h| h.service.as_deref()), true),
+        ("uncanonicalized_id", rows.strings(|h| h.uncanonicalized_id.as_deref()), true),
+    ])
+}
+
+pub(crate) fn attachment_batch(attachments: &[PyAttachment]) -> PyResult<RecordBatch> {
+    let rows = Columns(attachments);
+    record_batch(vec![
+        ("rowid", rows.ints(|a| Some(a.rowid)), false),
+        ("guid", rows.strings(|a| Some(&a.guid)), false),
+        ("filename", rows.strings(|a| a.filename.as_deref()), true),
+        ("mime_type", rows.strings(|a| a.mime_type.as_deref()), true),
+        ("transfer_name", rows.strings(|a| a.transfer_name.as_deref()), true),
+        ("total_bytes", rows.longs(|a| a.total_bytes), true),
+        ("is_sticker", rows.flags(|a| Some(a.is_sticker)), false),
+        ("hide_attachment", rows.flags(|a| Some(a.hide_attachment)), false),
+        ("uti", rows.strings(|a| a.uti.as_deref()), true),
+    ])
+}
+
+pub(crate) fn chat_batch(chats: &[PyChat]) -> PyResult<RecordBatch> {
+    let rows = Columns(chats);
+    record_batch(vec![
+        ("rowid", rows.ints(|c| Some(c.rowid)), false),
+        ("guid", rows.strings(|c| Some(&c.guid)), false),
+        ("chat_identifier", rows.strings(|c| Some(&c.chat_identifier)), false),
+        ("service_name", rows.strings(|c| c.service_name.as_deref()), true),
+        ("display_name", rows.strings(|c| c.display_name.as_deref()), true),
+        ("group_id", rows.strings(|c| c.group_id.as_deref()), true),
+        ("style", rows.ints(|c| c.style), true),
+        ("is_group", rows.flags(|c| Some(c.is_group)), false),
+        ("is_pinned", rows.flags(|c| Some(c.is_pinned)), false),
+        ("pin_order", rows.longs(|c| c.pin_order.map(|order| order as i64)), true),
+    ])
+}
+
+pub(crate) fn chat_summary_batch(summaries: &[PyChatSummary]) -> PyResult<RecordBatch> {
+    let rows = Columns(summaries);
+    record_batch(vec![
+        ("chat_id", rows.ints(|c| Some(c.chat_id)), false),
+        ("chat_identifier", rows.strings(|c| Some(&c.chat_identifier)), false),
+        ("display_name", rows.strings(|c| c.display_name.as_deref()), true),
+        ("service_name", rows.strings(|c| c.service_name.as_deref()), true),
+        ("is_group", rows.flags(|c| Some(c.is_group)), false),
+        ("message_count", rows.longs(|c| Some(c.message_count)), false),
+        ("unread_count", rows.longs(|c| Some(c.unread_count)), false),
+        ("last_message_rowid", rows.ints(|c| c.last_message_rowid), true),
+        ("last_message_date", rows.floats(|c| c.last_message_date), true),
+        ("last_message_text", rows.strings(|c| c.last_message_text.as_deref()), true),
+        ("last_message_is_from_me", rows.flags(|c| c.last_message_is_from_me), true),
+    ])
+}
+
+/// Turn the Unix timestamp columns of `batch` into UTC timestamps, for pandas
+///
+/// Timestamp columns are the floats named `date`, `date_*` or `*_date`; the
+/// rest are kept as they are.
+pub(crate) fn with_datetimes(batch: RecordBatch) -> PyResult<RecordBatch> {
+    let schema = batch.schema();
+    let columns = schema.fields().iter().zip(batch.columns()).map(|(field, column)| {
+        let name = field.name();
+        let is_timestamp = name == "date" || name.starts_with("date_") || name.ends_with("_date");
+        match column.as_any().downcast_ref::<Float64Array>() {
+            Some(seconds) if is_timestamp => {
+                let micros: TimestampMicrosecondArray = seconds.iter()
+                    .map(|s| s.map(|s| (s * 1_000_000.0).round() as i64))
+                    .collect();
+                (name.as_str(), Arc::new(micros.with_timezone("UTC")) as ArrayRef, field.is_nullable())
+            }
+            _ => (name.as_str(), column.clone(), field.is_nullable()),
+        }
+    }).collect();
+    record_batch(columns)
+}
+
+/// Hand `batch` to pyarrow through the Arrow C stream interface, without copying its buffers
+pub(crate) fn batch_to_pyarrow(py: Python, batch: RecordBatch) -> PyResult<PyObject> {
+    let schema = batch.schema();
+    let reader = reader_to_pyarrow(py, Box::new(RecordBatchIterator::new([Ok(batch)], schema)))?;
+    Ok(reader.call_method0("read_next_batch")?.unbind())
+}
+
+/// Hand `batch` to polars by way of pyarrow, which shares its buffers rather than copying them
+pub(crate) fn batch_to_polars(py: Python, batch: RecordBatch) -> PyResult<PyObject> {
+    let batch = batch_to_pyarrow(py, batch)?;
+    Ok(py.import_bound("polars")?.call_method1("from_arrow", (batch,))?.unbind())
+}
+
+/// Wrap `reader` in a `pyarrow.RecordBatchReader` that pulls batches from it on demand
+pub(crate) fn reader_to_pyarrow(py: Python<'_>, reader: Box<dyn RecordBatchReader + Send>) -> PyResult<Bound<'_, PyAny>> {
+    // pyarrow moves the stream out, leaving a released husk behind to drop
+    let mut stream = FFI_ArrowArrayStream::new(reader);
+    py.import_bound("pyarrow")?
+        .getattr("RecordBatchReader")?
+        .call_method1("_import_from_c", (std::ptr::addr_of_mut!(stream) as usize,))
+}
+
+/// Columns `MessageQuery.export_csv` can write
+pub(crate) const CSV_COLUMNS: &[&str] = &[
+    "rowid", "guid", "date", "date_read", "date_delivered", "date_edited", "date_retracted",
+    "sender", "handle_id", "is_from_me", "service", "subject", "text", "display_text", "kind",
+    "group_title", "is_read", "is_sent", "is_delivered", "was_unsent", "is_deleted",
+    "thread_originator_guid", "reaction", "reaction_target_guid", "reactions", "effect",
+    "is_audio_message", "audio_duration",
+];
+
+/// Columns `MessageQuery.export_csv` writes when none are given
+pub(crate) const CSV_DEFAULT_COLUMNS: &[&str] = &["rowid", "date", "sender", "is_from_me", "service", "text"];
+
+/// Render one `CSV_COLUMNS` field of `msg`, empty when it has no value
+pub(crate) fn csv_field(column: &str, msg: &PyMessage, lookups: &IMessageDB) -> PyResult<String> {
+    let date = |timestamp: Option<f64>| {
+        timestamp
+            .and_then(|timestamp| chrono::DateTime::from_timestamp(timestamp.floor() as i64, 0))
+            .map(|date| date.format("%Y-%m-%dT%H:%M:%SZ").to_string())
+            .unwrap_or_default()
+    };
+    let text = |value: &Option<String>| value.clone().unwrap_or_default();
+    let number = |value: Option<i32>| value.map(|value| value.to_string()).unwrap_or_default();
+
+    Ok(match column {
+        "rowid" => msg.rowid.to_string(),
+        "guid" => msg.guid.clone(),
+        "date" => date(Some(msg.date)),
+        "date_read" => date(msg.date_read),
+        "date_delivered" => date(msg.date_delivered),
+        "date_edited" => date(msg.date_edited),
+        "date_retracted" => date(msg.date_retracted),
+        "sender" if msg.is_from_me => "Me".to_string(),
+        "sender" => match msg.handle_id {
+            Some(handle_id) => lookups.handle(handle_id)?.map(|handle| handle.id).unwrap_or_default(),
+            None => String::new(),
+        },
+        "handle_id" => number(msg.handle_id),
+        "is_from_me" => msg.is_from_me.to_string(),
+        "service" => msg.service.clone(),
+        "subject" => text(&msg.subject),
+        "text" => text(&msg.text),
+        "display_text" => text(&msg.display_text),
+        "kind" => msg.kind.clone(),
+        "group_title" => text(&msg.group_title),
+        "is_read" => msg.is_read.to_string(),
+        "is_sent" => msg.is_sent.to_string(),
+        "is_delivered" => msg.is_delivered.to_string(),
+        "was_unsent" => msg.was_unsent.to_string(),
+        "is_deleted" => msg.is_deleted.to_string(),
+        "thread_originator_guid" => text(&msg.thread_originator_guid),
+        "reaction" => text(&msg.reaction),
+        "reaction_target_guid" => text(&msg.reaction_target_guid),
+        "reactions" => msg.reactions.iter().map(|r| r.emoji.as_deref().unwrap_or(&r.reaction)).collect::<Vec<_>>().join(", "),
+        "effect" => text(&msg.effect),
+        "is_audio_message" => msg.is_audio_message.to_string(),
+        "audio_duration" => msg.audio_duration.map(|seconds| seconds.to_string()).unwrap_or_default(),
+        _ => String::new(),
+    })
+}
+
+/// Version of `SQLITE_MIRROR_SCHEMA`, stored as the mirror's `user_version`
+pub(crate) const SQLITE_MIRROR_VERSION: i32 = 1;
+
+/// Tables written by `export_sqlite`, kept the same across macOS releases
+///
+/// The comments are kept in `sqlite_master`, so the mirror documents itself.
+pub(crate) const SQLITE_MIRROR_SCHEMA: &str = "
+CREATE TABLE handles (
+    rowid INTEGER PRIMARY KEY,    -- ROWID in chat.db
+    id TEXT NOT NULL,             -- Phone number or email address
+    service TEXT,                 -- iMessage, SMS, RCS...
+    uncanonicalized_id TEXT       -- The address as originally entered
+);
+
+CREATE TABLE chats (
+    rowid INTEGER PRIMARY KEY,    -- ROWID in chat.db
+    guid TEXT NOT NULL,
+    chat_identifier TEXT NOT NULL,
+    service_name TEXT,
+    display_name TEXT,            -- Name given to a group chat, if any
+    group_id TEXT,
+    is_group INTEGER NOT NULL     -- 1 for group chats, 0 for direct ones
+);
+
+-- Participants of each chat, not including the database owner
+CREATE TABLE chat_handles (
+    chat_id INTEGER NOT NULL REFERENCES chats (rowid),
+    handle_id INTEGER NOT NULL REFERENCES handles (rowid),
+    PRIMARY KEY (chat_id, handle_id)
+);
+
+-- Messages other than tapbacks, which are in reactions
+CREATE TABLE messages (
+    rowid INTEGER PRIMARY KEY,    -- ROWID in chat.db
+    guid TEXT NOT NULL UNIQUE,
+    chat_id INTEGER REFERENCES chats (rowid),     -- NULL for messages in no chat
+    handle_id INTEGER REFERENCES handles (rowid),  -- Sender, or the other party of messages from me; NULL when unknown
+    is_from_me INTEGER NOT NULL,
+    service TEXT NOT NULL,
+    date REAL NOT NULL,           -- Unix timestamps, in seconds
+    date_read REAL,
+    date_delivered REAL,
+    date_edited REAL,
+    date_retracted REAL,
+    subject TEXT,
+    text TEXT,                    -- Decoded body, including for messages stored only as attributedBody
+    kind TEXT NOT NULL,           -- text, attachment, group_event, app...
+    thread_originator_guid TEXT,  -- Message this one replies to
+    group_title TEXT,             -- New name of renamed group chats
+    effect TEXT,                  -- Expressive send effect, e.g. slam or confetti
+    balloon_bundle_id TEXT,       -- iMessage app that produced the message
+    is_read INTEGER NOT NULL,
+    is_sent INTEGER NOT NULL,
+    is_delivered INTEGER NOT NULL,
+    was_unsent INTEGER NOT NULL,
+    is_deleted INTEGER NOT NULL,  -- In Recently Deleted
+    is_audio_message INTEGER NOT NULL,
+    audio_duration REAL,          -- Seconds
+    audio_transcript TEXT
+);
+
+CREATE TABLE attachments (
+    rowid INTEGER PRIMARY KEY,    -- ROWID in chat.db
+    message_id INTEGER NOT NULL REFERENCES messages (rowid),
+    guid TEXT NOT NULL,
+    filename TEXT,                -- Path on the Mac the database came from
+    mime_type TEXT,
+    uti TEXT,                     -- Uniform Type Identifier, e.g. public.jpeg
+    transfer_name TEXT,           -- File name shown in the chat
+    total_bytes INTEGER,
+    is_sticker INTEGER NOT NULL
+);
+
+-- Tapbacks in effect on each message, after later removals are applied
+CREATE TABLE reactions (
+    rowid INTEGER PRIMARY KEY,    -- ROWID in chat.db of the tapback message
+    guid TEXT NOT NULL,
+    message_id INTEGER NOT NULL REFERENCES messages (rowid),
+    part INTEGER NOT NULL,        -- Index of the message part reacted to
+    reaction TEXT NOT NULL,       -- loved, liked, disliked, laughed, emphasized, questioned, emoji or sticker
+    emoji TEXT,                   -- Emoji of custom emoji tapbacks
+    handle_id INTEGER REFERENCES handles (rowid),  -- NULL when unknown
+    is_from_me INTEGER NOT NULL,
+    date REAL NOT NULL
+);
+
+CREATE INDEX messages_date ON messages (date);
+CREATE INDEX messages_chat_date ON messages (chat_id, date);
+CREATE INDEX messages_handle ON messages (handle_id);
+CREATE INDEX attachments_message ON attachments (message_id);
+CREATE INDEX reactions_message ON reactions (message_id);
+CREATE INDEX chat_handles_handle ON chat_handles (handle_id);
+";
+
+/// Copy every handle, chat and chat participant from `source` into a new `SQLITE_MIRROR_SCHEMA` mirror
+pub(crate) fn copy_directory(source: &Connection, mirror: &Connection) -> rusqlite::Result<()> {
+    let mut insert = mirror.prepare("INSERT INTO handles VALUES (?1, ?2, ?3, ?4)")?;
+    let mut select = source.prepare("SELECT ROWID, id, service, uncanonicalized_id FROM handle")?;
+    let mut rows = select.query([])?;
+    while let Some(row) = rows.next()? {
+        insert.execute((row.get::<_, i64>(0)?, row.get::<_, String>(1)?, row.get::<_, Option<String>>(2)?, row.get::<_, Option<String>>(3)?))?;
+    }
+
+    let mut insert = mirror.prepare("INSERT INTO chats VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)")?;
+    let mut select = source.prepare(&format!(
+        "SELECT c.ROWID, c.guid, c.chat_identifier, c.service_name, c.display_name, c.group_id, {} FROM chat c",
+        group_chat_sql("c")
+    ))?;
+    let mut rows = select.query([])?;
+    while let Some(row) = rows.next()? {
+        insert.execute((
+            row.get::<_, i64>(0)?,
+            row.get::<_, String>(1)?,
+            row.get::<_, String>(2)?,
+            row.get::<_, Option<String>>(3)?,
+            row.get::<_, Option<String>>(4)?.filter(|n| !n.is_empty()),
+            row.get::<_, Option<String>>(5)?,
+            row.get::<_, bool>(6)?,
+        ))?;
+    }
+
+    let mut insert = mirror.prepare("INSERT OR IGNORE INTO chat_handles VALUES (?1, ?2)")?;
+    let mut select = source.prepare("SELECT chat_id, handle_id FROM chat_handle_join")?;
+    let mut rows = select.query([])?;
+    while let Some(row) = rows.next()? {
+        insert.execute((row.get::<_, i64>(0)?, row.get::<_, i64>(1)?))?;
+    }
+    Ok(())
+}
+
+/// Add `msg` with its attachments and folded tapbacks to a `SQLITE_MIRROR_SCHEMA` mirror
+pub(crate) fn mirror_message(mirror: &Connection, msg: &PyMessage, lookups: &IMessageDB) -> PyResult<()> {
+    let read_error = |e: rusqlite::Error| runtime_err(format!("Failed to read chats of message {}", msg.rowid), e);
+    let chat_id: Option<i32> = lookups.conn
+        .prepare_cached("SELECT MIN(chat_id) FROM chat_message_join WHERE message_id = ?1")
+        .and_then(|mut stmt| stmt.query_row([msg.rowid], |row| row.get(0)))
+        .map_err(read_error)?;
+    let attachments = lookups.get_message_attachments(msg.rowid)?;
+
+    let write = || -> rusqlite::Result<()> {
+        mirror.prepare_cached(
+            "INSERT INTO messages VALUES (?1, ?2, (SELECT rowid FROM chats WHERE rowid = ?3), (SELECT rowid FROM handles WHERE rowid = ?4), ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17, ?18, ?19, ?20, ?21, ?22, ?23, ?24, ?25, ?26)"
+        )?.execute(rusqlite::params![
+            msg.rowid, msg.guid, chat_id, msg.handle_id, msg.is_from_me, msg.service,
+            msg.date, msg.date_read, msg.date_delivered, msg.date_edited, msg.date_retracted,
+            msg.subject, msg.text, msg.kind, msg.thread_originator_guid, msg.group_title,
+            msg.effect, msg.balloon_bundle_id, msg.is_read, msg.is_sent, msg.is_delivered,
+            msg.was_unsent, msg.is_deleted, msg.is_audio_message, msg.audio_duration, msg.audio_transcript,
+        ])?;
+
+        let mut insert = mirror.prepare_cached("INSERT OR IGNORE INTO attachments VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)")?;
+        for a in &attachments {
+            insert.execute(rusqlite::params![
+                a.rowid, msg.rowid, a.guid, a.filename, a.mime_type, a.uti, a.transfer_name, a.total_bytes, a.is_sticker,
+            ])?;
+        }
+
+        let mut insert = mirror.prepare_cached("INSERT OR IGNORE INTO reactions VALUES (?1, ?2, ?3, ?4, ?5, ?6, (SELECT rowid FROM handles WHERE rowid = ?7), ?8, ?9)")?;
+        for r in &msg.reactions {
+            insert.execute(rusqlite::params![
+                r.rowid, r.guid, msg.rowid, r.part as i64, r.reaction, r.emoji, r.handle_id, r.is_from_me, r.date,
+            ])?;
+        }
+        Ok(())
+    };
+    write().map_err(|e| io_err(format!("Failed to write message {}", msg.rowid), e))
+}
+
+/// Build the columns for a list returned by the API; see `to_arrow`
+pub(crate) fn list_batch(items: Vec<Bound<'_, PyAny>>, kind: Option<&str>) -> PyResult<RecordBatch> {
+    let kind = match (kind, items.first()) {
+        (Some(kind), _) => kind,
+        (None, Some(first)) if first.is_instance_of::<PyMessage>() => "message",
+        (None, Some(first)) if first.is_instance_of::<PyMessageLite>() => "message_lite",
+        (None, Some(first)) if first.is_instance_of::<PyHandle>() => "handle",
+        (None, Some(first)) if first.is_instance_of::<PyAttachment>() => "attachment",
+        (None, Some(first)) if first.is_instance_of::<PyChat>() => "chat",
+        (None, Some(first)) if first.is_instance_of::<PyChatSummary>() => "chat_summary",
+        (None, Some(first)) => {
+            return Err(PyErr::new::<pyo3::exceptions::PyTypeError, _>(
+                format!("Cannot convert {} to Arrow", first.get_type().name()?)
+            ));
+        }
+        (None, None) => {
+            return Err(PyErr::new::<pyo3::exceptions::PyValueError, _>(
+                "Cannot tell the columns of an empty list, pass kind"
+            ));
+        }
+    };
+    fn extract<'py, T: FromPyObject<'py>>(items: &[Bound<'py, PyAny>]) -> PyResult<Vec<T>> {
+        items.iter().map(|item| item.extract()).collect()
+    }
+
+    match kind {
+        "message" => {
+            let mut messages: Vec<PyMessage> = extract(&items)?;
+            resolve_all(&mut messages)?;
+            message_batch(&messages)
+        }
+        "message_lite" => message_lite_batch(&extract(&items)?),
+        "handle" => handle_batch(&extract(&items)?),
+        "attachment" => attachment_batch(&extract(&items)?),
+        "chat" => chat_batch(&extract(&items)?),
+        "chat_summary" => chat_summary_batch(&extract(&items)?),
+        other => Err(PyErr::new::<pyo3::exceptions::PyValueError, _>(
+            format!("Unknown kind: {}", other)
+        )),
+    }
+}
+
+/// Lay out a list returned by the API as a `pyarrow.RecordBatch`, one row per item
+///
+/// Takes lists of messages, handles, attachments, chats and chat summaries. The
+/// kind of item is read off the first one; pass `kind` ("message",
+/// "message_lite", "handle", "attachment", "chat" or "chat_summary") to get
+/// the right columns for a list that may come back empty.
+#[pyfunction]
+pub(crate) fn to_arrow(py: Python, items: Vec<Bound<'_, PyAny>>, kind: Option<&str>) -> PyResult<PyObject> {
+    batch_to_pyarrow(py, list_batch(items, kind)?)
+}
+
+/// Lay out a list returned by the API as a pandas DataFrame, one row per item
+///
+/// Same columns as `to_arrow`, with the Unix timestamps turned into UTC
+/// `datetime64` columns.
+#[pyfunction]
+pub(crate) fn to_pandas(py: Python, items: Vec<Bound<'_, PyAny>>, kind: Option<&str>) -> PyResult<PyObject> {
+    let batch = with_datetimes(list_batch(items, kind)?)?;
+    batch_to_pyarrow(py, batch)?.call_method0(py, "to_pandas")
+}
+
+/// Lay out a list returned by the API as a polars DataFrame, one row per item
+///
+/// Same columns as `to_pandas`, built from the Arrow batch without going
+/// through pandas.
+#[pyfunction]
+pub(crate) fn to_polars(py: Python, items: Vec<Bound<'_, PyAny>>, kind: Option<&str>) -> PyResult<PyObject> {
+    let batch = with_datetimes(list_batch(items, kind)?)?;
+    batch_to_polars(py, batch)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample_db() -> IMessageDB {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("../tests/fixtures/test_imessage_sample.db");
+        IMessageDB::open(path, ConnectionOptions::default()).unwrap()
+    }
+
+    /// A `MessageQuery` over every message of the sample database
+    fn sample_query(py: Python) -> MessageQuery {
+        MessageQuery { db: Py::new(py, sample_db()).unwrap(), filter: MessageFilter::default() }
+    }
+
+    /// A path under the temp directory, removed first if a previous run left it behind
+    fn scratch(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("imessage-bridge-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&path);
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    fn read_parquet(path: &Path) -> (SchemaRef, Vec<RecordBatch>) {
+        let reader = parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder::try_new(std::fs::File::open(path).unwrap())
+            .unwrap()
+            .build()
+            .unwrap();
+        (reader.schema(), reader.collect::<Result<_, _>>().unwrap())
+    }
+
+    fn rowids(batches: &[RecordBatch]) -> Vec<i32> {
+        batches.iter()
+            .flat_map(|batch| batch.column_by_name("rowid").unwrap().as_any().downcast_ref::<Int32Array>().unwrap().values().to_vec())
+            .collect()
+    }
+
+    fn reaction(kind: &str, emoji: Option<&str>) -> PyReaction {
+        PyReaction {
+            rowid: 0,
+            guid: String::new(),
+            reaction: kind.to_string(),
+            emoji: emoji.map(str::to_string),
+            part: 0,
+            handle_id: None,
+            is_from_me: false,
+            date: 0.0,
+        }
+    }
+
+    #[test]
+    fn csv_field_formats_values() {
+        let lookups = sample_db();
+        let msg = PyMessage {
+            text: Some("Hi, \"you\"\nthere".to_string()),
+            date: 1_700_000_000.75,
+            date_read: None,
+            handle_id: Some(459),
+            audio_duration: Some(1.5),
+            reactions: vec![reaction("loved", None), reaction("custom_emoji", Some("🎉"))],
+            ..PyMessage::for_test(7, "guid-7")
+        };
+        let field = |column| csv_field(column, &msg, &lookups).unwrap();
+
+        assert_eq!(field("rowid"), "7");
+        // Fractional seconds are dropped, and quoting is left to the CSV writer
+        assert_eq!(field("date"), "2023-11-14T22:13:20Z");
+        assert_eq!(field("text"), "Hi, \"you\"\nthere");
+        assert_eq!(field("sender"), "+15550000002");
+        assert_eq!(field("handle_id"), "459");
+        assert_eq!(field("is_from_me"), "false");
+        assert_eq!(field("reactions"), "loved, 🎉");
+        assert_eq!(field("audio_duration"), "1.5");
+    }
+
+    #[test]
+    fn csv_field_leaves_missing_values_empty() {
+        let lookups = sample_db();
+        let msg = PyMessage {
+            date_read: None,
+            text: None,
+            handle_id: Some(999_999),
+            ..PyMessage::for_test(7, "guid-7")
+        };
+        for column in ["date_read", "subject", "text", "sender", "reactions", "audio_duration", "not_a_column"] {
+            assert_eq!(csv_field(column, &msg, &lookups).unwrap(), "", "{}", column);
+        }
+
+        let msg = PyMessage { is_from_me: true, handle_id: None, ..msg };
+        assert_eq!(csv_field("sender", &msg, &lookups).unwrap(), "Me");
+        let msg = PyMessage { is_from_me: false, ..msg };
+        assert_eq!(csv_field("sender", &msg, &lookups).unwrap(), "");
+    }
+
+    #[test]
+    fn export_parquet_matches_fetch_arrow() {
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            let query = sample_query(py);
+            let path = scratch("export.parquet");
+            // Small pages so the file holds several row groups
+            let written = query.export_parquet(py, path.clone(), Some(7), Some(1)).unwrap();
+            let (schema, batches) = read_parquet(&path);
+            let expected = query.fetch_batch(py, None).unwrap();
+
+            assert_eq!(written, expected.num_rows());
+            assert_eq!(schema.fields(), expected.schema().fields());
+            assert_eq!(rowids(&batches), rowids(&[expected]));
+            std::fs::remove_file(&path).unwrap();
+        });
+    }
+
+    #[test]
+    fn export_parquet_keeps_columns_when_nothing_matches() {
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            let mut query = sample_query(py);
+            query.filter.after = Some(4_000_000_000.0);
+            let path = scratch("empty.parquet");
+
+            assert_eq!(query.export_parquet(py, path.clone(), None, None).unwrap(), 0);
+            let (schema, batches) = read_parquet(&path);
+            assert_eq!(schema.fields(), message_batch(&[]).unwrap().schema().fields());
+            assert!(rowids(&batches).is_empty());
+            std::fs::remove_file(&path).unwrap();
+        });
+    }
+
+    #[test]
+    fn export_parquet_honours_limit() {
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            let mut query = sample_query(py);
+            let all = rowids(&[query.fetch_batch(py, None).unwrap()]);
+            query.filter.limit = Some(5);
+            let path = scratch("limit.parquet");
+
+            assert_eq!(query.export_parquet(py, path.clone(), Some(2), None).unwrap(), 5);
+            assert_eq!(rowids(&read_parquet(&path).1), all[..5]);
+            std::fs::remove_file(&path).unwrap();
+        });
+    }
+
+    #[test]
+    fn export_parquet_partitioned_rolls_over_months() {
+        let db_path = scratch("months.db");
+        std::fs::copy(Path::new(env!("CARGO_MANIFEST_DIR")).join("../tests/fixtures/test_imessage_sample.db"), &db_path).unwrap();
+        // Spread the October 2025 sample over September 2024, September 2025 and October 2025
+        let day: i64 = 86_400_000_000_000;
+        let conn = Connection::open(&db_path).unwrap();
+        conn.execute("UPDATE message SET date = date - ?1 WHERE ROWID % 3 = 0", [400 * day]).unwrap();
+        conn.execute("UPDATE message SET date = date - ?1 WHERE ROWID % 3 = 1", [31 * day]).unwrap();
+        drop(conn);
+
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            let db = IMessageDB::open(db_path.clone(), ConnectionOptions::default()).unwrap();
+            let query = MessageQuery { db: Py::new(py, db).unwrap(), filter: MessageFilter::default() };
+            let dir = scratch("months");
+            // Pages of 4 put month boundaries both inside and between pages
+            let written = query.export_parquet_partitioned(py, dir.clone(), Some(4), None).unwrap();
+
+            let mut files: Vec<PathBuf> = std::fs::read_dir(&dir).unwrap()
+                .flat_map(|year| std::fs::read_dir(year.unwrap().path()).unwrap())
+                .flat_map(|month| std::fs::read_dir(month.unwrap().path()).unwrap())
+                .map(|part| part.unwrap().path())
+                .collect();
+            files.sort();
+            let relative: Vec<String> = files.iter().map(|file| file.strip_prefix(&dir).unwrap().display().to_string()).collect();
+            assert_eq!(relative, [
+                "year=2024/month=09/part-0.parquet",
+                "year=2025/month=09/part-0.parquet",
+                "year=2025/month=10/part-0.parquet",
+            ]);
+
+            let mut exported = Vec::new();
+            for file in &files {
+                let (_, batches) = read_parquet(file);
+                let month = file.parent().unwrap();
+                let year = month.parent().unwrap();
+                for batch in &batches {
+                    let dates = batch.column_by_name("date").unwrap().as_any().downcast_ref::<Float64Array>().unwrap();
+                    for date in dates.values() {
+                        let date = chrono::DateTime::from_timestamp(*date as i64, 0).unwrap();
+                        assert!(year.ends_with(format!("year={}", date.year())), "{} in {}", date, file.display());
+                        assert!(month.ends_with(format!("month={:02}", date.month())), "{} in {}", date, file.display());
+                    }
+                }
+                exported.extend(rowids(&batches));
+            }
+            assert_eq!(written, exported.len());
+            assert_eq!(exported, rowids(&[query.fetch_batch(py, None).unwrap()]));
+            std::fs::remove_dir_all(&dir).unwrap();
+        });
+        std::fs::remove_file(&db_path).unwrap();
+    }
+
+    #[test]
+    fn export_parquet_partitioned_rejects_non_empty_directory() {
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            let dir = scratch("not-empty");
+            std::fs::create_dir_all(&dir).unwrap();
+            std::fs::write(dir.join("keep.txt"), "earlier export").unwrap();
+
+            let err = sample_query(py).export_parquet_partitioned(py, dir.clone(), None, None).unwrap_err();
+            assert!(err.is_instance_of::<pyo3::exceptions::PyIOError>(py));
+            let entries: Vec<_> = std::fs::read_dir(&dir).unwrap().map(|entry| entry.unwrap().file_name()).collect();
+            assert_eq!(entries, ["keep.txt"]);
+            std::fs::remove_dir_all(&dir).unwrap();
+        });
+    }
+}
@@ -0,0 +1,142 @@
+//! Per-query timing of message loads, collected through `tracing`
+
+use super::*;
+
+/// Python-accessible timings of one query shape, from `get_perf_stats`
+#[pyclass]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct PyQueryStats {
+    #[pyo3(get)]
+    pub(crate) query_hash: String,  // First 16 hex digits of the SHA-256 of the SQL text
+    #[pyo3(get)]
+    pub(crate) query: String,  // SQL text with whitespace collapsed
+    #[pyo3(get)]
+    pub(crate) calls: u64,
+    #[pyo3(get)]
+    pub(crate) rows: u64,
+    #[pyo3(get)]
+    pub(crate) total_ms: f64,  // Time spent running the query and decoding its rows
+    #[pyo3(get)]
+    pub(crate) mean_ms: f64,
+    #[pyo3(get)]
+    pub(crate) max_ms: f64,
+}
+
+/// Whether `trace_query` emits events, toggled with `set_perf_tracing`
+pub(crate) static PERF_TRACING: AtomicBool = AtomicBool::new(false);
+
+/// Totals per query hash, collected by `PerfLayer`
+pub(crate) static PERF_STATS: Mutex<BTreeMap<String, PyQueryStats>> = Mutex::new(BTreeMap::new());
+
+/// Target of the tracing events emitted for each query
+pub(crate) const QUERY_TARGET: &str = "imessage_bridge::query";
+
+/// Emit a tracing event for a finished query, when tracing is on
+pub(crate) fn trace_query(query: &str, rows: usize, elapsed: std::time::Duration) {
+    if !PERF_TRACING.load(Ordering::Relaxed) {
+        return;
+    }
+    let query_hash: String = Sha256::digest(query.as_bytes())[..8].iter().map(|byte| format!("{:02x}", byte)).collect();
+    tracing::info!(
+        target: QUERY_TARGET,
+        query_hash = query_hash.as_str(),
+        query,
+        rows = rows as u64,
+        duration_us = elapsed.as_micros() as u64,
+    );
+}
+
+/// Tracing layer folding query events into `PERF_STATS`
+pub(crate) struct PerfLayer;
+
+impl<S: tracing::Subscriber> tracing_subscriber::Layer<S> for PerfLayer {
+    fn on_event(&self, event: &tracing::Event<'_>, _ctx: tracing_subscriber::layer::Context<'_, S>) {
+        if event.metadata().target() != QUERY_TARGET {
+            return;
+        }
+        let mut fields = QueryEvent::default();
+        event.record(&mut fields);
+
+        let mut stats = PERF_STATS.lock().unwrap_or_else(PoisonError::into_inner);
+        let entry = stats.entry(fields.query_hash.clone()).or_insert_with(|| PyQueryStats {
+            query_hash: fields.query_hash,
+            query: fields.query.split_whitespace().collect::<Vec<_>>().join(" "),
+            calls: 0,
+            rows: 0,
+            total_ms: 0.0,
+            mean_ms: 0.0,
+            max_ms: 0.0,
+        });
+        let ms = fields.duration_us as f64 / 1000.0;
+        entry.calls += 1;
+        entry.rows += fields.rows;
+        entry.total_ms += ms;
+        entry.max_ms = entry.max_ms.max(ms);
+    }
+}
+
+/// Fields of one query event
+#[derive(Default)]
+pub(crate) struct QueryEvent {
+    pub(crate) query_hash: String,
+    pub(crate) query: String,
+    pub(crate) rows: u64,
+    pub(crate) duration_us: u64,
+}
+
+impl tracing::field::Visit for QueryEvent {
+    fn record_str(&mut self, field: &tracing::field::Field, value: &str) {
+        match field.name() {
+            "query_hash" => self.query_hash = value.to_string(),
+            "query" => self.query = value.to_string(),
+            _ => {}
+        }
+    }
+
+    fn record_u64(&mut self, field: &tracing::field::Field, value: u64) {
+        match field.name() {
+            "rows" => self.rows = value,
+            "duration_us" => self.duration_us = value,
+            _ => {}
+        }
+    }
+
+    fn record_debug(&mut self, _field: &tracing::field::Field, _value: &dyn std::fmt::Debug) {}
+}
+
+/// Turn per-query timing on, or off with `enabled=False`, for every database in the process
+///
+/// While on, each message query emits a tracing event with the hash of its SQL
+/// text, the rows it returned and how long it took, including decoding; the
+/// events are summed up for `get_perf_stats`. Timed are the queries that load
+/// full messages, e.g. the `query_messages_*` windows, `MessageQuery` fetches,
+/// search results and lazy text batches, plus `fetch_lite` and `count`; chat,
+/// handle, attachment and export lookups are not. Timing starts off; while
+/// off, the only cost is one flag check per query.
+#[pyfunction]
+pub(crate) fn set_perf_tracing(enabled: Option<bool>) {
+    static INSTALL: Once = Once::new();
+    INSTALL.call_once(|| {
+        // Fails only if another subscriber was installed first, which leaves stats empty
+        let _ = tracing::subscriber::set_global_default(tracing_subscriber::registry().with(PerfLayer));
+    });
+    PERF_TRACING.store(enabled.unwrap_or(true), Ordering::Relaxed);
+}
+
+/// Summarize the queries timed since tracing was turned on, slowest in total first
+#[pyfunction]
+pub(crate) fn get_perf_stats() -> Vec<PyQueryStats> {
+    let stats = PERF_STATS.lock().unwrap_or_else(PoisonError::into_inner);
+    let mut summary: Vec<PyQueryStats> = stats.values().cloned().map(|mut entry| {
+        entry.mean_ms = entry.total_ms / entry.calls.max(1) as f64;
+        entry
+    }).collect();
+    summary.sort_by(|a, b| b.total_ms.total_cmp(&a.total_ms));
+    summary
+}
+
+/// Clear the totals reported by `get_perf_stats`
+#[pyfunction]
+pub(crate) fn reset_perf_stats() {
+    PERF_STATS.lock().unwrap_or_else(PoisonError::into_inner).clear();
+}
@@ -0,0 +1,263 @@
+//! `IMessageDBPool`, a fixed set of connections shared between threads
+
+use super::*;
+
+/// Thread-safe set of read-only connections to one iMessage database
+///
+/// `IMessageDB` is bound to the thread that created it. A pool can be shared
+/// by any number of Python threads: each call checks out an idle connection
+/// (waiting, without the GIL, if all `size` are busy) and returns it when the
+/// call completes, so concurrent queries run side by side. The pool's own
+/// methods cover the common queries; `connection()` lends a full `IMessageDB`.
+#[pyclass]
+pub(crate) struct IMessageDBPool {
+    pub(crate) idle: Mutex<Vec<IMessageDB>>,
+    pub(crate) returned: Condvar,
+    pub(crate) db_path: PathBuf,
+    pub(crate) size: usize,
+}
+
+/// A connection checked out of an `IMessageDBPool`, returned to it on drop
+pub(crate) struct PooledDB<'a> {
+    pub(crate) pool: &'a IMessageDBPool,
+    pub(crate) db: Option<IMessageDB>,
+}
+
+impl std::ops::Deref for PooledDB<'_> {
+    type Target = IMessageDB;
+
+    fn deref(&self) -> &IMessageDB {
+        self.db.as_ref().expect("pooled connection already returned")
+    }
+}
+
+impl Drop for PooledDB<'_> {
+    fn drop(&mut self) {
+        if let Some(db) = self.db.take() {
+            self.pool.checkin(db);
+        }
+    }
+}
+
+impl IMessageDBPool {
+    /// Take an idle connection, waiting with the GIL released until one is free
+    pub(crate) fn take(&self, py: Python) -> IMessageDB {
+        py.allow_threads(|| {
+            let mut idle = self.idle.lock().unwrap_or_else(PoisonError::into_inner);
+            loop {
+                if let Some(db) = idle.pop() {
+                    return db;
+                }
+                idle = self.returned.wait(idle).unwrap_or_else(PoisonError::into_inner);
+            }
+        })
+    }
+
+    /// Hand a connection from `take` back to the pool
+    pub(crate) fn checkin(&self, db: IMessageDB) {
+        self.idle.lock().unwrap_or_else(PoisonError::into_inner).push(db);
+        self.returned.notify_one();
+    }
+
+    /// Take an idle connection for the length of one call
+    pub(crate) fn checkout(&self, py: Python) -> PooledDB<'_> {
+        PooledDB { pool: self, db: Some(self.take(py)) }
+    }
+}
+
+/// Context manager returned by `IMessageDBPool.connection`
+///
+/// Entering checks out a connection, waiting until one is free, and returns it
+/// as an `IMessageDB` for the current thread; leaving gives it back to the
+/// pool, after which that `IMessageDB` and the queries made from it no longer
+/// work.
+#[pyclass]
+pub(crate) struct PoolConnection {
+    pub(crate) pool: Py<IMessageDBPool>,
+    pub(crate) db: Option<Py<IMessageDB>>,
+}
+
+#[pymethods]
+impl PoolConnection {
+    pub(crate) fn __enter__(&mut self, py: Python) -> PyResult<Py<IMessageDB>> {
+        if self.db.is_some() {
+            return Err(PyErr::new::<pyo3::exceptions::PyRuntimeError, _>("Pool connection is already in use"));
+        }
+        let db = Py::new(py, self.pool.borrow(py).take(py))?;
+        self.db = Some(db.clone_ref(py));
+        Ok(db)
+    }
+
+    pub(crate) fn __exit__(&mut self, py: Python, _exc_type: PyObject, _exc_value: PyObject, _traceback: PyObject) -> PyResult<bool> {
+        if let Some(db) = self.db.take() {
+            let conn = db.try_borrow_mut(py)
+                .map_err(|e| runtime_err("Failed to return connection to the pool", e))?
+                .detach();
+            self.pool.borrow(py).checkin(conn);
+        }
+        Ok(false)
+    }
+}
+
+#[pymethods]
+impl IMessageDBPool {
+    /// Open `size` read-only connections (default one per CPU) to the database
+    ///
+    /// The remaining arguments are those of `IMessageDB` and apply to every
+    /// connection. With a snapshot all connections share one copy.
+    #[new]
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn new(db_path: Option<String>, size: Option<usize>, mmap_size: Option<i64>, cache_size: Option<i64>, temp_store: Option<String>, read_uncommitted: Option<bool>, eager_text: Option<bool>, snapshot: Option<bool>, snapshot_mode: Option<&str>) -> PyResult<Self> {
+        let size = size
+            .or_else(|| std::thread::available_parallelism().ok().map(|n| n.get()))
+            .unwrap_or(1)
+            .max(1);
+        let first = IMessageDB::new(db_path, mmap_size, cache_size, temp_store, read_uncommitted, eager_text, snapshot, snapshot_mode)?;
+        let db_path = first.db_path.clone();
+
+        let mut idle = Vec::with_capacity(size);
+        for _ in 1..size {
+            idle.push(first.reopen()?);
+        }
+        idle.push(first);
+
+        Ok(IMessageDBPool { idle: Mutex::new(idle), returned: Condvar::new(), db_path, size })
+    }
+
+    /// Get the database path
+    #[getter]
+    pub(crate) fn path(&self) -> String {
+        self.db_path.to_string_lossy().to_string()
+    }
+
+    /// Number of connections in the pool
+    #[getter]
+    pub(crate) fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Check out a connection for a `with` block, giving access to the whole `IMessageDB` API
+    ///
+    /// `with pool.connection() as db:` waits for an idle connection and lends
+    /// it to the current thread until the block ends. Methods of the pool
+    /// itself check out a connection for a single call instead.
+    pub(crate) fn connection(slf: Py<Self>) -> PoolConnection {
+        PoolConnection { pool: slf, db: None }
+    }
+
+    /// See `IMessageDB.query_messages_after`
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn query_messages_after(&self, py: Python, timestamp: f64, limit: Option<usize>, chat_type: Option<&str>, attach_reactions: Option<bool>, sender: Option<i32>, service: Option<&str>, exclude_noise: Option<bool>, order: Option<&str>, is_from_me: Option<bool>) -> PyResult<Vec<PyMessage>> {
+        self.checkout(py).query_messages_after(py, timestamp, limit, chat_type, attach_reactions, sender, service, exclude_noise, order, is_from_me)
+    }
+
+    /// See `IMessageDB.query_messages_after_rowid`
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn query_messages_after_rowid(&self, py: Python, rowid: i64, limit: Option<usize>, chat_type: Option<&str>, attach_reactions: Option<bool>, sender: Option<i32>, service: Option<&str>, exclude_noise: Option<bool>, order: Option<&str>, is_from_me: Option<bool>) -> PyResult<Vec<PyMessage>> {
+        self.checkout(py).query_messages_after_rowid(py, rowid, limit, chat_type, attach_reactions, sender, service, exclude_noise, order, is_from_me)
+    }
+
+    /// See `IMessageDB.query_messages_between`
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn query_messages_between(&self, py: Python, start: f64, end: f64, limit: Option<usize>, chat_type: Option<&str>, attach_reactions: Option<bool>, sender: Option<i32>, service: Option<&str>, exclude_noise: Option<bool>, order: Option<&str>, is_from_me: Option<bool>) -> PyResult<Vec<PyMessage>> {
+        self.checkout(py).query_messages_between(py, start, end, limit, chat_type, attach_reactions, sender, service, exclude_noise, order, is_from_me)
+    }
+
+    /// See `IMessageDB.query_messages_changed_after`
+    pub(crate) fn query_messages_changed_after(&self, py: Python, timestamp: f64, limit: Option<usize>) -> PyResult<Vec<PyMessage>> {
+        self.checkout(py).query_messages_changed_after(py, timestamp, limit)
+    }
+
+    /// See `IMessageDB.count_messages`
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn count_messages(&self, py: Python, after: Option<f64>, before: Option<f64>, chat_id: Option<i32>, sender: Option<i32>, chat_type: Option<String>, service: Option<String>, exclude_noise: Option<bool>, is_from_me: Option<bool>) -> PyResult<i64> {
+        self.checkout(py).count_messages(py, after, before, chat_id, sender, chat_type, service, exclude_noise, is_from_me)
+    }
+
+    /// See `IMessageDB.get_sync_token`
+    pub(crate) fn get_sync_token(&self, py: Python) -> PyResult<String> {
+        self.checkout(py).get_sync_token()
+    }
+
+    /// See `IMessageDB.query_messages_since`
+    pub(crate) fn query_messages_since(&self, py: Python, token: Option<&str>) -> PyResult<PySyncBatch> {
+        self.checkout(py).query_messages_since(py, token)
+    }
+
+    /// See `IMessageDB.query_messages_page`
+    pub(crate) fn query_messages_page(&self, py: Python, cursor: Option<&str>, page_size: Option<usize>) -> PyResult<(Vec<PyMessage>, Option<String>)> {
+        self.checkout(py).query_messages_page(py, cursor, page_size)
+    }
+
+    /// See `IMessageDB.get_all_messages`
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn get_all_messages(&self, py: Python, limit: Option<usize>, chat_type: Option<&str>, attach_reactions: Option<bool>, sender: Option<i32>, service: Option<&str>, exclude_noise: Option<bool>, order: Option<&str>, is_from_me: Option<bool>) -> PyResult<Vec<PyMessage>> {
+        self.checkout(py).get_all_messages(py, limit, chat_type, attach_reactions, sender, service, exclude_noise, order, is_from_me)
+    }
+
+    /// See `IMessageDB.get_chat_messages`
+    pub(crate) fn get_chat_messages(&self, py: Python, chat_id: i32, limit: Option<usize>, cursor: Option<&str>, attach_reactions: Option<bool>) -> PyResult<(Vec<PyMessage>, Option<String>)> {
+        self.checkout(py).get_chat_messages(py, chat_id, limit, cursor, attach_reactions)
+    }
+
+    /// See `IMessageDB.get_conversation_messages`
+    pub(crate) fn get_conversation_messages(&self, py: Python, chat_ids: Vec<i32>, limit: Option<usize>, cursor: Option<&str>, attach_reactions: Option<bool>) -> PyResult<(Vec<PyMessage>, Option<String>)> {
+        self.checkout(py).get_conversation_messages(py, chat_ids, limit, cursor, attach_reactions)
+    }
+
+    /// See `IMessageDB.search_messages`
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn search_messages(&self, py: Python, term: &str, chat_id: Option<i32>, limit: Option<usize>, text_cache: Option<PathBuf>, normalize: Option<bool>, context: Option<usize>) -> PyResult<Vec<PySearchHit>> {
+        self.checkout(py).search_messages(py, term, chat_id, limit, text_cache, normalize, context)
+    }
+
+    /// See `IMessageDB.search_fts`
+    pub(crate) fn search_fts(&self, py: Python, query: &str, index_path: PathBuf, limit: Option<usize>, chat_id: Option<i32>) -> PyResult<Vec<PySearchHit>> {
+        self.checkout(py).search_fts(py, query, index_path, limit, chat_id)
+    }
+
+    /// See `IMessageDB.search_regex`
+    pub(crate) fn search_regex(&self, py: Python, pattern: &str, chat_id: Option<i32>, limit: Option<usize>) -> PyResult<Vec<PyRegexMatch>> {
+        self.checkout(py).search_regex(py, pattern, chat_id, limit)
+    }
+
+    /// See `IMessageDB.get_latest_messages_per_chat`
+    pub(crate) fn get_latest_messages_per_chat(&self, py: Python, n: Option<usize>, exclude_noise: Option<bool>) -> PyResult<HashMap<i32, Vec<PyMessage>>> {
+        self.checkout(py).get_latest_messages_per_chat(py, n, exclude_noise)
+    }
+
+    /// See `IMessageDB.get_messages`
+    pub(crate) fn get_messages(&self, py: Python, rowids: Vec<i32>) -> PyResult<Vec<PyMessage>> {
+        self.checkout(py).get_messages(py, rowids)
+    }
+
+    /// See `IMessageDB.get_messages_by_guids`
+    pub(crate) fn get_messages_by_guids(&self, py: Python, guids: Vec<String>) -> PyResult<Vec<PyMessage>> {
+        self.checkout(py).get_messages_by_guids(py, guids)
+    }
+
+    /// See `IMessageDB.hydrate_messages`
+    pub(crate) fn hydrate_messages(&self, py: Python, rowids: Vec<i32>) -> PyResult<Vec<PyObject>> {
+        self.checkout(py).hydrate_messages(py, rowids)
+    }
+
+    /// See `IMessageDB.get_handle`
+    pub(crate) fn get_handle(&self, py: Python, handle_id: i32) -> PyResult<Option<PyHandle>> {
+        self.checkout(py).get_handle(py, handle_id)
+    }
+
+    /// See `IMessageDB.get_all_handles`
+    pub(crate) fn get_all_handles(&self, py: Python) -> PyResult<Vec<PyHandle>> {
+        self.checkout(py).get_all_handles()
+    }
+
+    /// See `IMessageDB.get_conversations`
+    pub(crate) fn get_conversations(&self, py: Python, strategy: Option<&str>) -> PyResult<Vec<PyConversation>> {
+        self.checkout(py).get_conversations(py, strategy)
+    }
+
+    /// See `IMessageDB.get_chat_summaries`
+    pub(crate) fn get_chat_summaries(&self, py: Python) -> PyResult<Vec<PyChatSummary>> {
+        self.checkout(py).get_chat_summaries(py)
+    }
+}
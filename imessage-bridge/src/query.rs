@@ -0,0 +1,2270 @@
+//! Message queries: listings, paging, sync and the `MessageQuery` builder
+
+use super::*;
+
+/// SQL condition matching everything except tapbacks and sticker reactions
+pub(crate) const NOT_REACTION: &str = "COALESCE(m.associated_message_type, 0) NOT BETWEEN 2000 AND 3999 AND COALESCE(m.associated_message_type, 0) != 1000";
+
+#[pymethods]
+impl IMessageDB {
+    /// Query messages after a specific timestamp
+    ///
+    /// `chat_type` may be `"direct"` or `"group"` to only return messages from
+    /// 1:1 or group chats. With `attach_reactions`, tapbacks are folded into the
+    /// `reactions` list of their target message instead of returned on their own.
+    /// `sender` restricts results to messages received from that handle ROWID,
+    /// and `service` to one of `"imessage"`, `"sms"`, `"rcs"` or `"satellite"`.
+    /// `exclude_noise` drops tapbacks, unsent messages and system rows in SQL.
+    /// `order` is `"asc"` (default) or `"desc"` for newest first, with ROWID
+    /// breaking ties between messages sent at the same time. `is_from_me`
+    /// keeps only messages the owner sent (`True`) or received (`False`).
+    ///
+    /// Dates can repeat and delayed deliveries arrive with older dates, so
+    /// incremental sync should use `query_messages_after_rowid` instead.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn query_messages_after(&self, py: Python, timestamp: f64, limit: Option<usize>, chat_type: Option<&str>, attach_reactions: Option<bool>, sender: Option<i32>, service: Option<&str>, exclude_noise: Option<bool>, order: Option<&str>, is_from_me: Option<bool>) -> PyResult<Vec<PyMessage>> {
+        self.without_gil(py, |db| db.query_window(WindowStart::Date(timestamp), None, limit, chat_type, attach_reactions, sender, service, exclude_noise, order, is_from_me))
+    }
+
+    /// Query messages with a ROWID above `rowid`, in ROWID order
+    ///
+    /// ROWIDs only grow as messages are written, so passing the largest ROWID
+    /// seen so far picks up exactly the messages stored since, however their
+    /// dates compare. This is the primary incremental path: ROWID is the
+    /// table's key, so each call is a range seek. Takes the same filters as
+    /// `query_messages_after`; `order="desc"` returns the newest ROWIDs first.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn query_messages_after_rowid(&self, py: Python, rowid: i64, limit: Option<usize>, chat_type: Option<&str>, attach_reactions: Option<bool>, sender: Option<i32>, service: Option<&str>, exclude_noise: Option<bool>, order: Option<&str>, is_from_me: Option<bool>) -> PyResult<Vec<PyMessage>> {
+        self.without_gil(py, |db| db.query_window(WindowStart::Rowid(rowid), None, limit, chat_type, attach_reactions, sender, service, exclude_noise, order, is_from_me))
+    }
+
+    /// Query messages sent between two Unix timestamps (exclusive)
+    ///
+    /// Takes the same filters as `query_messages_after`. Fixed windows such as a
+    /// month or a year let backfills be split into chunks and ingested in parallel.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn query_messages_between(&self, py: Python, start: f64, end: f64, limit: Option<usize>, chat_type: Option<&str>, attach_reactions: Option<bool>, sender: Option<i32>, service: Option<&str>, exclude_noise: Option<bool>, order: Option<&str>, is_from_me: Option<bool>) -> PyResult<Vec<PyMessage>> {
+        self.without_gil(py, |db| db.query_window(WindowStart::Date(start), Some(end), limit, chat_type, attach_reactions, sender, service, exclude_noise, order, is_from_me))
+    }
+
+    /// Get messages edited or unsent after a Unix timestamp, in order of that change
+    ///
+    /// Sync pipelines can call this with their last sync time to find messages
+    /// they already ingested whose content has since changed.
+    pub(crate) fn query_messages_changed_after(&self, py: Python, timestamp: f64, limit: Option<usize>) -> PyResult<Vec<PyMessage>> {
+        self.without_gil(py, |db| {
+            let since = unix_to_apple(timestamp);
+            let limit = limit.map(|l| l as i64).unwrap_or(-1);
+            let edited = if column_exists(&db.conn, "message", "date_edited")? { "COALESCE(m.date_edited, 0)" } else { "0" };
+            let retracted = if column_exists(&db.conn, "message", "date_retracted")? { "COALESCE(m.date_retracted, 0)" } else { "0" };
+            let changed = format!("MAX({edited}, {retracted})");
+
+            let query = format!(
+                "SELECT 
+                    m.*,
+                    c.chat_id,
+                    (SELECT COUNT(*) FROM message_attachment_join a WHERE m.ROWID = a.message_id) as num_attachments,
+                    NULL as deleted_from,
+                    0 as num_replies
+                FROM message as m
+                LEFT JOIN chat_message_join as c ON m.ROWID = c.message_id
+                WHERE {changed} > MAX(?1, 0)
+                GROUP BY m.ROWID
+                ORDER BY {changed} ASC, m.ROWID ASC
+                LIMIT ?2"
+            );
+
+            db.collect_messages(&query, rusqlite::params![since, limit])
+        })
+    }
+
+    /// Count messages without loading them
+    ///
+    /// Takes the same filters as `query_messages_between`, with both bounds
+    /// optional, plus `chat_id`. Use `messages().count_by(...)` for totals per
+    /// chat, handle or period.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn count_messages(&self, py: Python, after: Option<f64>, before: Option<f64>, chat_id: Option<i32>, sender: Option<i32>, chat_type: Option<String>, service: Option<String>, exclude_noise: Option<bool>, is_from_me: Option<bool>) -> PyResult<i64> {
+        self.without_gil(py, |db| {
+            let filter = MessageFilter {
+                after,
+                before,
+                chat_ids: chat_id.into_iter().collect(),
+                sender_ids: sender.into_iter().collect(),
+                is_from_me,
+                chat_type,
+                service,
+                exclude_noise: exclude_noise.unwrap_or(false),
+                ..MessageFilter::default()
+            };
+            filter.count(&db.conn)
+        })
+    }
+
+    /// Pick `n` messages uniformly at random, in chronological order
+    ///
+    /// Use `messages()...sample(n)` to sample from filtered messages.
+    pub(crate) fn sample_messages(slf: Py<Self>, py: Python, n: usize, seed: Option<u64>) -> PyResult<Vec<PyMessage>> {
+        Self::messages(slf).sample(py, n, seed)
+    }
+
+    /// Get a sync token marking everything currently in the database
+    ///
+    /// The token records the highest message ROWID and the latest edit, unsend
+    /// and deletion times. ROWIDs rather than dates mark new messages, since
+    /// delayed deliveries can arrive with older dates.
+    pub(crate) fn get_sync_token(&self) -> PyResult<String> {
+        Ok(encode_cursor(&self.sync_watermarks()?))
+    }
+
+    /// Get everything that changed since `token` came from `get_sync_token`
+    ///
+    /// Without a token, every message is returned. Each change shows up in
+    /// exactly one batch as long as the returned `token` is passed to the next
+    /// call, so repeated ingestion runs neither miss nor repeat messages.
+    pub(crate) fn query_messages_since(&self, py: Python, token: Option<&str>) -> PyResult<PySyncBatch> {
+        self.without_gil(py, |db| {
+            let since = match token {
+                Some(token) => decode_cursor(token, 4)?,
+                None => vec![0; 4],
+            };
+            let until = db.sync_watermarks()?;
+
+            let edited = if column_exists(&db.conn, "message", "date_edited")? { "COALESCE(m.date_edited, 0)" } else { "0" };
+            let retracted = if column_exists(&db.conn, "message", "date_retracted")? { "COALESCE(m.date_retracted, 0)" } else { "0" };
+            // Upper bounds keep changes made during this call for the next batch
+            let messages = db.collect_messages(
+                &format!(
+                    "SELECT 
+                        m.*,
+                        c.chat_id,
+                        (SELECT COUNT(*) FROM message_attachment_join a WHERE m.ROWID = a.message_id) as num_attachments,
+                        NULL as deleted_from,
+                        0 as num_replies
+                    FROM message as m
+                    LEFT JOIN chat_message_join as c ON m.ROWID = c.message_id
+                    WHERE (m.ROWID > ?1 AND m.ROWID <= ?5)
+                        OR ({edited} > ?2 AND {edited} <= ?6)
+                        OR ({retracted} > ?3 AND {retracted} <= ?7)
+                    GROUP BY m.ROWID
+                    ORDER BY m.ROWID ASC"
+                ),
+                rusqlite::params![since[0], since[1], since[2], since[3], until[0], until[1], until[2]],
+            )?;
+
+            let deleted = if table_exists(&db.conn, "chat_recoverable_message_join")? {
+                db.collect_messages(
+                    "SELECT 
+                        m.*,
+                        d.chat_id,
+                        (SELECT COUNT(*) FROM message_attachment_join a WHERE m.ROWID = a.message_id) as num_attachments,
+                        d.chat_id as deleted_from,
+                        0 as num_replies,
+                        d.delete_date
+                    FROM message as m
+                    INNER JOIN chat_recoverable_message_join as d ON m.ROWID = d.message_id
+                    WHERE d.delete_date > ?1 AND d.delete_date <= ?2
+                    GROUP BY m.ROWID
+                    ORDER BY d.delete_date ASC, m.ROWID ASC",
+                    [since[3], until[3]],
+                )?
+            } else {
+                Vec::new()
+            };
+
+            Ok(PySyncBatch { messages, deleted, token: encode_cursor(&until) })
+        })
+    }
+
+    /// Iterate over all messages in chronological order without loading them at once
+    ///
+    /// Messages are read `batch_size` (default 500) at a time. Use
+    /// `messages()...iter()` to iterate over filtered messages.
+    pub(crate) fn iter_messages(slf: Py<Self>, py: Python, batch_size: Option<usize>) -> MessageIterator {
+        Self::messages(slf).iter(py, batch_size)
+    }
+
+    /// Start a `MessageQuery` over all messages
+    pub(crate) fn messages(slf: Py<Self>) -> MessageQuery {
+        MessageQuery { db: slf, filter: MessageFilter::default() }
+    }
+
+    /// Page through every message in chronological order
+    ///
+    /// Returns up to `page_size` messages (default 1000) and a cursor to pass
+    /// back for the next page, or `None` after the last page. Pages are keyed
+    /// on date and ROWID, so each one is an index seek rather than an OFFSET scan.
+    pub(crate) fn query_messages_page(&self, py: Python, cursor: Option<&str>, page_size: Option<usize>) -> PyResult<(Vec<PyMessage>, Option<String>)> {
+        self.without_gil(py, |db| {
+            let page_size = page_size.unwrap_or(1000).max(1);
+            let (after_date, after_rowid) = match cursor {
+                Some(cursor) => {
+                    let position = decode_cursor(cursor, 2)?;
+                    (position[0], position[1])
+                }
+                None => (i64::MIN, i64::MIN),
+            };
+
+            let (messages, next) = MessageFilter::default().page(db, (after_date, after_rowid), page_size)?;
+            let next = next.map(|(date, rowid)| encode_cursor(&[date, rowid]));
+
+            Ok((messages, next))
+        })
+    }
+
+    /// Get all messages (use with caution on large databases)
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn get_all_messages(&self, py: Python, limit: Option<usize>, chat_type: Option<&str>, attach_reactions: Option<bool>, sender: Option<i32>, service: Option<&str>, exclude_noise: Option<bool>, order: Option<&str>, is_from_me: Option<bool>) -> PyResult<Vec<PyMessage>> {
+        self.query_messages_after(py, 0.0, limit, chat_type, attach_reactions, sender, service, exclude_noise, order, is_from_me)
+    }
+
+    /// Get messages from a single chat, newest page first
+    ///
+    /// Returns up to `limit` messages in chronological order, starting from the
+    /// latest, and a cursor for the page of older messages, or `None` once the
+    /// chat's first message is reached. Pass the cursor back to page backwards;
+    /// like `query_messages_page`, it is a (date, ROWID) position, so messages
+    /// sent at the same time are neither skipped nor repeated across pages.
+    /// `attach_reactions` works like it does for `query_messages_after`.
+    pub(crate) fn get_chat_messages(&self, py: Python, chat_id: i32, limit: Option<usize>, cursor: Option<&str>, attach_reactions: Option<bool>) -> PyResult<(Vec<PyMessage>, Option<String>)> {
+        self.get_conversation_messages(py, vec![chat_id], limit, cursor, attach_reactions)
+    }
+
+    /// Get the merged timeline of several chats, e.g. the chats of a `PyConversation`
+    ///
+    /// Pagination works like `get_chat_messages`; a message that belongs to more
+    /// than one of the chats is returned once.
+    pub(crate) fn get_conversation_messages(&self, py: Python, chat_ids: Vec<i32>, limit: Option<usize>, cursor: Option<&str>, attach_reactions: Option<bool>) -> PyResult<(Vec<PyMessage>, Option<String>)> {
+        self.without_gil(py, |db| {
+            if chat_ids.is_empty() {
+                return Ok((Vec::new(), None));
+            }
+
+            let (before_date, before_rowid) = match cursor {
+                Some(cursor) => {
+                    let position = decode_cursor(cursor, 2)?;
+                    (position[0], position[1])
+                }
+                None => (i64::MAX, i64::MAX),
+            };
+            let limit = limit.map(|l| l as i64).unwrap_or(-1);
+
+            let placeholders = vec!["?"; chat_ids.len()].join(", ");
+            let query = format!(
+                "SELECT 
+                    m.*,
+                    c.chat_id,
+                    (SELECT COUNT(*) FROM message_attachment_join a WHERE m.ROWID = a.message_id) as num_attachments,
+                    NULL as deleted_from,
+                    0 as num_replies
+                FROM message as m
+                INNER JOIN chat_message_join as c ON m.ROWID = c.message_id
+                WHERE c.chat_id IN ({}) AND (m.date, m.ROWID) < (?, ?)
+                GROUP BY m.ROWID
+                ORDER BY m.date DESC, m.ROWID DESC
+                LIMIT ?",
+                placeholders
+            );
+
+            let mut params: Vec<i64> = chat_ids.iter().map(|&id| id as i64).collect();
+            params.extend([before_date, before_rowid, limit]);
+
+            let mut messages = db.collect_messages(&query, rusqlite::params_from_iter(params))?;
+
+            // The oldest message of a full page is where the next one starts,
+            // by its stored date since `PyMessage.date` is rounded
+            let next = match messages.last() {
+                Some(oldest) if messages.len() as i64 == limit => {
+                    let date: i64 = db.conn.query_row(
+                        "SELECT date FROM message WHERE ROWID = ?",
+                        [oldest.rowid],
+                        |row| row.get(0),
+                    ).map_err(|e| runtime_err("Failed to build page cursor", e))?;
+                    Some(encode_cursor(&[date, oldest.rowid.into()]))
+                }
+                _ => None,
+            };
+
+            messages.reverse();
+
+            if attach_reactions.unwrap_or(false) {
+                Ok((fold_reactions(messages), next))
+            } else {
+                Ok((messages, next))
+            }
+        })
+    }
+
+    /// Get the latest `n` messages (default 1) of every chat in one query
+    ///
+    /// Returns a dict from chat ROWID to its messages in chronological order.
+    /// With `exclude_noise`, tapbacks, unsent messages and system rows are
+    /// skipped, so each chat's preview is its latest substantive message.
+    pub(crate) fn get_latest_messages_per_chat(&self, py: Python, n: Option<usize>, exclude_noise: Option<bool>) -> PyResult<HashMap<i32, Vec<PyMessage>>> {
+        self.without_gil(py, |db| {
+            let noise = if exclude_noise.unwrap_or(false) {
+                noise_filter(&db.conn)?
+            } else {
+                String::new()
+            };
+            let query = format!(
+                "SELECT chat_id, message_id FROM (
+                    SELECT
+                        c.chat_id,
+                        c.message_id,
+                        ROW_NUMBER() OVER (PARTITION BY c.chat_id ORDER BY m.date DESC, m.ROWID DESC) as position
+                    FROM chat_message_join as c
+                    INNER JOIN message as m ON m.ROWID = c.message_id
+                    WHERE 1 = 1 {}
+                )
+                WHERE position <= ?
+                ORDER BY chat_id, position DESC",
+                noise
+            );
+
+            let mut stmt = db.conn.prepare_cached(&query).map_err(|e| runtime_err("Failed to prepare query", e))?;
+            let latest = stmt.query_map([n.unwrap_or(1) as i64], |row| Ok((row.get::<_, i32>(0)?, row.get::<_, i32>(1)?)))
+                .and_then(|rows| rows.collect::<rusqlite::Result<Vec<_>>>())
+                .map_err(|e| runtime_err("Failed to execute query", e))?;
+
+            let messages = db.messages_by_rowid(latest.iter().map(|&(_, rowid)| rowid))?;
+            let mut by_chat: HashMap<i32, Vec<PyMessage>> = HashMap::new();
+            for (chat_id, rowid) in latest {
+                if let Some(msg) = messages.get(&rowid) {
+                    by_chat.entry(chat_id).or_default().push(msg.clone());
+                }
+            }
+            Ok(by_chat)
+        })
+    }
+
+    /// Get a reply thread: the originator message followed by its inline replies
+    ///
+    /// `guid` may be the originator or any reply in the thread. Messages are
+    /// returned in chronological order, so the originator comes first.
+    pub(crate) fn get_thread(&self, py: Python, guid: &str) -> PyResult<Vec<PyMessage>> {
+        self.without_gil(py, |db| {
+            let Some(root) = db.thread_root(guid)? else {
+                return Ok(Vec::new());
+            };
+
+            db.collect_messages(
+                "SELECT 
+                    m.*,
+                    c.chat_id,
+                    (SELECT COUNT(*) FROM message_attachment_join a WHERE m.ROWID = a.message_id) as num_attachments,
+                    NULL as deleted_from,
+                    (SELECT COUNT(*) FROM message r WHERE r.thread_originator_guid = m.guid) as num_replies
+                FROM message as m
+                LEFT JOIN chat_message_join as c ON m.ROWID = c.message_id
+                WHERE m.guid = ?1 OR m.thread_originator_guid = ?1
+                ORDER BY m.date ASC, m.ROWID ASC",
+                [root],
+            )
+        })
+    }
+
+    /// Get a fully hydrated reply thread in one query
+    ///
+    /// `guid` may be the originator or any reply in the thread. Returns the
+    /// originator followed by its replies in chronological order, with the
+    /// tapbacks on each message folded into its `reactions`.
+    pub(crate) fn get_thread_messages(&self, py: Python, guid: &str) -> PyResult<Vec<PyMessage>> {
+        self.without_gil(py, |db| {
+            let Some(root) = db.thread_root(guid)? else {
+                return Ok(Vec::new());
+            };
+
+            // Tapbacks name their target as `p:N/GUID`, `bp:GUID` or a bare GUID;
+            // spelling out every form keeps the match on the associated_message_guid
+            // index. A message has at most one part per attachment plus the text
+            // around them.
+            let messages = db.collect_messages(
+                "WITH RECURSIVE thread AS (
+                    SELECT m.guid, 2 * (SELECT COUNT(*) FROM message_attachment_join a WHERE m.ROWID = a.message_id) + 1 as parts
+                    FROM message as m
+                    WHERE m.guid = ?1 OR m.thread_originator_guid = ?1
+                ),
+                part(guid, n, parts) AS (
+                    SELECT guid, 0, parts FROM thread
+                    UNION ALL SELECT guid, n + 1, parts FROM part WHERE n + 1 < parts
+                ),
+                target(guid) AS (
+                    SELECT guid FROM thread
+                    UNION ALL SELECT 'bp:' || guid FROM thread
+                    UNION ALL SELECT 'p:' || n || '/' || guid FROM part
+                )
+                SELECT 
+                    m.*,
+                    c.chat_id,
+                    (SELECT COUNT(*) FROM message_attachment_join a WHERE m.ROWID = a.message_id) as num_attachments,
+                    NULL as deleted_from,
+                    (SELECT COUNT(*) FROM message r WHERE r.thread_originator_guid = m.guid) as num_replies
+                FROM message as m
+                LEFT JOIN chat_message_join as c ON m.ROWID = c.message_id
+                WHERE m.guid IN (SELECT guid FROM thread)
+                    OR (m.associated_message_guid IN target AND m.associated_message_type != 0)
+                GROUP BY m.ROWID
+                ORDER BY m.date ASC, m.ROWID ASC",
+                [root],
+            )?;
+
+            Ok(fold_reactions(messages))
+        })
+    }
+
+    /// Get the inline replies to a message, in chronological order
+    pub(crate) fn get_replies(&self, py: Python, message_guid: &str) -> PyResult<Vec<PyMessage>> {
+        self.without_gil(py, |db| {
+            db.collect_messages(
+                "SELECT 
+                    m.*,
+                    c.chat_id,
+                    (SELECT COUNT(*) FROM message_attachment_join a WHERE m.ROWID = a.message_id) as num_attachments,
+                    NULL as deleted_from,
+                    0 as num_replies
+                FROM message as m
+                LEFT JOIN chat_message_join as c ON m.ROWID = c.message_id
+                WHERE m.thread_originator_guid = ?1
+                ORDER BY m.date ASC, m.ROWID ASC",
+                [message_guid],
+            )
+        })
+    }
+
+    /// Get the messages in "Recently Deleted", most recently deleted first
+    ///
+    /// These messages are purged after 30 days, so backups should capture them
+    /// early. Each is flagged `is_deleted` and carries `date_deleted` and the
+    /// `deleted_from` chat. Pass `chat_id` to limit the results to one chat.
+    /// Returns an empty list on databases that predate the recoverable tables.
+    pub(crate) fn get_deleted_messages(&self, py: Python, chat_id: Option<i32>, limit: Option<usize>) -> PyResult<Vec<PyMessage>> {
+        self.without_gil(py, |db| {
+            if !table_exists(&db.conn, "chat_recoverable_message_join")? {
+                return Ok(Vec::new());
+            }
+
+            let limit = limit.map(|l| l as i64).unwrap_or(-1);
+
+            db.collect_messages(
+                "SELECT 
+                    m.*,
+                    d.chat_id,
+                    (SELECT COUNT(*) FROM message_attachment_join a WHERE m.ROWID = a.message_id) as num_attachments,
+                    d.chat_id as deleted_from,
+                    0 as num_replies,
+                    d.delete_date
+                FROM message as m
+                INNER JOIN chat_recoverable_message_join as d ON m.ROWID = d.message_id
+                WHERE ?1 IS NULL OR d.chat_id = ?1
+                GROUP BY m.ROWID
+                ORDER BY d.delete_date DESC, m.ROWID DESC
+                LIMIT ?2",
+                rusqlite::params![chat_id, limit],
+            )
+        })
+    }
+
+    /// Convert a message to a Python dictionary with all related data
+    pub(crate) fn message_to_dict(&self, py: Python, message_rowid: i32) -> PyResult<PyObject> {
+        let (msg, handle, participants, attachments) = self.without_gil(py, |db| {
+            db.message_dict_rows(message_rowid)?.ok_or_else(|| {
+                PyErr::new::<pyo3::exceptions::PyRuntimeError, _>(
+                    format!("Failed to fetch message: no message with ROWID {}", message_rowid)
+                )
+            })
+        })?;
+
+        message_dict(py, msg, handle, participants, attachments)
+    }
+
+    /// Look up a message by GUID, returning the same dictionary as `message_to_dict`
+    ///
+    /// Returns `None` when no message has that GUID.
+    pub(crate) fn get_message_by_guid(&self, py: Python, guid: &str) -> PyResult<Option<PyObject>> {
+        let rows = self.without_gil(py, |db| {
+            let rowid: Option<i32> = db.conn.query_row(
+                "SELECT ROWID FROM message WHERE guid = ?",
+                [guid],
+                |row| row.get(0),
+            ).optional().map_err(|e| runtime_err("Failed to look up message by GUID", e))?;
+
+            match rowid {
+                Some(rowid) => db.message_dict_rows(rowid),
+                None => Ok(None),
+            }
+        })?;
+
+        rows.map(|(msg, handle, participants, attachments)| message_dict(py, msg, handle, participants, attachments)).transpose()
+    }
+
+    /// Load many messages by ROWID in one query
+    ///
+    /// Messages come back in the order of `rowids`; unknown ROWIDs are skipped.
+    pub(crate) fn get_messages(&self, py: Python, rowids: Vec<i32>) -> PyResult<Vec<PyMessage>> {
+        self.without_gil(py, |db| {
+            let by_rowid = db.messages_by_rowid(rowids.iter().copied())?;
+            Ok(rowids.iter().filter_map(|rowid| by_rowid.get(rowid).cloned()).collect())
+        })
+    }
+
+    /// Load many messages by GUID in one query
+    ///
+    /// Messages come back in the order of `guids`; unknown GUIDs are skipped.
+    pub(crate) fn get_messages_by_guids(&self, py: Python, guids: Vec<String>) -> PyResult<Vec<PyMessage>> {
+        self.without_gil(py, |db| {
+            if guids.is_empty() {
+                return Ok(Vec::new());
+            }
+
+            // Bind the whole list as one JSON array to stay clear of the parameter limit
+            let guid_list = serde_json::to_string(&guids).map_err(|e| runtime_err("Failed to encode GUIDs", e))?;
+            let messages = db.collect_messages(
+                "SELECT 
+                    m.*,
+                    c.chat_id,
+                    (SELECT COUNT(*) FROM message_attachment_join a WHERE m.ROWID = a.message_id) as num_attachments,
+                    NULL as deleted_from,
+                    0 as num_replies
+                FROM message as m
+                LEFT JOIN chat_message_join as c ON m.ROWID = c.message_id
+                WHERE m.guid IN (SELECT value FROM json_each(?))
+                GROUP BY m.ROWID",
+                [guid_list],
+            )?;
+
+            let by_guid: HashMap<&str, &PyMessage> = messages.iter().map(|msg| (msg.guid.as_str(), msg)).collect();
+            Ok(guids.iter().filter_map(|guid| by_guid.get(guid.as_str()).map(|&msg| msg.clone())).collect())
+        })
+    }
+
+    /// Build `message_to_dict` dictionaries for many messages at once
+    ///
+    /// Messages, attachments, participants and sender handles are each read
+    /// with a single query for the whole list instead of several per message.
+    /// Dictionaries come back in the order of `rowids`; unknown ROWIDs are skipped.
+    pub(crate) fn hydrate_messages(&self, py: Python, rowids: Vec<i32>) -> PyResult<Vec<PyObject>> {
+        let rows = self.without_gil(py, |db| -> PyResult<Vec<_>> {
+            let messages = db.messages_by_rowid(rowids.iter().copied())?;
+            if messages.is_empty() {
+                return Ok(Vec::new());
+            }
+            let message_ids = rowid_list(messages.keys());
+
+            let mut attachments: HashMap<i32, Vec<PyAttachment>> = HashMap::new();
+            {
+                let mut stmt = db.conn.prepare_cached(
+                    "SELECT maj.message_id as hydrate_message_id, a.*
+                     FROM attachment a
+                     INNER JOIN message_attachment_join maj ON a.rowid = maj.attachment_id
+                     WHERE maj.message_id IN (SELECT value FROM json_each(?))
+                     ORDER BY maj.message_id, a.rowid"
+                ).map_err(|e| runtime_err("Failed to prepare attachments query", e))?;
+                let rows = stmt.query_map([&message_ids], |row| {
+                    Ok((row.get::<_, i32>("hydrate_message_id")?, attachment_from_row(row, &db.db_path)?))
+                }).map_err(|e| runtime_err("Failed to execute attachments query", e))?;
+                for row in rows {
+                    let (message_id, attachment) = row.map_err(|e| runtime_err("Failed to read attachment", e))?;
+                    attachments.entry(message_id).or_default().push(attachment);
+                }
+            }
+
+            let mut participants: HashMap<i32, Vec<PyHandle>> = HashMap::new();
+            {
+                let mut stmt = db.conn.prepare_cached(
+                    "SELECT DISTINCT cmj.message_id, h.rowid, h.id, h.service, h.uncanonicalized_id
+                     FROM handle h
+                     INNER JOIN chat_handle_join chj ON h.rowid = chj.handle_id
+                     INNER JOIN chat_message_join cmj ON chj.chat_id = cmj.chat_id
+                     WHERE cmj.message_id IN (SELECT value FROM json_each(?))"
+                ).map_err(|e| runtime_err("Failed to prepare participants query", e))?;
+                let rows = stmt.query_map([&message_ids], |row| {
+                    Ok((row.get::<_, i32>(0)?, PyHandle {
+                        rowid: row.get(1)?,
+                        id: row.get(2)?,
+                        service: row.get(3)?,
+                        uncanonicalized_id: row.get(4)?,
+                    }))
+                }).map_err(|e| runtime_err("Failed to execute participants query", e))?;
+                for row in rows {
+                    let (message_id, handle) = row.map_err(|e| runtime_err("Failed to read participant", e))?;
+                    participants.entry(message_id).or_default().push(handle);
+                }
+            }
+
+            let mut handles: HashMap<i32, PyHandle> = HashMap::new();
+            let mut handle_ids: Vec<i32> = Vec::new();
+            {
+                let mut lookups = db.lookups.borrow_mut();
+                for handle_id in messages.values().filter_map(|msg| msg.handle_id) {
+                    match lookups.handles.get(&handle_id) {
+                        Some(handle) => {
+                            handles.insert(handle_id, handle.clone());
+                        }
+                        None => handle_ids.push(handle_id),
+                    }
+                }
+            }
+            if !handle_ids.is_empty() {
+                let mut stmt = db.conn.prepare_cached(
+                    "SELECT rowid, id, service, uncanonicalized_id FROM handle WHERE rowid IN (SELECT value FROM json_each(?))"
+                ).map_err(|e| runtime_err("Failed to prepare handle query", e))?;
+                let rows = stmt.query_map([rowid_list(handle_ids)], |row| {
+                    Ok(PyHandle {
+                        rowid: row.get(0)?,
+                        id: row.get(1)?,
+                        service: row.get(2)?,
+                        uncanonicalized_id: row.get(3)?,
+                    })
+                }).map_err(|e| runtime_err("Failed to fetch handle", e))?;
+                for row in rows {
+                    let handle = row.map_err(|e| runtime_err("Failed to fetch handle", e))?;
+                    db.lookups.borrow_mut().handles.put(handle.rowid, handle.clone());
+                    handles.insert(handle.rowid, handle);
+                }
+            }
+
+            rowids.iter().filter_map(|rowid| {
+                let mut msg = messages.get(rowid).cloned()?;
+                let handle = msg.handle_id.and_then(|id| handles.get(&id).cloned());
+                let msg_participants = participants.get(rowid).cloned().unwrap_or_default();
+                let msg_attachments = attachments.get(rowid).cloned().unwrap_or_default();
+                Some(msg.resolve().map(|()| (msg, handle, msg_participants, msg_attachments)))
+            }).collect()
+        })?;
+
+        rows.into_iter()
+            .map(|(msg, handle, participants, attachments)| message_dict(py, msg, handle, participants, attachments))
+            .collect()
+    }
+}
+
+/// Exclusive lower bound of a message window
+pub(crate) enum WindowStart {
+    Date(f64),  // Unix timestamp
+    Rowid(i64),
+}
+
+impl IMessageDB {
+    /// A message, decoded, and the rows `message_dict` adds to it, or `None` if there is no such message
+    pub(crate) fn message_dict_rows(&self, message_rowid: i32) -> PyResult<Option<MessageDictRows>> {
+        let query = "SELECT 
+                m.*,
+                c.chat_id,
+                (SELECT COUNT(*) FROM message_attachment_join a WHERE m.ROWID = a.message_id) as num_attachments,
+                NULL as deleted_from,
+                0 as num_replies
+            FROM message as m
+            LEFT JOIN chat_message_join as c ON m.ROWID = c.message_id
+            WHERE m.ROWID = ?
+            LIMIT 1";
+
+        let Some(mut msg) = self.collect_messages(query, [message_rowid])?.pop() else {
+            return Ok(None);
+        };
+        msg.resolve()?;
+
+        let handle = match msg.handle_id {
+            Some(handle_id) => self.handle(handle_id)?,
+            None => None,
+        };
+        let participants = self.get_message_participants(message_rowid)?;
+        let attachments = self.get_message_attachments(message_rowid)?;
+
+        Ok(Some((msg, handle, participants, attachments)))
+    }
+
+    /// Connections of the first `threads` `fetch_parallel` workers, by worker index
+    ///
+    /// Opened on the first call that needs them and kept for later calls, so
+    /// their prepared statements, lookup caches and text decoders are reused.
+    pub(crate) fn worker_connections(&self, threads: usize) -> PyResult<std::cell::Ref<'_, Vec<Mutex<IMessageDB>>>> {
+        let mut workers = self.workers.borrow_mut();
+        while workers.len() < threads {
+            workers.push(Mutex::new(self.reopen()?));
+        }
+        drop(workers);
+        Ok(self.workers.borrow())
+    }
+
+    /// Connection that lazily loaded messages use to decode themselves later
+    pub(crate) fn text_decoder(&self) -> PyResult<Arc<Mutex<IMessageDB>>> {
+        match self.decoder.get() {
+            Some(decoder) => Ok(decoder.clone()),
+            None => {
+                let options = ConnectionOptions { eager_text: true, ..self.options.clone() };
+                let decoder = Arc::new(Mutex::new(IMessageDB::open(self.db_path.clone(), options)?));
+                Ok(self.decoder.get_or_init(|| decoder).clone())
+            }
+        }
+    }
+
+    /// GUID of the thread `guid` belongs to, or `None` if there is no such message
+    pub(crate) fn thread_root(&self, guid: &str) -> PyResult<Option<String>> {
+        let originator: Option<Option<String>> = self.conn.query_row(
+            "SELECT thread_originator_guid FROM message WHERE guid = ?",
+            [guid],
+            |row| row.get(0),
+        ).optional().map_err(|e| runtime_err("Failed to fetch thread originator", e))?;
+
+        Ok(originator.map(|root| root.unwrap_or_else(|| guid.to_string())))
+    }
+
+    /// Highest ROWID, edit, unsend and deletion date, in sync token order
+    pub(crate) fn sync_watermarks(&self) -> PyResult<Vec<i64>> {
+        let max = |table: &str, column: &str| -> PyResult<i64> {
+            // `ROWID` is implicit, so it never shows up in the table info
+            if !table_exists(&self.conn, table)? || (column != "ROWID" && !column_exists(&self.conn, table, column)?) {
+                return Ok(0);
+            }
+            self.conn.query_row(
+                &format!("SELECT COALESCE(MAX({}), 0) FROM {}", column, table),
+                [],
+                |row| row.get(0),
+            ).map_err(|e| runtime_err("Failed to read sync watermark", e))
+        };
+
+        Ok(vec![
+            max("message", "ROWID")?,
+            max("message", "date_edited")?,
+            max("message", "date_retracted")?,
+            max("chat_recoverable_message_join", "delete_date")?,
+        ])
+    }
+
+    /// Index every tapback by the GUID of its target in a temp table, for `fold_tapbacks`
+    ///
+    /// Temp tables live outside the database file, so this works on read-only
+    /// connections and snapshots alike.
+    pub(crate) fn index_tapbacks(&self) -> PyResult<()> {
+        self.conn.execute_batch(&format!(
+            "DROP TABLE IF EXISTS temp.tapback_target;
+            CREATE TEMP TABLE tapback_target AS
+                SELECT
+                    CASE
+                        WHEN m.associated_message_guid LIKE 'p:%'
+                            THEN substr(m.associated_message_guid, instr(m.associated_message_guid, '/') + 1, 36)
+                        WHEN m.associated_message_guid LIKE 'bp:%' THEN substr(m.associated_message_guid, 4, 36)
+                        ELSE substr(m.associated_message_guid, 1, 36)
+                    END as target,
+                    m.ROWID as message_id
+                FROM message as m
+                WHERE m.associated_message_guid IS NOT NULL AND NOT ({});
+            CREATE INDEX temp.tapback_target_guid ON tapback_target(target);",
+            NOT_REACTION
+        )).map_err(|e| runtime_err("Failed to index tapbacks", e))
+    }
+
+    /// Fold the tapbacks on `messages` into their `reactions`, wherever those tapbacks are stored
+    ///
+    /// Needs `index_tapbacks` first. `messages` should hold no tapbacks of their own.
+    pub(crate) fn fold_tapbacks(&self, mut messages: Vec<PyMessage>) -> PyResult<Vec<PyMessage>> {
+        let guids: Vec<&str> = messages.iter().map(|msg| msg.guid.as_str()).collect();
+        let guid_list = serde_json::to_string(&guids).map_err(|e| runtime_err("Failed to encode GUIDs", e))?;
+        // Chronological, so that a removed tapback cancels the one before it
+        let tapbacks = self.collect_messages(
+            "SELECT 
+                m.*,
+                c.chat_id,
+                (SELECT COUNT(*) FROM message_attachment_join a WHERE m.ROWID = a.message_id) as num_attachments,
+                NULL as deleted_from,
+                0 as num_replies
+            FROM tapback_target as t
+            JOIN message as m ON m.ROWID = t.message_id
+            LEFT JOIN chat_message_join as c ON m.ROWID = c.message_id
+            WHERE t.target IN (SELECT value FROM json_each(?))
+            ORDER BY m.date ASC, m.ROWID ASC",
+            [guid_list],
+        )?;
+        let count = messages.len();
+        messages.extend(tapbacks);
+        let mut folded = fold_reactions(messages);
+        // Rows `fold_reactions` could not attach, such as unknown tapback kinds, are not part of the page
+        folded.truncate(count);
+        Ok(folded)
+    }
+
+    /// Load fully decoded messages by ROWID
+    pub(crate) fn messages_by_rowid(&self, rowids: impl IntoIterator<Item = i32>) -> PyResult<HashMap<i32, PyMessage>> {
+        let rowids: Vec<i32> = rowids.into_iter().collect();
+        if rowids.is_empty() {
+            return Ok(HashMap::new());
+        }
+
+        let messages = self.collect_messages(
+            "SELECT 
+                m.*,
+                c.chat_id,
+                (SELECT COUNT(*) FROM message_attachment_join a WHERE m.ROWID = a.message_id) as num_attachments,
+                NULL as deleted_from,
+                0 as num_replies
+            FROM message as m
+            LEFT JOIN chat_message_join as c ON m.ROWID = c.message_id
+            WHERE m.ROWID IN (SELECT value FROM json_each(?))
+            GROUP BY m.ROWID",
+            [rowid_list(rowids)],
+        )?;
+        Ok(messages.into_iter().map(|msg| (msg.rowid, msg)).collect())
+    }
+
+    /// Messages after `start` and, when given, before the Unix timestamp `end`
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn query_window(&self, start: WindowStart, end: Option<f64>, limit: Option<usize>, chat_type: Option<&str>, attach_reactions: Option<bool>, sender: Option<i32>, service: Option<&str>, exclude_noise: Option<bool>, order: Option<&str>, is_from_me: Option<bool>) -> PyResult<Vec<PyMessage>> {
+        let order = sort_order(order)?;
+        let mut chat_filter = chat_style_filter(chat_type)?;
+        let (bound, sort, first) = match start {
+            WindowStart::Date(timestamp) => ("m.date > ?", format!("m.date {order}, m.ROWID {order}"), unix_to_apple(timestamp)),
+            WindowStart::Rowid(rowid) => ("m.ROWID > ?", format!("m.ROWID {order}"), rowid),
+        };
+        let mut params = vec![first];
+        if let Some(sender) = sender {
+            chat_filter.push_str(" AND m.is_from_me = 0 AND m.handle_id = ?");
+            params.push(sender.into());
+        }
+        if let Some(end) = end {
+            chat_filter.push_str(" AND m.date < ?");
+            params.push(unix_to_apple(end));
+        }
+        if let Some(is_from_me) = is_from_me {
+            chat_filter.push_str(" AND m.is_from_me = ?");
+            params.push(is_from_me.into());
+        }
+        chat_filter.push_str(&service_filter(service)?);
+        if exclude_noise.unwrap_or(false) {
+            chat_filter.push_str(&noise_filter(&self.conn)?);
+        }
+        params.push(limit.map(|l| l as i64).unwrap_or(-1));
+
+        let query = format!(
+            "SELECT 
+                m.*,
+                c.chat_id,
+                (SELECT COUNT(*) FROM message_attachment_join a WHERE m.ROWID = a.message_id) as num_attachments,
+                NULL as deleted_from,
+                0 as num_replies
+            FROM message as m
+            LEFT JOIN chat_message_join as c ON m.ROWID = c.message_id
+            WHERE {} {}
+            ORDER BY {}
+            LIMIT ?",
+            bound, chat_filter, sort
+        );
+
+        let messages = self.collect_messages(&query, rusqlite::params_from_iter(params))?;
+
+        if attach_reactions.unwrap_or(false) {
+            Ok(fold_reactions_sorted(messages, order == "DESC"))
+        } else {
+            Ok(messages)
+        }
+    }
+
+    /// Run a message query and convert every row into a `PyMessage`
+    ///
+    /// The query must select the columns `Message::from_row` expects (`m.*`,
+    /// `chat_id`, `num_attachments`, `deleted_from`, `num_replies`).
+    pub(crate) fn collect_messages<P: Params>(&self, query: &str, params: P) -> PyResult<Vec<PyMessage>> {
+        let started = std::time::Instant::now();
+        let mut stmt = self.conn.prepare_cached(query).map_err(|e| runtime_err("Failed to prepare query", e))?;
+
+        let decoder = match self.options.eager_text {
+            true => None,
+            false => Some(self.text_decoder()?),
+        };
+        // Without `eager_text`, reads of payloads, edits and files wait for `PyMessage::resolve` too
+        let eager = decoder.is_none();
+        let mut messages = Vec::new();
+        let mut rows = stmt.query(params).map_err(|e| runtime_err("Failed to execute query", e))?;
+
+        while let Some(row) = rows.next().map_err(|e| runtime_err("Failed to fetch row", e))? {
+            // Create Message from row
+            let mut msg = Message::from_row(row).map_err(|e| runtime_err("Failed to parse message", e))?;
+            let retracted = date_retracted(row);
+            let date_deleted = date_deleted(row);
+            let is_deleted = msg.is_deleted();
+            let deleted_from = msg.deleted_from;
+            let (is_audio_message, expire_state) = audio_columns(row);
+
+            // Try to generate text from attributedBody if text is None
+            let message_text = match decoder {
+                Some(_) => None,
+                None => decode_text(&mut msg, &self.conn),
+            };
+
+            let edit_history = if eager { edit_history(&mut msg, &self.conn) } else { Vec::new() };
+            let was_unsent = retracted != 0 || msg.is_fully_unsent();
+            let (event_type, event_handle_id) = group_event(&msg);
+            let group_photo_attachment = match event_type.as_deref() {
+                Some("group_icon_changed") if eager => group_photo_attachment(&msg, &self.conn),
+                _ => None,
+            };
+            let (reaction, reaction_emoji) = reaction_kind(&msg);
+            let (effect, effect_type) = expressive_effect(&msg);
+            let mentions = extract_mentions(&msg);
+            let body_parts = body_parts(&msg);
+            let links = match decoder {
+                Some(_) => Vec::new(),
+                None => extract_links(&msg, &self.conn),
+            };
+            let (facetime_url, call_outcome) = facetime_info(&msg, &links);
+            let (balloon_bundle_id, app_type) = app_info(&msg);
+            let apple_pay = eager.then(|| apple_pay_info(&msg, &self.conn)).flatten();
+            let digital_touch = eager.then(|| digital_touch_kind(&msg, &self.conn)).flatten();
+            let audio_duration = if is_audio_message && eager { audio_duration(msg.rowid, &self.conn, &self.db_path) } else { None };
+            let audio_transcript = audio_transcript(&msg);
+            let sticker = eager.then(|| sticker_info(&msg, &self.conn)).flatten();
+            let location = eager.then(|| location_info(&msg, &self.conn)).flatten();
+            let (text_attributes, markdown_text) = text_styles(&msg);
+            let (is_emoji_only, emoji) = emoji_info(message_text.as_deref(), reaction_emoji.as_deref());
+            let message_text = match &apple_pay {
+                Some(pay) if is_placeholder_text(message_text.as_deref()) => pay.description.clone().or(message_text),
+                _ => message_text,
+            };
+            let display_text = combine_subject(msg.subject.as_deref(), message_text.as_deref());
+            let kind = message_kind(&msg, event_type.as_deref(), reaction.as_deref(), was_unsent, is_audio_message, sticker.is_some(), facetime_url.is_some());
+            let (reaction_target_guid, reaction_target_part) = match reaction {
+                Some(_) => reaction_target(&msg),
+                None => (None, None),
+            };
+
+            // Convert to PyMessage
+            let mut py_msg = PyMessage {
+                rowid: msg.rowid,
+                guid: msg.guid,
+                text: message_text,
+                service: service_name(msg.service.as_deref()),
+                handle_id: msg.handle_id,
+                subject: msg.subject,
+                date: apple_to_unix(msg.date),
+                date_read: apple_to_unix_opt(msg.date_read),
+                date_delivered: apple_to_unix_opt(msg.date_delivered),
+                is_from_me: msg.is_from_me,
+                is_read: msg.is_read,
+                is_sent: true,  // Messages in the database are always sent
+                is_delivered: msg.date_delivered != 0,
+                cache_roomnames: msg.thread_originator_guid.clone(),
+                group_title: msg.group_title,
+                associated_message_guid: msg.associated_message_guid,
+                associated_message_type: msg.associated_message_type,
+                thread_originator_guid: msg.thread_originator_guid,
+                item_type: msg.item_type,
+                event_type,
+                event_handle_id,
+                reaction,
+                reaction_emoji,
+                reaction_target_guid,
+                reaction_target_part,
+                reactions: Vec::new(),
+                date_edited: apple_to_unix_opt(msg.date_edited),
+                edit_history,
+                date_retracted: apple_to_unix_opt(retracted),
+                was_unsent,
+                effect,
+                effect_type,
+                mentions,
+                links,
+                balloon_bundle_id,
+                app_type,
+                apple_pay,
+                digital_touch,
+                is_audio_message,
+                audio_duration,
+                expire_state,
+                audio_transcript,
+                sticker,
+                display_text,
+                kind,
+                location,
+                text_attributes,
+                markdown_text,
+                is_emoji_only,
+                emoji,
+                is_text_synthesized: false,
+                facetime_url,
+                call_outcome,
+                group_photo_attachment,
+                body_parts,
+                is_deleted,
+                deleted_from,
+                date_deleted,
+                pending_text: None,
+            };
+
+            if eager && is_placeholder_text(py_msg.text.as_deref()) {
+                if let Some(text) = self.synthesize_text(&py_msg) {
+                    py_msg.display_text = Some(text.clone());
+                    py_msg.text = Some(text);
+                    py_msg.is_text_synthesized = true;
+                }
+            }
+
+            messages.push(py_msg);
+        }
+
+        if let Some(decoder) = decoder {
+            for chunk in messages.chunks_mut(LAZY_TEXT_BATCH_SIZE) {
+                let batch = Arc::new(PendingBatch {
+                    rowids: chunk.iter().map(|msg| msg.rowid).collect(),
+                    decoded: Mutex::new(None),
+                });
+                for msg in chunk {
+                    msg.pending_text = Some(PendingText { decoder: decoder.clone(), batch: batch.clone() });
+                }
+            }
+        }
+
+        trace_query(query, messages.len(), started.elapsed());
+        Ok(messages)
+    }
+
+    /// Describe an event, announcement, or effect-only message in words
+    ///
+    /// Mirrors the captions Messages.app shows, e.g. "You kept an audio message".
+    /// Returns `None` for messages that carry no such event.
+    pub(crate) fn synthesize_text(&self, msg: &PyMessage) -> Option<String> {
+        let name_of = |handle_id: Option<i32>| {
+            handle_id
+                .and_then(|id| self.handle(id).ok().flatten())
+                .map(|handle| handle.id)
+        };
+        let actor = if msg.is_from_me {
+            "You".to_string()
+        } else {
+            name_of(msg.handle_id).unwrap_or_else(|| "Someone".to_string())
+        };
+        let other = || name_of(msg.event_handle_id).unwrap_or_else(|| "someone".to_string());
+
+        let text = match msg.event_type.as_deref() {
+            Some("participant_added") => format!("{} added {} to the conversation", actor, other()),
+            Some("participant_removed") => format!("{} removed {} from the conversation", actor, other()),
+            Some("name_change") => match &msg.group_title {
+                Some(title) => format!("{} named the conversation \"{}\"", actor, title),
+                None => format!("{} removed the conversation name", actor),
+            },
+            Some("participant_left") => format!("{} left the conversation", actor),
+            Some("group_icon_changed") => format!("{} changed the group photo", actor),
+            Some("group_icon_removed") => format!("{} removed the group photo", actor),
+            _ => match msg.location.as_ref().map(|loc| loc.kind.as_str()) {
+                Some("started_sharing") => format!("{} started sharing location", actor),
+                Some("stopped_sharing") => format!("{} stopped sharing location", actor),
+                _ if msg.item_type == 5 => format!("{} kept an audio message", actor),
+                _ if msg.kind == "shareplay" => match msg.call_outcome.as_deref() {
+                    Some("ended") => format!("{} ended SharePlay", actor),
+                    _ => format!("{} started SharePlay", actor),
+                },
+                _ if msg.was_unsent => format!("{} unsent a message", actor),
+                _ => match (&msg.digital_touch, msg.app_type.as_deref(), msg.effect_type.as_deref()) {
+                    (Some(touch), _, _) => format!("{} sent a Digital Touch {}", actor, touch),
+                    (None, Some("handwriting"), _) => format!("{} sent a handwritten message", actor),
+                    (None, _, Some("screen")) => format!(
+                        "{} sent with {} effect",
+                        actor,
+                        msg.effect.as_deref().unwrap_or_default().replace('_', " ")
+                    ),
+                    _ => return None,
+                },
+            },
+        };
+
+        Some(text)
+    }
+}
+
+/// Python iterator yielding messages in query order, a batch at a time
+///
+/// Created with `IMessageDB.iter_messages()` or `MessageQuery.iter()`. At most
+/// one batch is held in memory; the next one is read once it runs out.
+#[pyclass]
+pub(crate) struct MessageIterator {
+    pub(crate) db: Py<IMessageDB>,
+    pub(crate) filter: MessageFilter,
+    pub(crate) position: Option<PagePosition>,  // `None` once exhausted
+    pub(crate) remaining: usize,
+    pub(crate) batch_size: usize,
+    pub(crate) buffer: VecDeque<PyMessage>,
+}
+
+#[pymethods]
+impl MessageIterator {
+    pub(crate) fn __iter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    pub(crate) fn __next__(&mut self, py: Python) -> PyResult<Option<PyMessage>> {
+        self.fill(py)?;
+        let message = self.buffer.pop_front();
+        if message.is_some() {
+            self.remaining -= 1;
+        }
+        Ok(message)
+    }
+}
+
+impl MessageIterator {
+    /// Read the next batch once the buffered one has run out
+    pub(crate) fn fill(&mut self, py: Python) -> PyResult<()> {
+        if self.remaining == 0 || !self.buffer.is_empty() {
+            return Ok(());
+        }
+        let Some(position) = self.position else {
+            return Ok(());
+        };
+        let size = self.batch_size.min(self.remaining);
+        let filter = &self.filter;
+        let (messages, next) = self.db.borrow(py).without_gil(py, |db| filter.page(db, position, size))?;
+        self.buffer.extend(messages);
+        self.position = next;
+        Ok(())
+    }
+
+    /// Take what is left of the current batch, or `None` once exhausted
+    pub(crate) fn next_batch(&mut self, py: Python) -> PyResult<Option<Vec<PyMessage>>> {
+        self.fill(py)?;
+        if self.buffer.is_empty() {
+            return Ok(None);
+        }
+        self.remaining -= self.buffer.len();
+        Ok(Some(self.buffer.drain(..).collect()))
+    }
+}
+
+/// Python iterator yielding lists of messages in query order, created with `MessageQuery.iter_batches()`
+#[pyclass]
+pub(crate) struct MessageBatchIterator {
+    pub(crate) messages: MessageIterator,
+}
+
+#[pymethods]
+impl MessageBatchIterator {
+    pub(crate) fn __iter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    pub(crate) fn __next__(&mut self, py: Python) -> PyResult<Option<Vec<PyMessage>>> {
+        self.messages.next_batch(py)
+    }
+}
+
+/// Fluent builder for message queries, created with `IMessageDB.messages()`
+///
+/// Filters combine with AND and compile to a single parameterized statement:
+///
+/// ```python
+/// db.messages().after(ts).in_chat(42).from_handle(7).exclude_reactions().limit(500).fetch()
+/// ```
+#[pyclass]
+pub(crate) struct MessageQuery {
+    pub(crate) db: Py<IMessageDB>,
+    pub(crate) filter: MessageFilter,
+}
+
+/// Raw stored date and ROWID of the message a page resumes after
+pub(crate) type PagePosition = (i64, i64);
+
+/// Conditions of a `MessageQuery`
+#[derive(Debug, Clone, Default)]
+pub(crate) struct MessageFilter {
+    pub(crate) after: Option<f64>,  // Unix timestamps
+    pub(crate) before: Option<f64>,
+    pub(crate) chat_ids: Vec<i32>,
+    pub(crate) handle_ids: Vec<i32>,
+    pub(crate) sender_ids: Vec<i32>,
+    pub(crate) is_from_me: Option<bool>,
+    pub(crate) chat_type: Option<String>,
+    pub(crate) service: Option<String>,
+    pub(crate) exclude_reactions: bool,
+    pub(crate) exclude_noise: bool,
+    pub(crate) has_attachments: bool,
+    pub(crate) has_links: bool,
+    pub(crate) descending: bool,
+    pub(crate) shard: Option<(i64, i64)>,  // Raw date range [start, end) of one `fetch_parallel` shard
+    pub(crate) limit: Option<usize>,
+    pub(crate) attach_reactions: bool,
+}
+
+impl MessageFilter {
+    /// Fetch listings of the matching messages in query order, see `MessageQuery.fetch_lite`
+    pub(crate) fn fetch_lite(&self, db: &IMessageDB, text_length: usize) -> PyResult<Vec<PyMessageLite>> {
+        let order = if self.descending { "DESC" } else { "ASC" };
+        let (clause, mut params) = self.where_clause(&db.conn)?;
+        params.push(rusqlite::types::Value::Integer(self.limit.map(|l| l as i64).unwrap_or(-1)));
+
+        let query = format!(
+            "SELECT 
+                m.ROWID,
+                m.guid,
+                m.date,
+                m.handle_id,
+                m.is_from_me,
+                c.chat_id,
+                m.text,
+                CASE WHEN COALESCE(m.text, '') = '' THEN m.attributedBody END
+            FROM message as m
+            LEFT JOIN chat_message_join as c ON m.ROWID = c.message_id
+            {}
+            GROUP BY m.ROWID
+            ORDER BY m.date {order}, m.ROWID {order}
+            LIMIT ?",
+            clause
+        );
+
+        let started = std::time::Instant::now();
+        let mut stmt = db.conn.prepare_cached(&query).map_err(|e| runtime_err("Failed to prepare query", e))?;
+        let rows = stmt.query_map(rusqlite::params_from_iter(params), |row| {
+            let text: Option<String> = row.get::<_, Option<String>>(6)?.filter(|text| !text.is_empty());
+            let body: Option<Vec<u8>> = row.get(7)?;
+            let text = text.or_else(|| body.and_then(|body| streamtyped::parse(body).ok()));
+            let (text, is_truncated) = match text {
+                Some(text) => {
+                    let (text, is_truncated) = truncate_graphemes(&text, text_length);
+                    (Some(text), is_truncated)
+                }
+                None => (None, false),
+            };
+            Ok(PyMessageLite {
+                rowid: row.get(0)?,
+                guid: row.get(1)?,
+                date: apple_to_unix(row.get(2)?),
+                handle_id: row.get(3)?,
+                is_from_me: row.get(4)?,
+                chat_id: row.get(5)?,
+                text,
+                is_truncated,
+            })
+        }).map_err(|e| runtime_err("Failed to execute query", e))?;
+
+        let listings = rows.collect::<rusqlite::Result<Vec<_>>>().map_err(|e| runtime_err("Failed to fetch row", e))?;
+        trace_query(&query, listings.len(), started.elapsed());
+        Ok(listings)
+    }
+
+    /// Keyset position before the first message in query order
+    pub(crate) fn start(&self) -> PagePosition {
+        if self.descending { (i64::MAX, i64::MAX) } else { (i64::MIN, i64::MIN) }
+    }
+
+    /// Fetch the matching messages in query order
+    pub(crate) fn fetch(&self, db: &IMessageDB) -> PyResult<Vec<PyMessage>> {
+        let order = if self.descending { "DESC" } else { "ASC" };
+        let (clause, mut params) = self.where_clause(&db.conn)?;
+        params.push(rusqlite::types::Value::Integer(self.limit.map(|l| l as i64).unwrap_or(-1)));
+
+        let query = format!(
+            "SELECT 
+                m.*,
+                c.chat_id,
+                (SELECT COUNT(*) FROM message_attachment_join a WHERE m.ROWID = a.message_id) as num_attachments,
+                NULL as deleted_from,
+                0 as num_replies
+            FROM message as m
+            LEFT JOIN chat_message_join as c ON m.ROWID = c.message_id
+            {}
+            GROUP BY m.ROWID
+            ORDER BY m.date {order}, m.ROWID {order}
+            LIMIT ?",
+            clause
+        );
+
+        db.collect_messages(&query, rusqlite::params_from_iter(params))
+    }
+
+    /// Fetch up to `size` matching messages after a (date, ROWID) position in query order
+    ///
+    /// Also returns the position of the last message when the page is full,
+    /// as the raw stored date since `PyMessage.date` is rounded.
+    pub(crate) fn page(&self, db: &IMessageDB, after: PagePosition, size: usize) -> PyResult<(Vec<PyMessage>, Option<PagePosition>)> {
+        use rusqlite::types::Value;
+
+        let (clause, mut params) = self.where_clause(&db.conn)?;
+        let (keyset, order) = match self.descending {
+            true => ("(m.date < ? OR (m.date = ? AND m.ROWID < ?))", "DESC"),
+            false => ("(m.date > ? OR (m.date = ? AND m.ROWID > ?))", "ASC"),
+        };
+        let clause = match clause.is_empty() {
+            true => format!("WHERE {}", keyset),
+            false => format!("{} AND {}", clause, keyset),
+        };
+        params.extend([Value::Integer(after.0), Value::Integer(after.0), Value::Integer(after.1), Value::Integer(size as i64)]);
+
+        let messages = db.collect_messages(
+            &format!(
+                "SELECT 
+                    m.*,
+                    c.chat_id,
+                    (SELECT COUNT(*) FROM message_attachment_join a WHERE m.ROWID = a.message_id) as num_attachments,
+                    NULL as deleted_from,
+                    0 as num_replies
+                FROM message as m
+                LEFT JOIN chat_message_join as c ON m.ROWID = c.message_id
+                {}
+                GROUP BY m.ROWID
+                ORDER BY m.date {order}, m.ROWID {order}
+                LIMIT ?",
+                clause
+            ),
+            rusqlite::params_from_iter(params),
+        )?;
+
+        let next = match messages.last() {
+            Some(last) if messages.len() == size => {
+                let date: i64 = db.conn.query_row(
+                    "SELECT date FROM message WHERE ROWID = ?",
+                    [last.rowid],
+                    |row| row.get(0),
+                ).map_err(|e| runtime_err("Failed to build page cursor", e))?;
+                Some((date, last.rowid.into()))
+            }
+            _ => None,
+        };
+        Ok((messages, next))
+    }
+
+    /// Count the matching messages
+    pub(crate) fn count(&self, conn: &Connection) -> PyResult<i64> {
+        let (clause, params) = self.where_clause(conn)?;
+        let query = format!(
+            "SELECT COUNT(DISTINCT m.ROWID)
+            FROM message as m
+            LEFT JOIN chat_message_join as c ON m.ROWID = c.message_id
+            {}",
+            clause
+        );
+
+        let started = std::time::Instant::now();
+        let count = conn.query_row(&query, rusqlite::params_from_iter(params), |row| row.get(0)).map_err(|e| runtime_err("Failed to count messages", e))?;
+        trace_query(&query, 1, started.elapsed());
+        Ok(count)
+    }
+
+    /// Build the WHERE clause and its parameters
+    pub(crate) fn where_clause(&self, conn: &Connection) -> PyResult<(String, Vec<rusqlite::types::Value>)> {
+        use rusqlite::types::Value;
+
+        let mut conditions = Vec::new();
+        let mut params = Vec::new();
+
+        if let Some(after) = self.after {
+            conditions.push("m.date > ?".to_string());
+            params.push(Value::Integer(unix_to_apple(after)));
+        }
+        if let Some(before) = self.before {
+            conditions.push("m.date < ?".to_string());
+            params.push(Value::Integer(unix_to_apple(before)));
+        }
+        if let Some((start, end)) = self.shard {
+            conditions.push("m.date >= ? AND m.date < ?".to_string());
+            params.extend([Value::Integer(start), Value::Integer(end)]);
+        }
+        if !self.chat_ids.is_empty() {
+            conditions.push(format!("c.chat_id IN ({})", vec!["?"; self.chat_ids.len()].join(", ")));
+            params.extend(self.chat_ids.iter().map(|&id| Value::Integer(id.into())));
+        }
+        if !self.handle_ids.is_empty() {
+            conditions.push(format!("m.handle_id IN ({})", vec!["?"; self.handle_ids.len()].join(", ")));
+            params.extend(self.handle_ids.iter().map(|&id| Value::Integer(id.into())));
+        }
+        if !self.sender_ids.is_empty() {
+            conditions.push(format!("m.is_from_me = 0 AND m.handle_id IN ({})", vec!["?"; self.sender_ids.len()].join(", ")));
+            params.extend(self.sender_ids.iter().map(|&id| Value::Integer(id.into())));
+        }
+        if let Some(is_from_me) = self.is_from_me {
+            conditions.push("m.is_from_me = ?".to_string());
+            params.push(Value::Integer(is_from_me.into()));
+        }
+        let chat_filter = chat_style_filter(self.chat_type.as_deref())?;
+        if let Some(chat_filter) = chat_filter.strip_prefix("AND ") {
+            conditions.push(chat_filter.to_string());
+        }
+        if let Some(service_filter) = service_filter(self.service.as_deref())?.strip_prefix(" AND ") {
+            conditions.push(service_filter.to_string());
+        }
+        if self.exclude_reactions {
+            conditions.push(NOT_REACTION.to_string());
+        }
+        if self.exclude_noise {
+            if let Some(noise_filter) = noise_filter(conn)?.strip_prefix(" AND ") {
+                conditions.push(noise_filter.to_string());
+            }
+        }
+        if self.has_attachments {
+            conditions.push("EXISTS (SELECT 1 FROM message_attachment_join a WHERE a.message_id = m.ROWID)".to_string());
+        }
+        if self.has_links {
+            // A cheap heuristic: rich link previews, or text that looks like it holds a URL
+            conditions.push(
+                "(m.balloon_bundle_id = 'com.apple.messages.URLBalloonProvider' OR m.text LIKE '%http://%' OR m.text LIKE '%https://%' OR m.text LIKE '%www.%')".to_string()
+            );
+        }
+
+        let clause = if conditions.is_empty() {
+            String::new()
+        } else {
+            format!("WHERE {}", conditions.join(" AND "))
+        };
+        Ok((clause, params))
+    }
+}
+
+#[pymethods]
+impl MessageQuery {
+    /// Only messages sent after a Unix timestamp
+    pub(crate) fn after(mut slf: PyRefMut<'_, Self>, timestamp: f64) -> PyRefMut<'_, Self> {
+        slf.filter.after = Some(timestamp);
+        slf
+    }
+
+    /// Only messages sent before a Unix timestamp
+    pub(crate) fn before(mut slf: PyRefMut<'_, Self>, timestamp: f64) -> PyRefMut<'_, Self> {
+        slf.filter.before = Some(timestamp);
+        slf
+    }
+
+    /// Only messages sent between two Unix timestamps (exclusive)
+    pub(crate) fn between(mut slf: PyRefMut<'_, Self>, start: f64, end: f64) -> PyRefMut<'_, Self> {
+        slf.filter.after = Some(start);
+        slf.filter.before = Some(end);
+        slf
+    }
+
+    /// Only messages in a chat; call again to add more chats
+    pub(crate) fn in_chat(mut slf: PyRefMut<'_, Self>, chat_id: i32) -> PyRefMut<'_, Self> {
+        slf.filter.chat_ids.push(chat_id);
+        slf
+    }
+
+    /// Only messages from a handle; call again to add more handles
+    pub(crate) fn from_handle(mut slf: PyRefMut<'_, Self>, handle_id: i32) -> PyRefMut<'_, Self> {
+        slf.filter.handle_ids.push(handle_id);
+        slf
+    }
+
+    /// Only messages received from a handle; call again to add more senders
+    ///
+    /// Unlike `from_handle`, this skips messages the owner sent into a 1:1 chat,
+    /// which carry the other party's `handle_id` too.
+    pub(crate) fn sent_by(mut slf: PyRefMut<'_, Self>, handle_id: i32) -> PyRefMut<'_, Self> {
+        slf.filter.sender_ids.push(handle_id);
+        slf
+    }
+
+    /// Only messages sent (`True`) or received (`False`) by the database owner
+    pub(crate) fn from_me(mut slf: PyRefMut<'_, Self>, is_from_me: Option<bool>) -> PyRefMut<'_, Self> {
+        slf.filter.is_from_me = Some(is_from_me.unwrap_or(true));
+        slf
+    }
+
+    /// Only messages from `"direct"` or `"group"` chats
+    pub(crate) fn chat_type(mut slf: PyRefMut<'_, Self>, chat_type: String) -> PyResult<PyRefMut<'_, Self>> {
+        chat_style_filter(Some(&chat_type))?;
+        slf.filter.chat_type = Some(chat_type);
+        Ok(slf)
+    }
+
+    /// Only messages sent over `"imessage"`, `"sms"`, `"rcs"` or `"satellite"`
+    pub(crate) fn service(mut slf: PyRefMut<'_, Self>, service: String) -> PyResult<PyRefMut<'_, Self>> {
+        service_filter(Some(&service))?;
+        slf.filter.service = Some(service);
+        Ok(slf)
+    }
+
+    /// Leave out tapbacks and stickers placed on messages
+    pub(crate) fn exclude_reactions(mut slf: PyRefMut<'_, Self>) -> PyRefMut<'_, Self> {
+        slf.filter.exclude_reactions = true;
+        slf
+    }
+
+    /// Leave out tapbacks, unsent messages and system rows, keeping only substantive content
+    pub(crate) fn exclude_noise(mut slf: PyRefMut<'_, Self>) -> PyRefMut<'_, Self> {
+        slf.filter.exclude_noise = true;
+        slf
+    }
+
+    /// Only messages with at least one attachment
+    pub(crate) fn has_attachments(mut slf: PyRefMut<'_, Self>) -> PyRefMut<'_, Self> {
+        slf.filter.has_attachments = true;
+        slf
+    }
+
+    /// Only messages that look like they carry a URL
+    ///
+    /// Matches link previews and text containing `http://`, `https://` or
+    /// `www.`; messages whose text is only in `attributedBody` are missed.
+    pub(crate) fn has_links(mut slf: PyRefMut<'_, Self>) -> PyRefMut<'_, Self> {
+        slf.filter.has_links = true;
+        slf
+    }
+
+    /// Fold tapbacks into the `reactions` of their target message
+    pub(crate) fn attach_reactions(mut slf: PyRefMut<'_, Self>) -> PyRefMut<'_, Self> {
+        slf.filter.attach_reactions = true;
+        slf
+    }
+
+    /// Sort by date `"asc"` (the default) or `"desc"`, breaking ties by ROWID
+    ///
+    /// Combined with `limit`, `"desc"` returns the latest messages.
+    pub(crate) fn order(mut slf: PyRefMut<'_, Self>, order: String) -> PyResult<PyRefMut<'_, Self>> {
+        slf.filter.descending = sort_order(Some(&order))? == "DESC";
+        Ok(slf)
+    }
+
+    /// Return at most `limit` messages
+    pub(crate) fn limit(mut slf: PyRefMut<'_, Self>, limit: usize) -> PyRefMut<'_, Self> {
+        slf.filter.limit = Some(limit);
+        slf
+    }
+
+    /// Run the query, returning messages in chronological order unless `order("desc")` was set
+    pub(crate) fn fetch(&self, py: Python) -> PyResult<Vec<PyMessage>> {
+        let filter = &self.filter;
+        let messages = self.db.borrow(py).without_gil(py, |db| filter.fetch(db))?;
+
+        if self.filter.attach_reactions {
+            Ok(fold_reactions_sorted(messages, self.filter.descending))
+        } else {
+            Ok(messages)
+        }
+    }
+
+    /// Run the query, returning `PyMessageLite` listings in query order
+    ///
+    /// Only the ROWID, GUID, date, sender, chat and the first `text_length`
+    /// characters (default 100) of the text are read, so listings decode about
+    /// twice as fast as `fetch` and hand far less to Python. Text that only
+    /// exists in `attributedBody` comes from the plain-text parser, without
+    /// mentions, links or styles, and events get no synthesized caption.
+    /// `attach_reactions` is ignored.
+    pub(crate) fn fetch_lite(&self, py: Python, text_length: Option<usize>) -> PyResult<Vec<PyMessageLite>> {
+        let filter = &self.filter;
+        let text_length = text_length.unwrap_or(100);
+        self.db.borrow(py).without_gil(py, |db| filter.fetch_lite(db, text_length))
+    }
+
+    /// Run the query split into date shards on a thread pool, returning messages in query order
+    ///
+    /// The date span of the matching messages is cut into `shards` equal
+    /// ranges (default four per thread) that are decoded concurrently on
+    /// `threads` threads (default one per CPU), each with its own read-only
+    /// connection. Shards cover consecutive dates, so concatenating them keeps
+    /// the order of `fetch`. With `limit`, shards run `threads` at a time in
+    /// query order, each reading at most the rows still missing, and no more
+    /// are started once the limit is reached; like `fetch`, tapbacks count
+    /// toward it before `attach_reactions` folds them. The pool for a given
+    /// `threads` and the workers' connections to this database are opened on
+    /// first use and kept for later calls.
+    pub(crate) fn fetch_parallel(&self, py: Python, shards: Option<usize>, threads: Option<usize>) -> PyResult<Vec<PyMessage>> {
+        let filter = &self.filter;
+        let messages = self.db.borrow(py).without_gil(py, |db| -> PyResult<Vec<PyMessage>> {
+            let (clause, params) = filter.where_clause(&db.conn)?;
+            let span: (Option<i64>, Option<i64>) = db.conn.query_row(
+                &format!(
+                    "SELECT MIN(m.date), MAX(m.date)
+                    FROM message as m
+                    LEFT JOIN chat_message_join as c ON m.ROWID = c.message_id
+                    {}",
+                    clause
+                ),
+                rusqlite::params_from_iter(params),
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            ).map_err(|e| runtime_err("Failed to measure date span", e))?;
+            let (Some(first), Some(last)) = span else {
+                return Ok(Vec::new());
+            };
+
+            let threads = threads
+                .or_else(|| std::thread::available_parallelism().ok().map(|n| n.get()))
+                .unwrap_or(1)
+                .max(1);
+            let shards = shards.unwrap_or(threads * 4).max(1) as i64;
+            let width = ((last - first) / shards).max(1);
+            let mut ranges: Vec<(i64, i64)> = (0..shards)
+                .map(|i| (first + i * width, if i == shards - 1 { last + 1 } else { first + (i + 1) * width }))
+                .filter(|(start, end)| start < end)
+                .collect();
+            if filter.descending {
+                ranges.reverse();
+            }
+
+            let pool = shard_pool(threads)?;
+
+            // One connection per worker, locked only by the worker it belongs to
+            let connections = db.worker_connections(threads)?;
+            let connections = connections.as_slice();
+            let fetch_shards = |ranges: &[(i64, i64)], limit: Option<usize>| pool.install(|| {
+                ranges.par_iter()
+                    .map(|&range| {
+                        let worker = rayon::current_thread_index().unwrap_or(0);
+                        let shard_db = connections[worker].lock().unwrap_or_else(PoisonError::into_inner);
+                        MessageFilter { shard: Some(range), limit, ..filter.clone() }.fetch(&shard_db)
+                    })
+                    .collect::<PyResult<Vec<Vec<PyMessage>>>>()
+            });
+
+            let Some(limit) = filter.limit else {
+                return Ok(fetch_shards(&ranges, None)?.into_iter().flatten().collect());
+            };
+            // Later shards only matter while the earlier ones fall short of the limit
+            let mut messages = Vec::new();
+            for wave in ranges.chunks(threads) {
+                let remaining = limit - messages.len();
+                messages.extend(fetch_shards(wave, Some(remaining))?.into_iter().flatten().take(remaining));
+                if messages.len() == limit {
+                    break;
+                }
+            }
+            Ok(messages)
+        })?;
+
+        if self.filter.attach_reactions {
+            Ok(fold_reactions_sorted(messages, self.filter.descending))
+        } else {
+            Ok(messages)
+        }
+    }
+
+    /// Iterate over the matching messages, reading `batch_size` (default 500) at a time
+    ///
+    /// Honours `limit`; `attach_reactions` is ignored since a message's
+    /// tapbacks may fall in a later batch.
+    pub(crate) fn iter(&self, py: Python, batch_size: Option<usize>) -> MessageIterator {
+        MessageIterator {
+            db: self.db.clone_ref(py),
+            filter: self.filter.clone(),
+            position: Some(self.filter.start()),
+            remaining: self.filter.limit.unwrap_or(usize::MAX),
+            batch_size: batch_size.unwrap_or(500).max(1),
+            buffer: VecDeque::new(),
+        }
+    }
+
+    /// Iterate over the matching messages as lists of up to `batch_size` (default 500)
+    ///
+    /// Like `iter`, but each step hands a whole batch to Python at once. Larger
+    /// batches cross into Python less often at the cost of waiting longer for
+    /// the first one. Honours `limit`; `attach_reactions` is ignored.
+    pub(crate) fn iter_batches(&self, py: Python, batch_size: Option<usize>) -> MessageBatchIterator {
+        MessageBatchIterator { messages: self.iter(py, batch_size) }
+    }
+
+    /// Pick `n` of the matching messages uniformly at random, in query order
+    ///
+    /// Only ROWIDs are read while sampling, so just the chosen messages are
+    /// decoded. Passing a `seed` makes the sample reproducible for a given
+    /// database. `limit` and `attach_reactions` are ignored.
+    pub(crate) fn sample(&self, py: Python, n: usize, seed: Option<u64>) -> PyResult<Vec<PyMessage>> {
+        let filter = &self.filter;
+        self.db.borrow(py).without_gil(py, |db| {
+            let (clause, params) = filter.where_clause(&db.conn)?;
+            let query = format!(
+                "SELECT DISTINCT m.ROWID
+                FROM message as m
+                LEFT JOIN chat_message_join as c ON m.ROWID = c.message_id
+                {}",
+                clause
+            );
+
+            let mut stmt = db.conn.prepare_cached(&query).map_err(|e| runtime_err("Failed to prepare sample query", e))?;
+            let rows = stmt.query_map(rusqlite::params_from_iter(params), |row| row.get::<_, i32>(0)).map_err(|e| runtime_err("Failed to sample messages", e))?;
+
+            let mut rng = seed.map(fastrand::Rng::with_seed).unwrap_or_default();
+            // Reservoir sampling, so the candidate ROWIDs are never held all at once;
+            // the first failed row ends it and is reported instead of a short sample
+            let mut failure = None;
+            let candidates = rows.map_while(|row| row.map_err(|e| failure = Some(e)).ok());
+            let chosen = rng.choose_multiple(candidates, n);
+            if let Some(e) = failure {
+                return Err(runtime_err("Failed to sample messages", e));
+            }
+
+            let mut messages: Vec<PyMessage> = db.messages_by_rowid(chosen)?.into_values().collect();
+            messages.sort_by(|a, b| a.date.total_cmp(&b.date).then(a.rowid.cmp(&b.rowid)));
+            if filter.descending {
+                messages.reverse();
+            }
+            Ok(messages)
+        })
+    }
+
+    /// Count the matching messages without loading them; `limit` is ignored
+    pub(crate) fn count(&self, py: Python) -> PyResult<i64> {
+        let filter = &self.filter;
+        self.db.borrow(py).without_gil(py, |db| filter.count(&db.conn))
+    }
+
+    /// Count the matching messages per `"chat"`, `"handle"`, `"day"`, `"month"` or `"year"`
+    ///
+    /// Chat and handle counts are keyed by ROWID, with `None` for messages
+    /// without one; date counts by UTC `YYYY-MM-DD`, `YYYY-MM` or `YYYY`.
+    /// `limit` is ignored.
+    pub(crate) fn count_by(&self, py: Python, key: &str) -> PyResult<PyObject> {
+        let date_key = |format: &str| format!("strftime('{}', m.date / 1000000000 + 978307200, 'unixepoch')", format);
+        let group = match key {
+            "chat" => "c.chat_id".to_string(),
+            "handle" => "m.handle_id".to_string(),
+            "day" => date_key("%Y-%m-%d"),
+            "month" => date_key("%Y-%m"),
+            "year" => date_key("%Y"),
+            other => {
+                return Err(PyErr::new::<pyo3::exceptions::PyValueError, _>(
+                    format!("Unknown count key: {} (expected \"chat\", \"handle\", \"day\", \"month\" or \"year\")", other)
+                ));
+            }
+        };
+
+        let filter = &self.filter;
+        let buckets = self.db.borrow(py).without_gil(py, |db| {
+            let (clause, params) = filter.where_clause(&db.conn)?;
+            let query = format!(
+                "SELECT {} as bucket, COUNT(DISTINCT m.ROWID)
+                FROM message as m
+                LEFT JOIN chat_message_join as c ON m.ROWID = c.message_id
+                {}
+                GROUP BY bucket",
+                group, clause
+            );
+
+            let mut stmt = db.conn.prepare_cached(&query).map_err(|e| runtime_err("Failed to prepare count query", e))?;
+            let rows = stmt.query_map(rusqlite::params_from_iter(params), |row| {
+                let bucket: rusqlite::types::Value = row.get(0)?;
+                Ok((bucket, row.get::<_, i64>(1)?))
+            }).map_err(|e| runtime_err("Failed to count messages", e))?;
+            rows.collect::<rusqlite::Result<Vec<_>>>().map_err(|e| runtime_err("Failed to count messages", e))
+        })?;
+
+        let counts = PyDict::new_bound(py);
+        for (bucket, count) in buckets {
+            match bucket {
+                rusqlite::types::Value::Integer(id) => counts.set_item(id, count)?,
+                rusqlite::types::Value::Text(date) => counts.set_item(date, count)?,
+                _ => counts.set_item(py.None(), count)?,
+            }
+        }
+        Ok(counts.into())
+    }
+}
+
+/// Convert an Apple Core Data timestamp (nanoseconds since 2001-01-01) to Unix time
+pub(crate) fn apple_to_unix(date: i64) -> f64 {
+    (date as f64 / 1_000_000_000.0) + 978307200.0
+}
+
+/// Convert a Unix timestamp to an Apple Core Data timestamp in nanoseconds
+pub(crate) fn unix_to_apple(timestamp: f64) -> i64 {
+    ((timestamp - 978307200.0) * 1_000_000_000.0) as i64
+}
+
+/// Like `apple_to_unix`, but treats the zero value Apple uses for "unset" as `None`
+pub(crate) fn apple_to_unix_opt(date: i64) -> Option<f64> {
+    if date != 0 {
+        Some(apple_to_unix(date))
+    } else {
+        None
+    }
+}
+
+/// Get a message's text, decoding it from attributedBody when the `text` column is empty
+///
+/// The body is always parsed so that `msg.components` carries mention and link
+/// ranges, but a non-empty `text` column still wins over the decoded text.
+pub(crate) fn decode_text(msg: &mut Message, conn: &Connection) -> Option<String> {
+    let column_text = msg.text.clone().filter(|s| !s.is_empty());
+    match msg.generate_text(conn) {
+        Ok(text) => column_text.or_else(|| Some(text.to_string())),
+        Err(_) => column_text.or_else(|| msg.text.clone()),
+    }
+}
+
+/// Collect the prior versions of an edited message from `message_summary_info`
+///
+/// `generate_text` normally loads the edit data; it is parsed here when missing.
+pub(crate) fn edit_history(msg: &mut Message, conn: &Connection) -> Vec<PyMessageEdit> {
+    if !msg.is_edited() {
+        return Vec::new();
+    }
+
+    if msg.edited_parts.is_none() {
+        msg.edited_parts = msg
+            .message_summary_info(conn)
+            .as_ref()
+            .and_then(|payload| EditedMessage::from_map(payload).ok());
+    }
+
+    let mut history = Vec::new();
+    if let Some(edited) = &msg.edited_parts {
+        for (part, edited_part) in edited.parts.iter().enumerate() {
+            for event in &edited_part.edit_history {
+                history.push(PyMessageEdit {
+                    part,
+                    date: apple_to_unix(event.date),
+                    text: event.text.clone(),
+                });
+            }
+        }
+    }
+
+    history.sort_by(|a, b| a.date.total_cmp(&b.date));
+    history
+}
+
+/// Read `date_retracted`, which `Message` does not carry; older databases lack the column
+pub(crate) fn date_retracted(row: &rusqlite::Row) -> i64 {
+    row.get::<_, Option<i64>>("date_retracted").ok().flatten().unwrap_or(0)
+}
+
+/// A message with the sender handle, chat participants and attachments of its dictionary
+pub(crate) type MessageDictRows = (PyMessage, Option<PyHandle>, Vec<PyHandle>, Vec<PyAttachment>);
+
+/// Build the dictionary returned by `message_to_dict` from a message and its related rows
+///
+/// `msg` is resolved beforehand, while the GIL is released.
+pub(crate) fn message_dict(py: Python, msg: PyMessage, handle: Option<PyHandle>, participants: Vec<PyHandle>, attachments: Vec<PyAttachment>) -> PyResult<PyObject> {
+    let dict = PyDict::new_bound(py);
+    dict.set_item("rowid", msg.rowid)?;
+    dict.set_item("guid", msg.guid)?;
+    dict.set_item("text", msg.text)?;
+    dict.set_item("service", msg.service)?;
+    dict.set_item("handle_id", msg.handle_id)?;
+    dict.set_item("subject", msg.subject)?;
+    dict.set_item("date", msg.date)?;
+    dict.set_item("date_read", msg.date_read)?;
+    dict.set_item("date_delivered", msg.date_delivered)?;
+    dict.set_item("is_from_me", msg.is_from_me)?;
+    dict.set_item("is_read", msg.is_read)?;
+    dict.set_item("is_sent", msg.is_sent)?;
+    dict.set_item("is_delivered", msg.is_delivered)?;
+    dict.set_item("cache_roomnames", msg.cache_roomnames)?;
+    dict.set_item("group_title", msg.group_title)?;
+    dict.set_item("associated_message_guid", msg.associated_message_guid)?;
+    dict.set_item("associated_message_type", msg.associated_message_type)?;
+    dict.set_item("thread_originator_guid", msg.thread_originator_guid)?;
+    dict.set_item("item_type", msg.item_type)?;
+    dict.set_item("event_type", msg.event_type)?;
+    dict.set_item("event_handle_id", msg.event_handle_id)?;
+    dict.set_item("reaction", msg.reaction)?;
+    dict.set_item("reaction_emoji", msg.reaction_emoji)?;
+    dict.set_item("reaction_target_guid", msg.reaction_target_guid)?;
+    dict.set_item("reaction_target_part", msg.reaction_target_part)?;
+    dict.set_item("date_edited", msg.date_edited)?;
+    dict.set_item("edit_history", msg.edit_history.into_py(py))?;
+    dict.set_item("date_retracted", msg.date_retracted)?;
+    dict.set_item("was_unsent", msg.was_unsent)?;
+    dict.set_item("effect", msg.effect)?;
+    dict.set_item("effect_type", msg.effect_type)?;
+    dict.set_item("mentions", msg.mentions.into_py(py))?;
+    dict.set_item("links", msg.links.into_py(py))?;
+    dict.set_item("balloon_bundle_id", msg.balloon_bundle_id)?;
+    dict.set_item("app_type", msg.app_type)?;
+    dict.set_item("apple_pay", msg.apple_pay.map(|p| p.into_py(py)))?;
+    dict.set_item("digital_touch", msg.digital_touch)?;
+    dict.set_item("is_audio_message", msg.is_audio_message)?;
+    dict.set_item("audio_duration", msg.audio_duration)?;
+    dict.set_item("expire_state", msg.expire_state)?;
+    dict.set_item("audio_transcript", msg.audio_transcript)?;
+    dict.set_item("sticker", msg.sticker.map(|st| st.into_py(py)))?;
+    dict.set_item("display_text", msg.display_text)?;
+    dict.set_item("kind", msg.kind)?;
+    dict.set_item("location", msg.location.map(|loc| loc.into_py(py)))?;
+    dict.set_item("text_attributes", msg.text_attributes.into_py(py))?;
+    dict.set_item("markdown_text", msg.markdown_text)?;
+    dict.set_item("is_emoji_only", msg.is_emoji_only)?;
+    dict.set_item("emoji", msg.emoji)?;
+    dict.set_item("is_text_synthesized", msg.is_text_synthesized)?;
+    dict.set_item("facetime_url", msg.facetime_url)?;
+    dict.set_item("call_outcome", msg.call_outcome)?;
+    dict.set_item("group_photo_attachment", msg.group_photo_attachment)?;
+    dict.set_item("body_parts", msg.body_parts.into_py(py))?;
+    dict.set_item("is_deleted", msg.is_deleted)?;
+    dict.set_item("deleted_from", msg.deleted_from)?;
+    dict.set_item("date_deleted", msg.date_deleted)?;
+    
+    // Add related data
+    dict.set_item("handle", handle.map(|h| h.into_py(py)))?;
+    dict.set_item("participants", participants.into_py(py))?;
+    dict.set_item("attachments", attachments.into_py(py))?;
+
+    Ok(dict.into())
+}
+
+/// Thread pools of `fetch_parallel`, one per thread count
+pub(crate) static SHARD_POOLS: Mutex<BTreeMap<usize, Arc<rayon::ThreadPool>>> = Mutex::new(BTreeMap::new());
+
+/// The `fetch_parallel` pool with `threads` threads, started on first use
+pub(crate) fn shard_pool(threads: usize) -> PyResult<Arc<rayon::ThreadPool>> {
+    let mut pools = SHARD_POOLS.lock().unwrap_or_else(PoisonError::into_inner);
+    if let Some(pool) = pools.get(&threads) {
+        return Ok(pool.clone());
+    }
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .build()
+        .map_err(|e| runtime_err("Failed to start thread pool", e))?;
+    Ok(pools.entry(threads).or_insert(Arc::new(pool)).clone())
+}
+
+/// Encode ROWIDs as a JSON array, bound as one `json_each(?)` parameter
+///
+/// A single parameter keeps the statement text fixed, so it can be cached, and
+/// stays clear of SQLite's limit on the number of bound parameters.
+pub(crate) fn rowid_list<T: std::fmt::Display>(rowids: impl IntoIterator<Item = T>) -> String {
+    format!("[{}]", rowids.into_iter().map(|rowid| rowid.to_string()).collect::<Vec<_>>().join(","))
+}
+
+/// Encode a position, such as a page's last date and ROWID, as an opaque cursor string
+pub(crate) fn encode_cursor(values: &[i64]) -> String {
+    let text = values.iter().map(|value| value.to_string()).collect::<Vec<_>>().join(":");
+    text.bytes().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Decode a cursor made by `encode_cursor` from `len` values
+pub(crate) fn decode_cursor(cursor: &str, len: usize) -> PyResult<Vec<i64>> {
+    let invalid = || PyErr::new::<pyo3::exceptions::PyValueError, _>(
+        format!("Invalid cursor: {}", cursor)
+    );
+
+    let bytes = (0..cursor.len())
+        .step_by(2)
+        .map(|i| cursor.get(i..i + 2).and_then(|hex| u8::from_str_radix(hex, 16).ok()))
+        .collect::<Option<Vec<u8>>>()
+        .ok_or_else(invalid)?;
+    let text = String::from_utf8(bytes).map_err(|_| invalid())?;
+    let values = text.split(':')
+        .map(|value| value.parse().ok())
+        .collect::<Option<Vec<i64>>>()
+        .filter(|values| values.len() == len)
+        .ok_or_else(invalid)?;
+
+    Ok(values)
+}
+
+/// Map an `"asc"`/`"desc"` sort option onto its SQL keyword
+pub(crate) fn sort_order(order: Option<&str>) -> PyResult<&'static str> {
+    match order {
+        None | Some("asc") => Ok("ASC"),
+        Some("desc") => Ok("DESC"),
+        Some(other) => Err(PyErr::new::<pyo3::exceptions::PyValueError, _>(
+            format!("Unknown sort order: {} (expected \"asc\" or \"desc\")", other)
+        )),
+    }
+}
+
+/// Build the SQL fragment restricting messages to direct or group chats, as told apart by `is_group_chat`
+pub(crate) fn chat_style_filter(chat_type: Option<&str>) -> PyResult<String> {
+    let operator = match chat_type {
+        None => return Ok(String::new()),
+        Some("direct") => "NOT ",
+        Some("group") => "",
+        Some(other) => {
+            return Err(PyErr::new::<pyo3::exceptions::PyValueError, _>(
+                format!("Unknown chat type: {} (expected \"direct\" or \"group\")", other)
+            ));
+        }
+    };
+    Ok(format!("AND c.chat_id IN (SELECT ROWID FROM chat WHERE {}{})", operator, group_chat_sql("chat")))
+}
+
+/// Build the SQL fragment dropping rows without substantive content
+///
+/// That is tapbacks, group events, announcements and other `item_type != 0`
+/// rows, and messages removed with "Undo Send", which keep their row behind.
+/// Databases older than `date_retracted` only mark an unsent message by an
+/// edit date on a row without text.
+pub(crate) fn noise_filter(conn: &Connection) -> PyResult<String> {
+    let mut filter = format!(" AND {} AND m.item_type = 0", NOT_REACTION);
+    if column_exists(conn, "message", "date_retracted")? {
+        filter.push_str(" AND COALESCE(m.date_retracted, 0) = 0");
+    } else if column_exists(conn, "message", "date_edited")? {
+        filter.push_str(" AND NOT (COALESCE(m.date_edited, 0) != 0 AND m.text IS NULL AND m.attributedBody IS NULL)");
+    }
+    Ok(filter)
+}
+
+/// Services by the name `service` filters take, the name messages report, and
+/// the `service` column values that mean it
+///
+/// Both `service_filter` and `service_name` read the column through this one
+/// mapping. Rows without a service predate the column and are always
+/// iMessages; any other value is reported as written and matches no filter.
+pub(crate) const SERVICES: &[(&str, &str, &[&str])] = &[
+    ("imessage", "iMessage", &["iMessage"]),
+    ("sms", "SMS", &["SMS"]),
+    ("rcs", "RCS", &["rcs", "RCS"]),  // Newer macOS writes `rcs`
+    ("satellite", "Satellite", &["iMessageLite"]),
+];
+
+/// Build the SQL fragment restricting messages to one service
+pub(crate) fn service_filter(service: Option<&str>) -> PyResult<String> {
+    let Some(service) = service else {
+        return Ok(String::new());
+    };
+    let Some((_, _, values)) = SERVICES.iter().find(|(filter, _, _)| filter.eq_ignore_ascii_case(service)) else {
+        return Err(PyErr::new::<pyo3::exceptions::PyValueError, _>(
+            format!("Unknown service: {} (expected \"imessage\", \"sms\", \"rcs\" or \"satellite\")", service)
+        ));
+    };
+    let values = values.iter().map(|value| format!("'{}'", value)).collect::<Vec<_>>().join(", ");
+    Ok(format!(" AND TRIM(COALESCE(m.service, 'iMessage')) IN ({})", values))
+}
+
+/// Name of a message's service, from its `service` column as mapped in `SERVICES`
+pub(crate) fn service_name(service: Option<&str>) -> String {
+    let service = service.map_or("iMessage", str::trim);
+    SERVICES.iter()
+        .find(|(_, _, values)| values.contains(&service))
+        .map_or(service, |(_, name, _)| name)
+        .to_string()
+}
+
+/// Read the messages matching `filter` on a connection of its own, handing them
+/// to `consume` a batch at a time through a channel of at most `buffered` batches
+///
+/// The reader blocks while the channel is full, so at most `buffered + 2`
+/// batches are alive at once. An error from either side stops both.
+pub(crate) fn stream_pages(
+    db_path: &Path,
+    options: &ConnectionOptions,
+    filter: &MessageFilter,
+    batch_size: usize,
+    buffered: usize,
+    mut consume: impl FnMut(Vec<PyMessage>) -> PyResult<()>,
+) -> PyResult<()> {
+    let (sender, receiver) = std::sync::mpsc::sync_channel::<PyResult<Vec<PyMessage>>>(buffered);
+
+    std::thread::scope(|scope| {
+        scope.spawn(move || {
+            let db = match IMessageDB::open(db_path.to_path_buf(), options.clone()) {
+                Ok(db) => db,
+                Err(e) => {
+                    let _ = sender.send(Err(e));
+                    return;
+                }
+            };
+            let mut position = Some(filter.start());
+            let mut remaining = filter.limit.unwrap_or(usize::MAX);
+
+            while let (Some(after), true) = (position, remaining > 0) {
+                let batch = filter.page(&db, after, batch_size.min(remaining)).map(|(messages, next)| {
+                    position = next;
+                    remaining -= messages.len();
+                    messages
+                });
+                let failed = batch.is_err();
+                // Sending fails once the consumer has given up
+                if sender.send(batch).is_err() || failed {
+                    break;
+                }
+            }
+        });
+
+        for batch in receiver {
+            consume(batch?)?;
+        }
+        Ok(())
+    })
+}
+
+/// Move tapback rows onto the `reactions` list of their target messages
+///
+/// Messages must be in chronological order. A removed tapback cancels the
+/// earlier tapback of the same kind from the same sender. Tapbacks whose target
+/// is not in `messages` are kept as standalone rows.
+pub(crate) fn fold_reactions(messages: Vec<PyMessage>) -> Vec<PyMessage> {
+    let mut out: Vec<PyMessage> = Vec::with_capacity(messages.len());
+    let mut index_by_guid: HashMap<String, usize> = HashMap::new();
+
+    for msg in messages {
+        let target = msg.reaction_target_guid.as_ref()
+            .and_then(|guid| index_by_guid.get(guid).copied());
+
+        let (Some(kind), Some(target)) = (msg.reaction.as_ref(), target) else {
+            index_by_guid.insert(msg.guid.clone(), out.len());
+            out.push(msg);
+            continue;
+        };
+
+        let reactions = &mut out[target].reactions;
+        let part = msg.reaction_target_part.unwrap_or(0);
+        if let Some(removed) = kind.strip_prefix("removed_") {
+            if let Some(pos) = reactions.iter().rposition(|r| {
+                r.reaction == removed
+                    && r.part == part
+                    && r.is_from_me == msg.is_from_me
+                    && r.handle_id == msg.handle_id
+            }) {
+                reactions.remove(pos);
+            }
+        } else {
+            reactions.push(PyReaction {
+                rowid: msg.rowid,
+                guid: msg.guid,
+                reaction: kind.clone(),
+                emoji: msg.reaction_emoji,
+                part,
+                handle_id: msg.handle_id,
+                is_from_me: msg.is_from_me,
+                date: msg.date,
+            });
+        }
+    }
+
+    out
+}
+
+/// `fold_reactions` for messages sorted oldest first or, with `descending`, newest first
+pub(crate) fn fold_reactions_sorted(mut messages: Vec<PyMessage>, descending: bool) -> Vec<PyMessage> {
+    if !descending {
+        return fold_reactions(messages);
+    }
+    messages.reverse();
+    let mut folded = fold_reactions(messages);
+    folded.reverse();
+    folded
+}
+
+/// Split `associated_message_guid` (`p:0/GUID` or `bp:GUID`) into the target GUID and part index
+pub(crate) fn reaction_target(msg: &Message) -> (Option<String>, Option<usize>) {
+    match msg.clean_associated_guid() {
+        Some((part, guid)) => (Some(guid.to_string()), Some(part)),
+        None => (None, None),
+    }
+}
+
+/// Classify a group event row, returning the event kind and the affected handle
+pub(crate) fn group_event(msg: &Message) -> (Option<String>, Option<i32>) {
+    match msg.group_action() {
+        Some(GroupAction::ParticipantAdded(handle)) => (Some("participant_added".to_string()), Some(handle)),
+        Some(GroupAction::ParticipantRemoved(handle)) => (Some("participant_removed".to_string()), Some(handle)),
+        Some(GroupAction::NameChange(_)) => (Some("name_change".to_string()), None),
+        Some(GroupAction::ParticipantLeft) => (Some("participant_left".to_string()), None),
+        Some(GroupAction::GroupIconChanged) => (Some("group_icon_changed".to_string()), None),
+        Some(GroupAction::GroupIconRemoved) => (Some("group_icon_removed".to_string()), None),
+        None => (None, None),
+    }
+}
+
+/// Get the attachment holding the new image of a group photo change
+pub(crate) fn group_photo_attachment(msg: &Message, conn: &Connection) -> Option<i32> {
+    if !msg.has_attachments() {
+        return None;
+    }
+
+    Attachment::from_message(conn, msg)
+        .ok()?
+        .first()
+        .map(|attachment| attachment.rowid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cursor_round_trip() {
+        for values in [vec![0, 0], vec![-1, 7], vec![i64::MIN, i64::MAX], vec![725_000_000_000_000_000, 42, 3]] {
+            let cursor = encode_cursor(&values);
+            assert!(cursor.bytes().all(|byte| byte.is_ascii_hexdigit()));
+            assert_eq!(decode_cursor(&cursor, values.len()).unwrap(), values);
+        }
+    }
+
+    #[test]
+    fn cursor_rejects_malformed_input() {
+        let cursor = encode_cursor(&[12, 34]);
+        // Wrong number of values
+        assert!(decode_cursor(&cursor, 1).is_err());
+        assert!(decode_cursor(&cursor, 3).is_err());
+        // Truncated, not hex, not UTF-8, or not numbers
+        assert!(decode_cursor(&cursor[..cursor.len() - 1], 2).is_err());
+        assert!(decode_cursor("zz", 1).is_err());
+        assert!(decode_cursor("ff", 1).is_err());
+        assert!(decode_cursor(&encode_cursor(&[1]).replace("31", "61"), 1).is_err());
+        assert!(decode_cursor("", 1).is_err());
+        // A multibyte character is not split while reading hex pairs
+        assert!(decode_cursor("3é1", 1).is_err());
+    }
+
+    #[test]
+    fn service_filters_match_service_names() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE message (service TEXT);
+            INSERT INTO message VALUES (NULL), ('iMessage'), ('SMS '), ('rcs'), ('RCS'), ('iMessageLite'), ('Other'), ('');"
+        ).unwrap();
+        let names = |filter: &str| -> Vec<String> {
+            let query = format!("SELECT service FROM message as m WHERE 1 = 1{}", service_filter(Some(filter)).unwrap());
+            let mut stmt = conn.prepare(&query).unwrap();
+            let rows = stmt.query_map([], |row| row.get::<_, Option<String>>(0)).unwrap();
+            rows.map(|service| service_name(service.unwrap().as_deref())).collect()
+        };
+        assert_eq!(names("IMESSAGE"), ["iMessage", "iMessage"]);
+        assert_eq!(names("sms"), ["SMS"]);
+        assert_eq!(names("rcs"), ["RCS", "RCS"]);
+        assert_eq!(names("satellite"), ["Satellite"]);
+        assert_eq!(service_name(Some("Other")), "Other");
+        assert!(service_filter(Some("other")).is_err());
+    }
+
+    #[test]
+    fn worker_connections_are_kept_between_calls() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("../tests/fixtures/test_imessage_sample.db");
+        let db = IMessageDB::open(path, ConnectionOptions::default()).unwrap();
+        let decoder = |workers: &[Mutex<IMessageDB>]| workers[0].lock().unwrap().text_decoder().unwrap();
+        let first = decoder(&db.worker_connections(2).unwrap());
+        assert_eq!(db.worker_connections(3).unwrap().len(), 3);
+        let again = db.worker_connections(1).unwrap();
+        assert_eq!(again.len(), 3);
+        assert!(Arc::ptr_eq(&first, &decoder(&again)));
+    }
+
+    /// A tapback of `kind` from `handle_id` on part 0 of the message `target`
+    fn tapback(rowid: i32, target: &str, kind: &str, handle_id: i32) -> PyMessage {
+        PyMessage {
+            handle_id: Some(handle_id),
+            reaction: Some(kind.to_string()),
+            reaction_target_guid: Some(target.to_string()),
+            reaction_target_part: Some(0),
+            ..PyMessage::for_test(rowid, &format!("tapback-{}", rowid))
+        }
+    }
+
+    fn reactions(message: &PyMessage) -> Vec<(i32, &str, Option<i32>)> {
+        message.reactions.iter().map(|r| (r.rowid, r.reaction.as_str(), r.handle_id)).collect()
+    }
+
+    #[test]
+    fn fold_reactions_applies_removals_in_order() {
+        let folded = fold_reactions(vec![
+            PyMessage::for_test(1, "target"),
+            tapback(2, "target", "loved", 10),
+            tapback(3, "target", "liked", 11),
+            tapback(4, "target", "removed_loved", 10),
+            tapback(5, "target", "loved", 10),
+            // Only cancels a tapback of the same kind from the same sender
+            tapback(6, "target", "removed_liked", 10),
+            tapback(7, "target", "removed_laughed", 11),
+        ]);
+        assert_eq!(folded.len(), 1);
+        assert_eq!(reactions(&folded[0]), vec![(3, "liked", Some(11)), (5, "loved", Some(10))]);
+    }
+
+    #[test]
+    fn fold_reactions_ignores_removal_before_tapback() {
+        let folded = fold_reactions(vec![
+            PyMessage::for_test(1, "target"),
+            tapback(2, "target", "removed_loved", 10),
+            tapback(3, "target", "loved", 10),
+        ]);
+        assert_eq!(reactions(&folded[0]), vec![(3, "loved", Some(10))]);
+    }
+
+    #[test]
+    fn fold_reactions_keeps_tapbacks_without_target() {
+        // The target follows the tapback, or is not loaded at all
+        let folded = fold_reactions(vec![
+            tapback(1, "later", "loved", 10),
+            PyMessage::for_test(2, "later"),
+            tapback(3, "missing", "loved", 10),
+        ]);
+        assert_eq!(folded.iter().map(|m| m.rowid).collect::<Vec<_>>(), vec![1, 2, 3]);
+        assert!(folded.iter().all(|m| m.reactions.is_empty()));
+    }
+}
@@ -0,0 +1,639 @@
+//! Substring, full-text and regex search
+
+use super::*;
+
+#[pymethods]
+impl IMessageDB {
+    /// Find messages whose text contains `term`, ignoring ASCII case
+    ///
+    /// Returns a hit with highlight ranges and a snippet of about `context`
+    /// characters (default 40) either side of the first match for each of the
+    /// latest `limit` matching messages (default 100) in chronological order,
+    /// optionally within one chat. Messages that only carry their text in
+    /// `attributedBody` are matched against a `message_text` table of decoded
+    /// text in the SQLite file at `text_cache`, which is brought up to date on
+    /// each call; without `text_cache` that text is decoded again every time.
+    ///
+    /// With `normalize`, both `term` and message text are NFKC-normalized,
+    /// lowercased and stripped of diacritics before matching, so "cafe" finds
+    /// "Café" and "ＡＢＣ" finds "abc". That mode decodes every message in Rust
+    /// and does not use `text_cache`.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn search_messages(&self, py: Python, term: &str, chat_id: Option<i32>, limit: Option<usize>, text_cache: Option<PathBuf>, normalize: Option<bool>, context: Option<usize>) -> PyResult<Vec<PySearchHit>> {
+        self.without_gil(py, |db| {
+            let normalize = normalize.unwrap_or(false);
+            let context = context.unwrap_or(40);
+            let to_hit = |mut message: PyMessage| {
+                message.resolve()?;
+                let text = message.text.as_deref().unwrap_or_default();
+                let highlights = find_ranges(text, term, normalize);
+                let snippet = highlight_snippet(text, &highlights, context);
+                Ok(PySearchHit { message, rank: None, snippet, highlights })
+            };
+
+            if term.is_empty() {
+                return Err(PyErr::new::<pyo3::exceptions::PyValueError, _>(
+                    "Search term must not be empty"
+                ));
+            }
+
+            if normalize {
+                let term = fold_text(term);
+                // A lone combining accent folds away and would match every message
+                if term.is_empty() {
+                    return Err(PyErr::new::<pyo3::exceptions::PyValueError, _>(
+                        "Search term must not be empty once accents are removed"
+                    ));
+                }
+                let mut matches = Vec::new();
+                db.scan_text(chat_id, |rowid, text| {
+                    if fold_text(text).contains(&term) {
+                        matches.push(rowid);
+                    }
+                    true
+                })?;
+                let mut by_rowid = db.messages_by_rowid(matches.iter().rev().take(limit.unwrap_or(100)).copied())?;
+                let keep = matches.len().saturating_sub(limit.unwrap_or(100));
+                return matches[keep..].iter().filter_map(|rowid| by_rowid.remove(rowid)).map(to_hit).collect();
+            }
+
+            let cache = open_text_cache(text_cache.as_deref()).map_err(|e| io_err("Failed to open text cache", e))?;
+            db.sync_text_cache(&cache)?;
+
+            let decoded_matches = cache.prepare(
+                "SELECT rowid FROM message_text WHERE instr(lower(text), lower(?)) > 0"
+            ).and_then(|mut stmt| {
+                stmt.query_map([term], |row| row.get::<_, i64>(0))?.collect::<rusqlite::Result<Vec<i64>>>()
+            }).map_err(|e| runtime_err("Failed to search text cache", e))?;
+            let query = "SELECT 
+                    m.*,
+                    c.chat_id,
+                    (SELECT COUNT(*) FROM message_attachment_join a WHERE m.ROWID = a.message_id) as num_attachments,
+                    NULL as deleted_from,
+                    0 as num_replies
+                FROM message as m
+                LEFT JOIN chat_message_join as c ON m.ROWID = c.message_id
+                WHERE (instr(lower(m.text), lower(?1)) > 0 OR m.ROWID IN (SELECT value FROM json_each(?4)))
+                    AND (?2 IS NULL OR c.chat_id = ?2)
+                GROUP BY m.ROWID
+                ORDER BY m.date DESC, m.ROWID DESC
+                LIMIT ?3";
+
+            let limit = limit.unwrap_or(100) as i64;
+            let mut messages = db.collect_messages(query, rusqlite::params![term, chat_id, limit, rowid_list(decoded_matches)])?;
+            messages.reverse();
+            messages.into_iter().map(to_hit).collect()
+        })
+    }
+
+    /// Bring the FTS5 index at `index_path` up to date
+    ///
+    /// The index is a separate SQLite file holding the decoded text of every
+    /// message, including text only found in `attributedBody`. The first call
+    /// indexes everything; later calls add new messages, re-index edited ones,
+    /// and drop messages that were unsent, moved to Recently Deleted or are
+    /// gone from the database. Rows that cannot be read as messages are
+    /// counted in `skipped` and only retried once they are edited.
+    pub(crate) fn update_fts_index(&self, py: Python, index_path: PathBuf) -> PyResult<PyIndexUpdate> {
+        use rusqlite::types::Value;
+
+        self.without_gil(py, |db| {
+            let mut index = open_fts_index(&index_path).map_err(|e| io_err("Failed to open search index", e))?;
+            let index_err = |e: rusqlite::Error| io_err("Failed to update search index", e);
+            let mut update = PyIndexUpdate { indexed: 0, removed: 0, skipped: 0 };
+
+            let (last_rowid, last_edited, tracked): (i64, i64, i64) = index.query_row(
+                "SELECT COALESCE(MAX(rowid), 0), COALESCE(MAX(date_edited), 0), COUNT(*) FROM fts_message",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            ).map_err(index_err)?;
+
+            let recoverable: HashSet<i64> = if table_exists(&db.conn, "chat_recoverable_message_join")? {
+                db.conn.prepare("SELECT message_id FROM chat_recoverable_message_join")
+                    .and_then(|mut stmt| stmt.query_map([], |row| row.get(0))?.collect())
+                    .map_err(|e| runtime_err("Failed to read deleted messages", e))?
+            } else {
+                HashSet::new()
+            };
+            let was_deleted: HashSet<i64> = index.prepare("SELECT rowid FROM fts_deleted")
+                .and_then(|mut stmt| stmt.query_map([], |row| row.get(0))?.collect())
+                .map_err(index_err)?;
+            // Restored from Recently Deleted since the last run, so indexed again below
+            let restored: Vec<i64> = was_deleted.difference(&recoverable).copied().collect();
+
+            // Rows removed from chat.db leave fewer messages than tracked rows,
+            // and only then are the ROWIDs themselves compared
+            let present: i64 = db.conn.query_row("SELECT COUNT(*) FROM message WHERE ROWID <= ?", [last_rowid], |row| row.get(0))
+                .map_err(|e| runtime_err("Failed to count messages", e))?;
+            let gone: Vec<i64> = if present < tracked {
+                let existing: HashSet<i64> = db.conn.prepare("SELECT ROWID FROM message WHERE ROWID <= ?")
+                    .and_then(|mut stmt| stmt.query_map([last_rowid], |row| row.get(0))?.collect())
+                    .map_err(|e| runtime_err("Failed to read message ROWIDs", e))?;
+                let tracked: Vec<i64> = index.prepare("SELECT rowid FROM fts_message")
+                    .and_then(|mut stmt| stmt.query_map([], |row| row.get(0))?.collect())
+                    .map_err(index_err)?;
+                tracked.into_iter().filter(|rowid| !existing.contains(rowid)).collect()
+            } else {
+                Vec::new()
+            };
+
+            let has_edits = column_exists(&db.conn, "message", "date_edited")?;
+            let mut query = format!(
+                "SELECT 
+                    m.*,
+                    NULL as chat_id,
+                    0 as num_attachments,
+                    NULL as deleted_from,
+                    0 as num_replies,
+                    {} as fts_edited
+                FROM message as m
+                WHERE m.ROWID > ? OR m.ROWID IN (SELECT value FROM json_each(?))",
+                if has_edits { "COALESCE(m.date_edited, 0)" } else { "0" }
+            );
+            let mut params = vec![Value::Integer(last_rowid), Value::Text(rowid_list(&restored))];
+            if has_edits {
+                query.push_str(" OR COALESCE(m.date_edited, 0) > ?");
+                params.push(Value::Integer(last_edited));
+            }
+            let mut stmt = db.conn.prepare_cached(&query).map_err(|e| runtime_err("Failed to prepare query", e))?;
+            let mut rows = stmt.query(rusqlite::params_from_iter(params)).map_err(|e| runtime_err("Failed to execute query", e))?;
+
+            // Rolled back when dropped on an error, leaving the index as it was
+            let tx = index.transaction().map_err(index_err)?;
+            let unindex = |rowid: i64| tx.execute("DELETE FROM message_fts WHERE rowid = ?", [rowid]).map_err(index_err);
+
+            for &rowid in recoverable.difference(&was_deleted) {
+                update.removed += unindex(rowid)?;
+                tx.execute("INSERT OR IGNORE INTO fts_deleted (rowid) VALUES (?)", [rowid]).map_err(index_err)?;
+            }
+            for &rowid in restored.iter().chain(&gone) {
+                tx.execute("DELETE FROM fts_deleted WHERE rowid = ?", [rowid]).map_err(index_err)?;
+            }
+            for &rowid in &gone {
+                update.removed += unindex(rowid)?;
+                tx.execute("DELETE FROM fts_message WHERE rowid = ?", [rowid]).map_err(index_err)?;
+            }
+
+            while let Some(row) = rows.next().map_err(|e| runtime_err("Failed to fetch row", e))? {
+                let rowid: i64 = row.get("ROWID").map_err(|e| runtime_err("Failed to read row", e))?;
+                let edited: i64 = row.get("fts_edited").unwrap_or(0);
+
+                // Unreadable rows are still tracked below, so they are not retried until edited
+                let text = match Message::from_row(row) {
+                    Ok(_) if recoverable.contains(&rowid) => None,
+                    Ok(mut msg) => {
+                        let text = decode_text(&mut msg, &db.conn);
+                        combine_subject(msg.subject.as_deref(), text.as_deref())
+                            .filter(|text| !text.trim().is_empty() && !msg.is_fully_unsent())
+                    }
+                    Err(_) => {
+                        update.skipped += 1;
+                        None
+                    }
+                };
+
+                let removed = unindex(rowid)?;
+                match text {
+                    Some(text) => {
+                        tx.execute(
+                            "INSERT INTO message_fts (rowid, text) VALUES (?1, ?2)",
+                            rusqlite::params![rowid, text],
+                        ).map_err(index_err)?;
+                        update.indexed += 1;
+                    }
+                    None => update.removed += removed,
+                }
+                tx.execute(
+                    "INSERT OR REPLACE INTO fts_message (rowid, date_edited) VALUES (?1, ?2)",
+                    rusqlite::params![rowid, edited],
+                ).map_err(index_err)?;
+                if recoverable.contains(&rowid) {
+                    tx.execute("INSERT OR IGNORE INTO fts_deleted (rowid) VALUES (?)", [rowid]).map_err(index_err)?;
+                }
+            }
+            tx.commit().map_err(index_err)?;
+
+            Ok(update)
+        })
+    }
+
+    /// Search the FTS5 index at `index_path`, best matches first
+    ///
+    /// `query` uses FTS5 syntax: terms, `"phrases"` and `AND`/`OR`/`NOT`. Terms
+    /// match anywhere in a word, ignoring case, but need at least three characters.
+    /// Returns up to `limit` hits (default 50), optionally within one chat.
+    /// Call `update_fts_index` first so recent messages are searchable.
+    pub(crate) fn search_fts(&self, py: Python, query: &str, index_path: PathBuf, limit: Option<usize>, chat_id: Option<i32>) -> PyResult<Vec<PySearchHit>> {
+        self.without_gil(py, |db| {
+            let limit = limit.unwrap_or(50);
+            let index = open_fts_index(&index_path).map_err(|e| io_err("Failed to open search index", e))?;
+            let mut ranking = index.prepare(
+                "SELECT
+                    rowid,
+                    bm25(message_fts),
+                    snippet(message_fts, 0, '[', ']', '…', 48),
+                    highlight(message_fts, 0, char(2), char(3))
+                FROM message_fts
+                WHERE message_fts MATCH ?1
+                ORDER BY bm25(message_fts), rowid
+                LIMIT ?2 OFFSET ?3"
+            ).map_err(|e| runtime_err("Failed to prepare query", e))?;
+
+            // Chat membership lives in the main database, so ranked matches are read a page
+            // at a time until enough of them are in the chat, or still in the database at all
+            let page = limit.max(FTS_PAGE_SIZE);
+            let mut hits = Vec::new();
+            for offset in (0..).step_by(page) {
+                let ranked: Vec<(i64, f64, String, String)> = ranking
+                    .query_map(rusqlite::params![query, page as i64, offset as i64], |row| {
+                        Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+                    })
+                    .and_then(|rows| rows.collect())
+                    .map_err(|e| {
+                        PyErr::new::<pyo3::exceptions::PyValueError, _>(
+                            format!("Invalid search query {:?}: {}", query, e)
+                        )
+                    })?;
+                if ranked.is_empty() {
+                    break;
+                }
+                let exhausted = ranked.len() < page;
+
+                let mut messages = db.collect_messages(
+                    "SELECT 
+                        m.*,
+                        c.chat_id,
+                        (SELECT COUNT(*) FROM message_attachment_join a WHERE m.ROWID = a.message_id) as num_attachments,
+                        NULL as deleted_from,
+                        0 as num_replies
+                    FROM message as m
+                    LEFT JOIN chat_message_join as c ON m.ROWID = c.message_id
+                    WHERE m.ROWID IN (SELECT value FROM json_each(?2)) AND (?1 IS NULL OR c.chat_id = ?1)
+                    GROUP BY m.ROWID",
+                    rusqlite::params![chat_id, rowid_list(ranked.iter().map(|(rowid, ..)| *rowid))],
+                )?;
+
+                resolve_all(&mut messages)?;
+                let mut by_rowid: HashMap<i64, PyMessage> = messages.into_iter().map(|message| (message.rowid.into(), message)).collect();
+                hits.extend(ranked.into_iter().filter_map(|(rowid, rank, snippet, marked)| {
+                    let message = by_rowid.remove(&rowid)?;
+                    // The index may hold the subject before the text, so shift ranges onto `text`
+                    let (indexed, ranges) = marked_ranges(&marked, '\u{2}', '\u{3}');
+                    let text = message.text.as_deref().unwrap_or_default();
+                    let highlights = match indexed.ends_with(text) {
+                        true => {
+                            let shift = indexed.chars().count() - text.chars().count();
+                            ranges.into_iter()
+                                .filter(|&(start, _)| start >= shift)
+                                .map(|(start, end)| (start - shift, end - shift))
+                                .collect()
+                        }
+                        false => Vec::new(),
+                    };
+                    Some(PySearchHit { message, rank: Some(rank), snippet, highlights })
+                }));
+                if hits.len() >= limit || exhausted {
+                    break;
+                }
+            }
+            hits.truncate(limit);
+            Ok(hits)
+        })
+    }
+
+    /// Find every match of a regular expression in message text, oldest first
+    ///
+    /// `pattern` uses Rust `regex` syntax; prefix it with `(?i)` to ignore case.
+    /// Messages are decoded and matched one at a time, so only the matching ones
+    /// are fully loaded. A message yields one result per non-overlapping match.
+    /// Stops after `limit` matches when given.
+    pub(crate) fn search_regex(&self, py: Python, pattern: &str, chat_id: Option<i32>, limit: Option<usize>) -> PyResult<Vec<PyRegexMatch>> {
+        self.without_gil(py, |db| {
+            let regex = Regex::new(pattern).map_err(|e| {
+                PyErr::new::<pyo3::exceptions::PyValueError, _>(
+                    format!("Invalid regex {:?}: {}", pattern, e)
+                )
+            })?;
+            let limit = limit.unwrap_or(usize::MAX);
+
+            // (ROWID, match, start, end, groups, named groups) for each hit
+            let mut hits = Vec::new();
+            db.scan_text(chat_id, |rowid, text| {
+                for captures in regex.captures_iter(text).take(limit - hits.len()) {
+                    let whole = captures.get(0).expect("group 0 is always the whole match");
+                    let groups = captures.iter().skip(1).map(|group| group.map(|g| g.as_str().to_string())).collect();
+                    let named_groups = regex.capture_names()
+                        .flatten()
+                        .map(|name| (name.to_string(), captures.name(name).map(|g| g.as_str().to_string())))
+                        .collect();
+                    hits.push((
+                        rowid,
+                        whole.as_str().to_string(),
+                        text[..whole.start()].chars().count(),
+                        text[..whole.end()].chars().count(),
+                        groups,
+                        named_groups,
+                    ));
+                }
+                hits.len() < limit
+            })?;
+
+            let by_rowid = db.messages_by_rowid(hits.iter().map(|hit| hit.0))?;
+            Ok(hits.into_iter()
+                .filter_map(|(rowid, matched, start, end, groups, named_groups)| {
+                    by_rowid.get(&rowid).map(|message| PyRegexMatch {
+                        message: message.clone(),
+                        matched,
+                        start,
+                        end,
+                        groups,
+                        named_groups,
+                    })
+                })
+                .collect())
+        })
+    }
+}
+
+impl IMessageDB {
+    /// Decode each message's text in chronological order, optionally within one chat
+    ///
+    /// `visit` gets the ROWID and text of every message that has text and
+    /// returns whether to keep scanning.
+    pub(crate) fn scan_text(&self, chat_id: Option<i32>, mut visit: impl FnMut(i32, &str) -> bool) -> PyResult<()> {
+        let mut stmt = self.conn.prepare_cached(
+            "SELECT 
+                m.*,
+                NULL as chat_id,
+                0 as num_attachments,
+                NULL as deleted_from,
+                0 as num_replies
+            FROM message as m
+            WHERE ?1 IS NULL OR m.ROWID IN (SELECT message_id FROM chat_message_join WHERE chat_id = ?1)
+            ORDER BY m.date ASC, m.ROWID ASC"
+        ).map_err(|e| runtime_err("Failed to prepare query", e))?;
+        let mut rows = stmt.query([chat_id]).map_err(|e| runtime_err("Failed to execute query", e))?;
+
+        while let Some(row) = rows.next().map_err(|e| runtime_err("Failed to fetch row", e))? {
+            let Ok(mut msg) = Message::from_row(row) else {
+                continue;
+            };
+            if let Some(text) = decode_text(&mut msg, &self.conn) {
+                if !visit(msg.rowid, &text) {
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Decode the text of messages without a `text` column into the text cache
+    ///
+    /// Rows already cached are only decoded again once they have been edited.
+    pub(crate) fn sync_text_cache(&self, cache: &Connection) -> PyResult<()> {
+        use rusqlite::types::Value;
+
+        let cache_err = |e: rusqlite::Error| io_err("Failed to update text cache", e);
+
+        // Rows past the highest cached ROWID are new and rows edited after the
+        // latest cached edit changed; everything else is already up to date
+        let (last_rowid, last_edited): (i64, i64) = cache.query_row(
+            "SELECT COALESCE(MAX(rowid), 0), COALESCE(MAX(date_edited), 0) FROM message_text",
+            [],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        ).map_err(cache_err)?;
+
+        let has_edits = column_exists(&self.conn, "message", "date_edited")?;
+        let mut changed = String::from("m.ROWID > ?");
+        let mut params = vec![Value::Integer(last_rowid)];
+        if has_edits {
+            changed.push_str(" OR COALESCE(m.date_edited, 0) > ?");
+            params.push(Value::Integer(last_edited));
+        }
+        let query = format!(
+            "SELECT 
+                m.*,
+                NULL as chat_id,
+                0 as num_attachments,
+                NULL as deleted_from,
+                0 as num_replies,
+                {} as text_cache_edited
+            FROM message as m
+            WHERE ({}) AND (m.text IS NULL OR m.text = '') AND m.attributedBody IS NOT NULL",
+            if has_edits { "COALESCE(m.date_edited, 0)" } else { "0" },
+            changed
+        );
+        let mut stmt = self.conn.prepare_cached(&query).map_err(|e| runtime_err("Failed to prepare query", e))?;
+        let mut rows = stmt.query(rusqlite::params_from_iter(params)).map_err(|e| runtime_err("Failed to execute query", e))?;
+
+        // Rolled back when dropped on an error, so a failed sync leaves the cache as it was
+        let tx = cache.unchecked_transaction().map_err(cache_err)?;
+        while let Some(row) = rows.next().map_err(|e| runtime_err("Failed to fetch row", e))? {
+            let edited: i64 = row.get("text_cache_edited").unwrap_or(0);
+            let rowid: i64 = row.get("ROWID").map_err(|e| runtime_err("Failed to read row", e))?;
+
+            // Rows that cannot be read are cached without text, so they are not retried until edited
+            let text = Message::from_row(row).ok().and_then(|mut msg| decode_text(&mut msg, &self.conn));
+            tx.execute(
+                "INSERT OR REPLACE INTO message_text (rowid, date_edited, text) VALUES (?1, ?2, ?3)",
+                rusqlite::params![rowid, edited, text],
+            ).map_err(cache_err)?;
+        }
+        tx.commit().map_err(cache_err)
+    }
+}
+
+/// Fold text for loose matching, ignoring compatibility forms, case and diacritics
+pub(crate) fn fold_text(text: &str) -> String {
+    fold_chars(text).0
+}
+
+/// Fold text like `fold_text`, also mapping each folded char to the index of the char it came from
+pub(crate) fn fold_chars(text: &str) -> (String, Vec<usize>) {
+    let mut folded = String::new();
+    let mut origins = Vec::new();
+    for (index, c) in text.chars().enumerate() {
+        for folded_char in std::iter::once(c)
+            .nfkd()
+            .filter(|&c| !is_combining_mark(c))
+            .nfc()
+            .flat_map(char::to_lowercase)
+        {
+            folded.push(folded_char);
+            origins.push(index);
+        }
+    }
+    (folded, origins)
+}
+
+/// Find the character ranges of `term` in `text`, ignoring ASCII case or, with `fold`, as `fold_text` does
+pub(crate) fn find_ranges(text: &str, term: &str, fold: bool) -> Vec<(usize, usize)> {
+    if term.is_empty() {
+        return Vec::new();
+    }
+
+    if fold {
+        let (folded, origins) = fold_chars(text);
+        let term = fold_text(term);
+        let char_index = |byte: usize| folded[..byte].chars().count();
+        folded.match_indices(&term)
+            .filter(|(_, hit)| !hit.is_empty())
+            .map(|(start, hit)| {
+                let (first, last) = (char_index(start), char_index(start + hit.len()) - 1);
+                (origins[first], origins[last] + 1)
+            })
+            .collect()
+    } else {
+        // ASCII lowercasing keeps byte offsets, matching SQLite's `lower()`
+        let lowered = text.to_ascii_lowercase();
+        lowered.match_indices(&term.to_ascii_lowercase())
+            .map(|(start, hit)| (text[..start].chars().count(), text[..start + hit.len()].chars().count()))
+            .collect()
+    }
+}
+
+/// Strip `open`/`close` markers from text, returning it with the character ranges they enclosed
+pub(crate) fn marked_ranges(marked: &str, open: char, close: char) -> (String, Vec<(usize, usize)>) {
+    let mut text = String::new();
+    let mut ranges = Vec::new();
+    let mut start = None;
+    let mut position = 0;
+    for c in marked.chars() {
+        if c == open {
+            start = Some(position);
+        } else if c == close {
+            if let Some(start) = start.take() {
+                ranges.push((start, position));
+            }
+        } else {
+            text.push(c);
+            position += 1;
+        }
+    }
+    (text, ranges)
+}
+
+/// Cut an excerpt around the first highlight, wrapping every highlight it contains in [ and ]
+pub(crate) fn highlight_snippet(text: &str, highlights: &[(usize, usize)], context: usize) -> String {
+    let chars: Vec<char> = text.chars().collect();
+    let (first_start, first_end) = highlights.first().copied().unwrap_or((0, 0));
+    let from = first_start.saturating_sub(context);
+    let to = (first_end + context).min(chars.len());
+
+    let mut snippet = String::new();
+    if from > 0 {
+        snippet.push('…');
+    }
+    for (index, &c) in chars.iter().enumerate().take(to).skip(from) {
+        // Highlights cut off by the excerpt are still opened and closed at its edges
+        if highlights.iter().any(|&(start, end)| start == index || (index == from && start < index && index < end)) {
+            snippet.push('[');
+        }
+        snippet.push(c);
+        if highlights.iter().any(|&(start, end)| end == index + 1 || (index + 1 == to && start <= index && end > to)) {
+            snippet.push(']');
+        }
+    }
+    if to < chars.len() {
+        snippet.push('…');
+    }
+    snippet
+}
+
+/// Open the decoded message text cache at `path`, or an in-memory one, creating its table
+pub(crate) fn open_text_cache(path: Option<&Path>) -> rusqlite::Result<Connection> {
+    let cache = match path {
+        Some(path) => Connection::open(path)?,
+        None => Connection::open_in_memory()?,
+    };
+    cache.execute_batch(
+        "CREATE TABLE IF NOT EXISTS message_text (
+            rowid INTEGER PRIMARY KEY,
+            date_edited INTEGER NOT NULL,
+            text TEXT
+        )"
+    )?;
+    Ok(cache)
+}
+
+/// Ranked full-text matches read at a time while filling `search_fts` results
+///
+/// Most searches are answered by the first page; a chat filter that drops
+/// most matches reads further pages rather than the whole ranking.
+pub(crate) const FTS_PAGE_SIZE: usize = 256;
+
+/// Open the full-text search index at `path`, creating its tables
+///
+/// Text is split into trigrams rather than words so that substrings match and
+/// scripts written without spaces, such as Chinese, are searchable. `fts_message`
+/// records every indexed message, including those without text, so that
+/// incremental updates know where they left off; `fts_deleted` the ones left
+/// out while in Recently Deleted, so they are indexed again once restored.
+pub(crate) fn open_fts_index(path: &Path) -> rusqlite::Result<Connection> {
+    let index = Connection::open(path)?;
+    index.execute_batch(
+        "CREATE VIRTUAL TABLE IF NOT EXISTS message_fts USING fts5(
+            text,
+            tokenize = 'trigram remove_diacritics 1'
+        );
+        CREATE TABLE IF NOT EXISTS fts_message (
+            rowid INTEGER PRIMARY KEY,
+            date_edited INTEGER NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS fts_deleted (
+            rowid INTEGER PRIMARY KEY
+        )"
+    )?;
+    Ok(index)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn find_ranges_counts_characters() {
+        assert_eq!(find_ranges("héllo wörld", "WÖRLD", false), vec![]);
+        assert_eq!(find_ranges("héllo wörld", "wörld", false), vec![(6, 11)]);
+        assert_eq!(find_ranges("👍🏽 OK, ok", "ok", false), vec![(3, 5), (7, 9)]);
+        assert_eq!(find_ranges("anything", "", false), vec![]);
+        assert_eq!(find_ranges("anything", "", true), vec![]);
+    }
+
+    #[test]
+    fn find_ranges_folded_map_back_to_original_characters() {
+        assert_eq!(find_ranges("Café ＡＢＣ", "cafe", true), vec![(0, 4)]);
+        assert_eq!(find_ranges("Café ＡＢＣ", "abc", true), vec![(5, 8)]);
+        // The ligature folds to two characters, which both point back at it
+        assert_eq!(find_ranges("ﬁle", "file", true), vec![(0, 3)]);
+        assert_eq!(find_ranges("ﬁle", "i", true), vec![(0, 1)]);
+    }
+
+    #[test]
+    fn marked_ranges_strips_markers() {
+        let (text, ranges) = marked_ranges("a\u{2}bé\u{3}c\u{2}😀\u{3}", '\u{2}', '\u{3}');
+        assert_eq!(text, "abéc😀");
+        assert_eq!(ranges, vec![(1, 3), (4, 5)]);
+
+        // A stray close is dropped, as is an open that is never closed
+        let (text, ranges) = marked_ranges("\u{3}ab\u{2}cd", '\u{2}', '\u{3}');
+        assert_eq!(text, "abcd");
+        assert_eq!(ranges, vec![]);
+    }
+
+    #[test]
+    fn highlight_snippet_cuts_around_first_highlight() {
+        assert_eq!(highlight_snippet("0123456789", &[(4, 6)], 2), "…23[45]67…");
+        assert_eq!(highlight_snippet("ééééhiéééé", &[(4, 6)], 1), "…é[hi]é…");
+        assert_eq!(highlight_snippet("hi", &[(0, 2)], 40), "[hi]");
+        assert_eq!(highlight_snippet("0123456789", &[], 3), "012…");
+        assert_eq!(highlight_snippet("", &[], 3), "");
+    }
+
+    #[test]
+    fn highlight_snippet_closes_highlights_cut_off_by_the_excerpt() {
+        let text = "abcdefghijklmnopqrst";
+        assert_eq!(highlight_snippet(text, &[(5, 7), (8, 15)], 2), "…de[fg]h[i]…");
+        assert_eq!(highlight_snippet(text, &[(5, 7), (0, 4)], 2), "…[d]e[fg]hi…");
+    }
+}
@@ -0,0 +1,971 @@
+//! Python-facing value types returned by the API
+
+use super::*;
+
+/// Python-accessible message structure
+#[pyclass]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct PyMessage {
+    #[pyo3(get)]
+    pub(crate) rowid: i32,
+    #[pyo3(get)]
+    pub(crate) guid: String,
+    pub(crate) text: Option<String>,
+    #[pyo3(get)]
+    pub(crate) service: String,
+    #[pyo3(get)]
+    pub(crate) handle_id: Option<i32>,
+    #[pyo3(get)]
+    pub(crate) subject: Option<String>,
+    #[pyo3(get)]
+    pub(crate) date: f64,  // Unix timestamp
+    #[pyo3(get)]
+    pub(crate) date_read: Option<f64>,
+    #[pyo3(get)]
+    pub(crate) date_delivered: Option<f64>,
+    #[pyo3(get)]
+    pub(crate) is_from_me: bool,
+    #[pyo3(get)]
+    pub(crate) is_read: bool,
+    #[pyo3(get)]
+    pub(crate) is_sent: bool,
+    #[pyo3(get)]
+    pub(crate) is_delivered: bool,
+    #[pyo3(get)]
+    pub(crate) cache_roomnames: Option<String>,
+    #[pyo3(get)]
+    pub(crate) group_title: Option<String>,
+    #[pyo3(get)]
+    pub(crate) associated_message_guid: Option<String>,
+    #[pyo3(get)]
+    pub(crate) associated_message_type: Option<i32>,
+    #[pyo3(get)]
+    pub(crate) thread_originator_guid: Option<String>,
+    #[pyo3(get)]
+    pub(crate) item_type: i32,
+    #[pyo3(get)]
+    pub(crate) event_type: Option<String>,  // Group event kind for item_type != 0 rows
+    #[pyo3(get)]
+    pub(crate) event_handle_id: Option<i32>,  // Participant added or removed by the event
+    #[pyo3(get)]
+    pub(crate) reaction: Option<String>,  // Tapback kind, e.g. "loved" or "removed_loved"
+    #[pyo3(get)]
+    pub(crate) reaction_emoji: Option<String>,  // Emoji for custom emoji tapbacks
+    #[pyo3(get)]
+    pub(crate) reaction_target_guid: Option<String>,  // GUID of the message the tapback applies to
+    #[pyo3(get)]
+    pub(crate) reaction_target_part: Option<usize>,  // Index of the message part the tapback applies to
+    #[pyo3(get)]
+    pub(crate) reactions: Vec<PyReaction>,  // Tapbacks folded in when `attach_reactions` is set
+    #[pyo3(get)]
+    pub(crate) date_edited: Option<f64>,  // Unix timestamp of the latest edit
+    pub(crate) edit_history: Vec<PyMessageEdit>,  // Prior versions of edited message parts, oldest first
+    #[pyo3(get)]
+    pub(crate) date_retracted: Option<f64>,  // Unix timestamp of the latest unsent part
+    #[pyo3(get)]
+    pub(crate) was_unsent: bool,  // Removed with "Undo Send"
+    #[pyo3(get)]
+    pub(crate) effect: Option<String>,  // Expressive send effect, e.g. "slam" or "confetti"
+    #[pyo3(get)]
+    pub(crate) effect_type: Option<String>,  // "bubble", "screen", or "unknown"
+    pub(crate) mentions: Vec<PyMention>,
+    pub(crate) links: Vec<PyLink>,
+    pub(crate) balloon_bundle_id: Option<String>,  // iMessage app that produced the message
+    pub(crate) app_type: Option<String>,  // e.g. "url", "apple_pay", "fitness", or "application"
+    pub(crate) apple_pay: Option<PyApplePay>,
+    pub(crate) digital_touch: Option<String>,  // "tap", "heartbeat", "sketch", "kiss", "fireball", or "unknown"
+    #[pyo3(get)]
+    pub(crate) is_audio_message: bool,
+    pub(crate) audio_duration: Option<f64>,  // Seconds, read from the local .caf or .m4a file when available
+    #[pyo3(get)]
+    pub(crate) expire_state: Option<i32>,  // Raw `expire_state`; audio messages expire unless kept
+    pub(crate) audio_transcript: Option<String>,  // System transcription of the voice message
+    pub(crate) sticker: Option<PySticker>,
+    pub(crate) display_text: Option<String>,  // Subject and text combined the way Messages.app shows them
+    pub(crate) kind: String,  // See `message_kind`
+    pub(crate) location: Option<PyLocation>,
+    pub(crate) text_attributes: Vec<PyTextAttribute>,
+    pub(crate) markdown_text: Option<String>,  // Text with style runs rendered as Markdown, when it has any
+    pub(crate) is_emoji_only: bool,
+    pub(crate) emoji: Vec<String>,  // Emoji in the text, plus a custom tapback emoji, in order of appearance
+    pub(crate) is_text_synthesized: bool,  // `text` was generated for an event or effect with no text of its own
+    pub(crate) facetime_url: Option<String>,  // FaceTime link shared in the message
+    pub(crate) call_outcome: Option<String>,  // "started" or "ended" for SharePlay/FaceTime rows
+    pub(crate) group_photo_attachment: Option<i32>,  // Attachment ROWID of the new image for group_icon_changed events
+    pub(crate) body_parts: Vec<PyBodyPart>,  // Indexed like the `part` of reactions and edits
+    #[pyo3(get)]
+    pub(crate) is_deleted: bool,  // In "Recently Deleted" awaiting purge
+    #[pyo3(get)]
+    pub(crate) deleted_from: Option<i32>,  // Chat the message was deleted from
+    #[pyo3(get)]
+    pub(crate) date_deleted: Option<f64>,  // Unix timestamp, set by `get_deleted_messages`
+    #[serde(skip)]
+    pub(crate) pending_text: Option<PendingText>,  // Set until the fields decoded from `attributedBody` are filled in
+}
+
+/// Connection that decodes a lazily loaded `PyMessage` on first access
+///
+/// Messages loaded together share a batch, so the first access decodes the
+/// whole batch in one query rather than one query per message.
+#[derive(Clone)]
+pub(crate) struct PendingText {
+    pub(crate) decoder: Arc<Mutex<IMessageDB>>,
+    pub(crate) batch: Arc<PendingBatch>,
+}
+
+/// ROWIDs of lazily loaded messages decoded together, and their decoded copies once read
+pub(crate) struct PendingBatch {
+    pub(crate) rowids: Vec<i32>,
+    pub(crate) decoded: Mutex<Option<HashMap<i32, PyMessage>>>,
+}
+
+impl std::fmt::Debug for PendingText {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("PendingText")
+    }
+}
+
+#[cfg(test)]
+impl PyMessage {
+    /// A received iMessage with no text or other content, for tests to fill in
+    pub(crate) fn for_test(rowid: i32, guid: &str) -> PyMessage {
+        PyMessage {
+            rowid,
+            guid: guid.to_string(),
+            text: None,
+            service: "iMessage".to_string(),
+            handle_id: None,
+            subject: None,
+            date: rowid as f64,
+            date_read: None,
+            date_delivered: None,
+            is_from_me: false,
+            is_read: true,
+            is_sent: true,
+            is_delivered: true,
+            cache_roomnames: None,
+            group_title: None,
+            associated_message_guid: None,
+            associated_message_type: None,
+            thread_originator_guid: None,
+            item_type: 0,
+            event_type: None,
+            event_handle_id: None,
+            reaction: None,
+            reaction_emoji: None,
+            reaction_target_guid: None,
+            reaction_target_part: None,
+            reactions: Vec::new(),
+            date_edited: None,
+            edit_history: Vec::new(),
+            date_retracted: None,
+            was_unsent: false,
+            effect: None,
+            effect_type: None,
+            mentions: Vec::new(),
+            links: Vec::new(),
+            balloon_bundle_id: None,
+            app_type: None,
+            apple_pay: None,
+            digital_touch: None,
+            is_audio_message: false,
+            audio_duration: None,
+            expire_state: None,
+            audio_transcript: None,
+            sticker: None,
+            display_text: None,
+            kind: "text".to_string(),
+            location: None,
+            text_attributes: Vec::new(),
+            markdown_text: None,
+            is_emoji_only: false,
+            emoji: Vec::new(),
+            is_text_synthesized: false,
+            facetime_url: None,
+            call_outcome: None,
+            group_photo_attachment: None,
+            body_parts: Vec::new(),
+            is_deleted: false,
+            deleted_from: None,
+            date_deleted: None,
+            pending_text: None,
+        }
+    }
+}
+
+/// Decode the text of lazily loaded messages before their fields are read in bulk
+pub(crate) fn resolve_all(messages: &mut [PyMessage]) -> PyResult<()> {
+    messages.iter_mut().try_for_each(PyMessage::resolve)
+}
+
+impl PyMessage {
+    /// Decode `attributedBody` and fill in the fields that depend on it, once
+    ///
+    /// Without `eager_text`, messages are loaded without their body, payload,
+    /// edit history, sticker, location, group photo or audio file duration, and
+    /// this runs on first access to one of those or a field derived from them.
+    pub(crate) fn resolve(&mut self) -> PyResult<()> {
+        let Some(pending) = self.pending_text.take() else {
+            return Ok(());
+        };
+        let mut decoded = pending.batch.decoded.lock().unwrap_or_else(PoisonError::into_inner);
+        if decoded.is_none() {
+            let decoder = pending.decoder.lock().unwrap_or_else(PoisonError::into_inner);
+            *decoded = Some(decoder.messages_by_rowid(pending.batch.rowids.iter().copied())?);
+        }
+        // Copies of a message (e.g. from a search hit) each resolve from the batch,
+        // and one purged since it was loaded keeps what was read up front
+        let Some(full) = decoded.as_ref().and_then(|decoded| decoded.get(&self.rowid)).cloned() else {
+            return Ok(());
+        };
+        self.text = full.text;
+        self.mentions = full.mentions;
+        self.links = full.links;
+        self.balloon_bundle_id = full.balloon_bundle_id;
+        self.app_type = full.app_type;
+        self.audio_transcript = full.audio_transcript;
+        self.display_text = full.display_text;
+        self.kind = full.kind;
+        self.text_attributes = full.text_attributes;
+        self.markdown_text = full.markdown_text;
+        self.is_emoji_only = full.is_emoji_only;
+        self.emoji = full.emoji;
+        self.is_text_synthesized = full.is_text_synthesized;
+        self.facetime_url = full.facetime_url;
+        self.call_outcome = full.call_outcome;
+        self.body_parts = full.body_parts;
+        self.edit_history = full.edit_history;
+        self.apple_pay = full.apple_pay;
+        self.digital_touch = full.digital_touch;
+        self.audio_duration = full.audio_duration;
+        self.sticker = full.sticker;
+        self.location = full.location;
+        self.group_photo_attachment = full.group_photo_attachment;
+        Ok(())
+    }
+
+    /// `resolve` for the field getters, with the GIL released while the batch is decoded
+    pub(crate) fn resolve_without_gil(&mut self, py: Python) -> PyResult<()> {
+        if self.pending_text.is_none() {
+            return Ok(());
+        }
+        py.allow_threads(|| self.resolve())
+    }
+}
+
+#[pymethods]
+impl PyMessage {
+    #[getter]
+    pub(crate) fn text(&mut self, py: Python) -> PyResult<Option<String>> {
+        self.resolve_without_gil(py)?;
+        Ok(self.text.clone())
+    }
+
+    #[getter]
+    pub(crate) fn mentions(&mut self, py: Python) -> PyResult<Vec<PyMention>> {
+        self.resolve_without_gil(py)?;
+        Ok(self.mentions.clone())
+    }
+
+    #[getter]
+    pub(crate) fn links(&mut self, py: Python) -> PyResult<Vec<PyLink>> {
+        self.resolve_without_gil(py)?;
+        Ok(self.links.clone())
+    }
+
+    #[getter]
+    pub(crate) fn balloon_bundle_id(&mut self, py: Python) -> PyResult<Option<String>> {
+        self.resolve_without_gil(py)?;
+        Ok(self.balloon_bundle_id.clone())
+    }
+
+    #[getter]
+    pub(crate) fn app_type(&mut self, py: Python) -> PyResult<Option<String>> {
+        self.resolve_without_gil(py)?;
+        Ok(self.app_type.clone())
+    }
+
+    #[getter]
+    pub(crate) fn audio_transcript(&mut self, py: Python) -> PyResult<Option<String>> {
+        self.resolve_without_gil(py)?;
+        Ok(self.audio_transcript.clone())
+    }
+
+    #[getter]
+    pub(crate) fn display_text(&mut self, py: Python) -> PyResult<Option<String>> {
+        self.resolve_without_gil(py)?;
+        Ok(self.display_text.clone())
+    }
+
+    #[getter]
+    pub(crate) fn kind(&mut self, py: Python) -> PyResult<String> {
+        self.resolve_without_gil(py)?;
+        Ok(self.kind.clone())
+    }
+
+    #[getter]
+    pub(crate) fn text_attributes(&mut self, py: Python) -> PyResult<Vec<PyTextAttribute>> {
+        self.resolve_without_gil(py)?;
+        Ok(self.text_attributes.clone())
+    }
+
+    #[getter]
+    pub(crate) fn markdown_text(&mut self, py: Python) -> PyResult<Option<String>> {
+        self.resolve_without_gil(py)?;
+        Ok(self.markdown_text.clone())
+    }
+
+    #[getter]
+    pub(crate) fn is_emoji_only(&mut self, py: Python) -> PyResult<bool> {
+        self.resolve_without_gil(py)?;
+        Ok(self.is_emoji_only)
+    }
+
+    #[getter]
+    pub(crate) fn emoji(&mut self, py: Python) -> PyResult<Vec<String>> {
+        self.resolve_without_gil(py)?;
+        Ok(self.emoji.clone())
+    }
+
+    #[getter]
+    pub(crate) fn is_text_synthesized(&mut self, py: Python) -> PyResult<bool> {
+        self.resolve_without_gil(py)?;
+        Ok(self.is_text_synthesized)
+    }
+
+    #[getter]
+    pub(crate) fn facetime_url(&mut self, py: Python) -> PyResult<Option<String>> {
+        self.resolve_without_gil(py)?;
+        Ok(self.facetime_url.clone())
+    }
+
+    #[getter]
+    pub(crate) fn call_outcome(&mut self, py: Python) -> PyResult<Option<String>> {
+        self.resolve_without_gil(py)?;
+        Ok(self.call_outcome.clone())
+    }
+
+    #[getter]
+    pub(crate) fn body_parts(&mut self, py: Python) -> PyResult<Vec<PyBodyPart>> {
+        self.resolve_without_gil(py)?;
+        Ok(self.body_parts.clone())
+    }
+
+    #[getter]
+    pub(crate) fn edit_history(&mut self, py: Python) -> PyResult<Vec<PyMessageEdit>> {
+        self.resolve_without_gil(py)?;
+        Ok(self.edit_history.clone())
+    }
+
+    #[getter]
+    pub(crate) fn apple_pay(&mut self, py: Python) -> PyResult<Option<PyApplePay>> {
+        self.resolve_without_gil(py)?;
+        Ok(self.apple_pay.clone())
+    }
+
+    #[getter]
+    pub(crate) fn digital_touch(&mut self, py: Python) -> PyResult<Option<String>> {
+        self.resolve_without_gil(py)?;
+        Ok(self.digital_touch.clone())
+    }
+
+    #[getter]
+    pub(crate) fn audio_duration(&mut self, py: Python) -> PyResult<Option<f64>> {
+        self.resolve_without_gil(py)?;
+        Ok(self.audio_duration)
+    }
+
+    #[getter]
+    pub(crate) fn sticker(&mut self, py: Python) -> PyResult<Option<PySticker>> {
+        self.resolve_without_gil(py)?;
+        Ok(self.sticker.clone())
+    }
+
+    #[getter]
+    pub(crate) fn location(&mut self, py: Python) -> PyResult<Option<PyLocation>> {
+        self.resolve_without_gil(py)?;
+        Ok(self.location.clone())
+    }
+
+    #[getter]
+    pub(crate) fn group_photo_attachment(&mut self, py: Python) -> PyResult<Option<i32>> {
+        self.resolve_without_gil(py)?;
+        Ok(self.group_photo_attachment)
+    }
+}
+
+/// One part of a message body, in the order Messages.app shows them
+#[pyclass]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct PyBodyPart {
+    #[pyo3(get)]
+    pub(crate) index: usize,  // The `N` in `p:N/GUID` targets
+    #[pyo3(get)]
+    pub(crate) kind: String,  // "text", "attachment", "app", or "retracted"
+    #[pyo3(get)]
+    pub(crate) text: Option<String>,
+    #[pyo3(get)]
+    pub(crate) attachment_guid: Option<String>,
+    #[pyo3(get)]
+    pub(crate) attachment_name: Option<String>,
+}
+
+/// A formatting style applied to a range of a message's text
+#[pyclass]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct PyTextAttribute {
+    #[pyo3(get)]
+    pub(crate) start: usize,  // Character offset into the message text
+    #[pyo3(get)]
+    pub(crate) end: usize,
+    #[pyo3(get)]
+    pub(crate) style: String,  // "bold", "italic", "strikethrough", or "underline"
+}
+
+/// A shared location, Maps placemark, or Find My update
+#[pyclass]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct PyLocation {
+    #[pyo3(get)]
+    pub(crate) kind: String,  // "placemark", "find_my", "started_sharing", or "stopped_sharing"
+    #[pyo3(get)]
+    pub(crate) latitude: Option<f64>,
+    #[pyo3(get)]
+    pub(crate) longitude: Option<f64>,
+    #[pyo3(get)]
+    pub(crate) name: Option<String>,
+    #[pyo3(get)]
+    pub(crate) address: Option<String>,
+    #[pyo3(get)]
+    pub(crate) city: Option<String>,
+    #[pyo3(get)]
+    pub(crate) state: Option<String>,
+    #[pyo3(get)]
+    pub(crate) postal_code: Option<String>,
+    #[pyo3(get)]
+    pub(crate) country: Option<String>,
+    #[pyo3(get)]
+    pub(crate) url: Option<String>,
+    #[pyo3(get)]
+    pub(crate) description: Option<String>,  // Find My bubble text, e.g. "Started Sharing Location"
+}
+
+/// Details of an Apple Pay / Apple Cash message
+#[pyclass]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct PyApplePay {
+    #[pyo3(get)]
+    pub(crate) amount: Option<f64>,
+    #[pyo3(get)]
+    pub(crate) currency: Option<String>,  // ISO code when the symbol is known, e.g. "USD"
+    #[pyo3(get)]
+    pub(crate) transaction_type: Option<String>,  // "sent", "received", "requested", or "recurring"
+    #[pyo3(get)]
+    pub(crate) description: Option<String>,  // e.g. "Sent $265 with Apple Pay."
+}
+
+/// A URL shared in a message, with link preview metadata when available
+#[pyclass]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct PyLink {
+    #[pyo3(get)]
+    pub(crate) url: String,
+    #[pyo3(get)]
+    pub(crate) original_url: Option<String>,  // URL before redirects
+    #[pyo3(get)]
+    pub(crate) title: Option<String>,
+    #[pyo3(get)]
+    pub(crate) summary: Option<String>,
+    #[pyo3(get)]
+    pub(crate) site_name: Option<String>,
+}
+
+/// An @-mention inside a message's text
+#[pyclass]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct PyMention {
+    #[pyo3(get)]
+    pub(crate) identifier: String,  // Phone number or email of the mentioned handle
+    #[pyo3(get)]
+    pub(crate) start: usize,  // Character offset into the message text
+    #[pyo3(get)]
+    pub(crate) end: usize,
+    #[pyo3(get)]
+    pub(crate) text: String,  // The mentioned name as it appears in the message
+}
+
+/// One revision of an edited message part
+#[pyclass]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct PyMessageEdit {
+    #[pyo3(get)]
+    pub(crate) part: usize,
+    #[pyo3(get)]
+    pub(crate) date: f64,  // Unix timestamp
+    #[pyo3(get)]
+    pub(crate) text: Option<String>,
+}
+
+/// A tapback attached to its target message
+#[pyclass]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct PyReaction {
+    #[pyo3(get)]
+    pub(crate) rowid: i32,
+    #[pyo3(get)]
+    pub(crate) guid: String,
+    #[pyo3(get)]
+    pub(crate) reaction: String,
+    #[pyo3(get)]
+    pub(crate) emoji: Option<String>,
+    #[pyo3(get)]
+    pub(crate) part: usize,
+    #[pyo3(get)]
+    pub(crate) handle_id: Option<i32>,
+    #[pyo3(get)]
+    pub(crate) is_from_me: bool,
+    #[pyo3(get)]
+    pub(crate) date: f64,  // Unix timestamp
+}
+
+/// Python-accessible handle (contact) structure
+#[pyclass]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct PyHandle {
+    #[pyo3(get)]
+    pub(crate) rowid: i32,
+    #[pyo3(get)]
+    pub(crate) id: String,
+    #[pyo3(get)]
+    pub(crate) service: Option<String>,
+    #[pyo3(get)]
+    pub(crate) uncanonicalized_id: Option<String>,
+}
+
+/// Python-accessible attachment structure
+#[pyclass]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct PyAttachment {
+    #[pyo3(get)]
+    pub(crate) rowid: i32,
+    #[pyo3(get)]
+    pub(crate) guid: String,
+    #[pyo3(get)]
+    pub(crate) filename: Option<String>,
+    #[pyo3(get)]
+    pub(crate) mime_type: Option<String>,
+    #[pyo3(get)]
+    pub(crate) transfer_name: Option<String>,
+    #[pyo3(get)]
+    pub(crate) total_bytes: Option<i64>,
+    #[pyo3(get)]
+    pub(crate) is_sticker: bool,
+    #[pyo3(get)]
+    pub(crate) hide_attachment: bool,  // Hidden from the chat, e.g. tapback images and link preview assets
+    #[pyo3(get)]
+    pub(crate) uti: Option<String>,  // Uniform Type Identifier, e.g. "public.jpeg"
+    #[serde(skip)]
+    pub(crate) db_path: PathBuf,  // Database the attachment came from, for resolving copied Attachments folders
+}
+
+#[pymethods]
+impl PyAttachment {
+    /// Resolve `filename` to an existing file on disk
+    ///
+    /// Returns `(path, None)` when the file exists, or `(None, reason)` when it
+    /// does not, e.g. because it was offloaded to iCloud. `~` expands to the home
+    /// directory, and an `Attachments/xx/yy/GUID/...` folder next to a copied
+    /// database is used when the original location is missing.
+    pub(crate) fn resolved_path(&self) -> (Option<String>, Option<String>) {
+        match resolve_attachment_path(self.filename.as_deref(), &self.db_path) {
+            Ok(path) => (Some(path.to_string_lossy().to_string()), None),
+            Err(reason) => (None, Some(reason)),
+        }
+    }
+
+    /// Whether the file can be archived
+    ///
+    /// - `"present"`: the complete file is on disk
+    /// - `"offloaded"`: the file was downloaded once but is gone or truncated,
+    ///   usually because iCloud optimized storage removed it
+    /// - `"missing"`: the file was never downloaded
+    /// - `"purged"`: the attachment no longer records a file path
+    #[getter]
+    pub(crate) fn status(&self) -> String {
+        if self.filename.as_deref().is_none_or(str::is_empty) {
+            return "purged".to_string();
+        }
+
+        let expected = self.total_bytes.unwrap_or(0).max(0) as u64;
+        let on_disk = resolve_attachment_path(self.filename.as_deref(), &self.db_path)
+            .ok()
+            .and_then(|path| std::fs::metadata(path).ok())
+            .map(|meta| meta.len());
+
+        let status = match on_disk {
+            Some(size) if size >= expected => "present",
+            Some(_) => "offloaded",
+            None if expected > 0 => "offloaded",
+            None => "missing",
+        };
+        status.to_string()
+    }
+
+    /// Path of the motion part when this attachment is the still image of a Live Photo
+    ///
+    /// Messages stores a Live Photo as an image plus a `.mov` with the same
+    /// name in the same folder.
+    #[getter]
+    pub(crate) fn live_photo_video(&self) -> Option<String> {
+        let path = resolve_attachment_path(self.filename.as_deref(), &self.db_path).ok()?;
+        if !matches!(live_photo_part(&path), Some(LivePhotoPart::Image)) {
+            return None;
+        }
+        live_photo_partner(&path, &["mov", "MOV"]).map(|video| video.to_string_lossy().to_string())
+    }
+
+    /// `true` for the `.mov` motion part of a Live Photo, which galleries can
+    /// skip in favor of the still image's `live_photo_video`
+    #[getter]
+    pub(crate) fn is_live_photo_video(&self) -> bool {
+        resolve_attachment_path(self.filename.as_deref(), &self.db_path)
+            .ok()
+            .filter(|path| matches!(live_photo_part(path), Some(LivePhotoPart::Video)))
+            .and_then(|path| live_photo_partner(&path, &["heic", "HEIC", "jpg", "JPG", "jpeg", "JPEG"]))
+            .is_some()
+    }
+}
+
+/// An attachment with the message and chat it was sent in
+#[pyclass]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct PyAttachmentContext {
+    #[pyo3(get)]
+    pub(crate) attachment: PyAttachment,
+    #[pyo3(get)]
+    pub(crate) message_rowid: i32,
+    #[pyo3(get)]
+    pub(crate) message_guid: String,
+    #[pyo3(get)]
+    pub(crate) chat_id: Option<i32>,
+    #[pyo3(get)]
+    pub(crate) handle_id: Option<i32>,
+    #[pyo3(get)]
+    pub(crate) is_from_me: bool,
+    #[pyo3(get)]
+    pub(crate) date: f64,  // Unix timestamp of the message
+}
+
+/// Playback details of an audio attachment
+#[pyclass]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct PyAudioInfo {
+    #[pyo3(get)]
+    pub(crate) attachment_rowid: i32,
+    #[pyo3(get)]
+    pub(crate) duration: Option<f64>,  // Seconds
+    #[pyo3(get)]
+    pub(crate) waveform: Option<Vec<f32>>,  // Peak amplitudes between 0 and 1, when requested
+}
+
+/// Result of an `export_attachments` run
+#[pyclass]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct PyExportSummary {
+    #[pyo3(get)]
+    pub(crate) exported: usize,  // Files written
+    #[pyo3(get)]
+    pub(crate) duplicates: usize,  // Attachments whose contents were already exported
+    #[pyo3(get)]
+    pub(crate) unavailable: usize,  // Attachments not on disk or that failed to copy
+    #[pyo3(get)]
+    pub(crate) manifest_path: String,
+}
+
+/// Container details of a video attachment
+#[pyclass]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct PyVideoInfo {
+    #[pyo3(get)]
+    pub(crate) attachment_rowid: i32,
+    #[pyo3(get)]
+    pub(crate) duration: Option<f64>,  // Seconds
+    #[pyo3(get)]
+    pub(crate) width: Option<u32>,
+    #[pyo3(get)]
+    pub(crate) height: Option<u32>,
+    #[pyo3(get)]
+    pub(crate) codec: Option<String>,  // "h264", "hevc", or the sample entry fourcc
+}
+
+/// Attachments whose files have identical contents
+#[pyclass]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct PyDuplicateGroup {
+    #[pyo3(get)]
+    pub(crate) sha256: String,  // Hex digest of the file contents
+    #[pyo3(get)]
+    pub(crate) size: u64,  // File size in bytes
+    #[pyo3(get)]
+    pub(crate) attachments: Vec<PyAttachment>,  // Ordered by ROWID, so the first is the earliest copy
+}
+
+/// Capture metadata read from an image attachment's EXIF block
+#[pyclass]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct PyImageMetadata {
+    #[pyo3(get)]
+    pub(crate) attachment_rowid: i32,
+    #[pyo3(get)]
+    pub(crate) captured_at: Option<String>,  // ISO 8601, with the UTC offset when the camera recorded one
+    #[pyo3(get)]
+    pub(crate) latitude: Option<f64>,
+    #[pyo3(get)]
+    pub(crate) longitude: Option<f64>,
+    #[pyo3(get)]
+    pub(crate) camera_make: Option<String>,
+    #[pyo3(get)]
+    pub(crate) camera_model: Option<String>,
+}
+
+/// A sticker sent on its own or placed on another message
+#[pyclass]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct PySticker {
+    #[pyo3(get)]
+    pub(crate) attachment_rowid: i32,
+    #[pyo3(get)]
+    pub(crate) filename: Option<String>,
+    #[pyo3(get)]
+    pub(crate) source: Option<String>,  // "genmoji", "memoji", "user_generated", or "app"
+    #[pyo3(get)]
+    pub(crate) source_app: Option<String>,  // Sticker pack app name or bundle id for "app" stickers
+    #[pyo3(get)]
+    pub(crate) emoji_description: Option<String>,  // Genmoji prompt
+    #[pyo3(get)]
+    pub(crate) target_guid: Option<String>,  // Message the sticker was placed on
+    #[pyo3(get)]
+    pub(crate) target_part: Option<usize>,
+}
+
+/// Python-accessible chat (conversation) structure
+#[pyclass]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct PyChat {
+    #[pyo3(get)]
+    pub(crate) rowid: i32,
+    #[pyo3(get)]
+    pub(crate) guid: String,
+    #[pyo3(get)]
+    pub(crate) chat_identifier: String,
+    #[pyo3(get)]
+    pub(crate) service_name: Option<String>,
+    #[pyo3(get)]
+    pub(crate) display_name: Option<String>,
+    #[pyo3(get)]
+    pub(crate) group_id: Option<String>,
+    #[pyo3(get)]
+    pub(crate) style: Option<i32>,  // 43 = group, 45 = direct
+    #[pyo3(get)]
+    pub(crate) is_group: bool,
+    #[pyo3(get)]
+    pub(crate) is_pinned: bool,
+    #[pyo3(get)]
+    pub(crate) pin_order: Option<usize>,  // Position in the Messages.app pinned row
+}
+
+/// Python-accessible logical conversation spanning one or more chats
+#[pyclass]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct PyConversation {
+    #[pyo3(get)]
+    pub(crate) key: String,  // Merge key, e.g. "handle:5550000001" or "group:chat123..."
+    #[pyo3(get)]
+    pub(crate) chat_ids: Vec<i32>,
+    #[pyo3(get)]
+    pub(crate) is_group: bool,
+    #[pyo3(get)]
+    pub(crate) display_name: Option<String>,
+}
+
+/// Python-accessible per-chat summary for conversation lists
+#[pyclass]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct PyChatSummary {
+    #[pyo3(get)]
+    pub(crate) chat_id: i32,
+    #[pyo3(get)]
+    pub(crate) chat_identifier: String,
+    #[pyo3(get)]
+    pub(crate) display_name: Option<String>,
+    #[pyo3(get)]
+    pub(crate) service_name: Option<String>,
+    #[pyo3(get)]
+    pub(crate) is_group: bool,
+    #[pyo3(get)]
+    pub(crate) message_count: i64,
+    #[pyo3(get)]
+    pub(crate) unread_count: i64,
+    #[pyo3(get)]
+    pub(crate) last_message_rowid: Option<i32>,
+    #[pyo3(get)]
+    pub(crate) last_message_date: Option<f64>,  // Unix timestamp
+    #[pyo3(get)]
+    pub(crate) last_message_text: Option<String>,
+    #[pyo3(get)]
+    pub(crate) last_message_is_from_me: Option<bool>,
+}
+
+/// Python-accessible run of messages with no gap longer than the session threshold
+#[pyclass]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct PySession {
+    #[pyo3(get)]
+    pub(crate) chat_id: i32,
+    #[pyo3(get)]
+    pub(crate) start_date: f64,  // Unix timestamp of the first message
+    #[pyo3(get)]
+    pub(crate) end_date: f64,  // Unix timestamp of the last message
+    #[pyo3(get)]
+    pub(crate) messages: Vec<PyMessage>,
+}
+
+/// Python-accessible batch of changes returned by `query_messages_since`
+#[pyclass]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct PySyncBatch {
+    #[pyo3(get)]
+    pub(crate) messages: Vec<PyMessage>,  // New messages plus ones edited or unsent since the token, by ROWID
+    #[pyo3(get)]
+    pub(crate) deleted: Vec<PyMessage>,  // Messages moved to Recently Deleted since the token
+    #[pyo3(get)]
+    pub(crate) token: String,  // Pass to the next `query_messages_since` call
+}
+
+/// Python-accessible search result
+#[pyclass]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct PySearchHit {
+    #[pyo3(get)]
+    pub(crate) message: PyMessage,
+    #[pyo3(get)]
+    pub(crate) rank: Option<f64>,  // BM25 score from `search_fts`, lower is more relevant
+    #[pyo3(get)]
+    pub(crate) snippet: String,  // Matching excerpt with hits wrapped in [ and ]
+    #[pyo3(get)]
+    pub(crate) highlights: Vec<(usize, usize)>,  // Character ranges of each hit in `message.text`
+}
+
+/// Result of an `update_fts_index` run
+#[pyclass]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct PyIndexUpdate {
+    #[pyo3(get)]
+    pub(crate) indexed: usize,  // New and edited messages (re)indexed
+    #[pyo3(get)]
+    pub(crate) removed: usize,  // Messages dropped as unsent, Recently Deleted or gone from the database
+    #[pyo3(get)]
+    pub(crate) skipped: usize,  // Rows that could not be read as messages, left out of the index
+}
+
+/// Python-accessible message with just the columns needed to list it
+#[pyclass]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct PyMessageLite {
+    #[pyo3(get)]
+    pub(crate) rowid: i32,
+    #[pyo3(get)]
+    pub(crate) guid: String,
+    #[pyo3(get)]
+    pub(crate) date: f64,  // Unix timestamp
+    #[pyo3(get)]
+    pub(crate) handle_id: Option<i32>,
+    #[pyo3(get)]
+    pub(crate) is_from_me: bool,
+    #[pyo3(get)]
+    pub(crate) chat_id: Option<i32>,
+    #[pyo3(get)]
+    pub(crate) text: Option<String>,  // Start of the text, cut to `text_length` characters
+    #[pyo3(get)]
+    pub(crate) is_truncated: bool,
+}
+
+/// Python-accessible regular expression match within a message
+#[pyclass]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct PyRegexMatch {
+    #[pyo3(get)]
+    pub(crate) message: PyMessage,
+    #[pyo3(get)]
+    pub(crate) matched: String,  // Text of the whole match
+    #[pyo3(get)]
+    pub(crate) start: usize,  // Character offsets of the match in `message.text`
+    #[pyo3(get)]
+    pub(crate) end: usize,
+    #[pyo3(get)]
+    pub(crate) groups: Vec<Option<String>>,  // Capture groups in order, `None` when a group did not participate
+    #[pyo3(get)]
+    pub(crate) named_groups: HashMap<String, Option<String>>,
+}
+
+/// Python-accessible aggregate statistics for a single chat
+#[pyclass]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct PyChatStats {
+    #[pyo3(get)]
+    pub(crate) chat_id: i32,
+    #[pyo3(get)]
+    pub(crate) message_count: i64,
+    #[pyo3(get)]
+    pub(crate) sent_count: i64,
+    #[pyo3(get)]
+    pub(crate) received_count: i64,
+    #[pyo3(get)]
+    pub(crate) messages_by_sender: HashMap<String, i64>,  // Keyed by handle id, "Me" for own messages
+    #[pyo3(get)]
+    pub(crate) first_message_date: Option<f64>,  // Unix timestamp
+    #[pyo3(get)]
+    pub(crate) last_message_date: Option<f64>,  // Unix timestamp
+    #[pyo3(get)]
+    pub(crate) attachment_count: i64,
+    #[pyo3(get)]
+    pub(crate) reaction_count: i64,
+}
+
+/// Python-accessible attachment storage usage
+#[pyclass]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct PyAttachmentStats {
+    #[pyo3(get)]
+    pub(crate) attachment_count: i64,
+    #[pyo3(get)]
+    pub(crate) total_bytes: i64,
+    #[pyo3(get)]
+    pub(crate) bytes_by_mime_type: HashMap<String, i64>,  // "unknown" for attachments without a MIME type
+    #[pyo3(get)]
+    pub(crate) bytes_by_chat: HashMap<i32, i64>,  // Keyed by chat ROWID
+    #[pyo3(get)]
+    pub(crate) bytes_by_year: HashMap<i32, i64>,  // Year the attachment was sent, in UTC
+}
+
+/// Python-accessible chat with messages in "Recently Deleted"
+#[pyclass]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct PyRecoverableChat {
+    #[pyo3(get)]
+    pub(crate) chat_id: i32,
+    #[pyo3(get)]
+    pub(crate) chat_identifier: String,
+    #[pyo3(get)]
+    pub(crate) display_name: Option<String>,
+    #[pyo3(get)]
+    pub(crate) recoverable_count: i64,
+    #[pyo3(get)]
+    pub(crate) remaining_count: i64,  // Messages still visible in the chat
+    #[pyo3(get)]
+    pub(crate) is_fully_deleted: bool,
+    #[pyo3(get)]
+    pub(crate) first_deleted: Option<f64>,  // Unix timestamp
+    #[pyo3(get)]
+    pub(crate) last_deleted: Option<f64>,  // Unix timestamp
+}
@@ -0,0 +1,765 @@
+"""
+Tests for the imessage_bridge Rust extension against the anonymized sample database.
+Build the extension first: cd imessage-bridge && maturin develop
+"""
+import gc
+import json
+import plistlib
+import shutil
+import sqlite3
+import threading
+from pathlib import Path
+
+import pytest
+
+imessage_bridge = pytest.importorskip("imessage_bridge")
+
+SAMPLE_DB = Path(__file__).parent / "fixtures" / "test_imessage_sample.db"
+
+
+@pytest.fixture(scope="module")
+def sample_db_path():
+    """Path to the sample database."""
+    if not SAMPLE_DB.exists():
+        pytest.skip("Sample database not found. Run extract_imessage_sample.py first.")
+    return str(SAMPLE_DB)
+
+
+@pytest.fixture(scope="module")
+def db(sample_db_path):
+    """A connection to the sample database shared by the tests of a module."""
+    return imessage_bridge.IMessageDB(sample_db_path)
+
+
+class WritableCopy:
+    """A private copy of the sample database that a test changes before opening it."""
+
+    def __init__(self, path):
+        self.path = path
+
+    def execute(self, sql, params=()):
+        """Run one statement on the copy and return the ROWID of the last row it inserted."""
+        with sqlite3.connect(self.path) as conn:
+            return conn.execute(sql, params).lastrowid
+
+    def apply(self, change):
+        """Call `change` with a connection to the copy and return its result."""
+        with sqlite3.connect(self.path) as conn:
+            return change(conn)
+
+    def open(self, **options):
+        """Open the copy as it is now, passing `options` to IMessageDB."""
+        return imessage_bridge.IMessageDB(str(self.path), **options)
+
+
+@pytest.fixture
+def writable_db(sample_db_path, tmp_path):
+    """A copy of the sample database in the test's temporary directory."""
+    copy = tmp_path / "chat.db"
+    shutil.copy(sample_db_path, copy)
+    return WritableCopy(copy)
+
+
+class TestPoolThreads:
+    """IMessageDBPool lends its connections to several Python threads at once."""
+
+    def test_threads_share_a_pool(self, sample_db_path):
+        pool = imessage_bridge.IMessageDBPool(sample_db_path, 2)
+        expected = [m.rowid for m in pool.get_all_messages(50)]
+        results, errors = [], []
+
+        def worker():
+            try:
+                for _ in range(20):
+                    results.append([m.rowid for m in pool.get_all_messages(50)])
+                    pool.get_chat_summaries()
+            except Exception as e:  # surfaced by the assertion below
+                errors.append(e)
+
+        threads = [threading.Thread(target=worker) for _ in range(4)]
+        for thread in threads:
+            thread.start()
+        for thread in threads:
+            thread.join()
+
+        assert not errors
+        assert len(results) == 80
+        assert all(rowids == expected for rowids in results)
+
+    def test_pool_size_bounds_connections(self, sample_db_path):
+        pool = imessage_bridge.IMessageDBPool(sample_db_path, 1)
+        assert pool.size == 1
+        barrier = threading.Barrier(3)
+        counts = []
+
+        def worker():
+            barrier.wait()
+            counts.append(pool.count_messages())
+
+        threads = [threading.Thread(target=worker) for _ in range(3)]
+        for thread in threads:
+            thread.start()
+        for thread in threads:
+            thread.join()
+
+        assert len(set(counts)) == 1 and counts[0] > 0
+
+    def test_connection_lends_full_api(self, sample_db_path):
+        pool = imessage_bridge.IMessageDBPool(sample_db_path, 2)
+        expected = [m.rowid for m in pool.get_all_messages(50)]
+        results, errors = [], []
+
+        def worker():
+            try:
+                for _ in range(10):
+                    with pool.connection() as db:
+                        results.append([m.rowid for m in db.messages().limit(50).fetch()])
+                        db.get_deleted_messages()
+            except Exception as e:  # surfaced by the assertion below
+                errors.append(e)
+
+        threads = [threading.Thread(target=worker) for _ in range(4)]
+        for thread in threads:
+            thread.start()
+        for thread in threads:
+            thread.join()
+
+        assert not errors
+        assert len(results) == 40
+        assert all(sorted(rowids) == sorted(expected) for rowids in results)
+
+    def test_connection_returns_to_pool(self, sample_db_path):
+        pool = imessage_bridge.IMessageDBPool(sample_db_path, 1)
+        with pool.connection() as db:
+            count = db.count_messages()
+        # The single connection is idle again, so pool methods do not block
+        assert pool.count_messages() == count
+        with pool.connection() as db:
+            assert db.count_messages() == count
+
+
+class TestSnapshot:
+    """snapshot=True queries a private copy that is removed with the connection."""
+
+    def test_file_snapshot_is_private(self, sample_db_path):
+        first = imessage_bridge.IMessageDB(sample_db_path, snapshot=True)
+        second = imessage_bridge.IMessageDB(sample_db_path, snapshot=True)
+        path = Path(first.snapshot_path)
+        assert path != Path(second.snapshot_path)
+        assert path.stat().st_mode & 0o777 == 0o600
+        assert first.count_messages() == imessage_bridge.IMessageDB(sample_db_path).count_messages()
+
+        del first
+        gc.collect()
+        assert not path.exists()
+
+
+class TestFindChatsForHandle:
+    """find_chats_for_handle matches identifiers however they are written."""
+
+    def test_formatted_phone_number(self, db):
+        chats = db.find_chats_for_handle("(555) 000-0001")
+        assert [c.chat_identifier for c in chats] == ["+15550000001"]
+
+    def test_missing_country_code(self, db):
+        chats = db.find_chats_for_handle("5550000002")
+        assert [c.chat_identifier for c in chats] == ["+15550000002"]
+
+    def test_other_country_code(self, db):
+        assert db.find_chats_for_handle("+44 555 000 0001") == []
+
+    def test_email_ignores_case(self, db):
+        chats = db.find_chats_for_handle("USER5@example.com")
+        assert [c.chat_identifier for c in chats] == ["user5@example.com"]
+
+    def test_group_chats_match_on_participants(self, db):
+        chats = db.find_chats_for_handle("+1 555 000 0003")
+        assert chats and all(c.is_group for c in chats)
+
+    def test_unknown_or_empty(self, db):
+        assert db.find_chats_for_handle("nobody@example.com") == []
+        assert db.find_chats_for_handle("") == []
+
+
+class TestConversations:
+    """get_conversations merges spellings of one number and nothing else."""
+
+    def _with_chats(self, writable_db, identifiers):
+        for identifier in identifiers:
+            chat = writable_db.execute(
+                "INSERT INTO chat (guid, style, chat_identifier, service_name) VALUES (?, 45, ?, 'SMS')",
+                [f"SMS;-;{identifier}", identifier],
+            )
+        return writable_db.open(), chat
+
+    def _chat_ids(self, db):
+        return sorted(sorted(c.chat_ids) for c in db.get_conversations())
+
+    def test_sender_names_stay_apart(self, writable_db):
+        db, _ = self._with_chats(writable_db, ["AMAZON", "VERIFY", "urn:biz:1f2e3d4c-5555"])
+        keys = [c.key for c in db.get_conversations()]
+        assert len(keys) == len(set(keys))
+        assert {"handle:AMAZON", "handle:VERIFY", "handle:urn:biz:1f2e3d4c-5555"} <= set(keys)
+
+    def test_national_number_joins_its_country(self, writable_db):
+        db, national = self._with_chats(writable_db, ["(555) 000-0001"])
+        assert [856, national] in self._chat_ids(db)
+
+    def test_ambiguous_national_number_stays_apart(self, writable_db):
+        # Matches both +1 555 000 0001 and +44 555 000 0001, whichever comes first
+        db, _ = self._with_chats(writable_db, ["(555) 000-0001", "+44 555 000 0001"])
+        assert [856] in self._chat_ids(db)
+
+
+class TestChatPagination:
+    """get_chat_messages pages backwards through a chat with an opaque cursor."""
+
+    def _all_pages(self, db, chat_id, limit):
+        pages, cursor = [], None
+        while True:
+            messages, cursor = db.get_chat_messages(chat_id, limit, cursor)
+            pages.append([m.rowid for m in messages])
+            if cursor is None:
+                return pages
+
+    def test_pages_cover_the_chat_once(self, db):
+        everything, cursor = db.get_chat_messages(1207)
+        assert cursor is None
+        pages = self._all_pages(db, 1207, 3)
+        assert len(pages) > 1
+        assert sum(reversed(pages), []) == [m.rowid for m in everything]
+
+    def test_messages_sent_at_the_same_time(self, writable_db):
+        writable_db.execute(
+            "UPDATE message SET date = 700000000000000000 WHERE ROWID IN "
+            "(SELECT message_id FROM chat_message_join WHERE chat_id = 1207)"
+        )
+        db = writable_db.open()
+        rowids = sum(self._all_pages(db, 1207, 2), [])
+        assert len(rowids) == len(set(rowids))
+        assert sorted(rowids) == sorted(m.rowid for m in db.get_chat_messages(1207)[0])
+
+    def test_invalid_cursor(self, db):
+        with pytest.raises(ValueError, match="Invalid cursor"):
+            db.get_chat_messages(1207, 3, "not a cursor")
+
+
+def _copy_message(conn, source_rowid, **overrides):
+    """Insert a copy of a message into the same chat, with some columns replaced by SQL expressions."""
+    columns = [
+        row[1] for row in conn.execute("PRAGMA table_info(message)") if row[1].upper() != "ROWID"
+    ]
+    values = ", ".join(overrides.get(c, c) for c in columns)
+    cursor = conn.execute(
+        f"INSERT INTO message ({', '.join(columns)}) SELECT {values} FROM message WHERE ROWID = ?",
+        [source_rowid],
+    )
+    conn.execute(
+        "INSERT INTO chat_message_join (chat_id, message_id) "
+        "SELECT chat_id, ? FROM chat_message_join WHERE message_id = ?",
+        [cursor.lastrowid, source_rowid],
+    )
+    return cursor.lastrowid
+
+
+def _add_tapback_event(conn, source_rowid, associated_message_type, guid):
+    """Copy a tapback row as a later event of the given type in the same chat."""
+    _copy_message(
+        conn,
+        source_rowid,
+        guid=f"'{guid}'",
+        associated_message_type=str(associated_message_type),
+        date="date + 1000000000",
+    )
+
+
+class TestChatStats:
+    """get_chat_stats counts tapbacks still standing after removals."""
+
+    TAPBACK_ROWID = 128601
+
+    def test_reaction_count(self, db):
+        assert db.get_chat_stats(1207).reaction_count == 1
+
+    def test_removal_cancels_tapback(self, writable_db):
+        writable_db.apply(lambda conn: _add_tapback_event(conn, self.TAPBACK_ROWID, 3001, "REMOVED-TAPBACK"))
+        assert writable_db.open().get_chat_stats(1207).reaction_count == 0
+
+        writable_db.apply(lambda conn: _add_tapback_event(conn, self.TAPBACK_ROWID, 2001, "READDED-TAPBACK"))
+        assert writable_db.open().get_chat_stats(1207).reaction_count == 1
+
+
+class TestGroupChats:
+    """Every API agrees on which chats are group chats."""
+
+    @pytest.fixture
+    def db_with_widened_chat(self, writable_db):
+        """A copy of the sample where 1:1-style chat 44 gained a second participant."""
+        writable_db.execute("INSERT INTO chat_handle_join (chat_id, handle_id) VALUES (44, 933)")
+        return writable_db.open()
+
+    def test_participants_make_a_group(self, db_with_widened_chat):
+        db = db_with_widened_chat
+        assert db.get_chat_participants(44)[1]
+        assert next(s for s in db.get_chat_summaries() if s.chat_id == 44).is_group
+        assert all(c.is_group for c in db.find_chats_for_handle("user5@example.com"))
+        assert next(c for c in db.get_conversations() if 44 in c.chat_ids).is_group
+
+    def test_chat_type_filter(self, db_with_widened_chat):
+        db = db_with_widened_chat
+        in_chat = db.count_messages(chat_id=44)
+        assert db.count_messages(chat_id=44, chat_type="group") == in_chat
+        assert db.count_messages(chat_id=44, chat_type="direct") == 0
+
+    def test_sqlite_mirror(self, db_with_widened_chat, tmp_path):
+        mirror = tmp_path / "mirror.db"
+        db_with_widened_chat.export_sqlite(str(mirror))
+        with sqlite3.connect(mirror) as conn:
+            groups = dict(conn.execute("SELECT rowid, is_group FROM chats"))
+        assert groups[44] == 1
+        assert groups[856] == 0
+
+    @pytest.fixture
+    def db_with_unstyled_chat(self, writable_db):
+        """A copy of the sample where 1:1 chat 856 has no style."""
+        writable_db.execute("UPDATE chat SET style = NULL WHERE ROWID = 856")
+        return writable_db.open()
+
+    def test_missing_style_is_direct(self, db_with_unstyled_chat, tmp_path):
+        db = db_with_unstyled_chat
+        pinning = tmp_path / "pinning.plist"
+        pinning.write_bytes(plistlib.dumps({"pinnedConversations": {"pP": ["+15550000001"]}}))
+
+        assert not db.get_chat_participants(856)[1]
+        assert not next(s for s in db.get_chat_summaries() if s.chat_id == 856).is_group
+        assert not any(c.is_group for c in db.find_chats_for_handle("+15550000001"))
+        assert [(c.rowid, c.is_group) for c in db.get_pinned_chats(str(pinning))] == [(856, False)]
+        assert db.count_messages(chat_id=856, chat_type="direct") == db.count_messages(chat_id=856)
+
+        mirror = tmp_path / "mirror.db"
+        db.export_sqlite(str(mirror))
+        with sqlite3.connect(mirror) as conn:
+            assert dict(conn.execute("SELECT rowid, is_group FROM chats"))[856] == 0
+
+
+class TestFtsIndex:
+    """update_fts_index keeps the search index in step with the database."""
+
+    @pytest.fixture
+    def index(self, tmp_path):
+        """The path of a new search index."""
+        return str(tmp_path / "index.db")
+
+    def _hits(self, db, index, term):
+        return [hit.message.rowid for hit in db.search_fts(term, index)]
+
+    def test_incremental_updates(self, writable_db, index):
+        db = writable_db.open()
+        first = db.update_fts_index(index)
+        assert first.indexed > 0 and first.skipped == 0
+        again = db.update_fts_index(index)
+        assert (again.indexed, again.removed, again.skipped) == (0, 0, 0)
+
+    def test_removes_unsent_deleted_and_gone_messages(self, writable_db, index):
+        db = writable_db.open()
+        db.update_fts_index(index)
+        hits = self._hits(db, index, "sample test")
+        assert len(hits) >= 3
+        unsent, deleted, gone = hits[:3]
+
+        writable_db.execute(
+            "UPDATE message SET text = NULL, attributedBody = NULL, date_edited = date + 1 WHERE ROWID = ?",
+            [unsent],
+        )
+        writable_db.execute(
+            "INSERT INTO chat_recoverable_message_join (chat_id, message_id, delete_date) "
+            "SELECT chat_id, message_id, 1 FROM chat_message_join WHERE message_id = ?",
+            [deleted],
+        )
+        writable_db.execute("DELETE FROM message WHERE ROWID = ?", [gone])
+        update = db.update_fts_index(index)
+        assert update.removed == 3
+        assert not {unsent, deleted, gone} & set(self._hits(db, index, "sample test"))
+
+        writable_db.execute("DELETE FROM chat_recoverable_message_join WHERE message_id = ?", [deleted])
+        assert db.update_fts_index(index).indexed == 1
+        assert deleted in self._hits(db, index, "sample test")
+
+    def test_chat_filter_reads_past_the_first_page(self, writable_db, index):
+        (other, other_chat), (target, chat) = writable_db.apply(lambda conn: conn.execute(
+            "SELECT MIN(message_id), chat_id FROM chat_message_join GROUP BY chat_id ORDER BY chat_id LIMIT 2"
+        ).fetchall())
+        # Equal texts tie on rank, so the one copy in `chat` comes after all the others
+        same_text = {"text": "'zebra crossing'", "attributedBody": "NULL"}
+        crowd = writable_db.apply(
+            lambda conn: [_copy_message(conn, other, guid=f"'fts-crowd-{i}'", **same_text) for i in range(300)]
+        )
+        last = writable_db.apply(lambda conn: _copy_message(conn, target, guid="'fts-last'", **same_text))
+        db = writable_db.open()
+        db.update_fts_index(index)
+
+        assert [hit.message.rowid for hit in db.search_fts("zebra", index, chat_id=chat)] == [last]
+        assert [hit.message.rowid for hit in db.search_fts("zebra", index, 2, other_chat)] == crowd[:2]
+
+
+class TestSample:
+    """MessageQuery.sample picks messages uniformly at random."""
+
+    def test_seeded_sample_is_reproducible(self, db):
+        first = [m.rowid for m in db.messages().sample(5, seed=7)]
+        assert len(first) == 5
+        assert first == [m.rowid for m in db.messages().sample(5, seed=7)]
+
+    def test_sample_larger_than_matches(self, db):
+        everything = db.messages().count()
+        assert len(db.messages().sample(everything + 10, seed=1)) == everything
+
+
+class TestMessageKind:
+    """kind describes a message's content, whatever happened to it afterwards."""
+
+    def test_edited_message_keeps_its_kind(self, writable_db):
+        rowid = writable_db.apply(
+            lambda conn: _copy_message(conn, 128589, guid="'EDITED'", date_edited="800000000000000000")
+        )
+        by_rowid = {m.rowid: m for m in writable_db.open().messages().fetch()}
+        assert by_rowid[rowid].date_edited is not None
+        assert by_rowid[rowid].kind == by_rowid[128589].kind == "text"
+
+
+class TestNoiseFilter:
+    """exclude_noise drops unsent messages by their retraction date."""
+
+    def test_unsent_messages_are_noise(self, writable_db):
+        # Undo Send on one part of a message leaves the rest of its text
+        unsent, edited = writable_db.apply(lambda conn: (
+            _copy_message(conn, 128589, guid="'UNSENT'", date_retracted="800000000000000000"),
+            _copy_message(conn, 128589, guid="'EDITED'", date_edited="800000000000000000"),
+        ))
+        db = writable_db.open()
+
+        kept = {m.rowid for m in db.messages().exclude_noise().fetch()}
+        assert unsent not in kept and edited in kept
+        assert db.count_messages(exclude_noise=True) == len(kept)
+
+
+class TestChangedAfter:
+    """query_messages_changed_after works on schemas from before edits and unsends."""
+
+    def test_schema_without_edit_columns(self, writable_db):
+        writable_db.execute("UPDATE message SET date_edited = 800000000000000000 WHERE ROWID = 128589")
+        writable_db.execute("ALTER TABLE message DROP COLUMN date_retracted")
+        assert [m.rowid for m in writable_db.open().query_messages_changed_after(0)] == [128589]
+
+        writable_db.execute("ALTER TABLE message DROP COLUMN date_edited")
+        assert writable_db.open().query_messages_changed_after(0) == []
+
+
+class TestTextCache:
+    """search_messages keeps the decoded text cache current across calls."""
+
+    def test_new_and_edited_messages(self, writable_db, tmp_path):
+        db, cache = writable_db.open(), str(tmp_path / "text.db")
+        matches = lambda term: {hit.message.rowid for hit in db.search_messages(term, text_cache=cache)}
+        matches("think")
+
+        # New text only found in attributedBody is decoded into the cache
+        rowid = writable_db.apply(lambda conn: _copy_message(conn, 128589, guid="'ATTRIBUTED-ONLY'", text="NULL"))
+        assert rowid in matches("think")
+
+        # An edit replaces the cached text
+        writable_db.execute(
+            "UPDATE message SET attributedBody = (SELECT attributedBody FROM message WHERE ROWID = 128606), "
+            "date_edited = 800000000000000000 WHERE ROWID = ?",
+            [rowid],
+        )
+        assert rowid not in matches("think")
+        assert rowid in matches("apwg")
+
+
+class TestLazyText:
+    """Lazily loaded messages decode their text in batches on first access."""
+
+    def test_lazy_text_matches_eager(self, sample_db_path):
+        lazy = imessage_bridge.IMessageDB(sample_db_path).get_all_messages()
+        eager = imessage_bridge.IMessageDB(sample_db_path, eager_text=True).get_all_messages()
+        assert [(m.rowid, m.text, m.kind) for m in lazy] == [(m.rowid, m.text, m.kind) for m in eager]
+
+    def test_payloads_and_files_match_eager(self, writable_db):
+        def payloads(m):
+            return (
+                m.rowid,
+                [(e.part, e.date, e.text) for e in m.edit_history],
+                m.apple_pay and (m.apple_pay.amount, m.apple_pay.currency, m.apple_pay.transaction_type),
+                m.digital_touch,
+                m.audio_duration,
+                m.sticker and (m.sticker.attachment_rowid, m.sticker.source),
+                m.location and m.location.kind,
+                m.group_photo_attachment,
+            )
+
+        # A group photo change, whose new image is looked up per message
+        rowid = writable_db.apply(
+            lambda conn: _copy_message(conn, 128599, guid="'group-photo'", item_type="3", group_action_type="1")
+        )
+        writable_db.execute("INSERT INTO message_attachment_join (message_id, attachment_id) VALUES (?, 7732)", [rowid])
+
+        lazy = writable_db.open().get_all_messages()
+        eager = writable_db.open(eager_text=True).get_all_messages()
+        assert [payloads(m) for m in lazy] == [payloads(m) for m in eager]
+        assert next(m for m in lazy if m.rowid == rowid).group_photo_attachment == 7732
+
+    def test_one_query_decodes_a_result_set(self, sample_db_path):
+        messages = imessage_bridge.IMessageDB(sample_db_path).get_all_messages(100)
+        imessage_bridge.reset_perf_stats()
+        imessage_bridge.set_perf_tracing(True)
+        try:
+            texts = [m.text for m in messages]
+            stats = imessage_bridge.get_perf_stats()
+        finally:
+            imessage_bridge.set_perf_tracing(False)
+        assert any(texts)
+        assert sum(s.calls for s in stats) == 1
+        assert stats[0].rows == len(messages)
+
+
+class TestFetchParallel:
+    """fetch_parallel returns what fetch does, however the work is split."""
+
+    @pytest.mark.parametrize("shards, threads", [(1, 1), (3, 2), (16, 4), (None, None)])
+    def test_matches_fetch(self, db, shards, threads):
+        expected = [m.rowid for m in db.messages().fetch()]
+        for _ in range(3):  # Later calls reuse the pool started by the first
+            assert [m.rowid for m in db.messages().fetch_parallel(shards, threads)] == expected
+
+    def test_descending_with_limit(self, db):
+        query = db.messages().order("desc").limit(10)
+        assert [m.rowid for m in query.fetch_parallel(8, 2)] == [m.rowid for m in query.fetch()]
+
+    def test_limit_bounds_decoded_rows(self, db):
+        query = db.messages().attach_reactions().limit(5)
+        imessage_bridge.reset_perf_stats()
+        imessage_bridge.set_perf_tracing(True)
+        try:
+            messages = query.fetch_parallel(16, 2)
+            stats = imessage_bridge.get_perf_stats()
+        finally:
+            imessage_bridge.set_perf_tracing(False)
+        assert [m.rowid for m in messages] == [m.rowid for m in query.fetch()]
+        # Each running shard reads at most the 5 rows still missing
+        assert 5 <= sum(s.rows for s in stats) <= 5 * 2 < db.messages().count()
+
+
+class TestSqliteMirror:
+    """export_sqlite only replaces its target with a complete mirror."""
+
+    def test_failed_export_keeps_previous_file(self, writable_db, tmp_path):
+        db = writable_db.open()
+        mirror = tmp_path / "mirror.db"
+        written = db.export_sqlite(str(mirror))
+
+        # Attachments are read while messages are written, after chats and handles
+        writable_db.execute("ALTER TABLE message_attachment_join RENAME TO moved")
+        with pytest.raises(RuntimeError):
+            db.export_sqlite(str(mirror))
+
+        assert sorted(p.name for p in tmp_path.iterdir()) == ["chat.db", "mirror.db"]
+        with sqlite3.connect(mirror) as conn:
+            assert conn.execute("SELECT COUNT(*) FROM messages").fetchone()[0] == written
+
+
+class TestAttachReactions:
+    """Folding tapbacks gives the same result whichever way messages are sorted."""
+
+    @staticmethod
+    def _folded(messages):
+        return [(m.rowid, [r.rowid for r in m.reactions]) for m in messages]
+
+    def test_fetch_descending(self, db):
+        ascending = self._folded(db.messages().attach_reactions().fetch())
+        assert any(reactions for _, reactions in ascending)
+        query = db.messages().attach_reactions().order("desc")
+        assert self._folded(query.fetch()) == ascending[::-1]
+        assert self._folded(query.fetch_parallel(4, 2)) == ascending[::-1]
+
+    def test_query_window_descending(self, db):
+        ascending = self._folded(db.query_messages_after(0.0, attach_reactions=True))
+        descending = self._folded(db.query_messages_after(0.0, attach_reactions=True, order="desc"))
+        assert any(reactions for _, reactions in ascending)
+        assert descending == ascending[::-1]
+
+
+class TestThreadMessages:
+    """get_thread_messages returns a whole thread from any of its messages."""
+
+    ROOT_GUID = "16706557-525C-4F31-8658-156500D7DF62"
+    REPLY_GUID = "5834EE0C-EB40-4D41-A17F-81762784E005"
+
+    def test_reply_guid_finds_the_thread(self, writable_db):
+        tapback = lambda guid, target: writable_db.apply(lambda conn: _copy_message(
+            conn,
+            128601,
+            guid=f"'{guid}'",
+            associated_message_guid=f"'{target}'",
+            date="(SELECT MAX(date) FROM message) + 1",
+        ))
+        on_reply = tapback("REPLY-TAPBACK", f"p:0/{self.REPLY_GUID}")
+        on_root = tapback("ROOT-TAPBACK", f"bp:{self.ROOT_GUID}")
+        db = writable_db.open()
+
+        thread = [(m.rowid, [r.rowid for r in m.reactions]) for m in db.get_thread_messages(self.REPLY_GUID)]
+        assert thread == [(128612, [on_root]), (128617, [on_reply])]
+        assert [m.rowid for m in db.get_thread_messages(self.ROOT_GUID)] == [128612, 128617]
+        assert db.get_thread_messages("NO-SUCH-GUID") == []
+
+
+class TestSearchMessages:
+    """search_messages rejects terms that would match every message."""
+
+    @pytest.mark.parametrize("term", ["", "\u0301", "\u0301\u0308"])
+    def test_empty_terms(self, db, term):
+        with pytest.raises(ValueError):
+            db.search_messages(term, normalize=True)
+
+    def test_accents_are_folded(self, db):
+        rowids = lambda term: [hit.message.rowid for hit in db.search_messages(term, normalize=True)]
+        assert rowids("sämple") == rowids("sample") != []
+
+
+class TestSearchRegex:
+    """search_regex reports matches as character offsets into message.text."""
+
+    TEXT = "Café 🎉👍🏽 order id-42, then id-7 ñ"
+
+    @pytest.fixture
+    def db_with_multibyte_text(self, writable_db):
+        writable_db.apply(
+            lambda conn: _copy_message(conn, 128589, guid="'MULTIBYTE'", text=f"'{self.TEXT}'", attributedBody="NULL")
+        )
+        return writable_db.open()
+
+    def test_offsets_count_characters(self, db_with_multibyte_text):
+        matches = db_with_multibyte_text.search_regex(r"id-(?P<number>\d+)")
+        assert [(m.matched, m.groups, m.named_groups) for m in matches] == [
+            ("id-42", ["42"], {"number": "42"}),
+            ("id-7", ["7"], {"number": "7"}),
+        ]
+        for match in matches:
+            assert match.message.text == self.TEXT
+            assert match.message.text[match.start:match.end] == match.matched
+
+    def test_limit_counts_matches_not_messages(self, db_with_multibyte_text):
+        assert [m.matched for m in db_with_multibyte_text.search_regex(r"id-\d+", limit=1)] == ["id-42"]
+        assert len(db_with_multibyte_text.search_regex(r"id-\d+", limit=5)) == 2
+        assert db_with_multibyte_text.search_regex(r"id-\d+", limit=0) == []
+
+    def test_invalid_pattern(self, db):
+        with pytest.raises(ValueError):
+            db.search_regex("(unclosed")
+
+
+class TestExportJsonl:
+    """export_jsonl writes one message per line, with tapbacks folded and senders resolved."""
+
+    TAPBACK_ROWID = 128601
+
+    @staticmethod
+    def _lines(path):
+        with open(path) as f:
+            return [json.loads(line) for line in f]
+
+    def test_lines_match_fetch(self, db, tmp_path):
+        path = tmp_path / "messages.jsonl"
+        written = db.export_jsonl(str(path))
+        lines = self._lines(path)
+        assert written == len(lines)
+
+        fetched = db.messages().attach_reactions().fetch()
+        assert [line["rowid"] for line in lines] == [m.rowid for m in fetched]
+        assert [line["text"] for line in lines] == [m.text for m in fetched]
+        assert self.TAPBACK_ROWID not in {line["rowid"] for line in lines}
+        assert [r["rowid"] for line in lines for r in line["reactions"]] == [self.TAPBACK_ROWID]
+
+    def test_handles_are_resolved(self, db, tmp_path):
+        path = tmp_path / "messages.jsonl"
+        db.export_jsonl(str(path))
+        for line in self._lines(path):
+            if line["handle_id"]:
+                assert line["handle"]["rowid"] == line["handle_id"]
+            else:
+                assert line["handle"] is None
+        assert any(line["handle"] for line in self._lines(path))
+
+    def test_filters_and_limit(self, db, tmp_path):
+        path = tmp_path / "messages.jsonl"
+        assert db.messages().limit(5).export_jsonl(str(path)) == 5
+        assert [line["rowid"] for line in self._lines(path)] == [m.rowid for m in db.messages().limit(5).fetch()]
+
+        written = db.export_jsonl(str(path), db.messages().in_chat(1207))
+        expected = [m.rowid for m in db.messages().in_chat(1207).attach_reactions().fetch()]
+        assert written == len(expected) > 0
+        assert [line["rowid"] for line in self._lines(path)] == expected
+
+
+class TestExportAttachments:
+    """export_attachments writes each distinct file once and lists every attachment in the manifest."""
+
+    @pytest.fixture
+    def db_with_files(self, writable_db, tmp_path):
+        files = tmp_path / "files"
+        files.mkdir()
+        for name in ("first.png", "second.png"):
+            (files / name).write_bytes(b"\x89PNG same contents")
+        writable_db.execute("UPDATE attachment SET filename = ? WHERE ROWID = 7732", [str(files / "first.png")])
+        writable_db.execute("UPDATE attachment SET filename = ? WHERE ROWID = 7733", [str(files / "second.png")])
+        return writable_db.open()
+
+    @staticmethod
+    def _manifest(summary):
+        with open(summary.manifest_path) as f:
+            return {entry["attachment_rowid"]: entry for entry in json.load(f)}
+
+    def test_duplicates_point_at_the_first_copy(self, db_with_files, tmp_path):
+        dest = tmp_path / "export"
+        summary = db_with_files.export_attachments(str(dest))
+        assert (summary.exported, summary.duplicates, summary.unavailable) == (1, 1, 0)
+        assert Path(summary.manifest_path) == dest / "manifest.json"
+
+        manifest = self._manifest(summary)
+        first, second = manifest[7732], manifest[7733]
+        assert first["duplicate_of"] is None and first["error"] is None
+        assert second["duplicate_of"] == second["path"] == first["path"]
+        assert first["sha256"] == second["sha256"]
+        assert (first["message_rowid"], second["message_rowid"]) == (128599, 128604)
+        assert Path(first["path"]).read_bytes() == b"\x89PNG same contents"
+        assert sorted(p.name for p in dest.rglob("*") if p.is_file()) == sorted(["manifest.json", Path(first["path"]).name])
+
+    def test_without_deduplicate(self, db_with_files, tmp_path):
+        summary = db_with_files.export_attachments(str(tmp_path / "export"), deduplicate=False)
+        assert (summary.exported, summary.duplicates) == (2, 0)
+        manifest = self._manifest(summary)
+        assert manifest[7732]["path"] != manifest[7733]["path"]
+        assert all(entry["duplicate_of"] is None for entry in manifest.values())
+
+    def test_missing_files_are_listed_with_their_reason(self, db, tmp_path):
+        summary = db.export_attachments(str(tmp_path / "export"))
+        assert (summary.exported, summary.unavailable) == (0, 2)
+        manifest = self._manifest(summary)
+        assert sorted(manifest) == [7732, 7733]
+        assert all(entry["path"] is None and entry["error"] for entry in manifest.values())
+
+
+class TestAttachmentStats:
+    """attachment_stats counts each attachment toward the chat and year of its earliest message."""
+
+    def test_resent_attachment_counts_where_first_sent(self, writable_db):
+        # Forward attachment 7732 into chat 44, whose ID sorts before chat 1207, two years later
+        resent = writable_db.apply(
+            lambda conn: _copy_message(conn, 128620, guid="'RESENT'", date="date + 2 * 365 * 86400000000000")
+        )
+        writable_db.execute("INSERT INTO message_attachment_join (message_id, attachment_id) VALUES (?, 7732)", [resent])
+        stats = writable_db.open().attachment_stats()
+
+        assert stats.attachment_count == 2
+        assert stats.bytes_by_chat == {1207: stats.total_bytes}
+        assert stats.bytes_by_year == {2025: stats.total_bytes}